@@ -0,0 +1,78 @@
+//! Run with `cargo bench --bench search`
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use jieba_rs::Jieba;
+use mote::service::kv_service::MemoryStore;
+use mote::service::search_service::{top_k, FullTextSearch};
+use std::sync::Arc;
+
+/// Pseudo-random scores, so that runs are comparable
+fn scores(n: usize) -> Vec<(i64, f64)> {
+    let mut seed: u64 = 42;
+    (0..n as i64)
+        .map(|id| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (id, (seed >> 11) as f64 / (1u64 << 53) as f64)
+        })
+        .collect()
+}
+
+fn bench_top_k(c: &mut Criterion) {
+    let hits = scores(100_000);
+    let mut group = c.benchmark_group("top 20 of 100k hits");
+
+    group.bench_function("sort", |b| {
+        b.iter_batched(
+            || hits.clone(),
+            |mut hits| {
+                hits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+                hits.truncate(20);
+                black_box(hits)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("heap", |b| {
+        b.iter_batched(
+            || hits.clone(),
+            |hits| black_box(top_k(hits, 20)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_search(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let fts = FullTextSearch::new(
+        Arc::new(MemoryStore::new()),
+        Arc::new(Jieba::new()),
+        "bench:".to_string(),
+    );
+    rt.block_on(async {
+        let words = ["rust", "python", "notes", "search", "index", "memo"];
+        for id in 0..20_000 {
+            let text = format!(
+                "common {} {} {}",
+                words[id % words.len()],
+                words[id % 5],
+                "word ".repeat(id % 7 + 1)
+            );
+            fts.index(id as i64, &text).await.unwrap();
+        }
+    });
+
+    let mut group = c.benchmark_group("search 20k docs");
+    group.sample_size(20);
+    group.bench_function("limit 20", |b| {
+        b.to_async(&rt)
+            .iter(|| async { black_box(fts.search("common", false, 20).await.unwrap()) })
+    });
+    group.bench_function("no limit", |b| {
+        b.to_async(&rt)
+            .iter(|| async { black_box(fts.search("common", false, 0).await.unwrap()) })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_top_k, bench_search);
+criterion_main!(benches);
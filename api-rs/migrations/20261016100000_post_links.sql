@@ -0,0 +1,12 @@
+-- Internal links between posts, used to protect link targets from hard deletion
+
+CREATE TABLE IF NOT EXISTS post_links
+(
+  source_id INTEGER NOT NULL,
+  target_id INTEGER NOT NULL,
+  PRIMARY KEY (source_id, target_id),
+  FOREIGN KEY (source_id) REFERENCES posts (id) ON DELETE CASCADE,
+  FOREIGN KEY (target_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_post_links_target_id ON post_links (target_id);
@@ -0,0 +1,11 @@
+-- Uploaded files, addressed by the SHA-256 hash of their content
+
+CREATE TABLE IF NOT EXISTS files
+(
+  id            TEXT PRIMARY KEY NOT NULL,
+  filename      TEXT             NOT NULL,
+  original_name TEXT             NOT NULL,
+  content_type  TEXT,
+  size          BIGINT           NOT NULL,
+  created_at    BIGINT           NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Searchable text extracted from the file (pdf text, plain text or OCR), cached for reindexing
+
+ALTER TABLE files ADD COLUMN text TEXT;
@@ -0,0 +1,12 @@
+-- Security relevant events, such as the results of scanning uploads
+
+CREATE TABLE IF NOT EXISTS audit_logs
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT,
+  action     TEXT   NOT NULL,
+  target     TEXT   NOT NULL,
+  detail     TEXT,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_logs_created_at ON audit_logs (created_at);
@@ -0,0 +1,10 @@
+-- Old tag names, so that links and filters with them still work after a rename
+
+CREATE TABLE IF NOT EXISTS tag_renames
+(
+  old_name   TEXT PRIMARY KEY,
+  new_name   TEXT   NOT NULL,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_tag_renames_new_name ON tag_renames (new_name);
@@ -0,0 +1,3 @@
+-- Manual order of tags, e.g. of the pinned ones in the sidebar
+
+ALTER TABLE tags ADD COLUMN sort_order INTEGER NOT NULL DEFAULT 0;
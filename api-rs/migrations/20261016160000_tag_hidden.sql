@@ -0,0 +1,3 @@
+-- Hidden tags, and their subtags, are left out of the tag list and the default timeline
+
+ALTER TABLE tags ADD COLUMN hidden BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- Emoji reactions on posts, one of each emoji per reactor
+
+CREATE TABLE IF NOT EXISTS post_reactions
+(
+  post_id    INTEGER NOT NULL,
+  emoji      TEXT    NOT NULL,
+  reactor    TEXT    NOT NULL,
+  created_at BIGINT  NOT NULL,
+  PRIMARY KEY (post_id, emoji, reactor),
+  FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+-- Indexes for listing posts, see `Post::filter_posts`
+
+CREATE INDEX IF NOT EXISTS idx_posts_deleted_at_created_at ON posts (deleted_at, created_at);
+CREATE INDEX IF NOT EXISTS idx_posts_deleted_at_updated_at ON posts (deleted_at, updated_at);
+CREATE INDEX IF NOT EXISTS idx_posts_parent_id ON posts (parent_id);
+
+-- The primary key (tag_id, post_id) covers the lookups by tag
+CREATE INDEX IF NOT EXISTS idx_tag_post_assoc_post_id ON tag_post_assoc (post_id, tag_id);
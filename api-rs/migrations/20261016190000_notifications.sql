@@ -0,0 +1,12 @@
+-- Outcomes of operations running in the background, such as failed jobs
+
+CREATE TABLE IF NOT EXISTS notifications
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT,
+  kind       TEXT   NOT NULL,
+  message    TEXT   NOT NULL,
+  read_at    BIGINT,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_notifications_created_at ON notifications (created_at);
@@ -0,0 +1,3 @@
+-- How a shared post is shown on its public page, as a JSON object
+
+ALTER TABLE posts ADD COLUMN share_options TEXT;
@@ -0,0 +1,3 @@
+-- The address of the client which made the change, behind trusted proxies
+
+ALTER TABLE audit_logs ADD COLUMN ip TEXT;
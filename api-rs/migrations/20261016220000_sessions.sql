@@ -0,0 +1,11 @@
+-- The logins, a token is kept only as its SHA-256 hash
+
+CREATE TABLE IF NOT EXISTS sessions
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT,
+  token_hash TEXT   NOT NULL UNIQUE,
+  created_at BIGINT NOT NULL,
+  expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_expires_at ON sessions (expires_at);
@@ -0,0 +1,5 @@
+-- The device of a session, and where it was last used from
+
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip TEXT;
+ALTER TABLE sessions ADD COLUMN last_seen_at BIGINT;
@@ -0,0 +1,12 @@
+-- Passkeys which can log in instead of the password
+
+CREATE TABLE IF NOT EXISTS passkeys
+(
+  id            INTEGER PRIMARY KEY AUTOINCREMENT,
+  credential_id TEXT    NOT NULL UNIQUE,
+  name          TEXT    NOT NULL,
+  public_key    BLOB    NOT NULL,
+  sign_count    INTEGER NOT NULL DEFAULT 0,
+  created_at    BIGINT  NOT NULL,
+  last_used_at  BIGINT
+);
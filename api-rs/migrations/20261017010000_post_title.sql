@@ -0,0 +1,5 @@
+-- The title is derived from the first heading of the content when a post is saved,
+-- unless it is set by the client, then `title_custom` is set and it is kept on content changes
+
+ALTER TABLE posts ADD COLUMN title TEXT;
+ALTER TABLE posts ADD COLUMN title_custom BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,63 @@
+-- Users, each with their own posts and tags. The admin user, created on startup with the configured password,
+-- gets the id 1, so the data of the single user before owns it.
+
+CREATE TABLE IF NOT EXISTS users
+(
+  id            INTEGER PRIMARY KEY AUTOINCREMENT,
+  name          TEXT    NOT NULL UNIQUE,
+  password_hash TEXT    NOT NULL,
+  admin         BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at    BIGINT  NOT NULL
+);
+
+ALTER TABLE posts ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+CREATE INDEX IF NOT EXISTS idx_posts_user_id ON posts (user_id, deleted_at, created_at);
+
+ALTER TABLE sessions ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE passkeys ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+
+-- Who made the change, the post actions before were all made by the single user
+ALTER TABLE audit_logs ADD COLUMN user_id INTEGER;
+UPDATE audit_logs SET user_id = 1 WHERE action LIKE 'post.%';
+
+-- The tags table is rebuilt to make tag names unique for each user. Dropping it deletes its associations
+-- in cascade, the foreign keys cannot be turned off in the transaction of a migration, so they are kept aside.
+CREATE TEMP TABLE tag_post_assoc_kept AS SELECT tag_id, post_id FROM tag_post_assoc;
+
+CREATE TABLE tags_new
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  user_id    INTEGER                           NOT NULL,
+  name       TEXT                              NOT NULL,
+  sticky     BOOLEAN                           NOT NULL DEFAULT FALSE,
+  created_at BIGINT                            NOT NULL,
+  updated_at BIGINT                            NOT NULL,
+  sort_order INTEGER                           NOT NULL DEFAULT 0,
+  hidden     BOOLEAN                           NOT NULL DEFAULT FALSE,
+  CONSTRAINT uq_tags_user_id_name UNIQUE (user_id, name)
+);
+
+INSERT INTO tags_new (id, user_id, name, sticky, created_at, updated_at, sort_order, hidden)
+SELECT id, 1, name, sticky, created_at, updated_at, sort_order, hidden FROM tags;
+
+DROP TABLE tags;
+ALTER TABLE tags_new RENAME TO tags;
+
+INSERT INTO tag_post_assoc (tag_id, post_id) SELECT tag_id, post_id FROM tag_post_assoc_kept;
+DROP TABLE tag_post_assoc_kept;
+
+CREATE TABLE tag_renames_new
+(
+  user_id    INTEGER NOT NULL,
+  old_name   TEXT    NOT NULL,
+  new_name   TEXT    NOT NULL,
+  created_at BIGINT  NOT NULL,
+  PRIMARY KEY (user_id, old_name)
+);
+
+INSERT INTO tag_renames_new (user_id, old_name, new_name, created_at)
+SELECT 1, old_name, new_name, created_at FROM tag_renames;
+
+DROP TABLE tag_renames;
+ALTER TABLE tag_renames_new RENAME TO tag_renames;
+CREATE INDEX IF NOT EXISTS idx_tag_renames_new_name ON tag_renames (user_id, new_name);
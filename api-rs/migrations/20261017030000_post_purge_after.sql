@@ -0,0 +1,4 @@
+-- When a post in the trash is deleted permanently, if it should not wait until the trash is purged,
+-- e.g. for sensitive notes. It is cleared when the post is restored.
+
+ALTER TABLE posts ADD COLUMN purge_after BIGINT;
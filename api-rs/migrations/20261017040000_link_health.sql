@@ -0,0 +1,18 @@
+-- The results of checking the external links of shared posts, replaced by each run of the check-links job
+
+CREATE TABLE IF NOT EXISTS link_health
+(
+  post_id    INTEGER NOT NULL,
+  url        TEXT    NOT NULL,
+  -- The HTTP status, none if the request failed
+  status     INTEGER,
+  -- Where a redirect leads to
+  location   TEXT,
+  error      TEXT,
+  ok         BOOLEAN NOT NULL,
+  checked_at BIGINT  NOT NULL,
+  PRIMARY KEY (post_id, url),
+  FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_link_health_ok ON link_health (ok, post_id);
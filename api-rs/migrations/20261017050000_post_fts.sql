@@ -0,0 +1,15 @@
+-- The search index of the SQLite backend, see `SEARCH_BACKEND`.
+-- The rowid is the id of a post, and the tokens are analyzed in the app, separated by spaces,
+-- so that Chinese is segmented and stemming applies like in the Redis index.
+
+CREATE VIRTUAL TABLE IF NOT EXISTS post_fts USING fts5
+(
+  tokens,
+  tokenize = 'unicode61 remove_diacritics 0'
+);
+
+CREATE TABLE IF NOT EXISTS search_meta
+(
+  key   TEXT PRIMARY KEY,
+  value TEXT NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Who uploaded the file, the files before were all uploaded by the admin user.
+-- A file uploaded again by another user keeps its first owner, since its content is stored once.
+
+ALTER TABLE files ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+CREATE INDEX IF NOT EXISTS idx_files_user_id ON files (user_id);
@@ -0,0 +1,16 @@
+-- Each upload of a file, with the name it was uploaded with. The content of a file is stored once,
+-- so the same content uploaded again gets an upload of its own, which keeps its name.
+
+CREATE TABLE IF NOT EXISTS file_uploads
+(
+  id            TEXT PRIMARY KEY NOT NULL,
+  file_id       TEXT             NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  user_id       INTEGER          NOT NULL,
+  original_name TEXT             NOT NULL,
+  created_at    BIGINT           NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_file_uploads_file_id ON file_uploads (file_id);
+
+-- The files uploaded before are downloaded by the hash of their content
+INSERT INTO file_uploads (id, file_id, user_id, original_name, created_at)
+SELECT id, id, user_id, original_name, created_at FROM files;
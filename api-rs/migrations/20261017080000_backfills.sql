@@ -0,0 +1,9 @@
+-- The data fixes run once by the app at startup, each one is removed once done
+
+CREATE TABLE IF NOT EXISTS backfills
+(
+  name TEXT PRIMARY KEY NOT NULL
+);
+
+-- The links of the posts saved before links were recorded
+INSERT OR IGNORE INTO backfills (name) VALUES ('post_links');
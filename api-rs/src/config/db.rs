@@ -1,32 +1,136 @@
-use ::anyhow::Result;
+use crate::config::DBConfig;
+use ::anyhow::{bail, Result};
 use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqliteSynchronous};
 use std::ops::Deref;
 use std::str::FromStr;
+use std::time::Duration;
 
+/// The connection pools, which are shared across the entire application.
+///
+/// SQLite allows only one writer at a time, so writes go through a single connection,
+/// where they wait in turn instead of failing with `SQLITE_BUSY`. Reads use the other pool,
+/// and are not blocked by writes in WAL mode.
 pub struct DB {
+    /// The pool for reads
     pub pool: SqlitePool,
+    /// The pool with a single connection for writes
+    pub writer: SqlitePool,
 }
 
 impl DB {
-    // Create a single connection pool for SQLx that is shared across the entire application.
-    // This prevents the need to open a new connection for every API call, which would be wasteful.
-    pub async fn new(url: &str, pool_size: u32) -> Result<Self> {
-        let opts = SqliteConnectOptions::from_str(url)?
+    pub async fn new(config: &DBConfig) -> Result<Self> {
+        let mut opts = SqliteConnectOptions::from_str(&config.url)?;
+        // sqlx runs the `key` pragma before the others, as SQLCipher requires
+        if let Some(ref key) = config.key {
+            opts = opts.pragma("key", quote(key));
+        }
+        let opts = opts
             .journal_mode(SqliteJournalMode::Wal)
-            .foreign_keys(true);
+            .foreign_keys(true)
+            .busy_timeout(Duration::from_millis(config.busy_timeout_ms))
+            .synchronous(SqliteSynchronous::from_str(&config.synchronous)?);
+        // The WAL must not be checkpointed before its frames are replicated
+        let opts = if config.auto_checkpoint {
+            opts
+        } else {
+            opts.pragma("wal_autocheckpoint", "0")
+        };
+
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(opts.clone())
+            .await?;
+
+        // Each connection to an in-memory database opens a new database
+        if config.url.contains(":memory:") || config.url.contains("mode=memory") {
+            return Ok(DB {
+                pool: writer.clone(),
+                writer,
+            });
+        }
 
         let pool = SqlitePoolOptions::new()
-            .max_connections(pool_size)
+            .max_connections(config.pool_size)
             .connect_with(opts)
             .await?;
-        Ok(DB { pool })
+        Ok(DB { pool, writer })
     }
 
     pub async fn migrate(&self) -> Result<()> {
-        sqlx::migrate!("./migrations").run(&self.pool).await?;
+        sqlx::migrate!("./migrations").run(&self.writer).await?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file and truncates the WAL, which may grow large on long-running instances.
+    /// It blocks other writers until it is done.
+    pub async fn optimize(&self) -> Result<()> {
+        let mut conn = self.writer.acquire().await?;
+        sqlx::query("PRAGMA optimize").execute(&mut *conn).await?;
+        sqlx::query("VACUUM").execute(&mut *conn).await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&mut *conn)
+            .await?;
         Ok(())
     }
+
+    /// Writes a consistent copy of the database to the path, which must not exist.
+    /// Readers and writers are not blocked while copying.
+    pub async fn backup(&self, path: &str) -> Result<()> {
+        sqlx::query("VACUUM INTO ?")
+            .bind(path)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Encrypts the database with a new key, the database must be opened with the current one.
+    /// It only works with SQLCipher, see the `sqlcipher` feature, and with a database which is
+    /// encrypted already, since SQLCipher cannot encrypt a plaintext database in place.
+    pub async fn rekey(&self, new_key: &str) -> Result<()> {
+        let mut conn = self.writer.acquire().await?;
+        // Without SQLCipher the pragmas are ignored, and the database would stay as it is
+        let cipher: Option<String> = sqlx::query_scalar("PRAGMA cipher_version")
+            .fetch_optional(&mut *conn)
+            .await?;
+        if cipher.is_none_or(|version| version.is_empty()) {
+            bail!("The app is not built with SQLCipher");
+        }
+        // SQLCipher cannot rekey a database in WAL mode
+        sqlx::query("PRAGMA journal_mode = DELETE")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(&format!("PRAGMA rekey = {}", quote(new_key)))
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("PRAGMA journal_mode = WAL")
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the path of the database file, `None` for an in-memory database.
+    pub async fn path(&self) -> Result<Option<String>> {
+        let path: String =
+            sqlx::query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main'")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok((!path.is_empty()).then_some(path))
+    }
+
+    /// Returns the sizes of the database file and its WAL file in bytes.
+    pub async fn file_sizes(&self) -> Result<(u64, u64)> {
+        let Some(path) = self.path().await? else {
+            return Ok((0, 0));
+        };
+
+        let size = tokio::fs::metadata(&path).await?.len();
+        let wal_size = tokio::fs::metadata(format!("{}-wal", path))
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+        Ok((size, wal_size))
+    }
 }
 
 impl Deref for DB {
@@ -36,3 +140,8 @@ impl Deref for DB {
         &self.pool
     }
 }
+
+/// Quotes a key as a SQL string literal for the `key` and `rekey` pragmas.
+fn quote(key: &str) -> String {
+    format!("'{}'", key.replace('\'', "''"))
+}
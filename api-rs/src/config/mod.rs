@@ -1,14 +1,26 @@
-use crate::util::env::{get_env_or, get_size_from_env_or, get_vec_from_env_or, load_dotenv};
+use crate::config::registry::{read, read_list, read_size};
+use crate::util::env::load_dotenv;
+use crate::util::net::IpNet;
+use crate::util::retry::{CircuitBreaker, RetryPolicy};
+use argon2::PasswordHash;
+use rust_stemmers::Algorithm;
+use sqlx::sqlite::SqliteSynchronous;
+use std::env;
+use std::fmt;
 use std::fmt::Debug;
 use std::fs;
 use std::net::IpAddr;
 use std::path::Path;
 use std::str::FromStr;
 use std::time::Duration;
+use tokio_cron_scheduler::Job;
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+use tracing::warn;
 
 pub mod db;
 pub mod rd;
+pub mod registry;
+pub mod telemetry;
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
@@ -18,17 +30,75 @@ pub struct AppConfig {
 
     // App settings
     pub posts_per_page: u32,
+    /// The max size of the content of a post in bytes
+    pub max_content_size: u64,
+    /// Content in post lists is truncated to this many chars, 0 to disable
+    pub list_content_length: usize,
+    /// How deep replies may be nested, 0 means unlimited
+    pub max_thread_depth: usize,
+    /// How long an edit lock lasts without a heartbeat, in seconds
+    pub post_lock_ttl_secs: u64,
+    /// Whether warnings about the content are returned when posts are saved
+    pub lint_posts: bool,
+    /// Inline images larger than this many bytes are reported by the lint
+    pub max_inline_image_size: u64,
+    /// Whether the images pasted into posts as data uris are stored as uploads
+    pub extract_inline_images: bool,
     pub static_url: String,
     pub static_path: String,
+    /// A link to the author shown on shared pages
+    pub about_url: String,
 
     // Server settings
+    pub auth: AuthConfig,
     pub http: HTTPConfig,
     pub upload: UploadConfig,
+    pub ocr: OcrConfig,
+    pub scan: ScanConfig,
+    pub demo: DemoConfig,
+    pub slack: SlackConfig,
+    pub notify: NotifyConfig,
+    pub jobs: JobsConfig,
+    pub search: SearchConfig,
     pub db: DBConfig,
+    pub replica: ReplicaConfig,
     pub redis: RedisConfig,
     pub log: LogConfig,
 }
 
+/// The problems found by [`AppConfig::check`]
+#[derive(Debug, Default)]
+pub struct ConfigIssues {
+    /// The app cannot start with these
+    pub errors: Vec<String>,
+    /// Optional features do not work
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// The name of the admin user, who is created on startup and owns the posts made before there were users
+    pub admin_name: String,
+    /// An argon2id hash of the password of the admin user, generated by `mote hash-password`
+    pub password_hash: String,
+    /// The password in plain text, deprecated in favor of `password_hash`
+    pub password: Option<String>,
+    /// How long a login lasts, in seconds
+    pub session_ttl_secs: u64,
+    /// Accept the password of the admin user as a token, as older clients send it on every request
+    pub legacy_password_token: bool,
+    /// The cookie the token is read from, the `Authorization` header is checked otherwise
+    pub cookie_name: String,
+    /// The domain passkeys are registered for, e.g. `example.com`, empty to disable passkeys
+    pub passkey_rp_id: String,
+    /// The origins passkeys are used from, `https://{passkey_rp_id}` if empty
+    pub passkey_origins: Vec<String>,
+    /// An argon2id hash of the token of the admin routes, which accept a login if empty
+    pub admin_token_hash: String,
+    /// The addresses the admin routes are served to, all if empty
+    pub admin_allowed_ips: Vec<IpNet>,
+}
+
 #[derive(Debug, Clone)]
 pub struct HTTPConfig {
     pub ip: String,
@@ -37,6 +107,10 @@ pub struct HTTPConfig {
     pub read_timeout_secs: u64,
     pub write_timeout_secs: u64,
     pub idle_timeout_secs: u64,
+    /// Serve `/api/foo/` the same as `/api/foo`
+    pub trim_trailing_slash: bool,
+    /// The reverse proxies whose `X-Forwarded-*` headers are trusted
+    pub trusted_proxies: Vec<IpNet>,
     pub cors: CORSConfig,
 }
 
@@ -45,19 +119,184 @@ pub struct UploadConfig {
     pub base_path: String,
     pub base_url: String,
     pub thumb_width: u32,
+    pub animated_thumb: bool,
+    pub thumb_max_frames: u32,
     pub image_formats: Vec<String>,
+    /// Whether the GPS coordinates of photos are read from their EXIF, they are never shared
+    pub exif_location: bool,
+    /// The widths of the larger thumbnails of images, e.g. for galleries
+    pub variant_widths: Vec<u32>,
+    /// The format of the larger thumbnails, `original`, `webp` or `avif`
+    pub variant_format: String,
+    /// Command converting HEIC images, empty to keep them as they are
+    pub heic_converter: String,
+    /// Format of converted HEIC images, jpeg or webp
+    pub heic_format: String,
+    /// Total size of all uploaded files in bytes, 0 means unlimited
+    pub quota: u64,
+    /// Total size of the files uploaded first by each user in bytes, 0 means unlimited
+    pub user_quota: u64,
+    /// Where the chunks of resumable uploads are kept until they are complete
+    pub chunk_path: String,
+    /// The size of the chunks of resumable uploads in bytes
+    pub chunk_size: u64,
+    /// The largest file of resumable uploads in bytes, other uploads are limited by `http.max_body_size`
+    pub max_file_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OcrConfig {
+    pub provider: String,
+    pub tesseract_path: String,
+    pub languages: String,
+    pub api_url: String,
+    pub api_key: Option<String>,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    pub provider: String,
+    pub clamd_addr: String,
+    pub api_url: String,
+    pub api_key: Option<String>,
+    pub quarantine_path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DemoConfig {
+    /// Seeds sample data and resets everything periodically, for public playgrounds
+    pub enabled: bool,
+    pub reset_hours: u64,
+}
+
+#[derive(Clone)]
+pub struct SlackConfig {
+    /// The signing secret of the Slack app whose slash command creates posts, empty to disable it
+    pub signing_secret: String,
+    /// The Slack users who can create posts and the users they post as, e.g. `U012AB3CD=alice`
+    pub users: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct NotifyConfig {
+    /// Where notifications are sent out: smtp, ntfy, webhook and telegram
+    pub channels: Vec<String>,
+    /// The kinds of notifications sent out, e.g. `job.failed`
+    pub kinds: Vec<String>,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /// `tls`, `starttls` or `none`
+    pub smtp_security: String,
+    pub smtp_username: String,
+    pub smtp_password: String,
+    pub smtp_from: String,
+    pub smtp_to: Vec<String>,
+    /// The url of a topic, e.g. `https://ntfy.sh/my-topic`
+    pub ntfy_url: String,
+    pub ntfy_token: Option<String>,
+    pub webhook_url: String,
+    pub telegram_bot_token: String,
+    pub telegram_chat_id: String,
 }
 
 #[derive(Debug, Clone)]
+pub struct JobsConfig {
+    pub purge_trash: JobConfig,
+    pub collect_files: JobConfig,
+    pub backup: JobConfig,
+    pub reconcile_index: JobConfig,
+    pub check_links: JobConfig,
+    pub prune_uploads: JobConfig,
+    /// The directory where database backups are written to
+    pub backup_path: String,
+    /// How many backups are kept, the older ones are removed
+    pub backup_keep: usize,
+    /// Files not used by any post are pruned only if they were modified before this many hours
+    pub prune_grace_hours: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// A cron expression with seconds in local time, e.g. `0 0 3 * * *`
+    pub cron: String,
+    pub enabled: bool,
+}
+
+#[derive(Clone)]
 pub struct DBConfig {
     pub url: String,
     pub pool_size: u32,
     pub auto_migrate: bool,
+    /// The SQLCipher key to encrypt the database with
+    pub key: Option<String>,
+    /// How long to wait for a lock before failing with `SQLITE_BUSY`
+    pub busy_timeout_ms: u64,
+    /// One of `OFF`, `NORMAL`, `FULL` and `EXTRA`
+    pub synchronous: String,
+    /// Whether SQLite checkpoints the WAL by itself, the replication does it otherwise
+    pub auto_checkpoint: bool,
+}
+
+#[derive(Clone)]
+pub struct ReplicaConfig {
+    /// Where the WAL is replicated to, `s3://bucket/prefix` or `file:///path`, empty to disable
+    pub url: String,
+    /// The S3 endpoint, e.g. `http://localhost:9000` of MinIO, AWS if empty
+    pub endpoint: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// How often the new frames of the WAL are shipped
+    pub sync_interval_secs: u64,
+    /// The WAL is checkpointed when it grows larger than this, in bytes
+    pub checkpoint_size: u64,
+    /// How often a new generation is started with a snapshot of the database
+    pub snapshot_interval_secs: u64,
+}
+
+impl fmt::Debug for DBConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DBConfig")
+            .field("url", &self.url)
+            .field("pool_size", &self.pool_size)
+            .field("auto_migrate", &self.auto_migrate)
+            .field("key", &self.key.as_ref().map(|_| "***"))
+            .field("busy_timeout_ms", &self.busy_timeout_ms)
+            .field("synchronous", &self.synchronous)
+            .field("auto_checkpoint", &self.auto_checkpoint)
+            .finish()
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("admin_name", &self.admin_name)
+            .field("password_hash", &"***")
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("session_ttl_secs", &self.session_ttl_secs)
+            .field("legacy_password_token", &self.legacy_password_token)
+            .field("cookie_name", &self.cookie_name)
+            .field("passkey_rp_id", &self.passkey_rp_id)
+            .field("passkey_origins", &self.passkey_origins)
+            .field("admin_token_hash", &"***")
+            .field("admin_allowed_ips", &self.admin_allowed_ips)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct RedisConfig {
     pub url: String,
+    /// How many times idempotent operations are tried again after transient errors
+    pub retries: u32,
+    /// The delay before the first retry in milliseconds, doubled for each following one, with jitter
+    pub retry_delay_ms: u64,
+    /// The failures in a row after which Redis is not called for a while, 0 to disable
+    pub breaker_threshold: u32,
+    /// How long Redis is not called after the breaker opens, in seconds
+    pub breaker_cooldown_secs: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -69,49 +308,143 @@ pub struct CORSConfig {
     pub max_age: u64,
 }
 
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    /// Where the index is kept, `redis` or `sqlite`
+    pub backend: String,
+    /// At most this many matched posts are ranked, 0 means unlimited
+    pub max_candidates: usize,
+    /// At most this many posts are returned, even if a larger limit is requested, 0 means unlimited
+    pub max_results: usize,
+    /// How long ranking may take in milliseconds, 0 means no limit
+    pub timeout_ms: u64,
+    /// Remove the accents of Latin letters, so that "café" matches "cafe"
+    pub fold_diacritics: bool,
+    /// The language Latin words are stemmed in, e.g. `english`, empty to disable
+    pub stemmer: String,
+    /// Rebuild the index on startup if it is built with another version, otherwise only notify
+    pub auto_rebuild: bool,
+    /// A file of synonym groups, empty to disable
+    pub synonyms_path: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct LogConfig {
     pub log_requests: bool,
+    /// Query params whose values are not logged, e.g. search queries and tokens
+    pub redact_params: Vec<String>,
+    /// Log the headers of requests at the debug level
+    pub log_headers: bool,
+    /// Headers whose values are not logged, e.g. `Authorization` and `Cookie`
+    pub redact_headers: Vec<String>,
+    /// Log the time spent in each span when it closes
+    pub log_spans: bool,
+    /// Where to export traces over OTLP/HTTP, e.g. `http://localhost:4318/v1/traces`, empty to disable
+    pub otlp_endpoint: String,
 }
 
 impl AppConfig {
     pub fn from_env() -> Self {
+        let cfg = Self::from_env_unchecked();
+        cfg.validate();
+        cfg
+    }
+
+    /// Reads the configuration without validating it
+    pub fn from_env_unchecked() -> Self {
         load_dotenv();
 
-        let app_name = get_env_or("APP_NAME", "mote".to_string()).unwrap();
-        let app_version = get_env_or("APP_VERSION", "1.0.0".to_string()).unwrap();
+        let app_name = read("APP_NAME").unwrap();
+        let app_version = read("APP_VERSION").unwrap();
 
-        let posts_per_page = get_env_or("POSTS_PER_PAGE", 20).unwrap();
-        let static_url = get_env_or("STATIC_URL", "/static".to_string()).unwrap();
-        let static_path = get_env_or("STATIC_PATH", "./static".to_string()).unwrap();
+        let posts_per_page = read("POSTS_PER_PAGE").unwrap();
+        let max_content_size = read_size("POST_MAX_CONTENT_SIZE").unwrap();
+        let list_content_length = read("POST_LIST_CONTENT_LENGTH").unwrap();
+        let max_thread_depth = read("POST_MAX_THREAD_DEPTH").unwrap();
+        let post_lock_ttl_secs = read("POST_LOCK_TTL_SECS").unwrap();
+        let lint_posts = read("POST_LINT").unwrap();
+        let max_inline_image_size = read_size("POST_LINT_MAX_INLINE_IMAGE").unwrap();
+        let extract_inline_images = read("POST_EXTRACT_INLINE_IMAGES").unwrap();
+        let static_url = read("STATIC_URL").unwrap();
+        let static_path = read("STATIC_PATH").unwrap();
+        let about_url = read("ABOUT_URL").unwrap();
+        let replica = ReplicaConfig::from_env();
 
-        let cfg = AppConfig {
+        AppConfig {
             app_name,
             app_version,
 
             posts_per_page,
+            max_content_size,
+            list_content_length,
+            max_thread_depth,
+            post_lock_ttl_secs,
+            lint_posts,
+            max_inline_image_size,
+            extract_inline_images,
             static_url,
             static_path,
+            about_url,
 
+            auth: AuthConfig::from_env(),
             http: HTTPConfig::from_env(),
             upload: UploadConfig::from_env(),
-            db: DBConfig::from_env(),
+            ocr: OcrConfig::from_env(),
+            scan: ScanConfig::from_env(),
+            demo: DemoConfig::from_env(),
+            slack: SlackConfig::from_env(),
+            notify: NotifyConfig::from_env(),
+            jobs: JobsConfig::from_env(),
+            search: SearchConfig::from_env(),
+            db: DBConfig {
+                auto_checkpoint: replica.url.is_empty(),
+                ..DBConfig::from_env()
+            },
+            replica,
             redis: RedisConfig::from_env(),
             log: LogConfig::from_env(),
-        };
-        cfg.validate();
-        cfg
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Self {
+        let admin_name = read("MOTE_ADMIN_NAME").unwrap();
+        let password_hash = read("MOTE_PASSWORD_HASH").unwrap();
+        let password: String = read("MOTE_PASSWORD").unwrap();
+        let session_ttl_secs = read("AUTH_SESSION_TTL_SECS").unwrap();
+        let legacy_password_token = read("AUTH_LEGACY_PASSWORD_TOKEN").unwrap();
+        let cookie_name = read("AUTH_COOKIE_NAME").unwrap();
+        let passkey_rp_id = read("AUTH_PASSKEY_RP_ID").unwrap();
+        let passkey_origins = read_list("AUTH_PASSKEY_ORIGINS").unwrap();
+        let admin_token_hash = read("AUTH_ADMIN_TOKEN_HASH").unwrap();
+        let admin_allowed_ips = read_list("AUTH_ADMIN_ALLOWED_IPS").unwrap();
+
+        AuthConfig {
+            admin_name,
+            password_hash,
+            password: (!password.is_empty()).then_some(password),
+            session_ttl_secs,
+            legacy_password_token,
+            cookie_name,
+            passkey_rp_id,
+            passkey_origins,
+            admin_token_hash,
+            admin_allowed_ips,
+        }
     }
 }
 
 impl HTTPConfig {
     pub fn from_env() -> Self {
-        let ip = get_env_or("HTTP_IP", "127.0.0.1".to_string()).unwrap();
-        let port = get_env_or("HTTP_PORT", 8000).unwrap();
-        let max_body_size = get_size_from_env_or("HTTP_MAX_BODY_SIZE", 10 * 1024 * 1024).unwrap();
-        let read_timeout_secs = get_env_or("HTTP_READ_TIMEOUT_SECS", 10).unwrap();
-        let write_timeout_secs = get_env_or("HTTP_WRITE_TIMEOUT_SECS", 10).unwrap();
-        let idle_timeout_secs = get_env_or("HTTP_IDLE_TIMEOUT_SECS", 30).unwrap();
+        let ip = read("HTTP_IP").unwrap();
+        let port = read("HTTP_PORT").unwrap();
+        let max_body_size = read_size("HTTP_MAX_BODY_SIZE").unwrap();
+        let read_timeout_secs = read("HTTP_READ_TIMEOUT_SECS").unwrap();
+        let write_timeout_secs = read("HTTP_WRITE_TIMEOUT_SECS").unwrap();
+        let idle_timeout_secs = read("HTTP_IDLE_TIMEOUT_SECS").unwrap();
+        let trim_trailing_slash = read("HTTP_TRIM_TRAILING_SLASH").unwrap();
+        let trusted_proxies = read_list("TRUSTED_PROXIES").unwrap();
         let cors = CORSConfig::from_env();
         HTTPConfig {
             ip,
@@ -120,6 +453,8 @@ impl HTTPConfig {
             read_timeout_secs,
             write_timeout_secs,
             idle_timeout_secs,
+            trim_trailing_slash,
+            trusted_proxies,
             cors,
         }
     }
@@ -127,61 +462,313 @@ impl HTTPConfig {
 
 impl UploadConfig {
     pub fn from_env() -> Self {
-        let base_path = get_env_or("UPLOAD_PATH", "./uploads".to_string()).unwrap();
-        let base_url = get_env_or("UPLOAD_URL", "/uploads".to_string()).unwrap();
-        let thumb_width = get_env_or("UPLOAD_THUMB_WIDTH", 128).unwrap();
-        let image_formats = get_vec_from_env_or(
-            "UPLOAD_IMAGE_FORMATS",
-            strs_to_strings(vec!["jpeg", "jpg", "png", "webp", "gif"]),
-        )
-        .unwrap();
+        let base_path = read("UPLOAD_PATH").unwrap();
+        let base_url = read("UPLOAD_URL").unwrap();
+        let thumb_width = read("UPLOAD_THUMB_WIDTH").unwrap();
+        let animated_thumb = read("UPLOAD_ANIMATED_THUMB").unwrap();
+        let thumb_max_frames = read("UPLOAD_THUMB_MAX_FRAMES").unwrap();
+        let image_formats = read_list("UPLOAD_IMAGE_FORMATS").unwrap();
+        let exif_location = read("UPLOAD_EXIF_LOCATION").unwrap();
+        let variant_widths = read_list("UPLOAD_VARIANT_WIDTHS").unwrap();
+        let variant_format = read("UPLOAD_VARIANT_FORMAT").unwrap();
+        let heic_converter = read("UPLOAD_HEIC_CONVERTER").unwrap();
+        let heic_format = read("UPLOAD_HEIC_FORMAT").unwrap();
+        let quota = read_size("UPLOAD_QUOTA").unwrap();
+        let user_quota = read_size("UPLOAD_USER_QUOTA").unwrap();
+        let chunk_path = read("UPLOAD_CHUNK_PATH").unwrap();
+        let chunk_size = read_size("UPLOAD_CHUNK_SIZE").unwrap();
+        let max_file_size = read_size("UPLOAD_MAX_FILE_SIZE").unwrap();
 
         UploadConfig {
             base_path,
             base_url,
             thumb_width,
+            animated_thumb,
+            thumb_max_frames,
             image_formats,
+            exif_location,
+            variant_widths,
+            variant_format,
+            heic_converter,
+            heic_format,
+            quota,
+            user_quota,
+            chunk_path,
+            chunk_size,
+            max_file_size,
+        }
+    }
+}
+
+impl OcrConfig {
+    pub fn from_env() -> Self {
+        let provider = read("OCR_PROVIDER").unwrap();
+        let tesseract_path = read("OCR_TESSERACT_PATH").unwrap();
+        let languages = read("OCR_LANGUAGES").unwrap();
+        let api_url = read("OCR_API_URL").unwrap();
+        let api_key: String = read("OCR_API_KEY").unwrap();
+        let timeout_secs = read("OCR_TIMEOUT_SECS").unwrap();
+
+        OcrConfig {
+            provider,
+            tesseract_path,
+            languages,
+            api_url,
+            api_key: (!api_key.is_empty()).then_some(api_key),
+            timeout_secs,
+        }
+    }
+}
+
+impl ScanConfig {
+    pub fn from_env() -> Self {
+        let provider = read("SCAN_PROVIDER").unwrap();
+        let clamd_addr = read("SCAN_CLAMD_ADDR").unwrap();
+        let api_url = read("SCAN_API_URL").unwrap();
+        let api_key: String = read("SCAN_API_KEY").unwrap();
+        let quarantine_path = read("SCAN_QUARANTINE_PATH").unwrap();
+
+        ScanConfig {
+            provider,
+            clamd_addr,
+            api_url,
+            api_key: (!api_key.is_empty()).then_some(api_key),
+            quarantine_path,
+        }
+    }
+}
+
+impl DemoConfig {
+    pub fn from_env() -> Self {
+        let enabled = read("DEMO_MODE").unwrap();
+        let reset_hours = read("DEMO_RESET_HOURS").unwrap();
+
+        DemoConfig {
+            enabled,
+            reset_hours,
         }
     }
 }
 
+impl SlackConfig {
+    pub fn from_env() -> Self {
+        let signing_secret = read("SLACK_SIGNING_SECRET").unwrap();
+        let users = read_list("SLACK_USERS").unwrap();
+
+        SlackConfig {
+            signing_secret,
+            users,
+        }
+    }
+
+    /// The name of the user a Slack user posts as.
+    pub fn user_name(&self, slack_id: &str) -> Option<&str> {
+        self.users.iter().find_map(|entry| {
+            let (id, name) = entry.split_once('=')?;
+            (id.trim() == slack_id).then_some(name.trim())
+        })
+    }
+}
+
+impl NotifyConfig {
+    pub fn from_env() -> Self {
+        let ntfy_token: String = read("NOTIFY_NTFY_TOKEN").unwrap();
+
+        NotifyConfig {
+            channels: read_list("NOTIFY_CHANNELS").unwrap(),
+            kinds: read_list("NOTIFY_KINDS").unwrap(),
+            smtp_host: read("NOTIFY_SMTP_HOST").unwrap(),
+            smtp_port: read("NOTIFY_SMTP_PORT").unwrap(),
+            smtp_security: read("NOTIFY_SMTP_SECURITY").unwrap(),
+            smtp_username: read("NOTIFY_SMTP_USERNAME").unwrap(),
+            smtp_password: read("NOTIFY_SMTP_PASSWORD").unwrap(),
+            smtp_from: read("NOTIFY_SMTP_FROM").unwrap(),
+            smtp_to: read_list("NOTIFY_SMTP_TO").unwrap(),
+            ntfy_url: read("NOTIFY_NTFY_URL").unwrap(),
+            ntfy_token: (!ntfy_token.is_empty()).then_some(ntfy_token),
+            webhook_url: read("NOTIFY_WEBHOOK_URL").unwrap(),
+            telegram_bot_token: read("NOTIFY_TELEGRAM_BOT_TOKEN").unwrap(),
+            telegram_chat_id: read("NOTIFY_TELEGRAM_CHAT_ID").unwrap(),
+        }
+    }
+
+    /// Splits the ntfy url into the server and the topic.
+    pub fn ntfy_topic(&self) -> Option<(&str, &str)> {
+        let url = self.ntfy_url.trim_end_matches('/');
+        let (server, topic) = url.rsplit_once('/')?;
+        (url.starts_with("http") && server.contains("://") && !topic.is_empty())
+            .then_some((server, topic))
+    }
+
+    /// Whether notifications of this kind are sent out.
+    pub fn is_sent(&self, kind: &str) -> bool {
+        !self.channels.is_empty() && self.kinds.iter().any(|k| k == kind)
+    }
+}
+
+impl JobsConfig {
+    pub fn from_env() -> Self {
+        let backup_path = read("BACKUP_PATH").unwrap();
+        let backup_keep = read("BACKUP_KEEP").unwrap();
+        let prune_grace_hours = read("JOB_PRUNE_UPLOADS_GRACE_HOURS").unwrap();
+
+        JobsConfig {
+            purge_trash: JobConfig::from_env("PURGE_TRASH"),
+            collect_files: JobConfig::from_env("COLLECT_FILES"),
+            backup: JobConfig::from_env("BACKUP"),
+            reconcile_index: JobConfig::from_env("RECONCILE_INDEX"),
+            check_links: JobConfig::from_env("CHECK_LINKS"),
+            prune_uploads: JobConfig::from_env("PRUNE_UPLOADS"),
+            backup_path,
+            backup_keep,
+            prune_grace_hours,
+        }
+    }
+}
+
+impl JobConfig {
+    /// Reads `JOB_{name}_CRON` and `JOB_{name}_ENABLED`
+    fn from_env(name: &str) -> Self {
+        let cron = read(&format!("JOB_{}_CRON", name)).unwrap();
+        let enabled = read(&format!("JOB_{}_ENABLED", name)).unwrap();
+
+        JobConfig { cron, enabled }
+    }
+}
+
 impl DBConfig {
     pub fn from_env() -> Self {
-        let url = get_env_or("DATABASE_URL", "sqlite://app.db".to_string()).unwrap();
-        let pool_size = get_env_or("DATABASE_POOL_SIZE", 5).unwrap();
-        let auto_migrate = get_env_or("DATABASE_AUTO_MIGRATE", true).unwrap();
+        let url = read("DATABASE_URL").unwrap();
+        let pool_size = read("DATABASE_POOL_SIZE").unwrap();
+        let auto_migrate = read("DATABASE_AUTO_MIGRATE").unwrap();
+        let key: String = read("DATABASE_KEY").unwrap();
+        let busy_timeout_ms = read("DATABASE_BUSY_TIMEOUT").unwrap();
+        let synchronous = read("DATABASE_SYNCHRONOUS").unwrap();
 
         DBConfig {
             url,
             pool_size,
             auto_migrate,
+            key: (!key.is_empty()).then_some(key),
+            busy_timeout_ms,
+            synchronous,
+            auto_checkpoint: true,
+        }
+    }
+}
+
+impl ReplicaConfig {
+    pub fn from_env() -> Self {
+        let url = read("REPLICA_URL").unwrap();
+        let endpoint = read("REPLICA_ENDPOINT").unwrap();
+        let region = read("REPLICA_REGION").unwrap();
+        let access_key_id = read("REPLICA_ACCESS_KEY_ID").unwrap();
+        let secret_access_key = read("REPLICA_SECRET_ACCESS_KEY").unwrap();
+        let sync_interval_secs = read("REPLICA_SYNC_INTERVAL_SECS").unwrap();
+        let checkpoint_size = read_size("REPLICA_CHECKPOINT_SIZE").unwrap();
+        let snapshot_interval_secs = read("REPLICA_SNAPSHOT_INTERVAL_SECS").unwrap();
+
+        ReplicaConfig {
+            url,
+            endpoint,
+            region,
+            access_key_id,
+            secret_access_key,
+            sync_interval_secs,
+            checkpoint_size,
+            snapshot_interval_secs,
         }
     }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.url.is_empty()
+    }
+}
+
+impl fmt::Debug for SlackConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlackConfig")
+            .field("signing_secret", &"***")
+            .field("users", &self.users)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NotifyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotifyConfig")
+            .field("channels", &self.channels)
+            .field("kinds", &self.kinds)
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("smtp_security", &self.smtp_security)
+            .field("smtp_username", &self.smtp_username)
+            .field("smtp_password", &"***")
+            .field("smtp_from", &self.smtp_from)
+            .field("smtp_to", &self.smtp_to)
+            .field("ntfy_url", &self.ntfy_url)
+            .field("ntfy_token", &self.ntfy_token.as_ref().map(|_| "***"))
+            .field("webhook_url", &self.webhook_url)
+            .field("telegram_bot_token", &"***")
+            .field("telegram_chat_id", &self.telegram_chat_id)
+            .finish()
+    }
+}
+
+impl fmt::Debug for ReplicaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplicaConfig")
+            .field("url", &self.url)
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"***")
+            .field("sync_interval_secs", &self.sync_interval_secs)
+            .field("checkpoint_size", &self.checkpoint_size)
+            .field("snapshot_interval_secs", &self.snapshot_interval_secs)
+            .finish()
+    }
 }
 
 impl RedisConfig {
     pub fn from_env() -> Self {
-        let url = get_env_or("REDIS_URL", "redis://localhost:6379/0".to_string()).unwrap();
+        let url = read("REDIS_URL").unwrap();
+        let retries = read("REDIS_RETRIES").unwrap();
+        let retry_delay_ms = read("REDIS_RETRY_DELAY_MS").unwrap();
+        let breaker_threshold = read("REDIS_BREAKER_THRESHOLD").unwrap();
+        let breaker_cooldown_secs = read("REDIS_BREAKER_COOLDOWN_SECS").unwrap();
 
-        RedisConfig { url }
+        RedisConfig {
+            url,
+            retries,
+            retry_delay_ms,
+            breaker_threshold,
+            breaker_cooldown_secs,
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            base_delay: Duration::from_millis(self.retry_delay_ms),
+        }
+    }
+
+    pub fn breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(
+            self.breaker_threshold,
+            Duration::from_secs(self.breaker_cooldown_secs),
+        )
     }
 }
 
 impl CORSConfig {
     pub fn from_env() -> Self {
-        let allowed_origins = get_vec_from_env_or("CORS_ALLOWED_ORIGINS", vec![]).unwrap();
-        let allowed_methods = get_vec_from_env_or(
-            "CORS_ALLOWED_METHODS",
-            strs_to_strings(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]),
-        )
-        .unwrap();
-        let allowed_headers = get_vec_from_env_or(
-            "CORS_ALLOWED_HEADERS",
-            vec!["Content-Type".to_string(), "Authorization".to_string()],
-        )
-        .unwrap();
-        let allow_credentials = get_env_or("CORS_ALLOW_CREDENTIALS", false).unwrap();
-        let max_age = get_env_or("CORS_MAX_AGE", 86400).unwrap();
+        let allowed_origins = read_list("CORS_ALLOWED_ORIGINS").unwrap();
+        let allowed_methods = read_list("CORS_ALLOWED_METHODS").unwrap();
+        let allowed_headers = read_list("CORS_ALLOWED_HEADERS").unwrap();
+        let allow_credentials = read("CORS_ALLOW_CREDENTIALS").unwrap();
+        let max_age = read("CORS_MAX_AGE").unwrap();
 
         CORSConfig {
             allowed_origins,
@@ -225,18 +812,98 @@ impl CORSConfig {
     }
 }
 
+impl SearchConfig {
+    pub fn from_env() -> Self {
+        let backend = read::<String>("SEARCH_BACKEND").unwrap().to_lowercase();
+        let max_candidates = read("SEARCH_MAX_CANDIDATES").unwrap();
+        let max_results = read("SEARCH_MAX_RESULTS").unwrap();
+        let timeout_ms = read("SEARCH_TIMEOUT_MS").unwrap();
+        let fold_diacritics = read("SEARCH_FOLD_DIACRITICS").unwrap();
+        let stemmer = read::<String>("SEARCH_STEMMER").unwrap().to_lowercase();
+        let auto_rebuild = read("SEARCH_AUTO_REBUILD").unwrap();
+        let synonyms_path = read("SEARCH_SYNONYMS_PATH").unwrap();
+
+        SearchConfig {
+            backend,
+            max_candidates,
+            max_results,
+            timeout_ms,
+            fold_diacritics,
+            stemmer,
+            auto_rebuild,
+            synonyms_path,
+        }
+    }
+
+    /// The stemming algorithm, `None` if disabled or the language is not supported
+    pub fn stemmer(&self) -> Option<Algorithm> {
+        let algorithm = match self.stemmer.as_str() {
+            "danish" => Algorithm::Danish,
+            "dutch" => Algorithm::Dutch,
+            "english" => Algorithm::English,
+            "finnish" => Algorithm::Finnish,
+            "french" => Algorithm::French,
+            "german" => Algorithm::German,
+            "hungarian" => Algorithm::Hungarian,
+            "italian" => Algorithm::Italian,
+            "norwegian" => Algorithm::Norwegian,
+            "portuguese" => Algorithm::Portuguese,
+            "romanian" => Algorithm::Romanian,
+            "spanish" => Algorithm::Spanish,
+            "swedish" => Algorithm::Swedish,
+            "turkish" => Algorithm::Turkish,
+            _ => return None,
+        };
+        Some(algorithm)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_ms > 0).then(|| Duration::from_millis(self.timeout_ms))
+    }
+}
+
 impl LogConfig {
     pub fn from_env() -> Self {
-        let log_requests = get_env_or("LOG_REQUESTS", true).unwrap();
+        let log_requests = read("LOG_REQUESTS").unwrap();
+        let redact_params = read_list("LOG_REDACT_PARAMS").unwrap();
+        let log_headers = read("LOG_HEADERS").unwrap();
+        let redact_headers = read_list("LOG_REDACT_HEADERS").unwrap();
+        let log_spans = read("LOG_SPANS").unwrap();
+        let otlp_endpoint = read("LOG_OTLP_ENDPOINT").unwrap();
 
-        LogConfig { log_requests }
+        LogConfig {
+            log_requests,
+            redact_params,
+            log_headers,
+            redact_headers,
+            log_spans,
+            otlp_endpoint,
+        }
     }
 }
 
 impl AppConfig {
     /// Validates the configuration and panics if any validation fails
     pub fn validate(&self) {
+        let ConfigIssues { errors, warnings } = self.check();
+
+        for warning in warnings {
+            warn!("Configuration: {}", warning);
+        }
+        // If there are validation errors, panic with all of them
+        if !errors.is_empty() {
+            panic!(
+                "Configuration validation failed:\n  - {}",
+                errors.join("\n  - ")
+            );
+        }
+    }
+
+    /// Validates the configuration. Problems of optional features are warnings,
+    /// the app can still start without them.
+    pub fn check(&self) -> ConfigIssues {
         let mut errors = Vec::new();
+        let mut warnings = Vec::new();
 
         // Validate basic app info
         if self.app_name.is_empty() {
@@ -253,6 +920,12 @@ impl AppConfig {
         if self.posts_per_page > 1000 {
             errors.push("posts_per_page cannot exceed 1000".to_string());
         }
+        if self.max_content_size == 0 {
+            errors.push("max_content_size must be greater than 0".to_string());
+        }
+        if self.post_lock_ttl_secs == 0 {
+            errors.push("post_lock_ttl_secs must be greater than 0".to_string());
+        }
         if self.static_url.is_empty() {
             errors.push("static_url cannot be empty".to_string());
         }
@@ -260,6 +933,75 @@ impl AppConfig {
             errors.push("static_path cannot be empty".to_string());
         }
 
+        // Validate auth config
+        if self.auth.admin_name.is_empty() || self.auth.admin_name.chars().count() > 64 {
+            errors.push("auth.admin_name must have 1 to 64 characters".to_string());
+        }
+        match (self.auth.password_hash.is_empty(), self.auth.password.is_some()) {
+            (true, false) => errors.push(
+                "auth.password_hash cannot be empty, set MOTE_PASSWORD_HASH to the output of `mote hash-password`"
+                    .to_string(),
+            ),
+            (true, true) => warnings.push(
+                "MOTE_PASSWORD keeps the password in plain text and is deprecated, set MOTE_PASSWORD_HASH to the output of `mote hash-password` instead"
+                    .to_string(),
+            ),
+            (false, has_password) => {
+                if PasswordHash::new(&self.auth.password_hash).is_err() {
+                    errors.push("auth.password_hash is not a valid argon2 hash".to_string());
+                }
+                if has_password {
+                    warnings.push(
+                        "MOTE_PASSWORD is ignored since MOTE_PASSWORD_HASH is set".to_string(),
+                    );
+                }
+            }
+        }
+        if self.auth.session_ttl_secs == 0 {
+            errors.push("auth.session_ttl_secs must be greater than 0".to_string());
+        }
+        if self.auth.cookie_name.is_empty()
+            || !self
+                .auth
+                .cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            errors.push(format!(
+                "auth.cookie_name '{}' is not a valid cookie name",
+                self.auth.cookie_name
+            ));
+        }
+
+        for origin in &self.auth.passkey_origins {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                errors.push(format!(
+                    "auth.passkey_origins '{}' must be an http(s) url",
+                    origin
+                ));
+            }
+        }
+        if self.auth.passkey_rp_id.contains([':', '/']) {
+            errors.push(format!(
+                "auth.passkey_rp_id '{}' must be a domain, without scheme and port",
+                self.auth.passkey_rp_id
+            ));
+        }
+
+        if !self.auth.admin_token_hash.is_empty()
+            && PasswordHash::new(&self.auth.admin_token_hash).is_err()
+        {
+            errors.push("auth.admin_token_hash is not a valid argon2 hash".to_string());
+        }
+        for ip in &self.auth.admin_allowed_ips {
+            if ip.prefix() == 0 {
+                warnings.push(format!(
+                    "auth.admin_allowed_ips contains {}, the admin routes are served to any client",
+                    ip
+                ));
+            }
+        }
+
         // Validate HTTP config
         if self.http.ip.is_empty() {
             errors.push("http.ip cannot be empty".to_string());
@@ -273,6 +1015,14 @@ impl AppConfig {
         if self.http.max_body_size == 0 {
             errors.push("http.max_body_size must be greater than 0".to_string());
         }
+        for proxy in &self.http.trusted_proxies {
+            if proxy.prefix() == 0 {
+                warnings.push(format!(
+                    "http.trusted_proxies contains {}, any client can set its own IP",
+                    proxy
+                ));
+            }
+        }
 
         // Validate Upload config
         if self.upload.base_url.is_empty() {
@@ -284,7 +1034,7 @@ impl AppConfig {
             let path = Path::new(&self.upload.base_path);
             // Try to create directory if it doesn't exist
             if let Err(e) = fs::create_dir_all(path) {
-                errors.push(format!(
+                warnings.push(format!(
                     "Failed to create upload directory '{}': {}",
                     self.upload.base_path, e
                 ));
@@ -292,7 +1042,7 @@ impl AppConfig {
                 // Check if directory is writable
                 let test_file = path.join(".write_test");
                 if let Err(e) = fs::write(&test_file, b"test") {
-                    errors.push(format!(
+                    warnings.push(format!(
                         "Upload directory '{}' is not writable: {}",
                         self.upload.base_path, e
                     ));
@@ -319,6 +1069,175 @@ impl AppConfig {
         if self.upload.thumb_width > 4096 {
             errors.push("upload.thumb_width cannot exceed 4096".to_string());
         }
+        if self
+            .upload
+            .variant_widths
+            .iter()
+            .any(|width| !(1..=4096).contains(width))
+        {
+            errors.push("upload.variant_widths must be between 1 and 4096".to_string());
+        }
+        if !["original", "webp", "avif"].contains(&self.upload.variant_format.as_str()) {
+            errors.push(format!(
+                "Invalid variant format: {}, expected original, webp or avif",
+                self.upload.variant_format
+            ));
+        }
+        if self.upload.chunk_path.is_empty() {
+            errors.push("upload.chunk_path cannot be empty".to_string());
+        }
+        if self.upload.chunk_size == 0 {
+            errors.push("upload.chunk_size must be greater than 0".to_string());
+        } else if self.upload.chunk_size > self.http.max_body_size {
+            errors.push("upload.chunk_size cannot exceed http.max_body_size".to_string());
+        }
+        if self.upload.max_file_size == 0 {
+            errors.push("upload.max_file_size must be greater than 0".to_string());
+        }
+        if self.upload.thumb_max_frames == 0 {
+            errors.push("upload.thumb_max_frames must be greater than 0".to_string());
+        }
+        if !["jpeg", "webp"].contains(&self.upload.heic_format.as_str()) {
+            errors.push(format!(
+                "Invalid heic format: {}, expected jpeg or webp",
+                self.upload.heic_format
+            ));
+        }
+
+        // Validate OCR config
+        match self.ocr.provider.as_str() {
+            "none" => {}
+            "tesseract" => {
+                if self.ocr.tesseract_path.is_empty() {
+                    warnings.push(
+                        "ocr.tesseract_path is empty, images cannot be recognized".to_string(),
+                    );
+                }
+            }
+            "http" => {
+                if self.ocr.api_url.is_empty() {
+                    warnings.push("ocr.api_url is empty, images cannot be recognized".to_string());
+                }
+            }
+            provider => warnings.push(format!(
+                "Invalid OCR provider: {}, OCR is disabled",
+                provider
+            )),
+        }
+
+        // Validate scan config
+        match self.scan.provider.as_str() {
+            "none" => {}
+            "clamd" => {
+                if self.scan.clamd_addr.is_empty() {
+                    errors.push("scan.clamd_addr cannot be empty".to_string());
+                }
+            }
+            "http" => {
+                if self.scan.api_url.is_empty() {
+                    errors.push("scan.api_url cannot be empty".to_string());
+                }
+            }
+            provider => errors.push(format!("Invalid scan provider: {}", provider)),
+        }
+        if self.scan.provider != "none" && self.scan.quarantine_path.is_empty() {
+            errors.push("scan.quarantine_path cannot be empty".to_string());
+        }
+
+        // Validate Slack config
+        for entry in self.slack.users.iter() {
+            if !matches!(entry.split_once('='), Some((id, name)) if !id.trim().is_empty() && !name.trim().is_empty())
+            {
+                errors.push(format!(
+                    "Invalid slack user: {}, expected slack_id=name",
+                    entry
+                ));
+            }
+        }
+        if !self.slack.signing_secret.is_empty() && self.slack.users.is_empty() {
+            warnings.push("slack.users is empty, no one can create posts from Slack".to_string());
+        }
+
+        // Validate notify config
+        let notify = &self.notify;
+        for channel in notify.channels.iter() {
+            match channel.as_str() {
+                "smtp" => {
+                    if notify.smtp_host.is_empty() {
+                        errors.push("notify.smtp_host cannot be empty".to_string());
+                    }
+                    if notify.smtp_from.is_empty() || notify.smtp_to.is_empty() {
+                        errors.push(
+                            "notify.smtp_from and notify.smtp_to cannot be empty".to_string(),
+                        );
+                    }
+                    if !["tls", "starttls", "none"].contains(&notify.smtp_security.as_str()) {
+                        errors.push(format!(
+                            "Invalid smtp security: {}, expected tls, starttls or none",
+                            notify.smtp_security
+                        ));
+                    }
+                    if notify.smtp_security == "none" && !notify.smtp_password.is_empty() {
+                        warnings.push(
+                            "notify.smtp_password is sent in plain text without tls".to_string(),
+                        );
+                    }
+                }
+                "ntfy" => {
+                    if notify.ntfy_topic().is_none() {
+                        errors.push(format!(
+                            "Invalid ntfy url: {}, expected e.g. https://ntfy.sh/my-topic",
+                            notify.ntfy_url
+                        ));
+                    }
+                }
+                "webhook" => {
+                    if notify.webhook_url.is_empty() {
+                        errors.push("notify.webhook_url cannot be empty".to_string());
+                    }
+                }
+                "telegram" => {
+                    if notify.telegram_bot_token.is_empty() || notify.telegram_chat_id.is_empty() {
+                        errors.push(
+                            "notify.telegram_bot_token and notify.telegram_chat_id cannot be empty"
+                                .to_string(),
+                        );
+                    }
+                }
+                channel => errors.push(format!("Invalid notify channel: {}", channel)),
+            }
+        }
+        if !notify.channels.is_empty() && notify.kinds.is_empty() {
+            warnings.push("notify.kinds is empty, no notifications are sent out".to_string());
+        }
+
+        // Validate demo config
+        if self.demo.enabled && self.demo.reset_hours == 0 {
+            errors.push("demo.reset_hours must be greater than 0".to_string());
+        }
+
+        // Validate jobs config
+        let jobs = [
+            ("purge_trash", &self.jobs.purge_trash),
+            ("collect_files", &self.jobs.collect_files),
+            ("backup", &self.jobs.backup),
+            ("reconcile_index", &self.jobs.reconcile_index),
+            ("check_links", &self.jobs.check_links),
+            ("prune_uploads", &self.jobs.prune_uploads),
+        ];
+        for (name, job) in jobs {
+            if Job::new(job.cron.as_str(), |_, _| {}).is_err() {
+                errors.push(format!("jobs.{}.cron '{}' is not valid", name, job.cron));
+            }
+        }
+        if self.jobs.backup.enabled {
+            if self.jobs.backup_path.is_empty() {
+                warnings.push("jobs.backup_path is empty, the backup job will fail".to_string());
+            }
+            if self.jobs.backup_keep == 0 {
+                warnings.push("jobs.backup_keep is 0, the backup job will fail".to_string());
+            }
+        }
 
         // Validate DB config
         if self.db.url.is_empty() {
@@ -330,25 +1249,95 @@ impl AppConfig {
         if self.db.pool_size > 1000 {
             errors.push("db.pool_size cannot exceed 1000".to_string());
         }
+        if SqliteSynchronous::from_str(&self.db.synchronous).is_err() {
+            errors.push("db.synchronous must be one of OFF, NORMAL, FULL and EXTRA".to_string());
+        }
+        if self.db.key.is_some() && !cfg!(feature = "sqlcipher") {
+            errors.push("db.key requires a build with the sqlcipher feature".to_string());
+        }
+
+        // Validate replica config
+        if self.replica.is_enabled() {
+            let replica = &self.replica;
+            if let Some(bucket) = replica.url.strip_prefix("s3://") {
+                if bucket.split('/').next().unwrap_or_default().is_empty() {
+                    errors.push(format!("replica.url '{}' has no bucket", replica.url));
+                }
+                if replica.access_key_id.is_empty() || replica.secret_access_key.is_empty() {
+                    errors.push(
+                        "replica.access_key_id and replica.secret_access_key are required by S3"
+                            .to_string(),
+                    );
+                }
+            } else if !replica.url.starts_with("file://") {
+                errors.push(format!(
+                    "replica.url '{}' must start with s3:// or file://",
+                    replica.url
+                ));
+            }
+            if self.db.url.contains(":memory:") || self.db.url.contains("mode=memory") {
+                errors.push("An in-memory database cannot be replicated".to_string());
+            }
+            if replica.sync_interval_secs == 0 {
+                errors.push("replica.sync_interval_secs must be greater than 0".to_string());
+            }
+            if replica.snapshot_interval_secs < replica.sync_interval_secs {
+                errors.push(
+                    "replica.snapshot_interval_secs cannot be less than replica.sync_interval_secs"
+                        .to_string(),
+                );
+            }
+        }
 
         // Validate Redis config
         if self.redis.url.is_empty() {
             errors.push("redis.url cannot be empty".to_string());
         }
 
-        // If there are validation errors, panic with all of them
-        if !errors.is_empty() {
-            panic!(
-                "Configuration validation failed:\n  - {}",
-                errors.join("\n  - ")
-            );
+        // Warn about variables which look like misspelled settings
+        let keys: Vec<String> = env::vars().map(|(key, _)| key).collect();
+        for key in registry::unknown_keys(keys) {
+            warnings.push(format!("{} is not a known setting, is it misspelled?", key));
+        }
+
+        if !["redis", "sqlite"].contains(&self.search.backend.as_str()) {
+            errors.push("search.backend must be one of redis and sqlite".to_string());
+        }
+
+        // Validate log config
+        let synonyms_path = &self.search.synonyms_path;
+        if !synonyms_path.is_empty() && !Path::new(synonyms_path).is_file() {
+            errors.push(format!(
+                "search.synonyms_path '{}' is not a file",
+                synonyms_path
+            ));
         }
-    }
-}
 
-// convert vectors of &str to owned Strings
-fn strs_to_strings(vec: Vec<&str>) -> Vec<String> {
-    vec.into_iter().map(|s| s.to_string()).collect()
+        if !self.search.stemmer.is_empty() && self.search.stemmer().is_none() {
+            errors.push(format!(
+                "search.stemmer '{}' is not a supported language",
+                self.search.stemmer
+            ));
+        }
+
+        let endpoint = &self.log.otlp_endpoint;
+        if !endpoint.is_empty() {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                errors.push(format!(
+                    "log.otlp_endpoint '{}' must be an http(s) url",
+                    endpoint
+                ));
+            }
+            if !cfg!(feature = "otel") {
+                warnings.push(
+                    "log.otlp_endpoint requires a build with the otel feature, traces are not exported"
+                        .to_string(),
+                );
+            }
+        }
+
+        ConfigIssues { errors, warnings }
+    }
 }
 
 // Helper function to convert Vec<String> to Vec<T>
@@ -358,3 +1347,27 @@ where
 {
     strings.into_iter().map(|s| s.parse().unwrap()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_warnings() {
+        let mut config = AppConfig::from_env_unchecked();
+        config.upload.base_path = env::temp_dir().to_string_lossy().into_owned();
+        config.ocr.provider = "nope".to_string();
+        config.http.trusted_proxies = vec!["0.0.0.0/0".parse().unwrap()];
+
+        let issues = config.check();
+        assert!(issues.errors.is_empty(), "{:?}", issues.errors);
+        assert!(issues
+            .warnings
+            .iter()
+            .any(|w| w.contains("OCR is disabled")));
+        assert!(issues.warnings.iter().any(|w| w.contains("0.0.0.0/0")));
+
+        config.posts_per_page = 0;
+        assert_eq!(config.check().errors.len(), 1);
+    }
+}
@@ -1,11 +1,16 @@
+use crate::util::retry::{CircuitBreaker, RetryPolicy};
 use bb8::Pool;
 use bb8_redis::RedisConnectionManager;
 use std::ops::Deref;
+use std::time::Duration;
 
 pub type RedisPool = Pool<RedisConnectionManager>;
 
 pub struct RD {
     pub pool: RedisPool,
+    /// How idempotent operations are tried again after transient errors
+    pub retry: RetryPolicy,
+    pub breaker: CircuitBreaker,
 }
 
 impl RD {
@@ -13,7 +18,18 @@ impl RD {
         let redis_manager = RedisConnectionManager::new(url)?;
         let redis_pool = Pool::builder().build(redis_manager).await?;
 
-        Ok(RD { pool: redis_pool })
+        Ok(RD {
+            pool: redis_pool,
+            retry: RetryPolicy::NONE,
+            breaker: CircuitBreaker::new(0, Duration::ZERO),
+        })
+    }
+
+    /// Tries operations again after transient errors, and stops calling Redis for a while after repeated failures.
+    pub fn with_resilience(mut self, retry: RetryPolicy, breaker: CircuitBreaker) -> Self {
+        self.retry = retry;
+        self.breaker = breaker;
+        self
     }
 }
 
@@ -0,0 +1,899 @@
+use crate::util::env::parse_size;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::env;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// How the value of a setting is parsed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingType {
+    Text,
+    Bool,
+    Integer,
+    /// A number of bytes, with an optional K, M or G suffix
+    Size,
+    /// Comma separated values
+    List,
+    /// A cron expression with seconds
+    Cron,
+    /// A string which is never shown, such as a password
+    Secret,
+}
+
+/// An environment variable read by the app
+#[derive(Debug, Serialize)]
+pub struct Setting {
+    pub key: &'static str,
+    #[serde(rename = "type")]
+    pub kind: SettingType,
+    /// The value used if the variable is not set
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+const fn setting(
+    key: &'static str,
+    kind: SettingType,
+    default: &'static str,
+    description: &'static str,
+) -> Setting {
+    Setting {
+        key,
+        kind,
+        default,
+        description,
+    }
+}
+
+use SettingType::{Bool, Cron, Integer, List, Secret, Size, Text};
+
+/// All settings of the app. They are read with [`read`], [`read_size`] and [`read_list`],
+/// which take the defaults from here, so a key missing from this list fails on startup.
+pub static SETTINGS: &[Setting] = &[
+    // Basic app info
+    setting("APP_NAME", Text, "mote", "The name shown on shared pages"),
+    setting(
+        "APP_VERSION",
+        Text,
+        "1.0.0",
+        "The version reported by /api/admin/status",
+    ),
+    // App settings
+    setting(
+        "MOTE_ADMIN_NAME",
+        Text,
+        "admin",
+        "The name of the admin user, who owns the posts made before there were users",
+    ),
+    setting(
+        "MOTE_PASSWORD_HASH",
+        Secret,
+        "",
+        "An argon2 hash of the password of the admin user, generated by `mote hash-password`",
+    ),
+    setting(
+        "MOTE_PASSWORD",
+        Secret,
+        "",
+        "Deprecated, the password in plain text, used if MOTE_PASSWORD_HASH is not set",
+    ),
+    setting(
+        "AUTH_SESSION_TTL_SECS",
+        Integer,
+        "2592000",
+        "How long a login lasts, 30 days by default, its random token is checked against the stored sessions",
+    ),
+    setting(
+        "AUTH_LEGACY_PASSWORD_TOKEN",
+        Bool,
+        "false",
+        "Accept the password of the admin user as a login token, for older clients which send it on every request",
+    ),
+    setting(
+        "AUTH_COOKIE_NAME",
+        Text,
+        "token",
+        "The cookie the login token is read from",
+    ),
+    setting(
+        "AUTH_PASSKEY_RP_ID",
+        Text,
+        "",
+        "The domain passkeys are registered for, e.g. `example.com`, empty to disable passkeys",
+    ),
+    setting(
+        "AUTH_PASSKEY_ORIGINS",
+        List,
+        "",
+        "The origins passkeys are used from, `https://{AUTH_PASSKEY_RP_ID}` by default",
+    ),
+    setting(
+        "AUTH_ADMIN_TOKEN_HASH",
+        Secret,
+        "",
+        "An argon2 hash of the token of /api/admin, generated by `mote hash-password`, a login is accepted if empty",
+    ),
+    setting(
+        "AUTH_ADMIN_ALLOWED_IPS",
+        List,
+        "",
+        "The IPs or CIDR ranges /api/admin is served to, all by default",
+    ),
+    setting(
+        "POSTS_PER_PAGE",
+        Integer,
+        "20",
+        "The number of posts in a page of a post list",
+    ),
+    setting(
+        "POST_MAX_CONTENT_SIZE",
+        Size,
+        "1M",
+        "The max size of the content of a post",
+    ),
+    setting(
+        "POST_LIST_CONTENT_LENGTH",
+        Integer,
+        "0",
+        "Truncate content in post lists to this many chars, 0 to disable",
+    ),
+    setting(
+        "POST_MAX_THREAD_DEPTH",
+        Integer,
+        "10",
+        "How deep replies may be nested, 0 means unlimited",
+    ),
+    setting(
+        "POST_LOCK_TTL_SECS",
+        Integer,
+        "60",
+        "How long the edit lock of a post lasts without a heartbeat",
+    ),
+    setting(
+        "POST_LINT",
+        Bool,
+        "true",
+        "Warn about broken links, empty hashtags and large inline images when posts are saved",
+    ),
+    setting(
+        "POST_LINT_MAX_INLINE_IMAGE",
+        Size,
+        "64K",
+        "Inline images larger than this are reported by the lint",
+    ),
+    setting(
+        "POST_EXTRACT_INLINE_IMAGES",
+        Bool,
+        "true",
+        "Store the images pasted into posts as data uris as uploads, and link to them instead",
+    ),
+    setting(
+        "ABOUT_URL",
+        Text,
+        "",
+        "A link to the author shown on shared pages",
+    ),
+    setting(
+        "STATIC_URL",
+        Text,
+        "/static",
+        "The url prefix of static files",
+    ),
+    setting(
+        "STATIC_PATH",
+        Text,
+        "./static",
+        "The directory of static files",
+    ),
+    // Server settings
+    setting("HTTP_IP", Text, "127.0.0.1", "The address to listen on"),
+    setting("HTTP_PORT", Integer, "8000", "The port to listen on"),
+    setting(
+        "HTTP_MAX_BODY_SIZE",
+        Size,
+        "10M",
+        "The max size of a request body",
+    ),
+    setting(
+        "HTTP_READ_TIMEOUT_SECS",
+        Integer,
+        "10",
+        "Timeout of reading a request",
+    ),
+    setting(
+        "HTTP_WRITE_TIMEOUT_SECS",
+        Integer,
+        "10",
+        "Timeout of writing a response",
+    ),
+    setting(
+        "HTTP_IDLE_TIMEOUT_SECS",
+        Integer,
+        "30",
+        "Timeout of idle connections",
+    ),
+    setting(
+        "HTTP_TRIM_TRAILING_SLASH",
+        Bool,
+        "false",
+        "Serve `/api/foo/` the same as `/api/foo`",
+    ),
+    setting(
+        "TRUSTED_PROXIES",
+        List,
+        "",
+        "Addresses or CIDR ranges of reverse proxies whose X-Forwarded-* headers are trusted",
+    ),
+    // CORS settings
+    setting(
+        "CORS_ALLOWED_ORIGINS",
+        List,
+        "",
+        "Origins allowed to call the API, * for any",
+    ),
+    setting(
+        "CORS_ALLOWED_METHODS",
+        List,
+        "GET,POST,PUT,DELETE,OPTIONS",
+        "Methods allowed in cross-origin requests",
+    ),
+    setting(
+        "CORS_ALLOWED_HEADERS",
+        List,
+        "Content-Type,Authorization",
+        "Headers allowed in cross-origin requests",
+    ),
+    setting(
+        "CORS_ALLOW_CREDENTIALS",
+        Bool,
+        "false",
+        "Allow cookies in cross-origin requests",
+    ),
+    setting(
+        "CORS_MAX_AGE",
+        Integer,
+        "86400",
+        "How long browsers cache preflight responses, in seconds",
+    ),
+    // Upload settings
+    setting(
+        "UPLOAD_PATH",
+        Text,
+        "./uploads",
+        "The directory of uploaded files",
+    ),
+    setting(
+        "UPLOAD_URL",
+        Text,
+        "/uploads",
+        "The url prefix of uploaded files, it can be changed since posts store only the file names",
+    ),
+    setting(
+        "UPLOAD_THUMB_WIDTH",
+        Integer,
+        "128",
+        "The width of thumbnails",
+    ),
+    setting(
+        "UPLOAD_ANIMATED_THUMB",
+        Bool,
+        "false",
+        "Keep the thumbnails of animated gif and webp images animated, in the format of the image",
+    ),
+    setting(
+        "UPLOAD_THUMB_MAX_FRAMES",
+        Integer,
+        "50",
+        "The max frames of animated thumbnails",
+    ),
+    setting(
+        "UPLOAD_EXIF_LOCATION",
+        Bool,
+        "false",
+        "Record where photos were taken from their EXIF GPS, shown only to their owners",
+    ),
+    setting(
+        "UPLOAD_IMAGE_FORMATS",
+        List,
+        "jpeg,jpg,png,webp,gif",
+        "Formats of images which get thumbnails",
+    ),
+    setting(
+        "UPLOAD_VARIANT_WIDTHS",
+        List,
+        "",
+        "Widths of larger thumbnails made of images, e.g. 512,1024",
+    ),
+    setting(
+        "UPLOAD_VARIANT_FORMAT",
+        Text,
+        "original",
+        "The format of the larger thumbnails, original, webp or avif",
+    ),
+    setting(
+        "UPLOAD_HEIC_CONVERTER",
+        Text,
+        "",
+        "A command converting HEIC photos, e.g. heif-convert or magick, empty to keep them",
+    ),
+    setting(
+        "UPLOAD_HEIC_FORMAT",
+        Text,
+        "jpeg",
+        "The format of converted HEIC photos, jpeg or webp",
+    ),
+    setting(
+        "UPLOAD_QUOTA",
+        Size,
+        "0",
+        "The total size of uploaded files, 0 means unlimited",
+    ),
+    setting(
+        "UPLOAD_USER_QUOTA",
+        Size,
+        "0",
+        "The total size of the files each user uploads, a file uploaded before by someone else does not count, 0 means unlimited",
+    ),
+    setting(
+        "UPLOAD_CHUNK_PATH",
+        Text,
+        "./upload-chunks",
+        "The directory of the chunks of resumable uploads, outside of UPLOAD_PATH",
+    ),
+    setting(
+        "UPLOAD_CHUNK_SIZE",
+        Size,
+        "5M",
+        "The size of the chunks of resumable uploads, at most HTTP_MAX_BODY_SIZE",
+    ),
+    setting(
+        "UPLOAD_MAX_FILE_SIZE",
+        Size,
+        "1G",
+        "The largest file of resumable uploads, other uploads are limited by HTTP_MAX_BODY_SIZE",
+    ),
+    // OCR settings
+    setting(
+        "OCR_PROVIDER",
+        Text,
+        "none",
+        "Recognizes text in images: none, tesseract or http",
+    ),
+    setting(
+        "OCR_TESSERACT_PATH",
+        Text,
+        "tesseract",
+        "The tesseract command",
+    ),
+    setting(
+        "OCR_LANGUAGES",
+        Text,
+        "eng+chi_sim",
+        "The languages of tesseract",
+    ),
+    setting("OCR_API_URL", Text, "", "The url of the http OCR service"),
+    setting("OCR_API_KEY", Secret, "", "The key of the http OCR service"),
+    setting(
+        "OCR_TIMEOUT_SECS",
+        Integer,
+        "60",
+        "Timeout of recognizing an image, the text of an image taking longer is not indexed",
+    ),
+    // Upload scanning settings
+    setting(
+        "SCAN_PROVIDER",
+        Text,
+        "none",
+        "Scans uploads for viruses: none, clamd or http",
+    ),
+    setting(
+        "SCAN_CLAMD_ADDR",
+        Text,
+        "127.0.0.1:3310",
+        "The address of clamd",
+    ),
+    setting("SCAN_API_URL", Text, "", "The url of the http scan service"),
+    setting(
+        "SCAN_API_KEY",
+        Secret,
+        "",
+        "The key of the http scan service",
+    ),
+    setting(
+        "SCAN_QUARANTINE_PATH",
+        Text,
+        "./quarantine",
+        "Where infected files are moved",
+    ),
+    // Slack integration
+    setting(
+        "SLACK_SIGNING_SECRET",
+        Secret,
+        "",
+        "The signing secret of the Slack app whose slash command creates posts, empty to disable it",
+    ),
+    setting(
+        "SLACK_USERS",
+        List,
+        "",
+        "The Slack users who can create posts, as slack_id=name of the user they post as",
+    ),
+    // Notification channels
+    setting(
+        "NOTIFY_CHANNELS",
+        List,
+        "",
+        "Where notifications are sent out: smtp, ntfy, webhook and telegram",
+    ),
+    setting(
+        "NOTIFY_KINDS",
+        List,
+        "job.failed,index.failed,index.outdated",
+        "The kinds of notifications sent out",
+    ),
+    setting("NOTIFY_SMTP_HOST", Text, "", "The host of the SMTP server"),
+    setting("NOTIFY_SMTP_PORT", Integer, "587", "The port of the SMTP server"),
+    setting(
+        "NOTIFY_SMTP_SECURITY",
+        Text,
+        "starttls",
+        "How the SMTP connection is secured: tls, starttls or none",
+    ),
+    setting(
+        "NOTIFY_SMTP_USERNAME",
+        Text,
+        "",
+        "The SMTP user, empty to send without logging in",
+    ),
+    setting(
+        "NOTIFY_SMTP_PASSWORD",
+        Secret,
+        "",
+        "The password of the SMTP user",
+    ),
+    setting("NOTIFY_SMTP_FROM", Text, "", "The sender of the emails"),
+    setting("NOTIFY_SMTP_TO", List, "", "The recipients of the emails"),
+    setting(
+        "NOTIFY_NTFY_URL",
+        Text,
+        "",
+        "The ntfy topic notifications are published to, e.g. https://ntfy.sh/my-topic",
+    ),
+    setting(
+        "NOTIFY_NTFY_TOKEN",
+        Secret,
+        "",
+        "The access token of the ntfy topic",
+    ),
+    setting(
+        "NOTIFY_WEBHOOK_URL",
+        Text,
+        "",
+        "The url notifications are posted to as json",
+    ),
+    setting(
+        "NOTIFY_TELEGRAM_BOT_TOKEN",
+        Secret,
+        "",
+        "The token of the Telegram bot sending notifications",
+    ),
+    setting(
+        "NOTIFY_TELEGRAM_CHAT_ID",
+        Text,
+        "",
+        "The Telegram chat notifications are sent to",
+    ),
+    // Demo mode
+    setting(
+        "DEMO_MODE",
+        Bool,
+        "false",
+        "Seeds sample data and resets it periodically, deleting all posts and files",
+    ),
+    setting(
+        "DEMO_RESET_HOURS",
+        Integer,
+        "6",
+        "How often the demo data is reset",
+    ),
+    // Background jobs
+    setting(
+        "JOB_PURGE_TRASH_CRON",
+        Cron,
+        "0 0 * * * *",
+        "When posts in the trash for 30 days, or past the purge time set on them, are deleted",
+    ),
+    setting(
+        "JOB_PURGE_TRASH_ENABLED",
+        Bool,
+        "true",
+        "Enables the purge-trash job",
+    ),
+    setting(
+        "JOB_COLLECT_FILES_CRON",
+        Cron,
+        "0 30 3 * * *",
+        "When files not attached to any post are deleted",
+    ),
+    setting(
+        "JOB_COLLECT_FILES_ENABLED",
+        Bool,
+        "true",
+        "Enables the collect-files job",
+    ),
+    setting(
+        "JOB_BACKUP_CRON",
+        Cron,
+        "0 0 4 * * *",
+        "When the database is copied into BACKUP_PATH",
+    ),
+    setting(
+        "JOB_BACKUP_ENABLED",
+        Bool,
+        "false",
+        "Enables the backup job",
+    ),
+    setting(
+        "JOB_RECONCILE_INDEX_CRON",
+        Cron,
+        "0 30 4 * * *",
+        "When posts missing from the search index are indexed",
+    ),
+    setting(
+        "JOB_RECONCILE_INDEX_ENABLED",
+        Bool,
+        "true",
+        "Enables the reconcile-index job",
+    ),
+    setting(
+        "JOB_CHECK_LINKS_CRON",
+        Cron,
+        "0 0 5 * * 0",
+        "When the external links of shared posts are checked for broken pages and redirects",
+    ),
+    setting(
+        "JOB_CHECK_LINKS_ENABLED",
+        Bool,
+        "false",
+        "Enables the check-links job, which requests the links from the server",
+    ),
+    setting(
+        "JOB_PRUNE_UPLOADS_CRON",
+        Cron,
+        "0 0 5 * * *",
+        "When the files in UPLOAD_PATH neither attached to nor linked from any post are deleted",
+    ),
+    setting(
+        "JOB_PRUNE_UPLOADS_ENABLED",
+        Bool,
+        "true",
+        "Enables the prune-uploads job",
+    ),
+    setting(
+        "JOB_PRUNE_UPLOADS_GRACE_HOURS",
+        Integer,
+        "72",
+        "How long unused files are kept after they were last modified",
+    ),
+    setting("BACKUP_PATH", Text, "./backups", "The directory of backups"),
+    setting("BACKUP_KEEP", Integer, "7", "The number of backups to keep"),
+    // Database settings
+    setting(
+        "DATABASE_URL",
+        Text,
+        "sqlite://app.db",
+        "The SQLite database",
+    ),
+    setting(
+        "DATABASE_POOL_SIZE",
+        Integer,
+        "5",
+        "The number of connections for reads",
+    ),
+    setting(
+        "DATABASE_AUTO_MIGRATE",
+        Bool,
+        "true",
+        "Migrate the database on startup",
+    ),
+    setting(
+        "DATABASE_BUSY_TIMEOUT",
+        Integer,
+        "5000",
+        "How long to wait for a lock, in milliseconds",
+    ),
+    setting(
+        "DATABASE_SYNCHRONOUS",
+        Text,
+        "NORMAL",
+        "The synchronous pragma: OFF, NORMAL, FULL or EXTRA",
+    ),
+    setting(
+        "DATABASE_KEY",
+        Secret,
+        "",
+        "Encrypts the database, needs a build with the sqlcipher feature",
+    ),
+    // Replication settings
+    setting(
+        "REPLICA_URL",
+        Text,
+        "",
+        "Where the database is replicated to, `s3://bucket/prefix` or `file:///path`, empty to disable",
+    ),
+    setting(
+        "REPLICA_ENDPOINT",
+        Text,
+        "",
+        "The S3 endpoint, e.g. `http://localhost:9000`, `https://s3.{REPLICA_REGION}.amazonaws.com` by default",
+    ),
+    setting("REPLICA_REGION", Text, "us-east-1", "The S3 region"),
+    setting("REPLICA_ACCESS_KEY_ID", Text, "", "The S3 access key"),
+    setting(
+        "REPLICA_SECRET_ACCESS_KEY",
+        Secret,
+        "",
+        "The S3 secret key",
+    ),
+    setting(
+        "REPLICA_SYNC_INTERVAL_SECS",
+        Integer,
+        "10",
+        "How often the new frames of the WAL are shipped",
+    ),
+    setting(
+        "REPLICA_CHECKPOINT_SIZE",
+        Size,
+        "4M",
+        "The WAL is checkpointed when it grows larger than this",
+    ),
+    setting(
+        "REPLICA_SNAPSHOT_INTERVAL_SECS",
+        Integer,
+        "86400",
+        "How often a new generation is started with a snapshot, restores replay the WAL since then",
+    ),
+    setting(
+        "NEW_DATABASE_KEY",
+        Secret,
+        "",
+        "The new key for `mote rekey`",
+    ),
+    // Search settings
+    setting(
+        "SEARCH_BACKEND",
+        Text,
+        "redis",
+        "Where the search index is kept, redis or sqlite, the index is rebuilt when switched to sqlite",
+    ),
+    setting(
+        "SEARCH_MAX_CANDIDATES",
+        Integer,
+        "2000",
+        "At most this many matched posts are ranked by the Redis index, 0 means unlimited",
+    ),
+    setting(
+        "SEARCH_MAX_RESULTS",
+        Integer,
+        "500",
+        "At most this many posts are returned by a search, 0 means unlimited",
+    ),
+    setting(
+        "SEARCH_TIMEOUT_MS",
+        Integer,
+        "2000",
+        "How long ranking may take in the Redis index, 0 means no limit",
+    ),
+    setting(
+        "SEARCH_FOLD_DIACRITICS",
+        Bool,
+        "false",
+        "Remove the accents of Latin letters, the index is rebuilt when changed",
+    ),
+    setting(
+        "SEARCH_STEMMER",
+        Text,
+        "",
+        "The language Latin words are stemmed in, e.g. english, the index is rebuilt when changed",
+    ),
+    setting(
+        "SEARCH_AUTO_REBUILD",
+        Bool,
+        "true",
+        "Rebuild an outdated index on startup, otherwise only notify",
+    ),
+    setting(
+        "SEARCH_SYNONYMS_PATH",
+        Text,
+        "",
+        "A file of synonym groups, such as `js = javascript` in a line",
+    ),
+    // Redis settings
+    setting(
+        "REDIS_URL",
+        Text,
+        "redis://localhost:6379/0",
+        "The Redis server",
+    ),
+    setting(
+        "REDIS_RETRIES",
+        Integer,
+        "2",
+        "How many times reads and idempotent writes are tried again after a connection error",
+    ),
+    setting(
+        "REDIS_RETRY_DELAY_MS",
+        Integer,
+        "50",
+        "The delay before the first retry, doubled for each following one, with jitter",
+    ),
+    setting(
+        "REDIS_BREAKER_THRESHOLD",
+        Integer,
+        "5",
+        "The failed calls in a row after which Redis is not called for a while, 0 to disable",
+    ),
+    setting(
+        "REDIS_BREAKER_COOLDOWN_SECS",
+        Integer,
+        "10",
+        "How long calls fail at once after the breaker opens",
+    ),
+    // Log
+    setting("LOG_REQUESTS", Bool, "true", "Log every request"),
+    setting(
+        "LOG_REDACT_PARAMS",
+        List,
+        "q,query,token,key,password,secret",
+        "Query params whose values are not logged",
+    ),
+    setting(
+        "LOG_HEADERS",
+        Bool,
+        "false",
+        "Log the headers of requests at the debug level",
+    ),
+    setting(
+        "LOG_REDACT_HEADERS",
+        List,
+        "authorization,cookie,set-cookie,x-admin-token,x-api-key",
+        "Headers whose values are not logged",
+    ),
+    setting(
+        "LOG_SPANS",
+        Bool,
+        "false",
+        "Log how long each traced function took",
+    ),
+    setting(
+        "LOG_OTLP_ENDPOINT",
+        Text,
+        "",
+        "Export traces over OTLP/HTTP, needs a build with the otel feature",
+    ),
+];
+
+/// Prefixes of the settings, other variables with them are likely misspelled settings
+const PREFIXES: &[&str] = &[
+    "APP_",
+    "AUTH_",
+    "POST_",
+    "HTTP_",
+    "CORS_",
+    "UPLOAD_",
+    "OCR_",
+    "SCAN_",
+    "DEMO_",
+    "SLACK_",
+    "NOTIFY_",
+    "JOB_",
+    "BACKUP_",
+    "DATABASE_",
+    "REPLICA_",
+    "REDIS_",
+    "SEARCH_",
+    "LOG_",
+    "STATIC_",
+];
+
+pub fn find(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+fn lookup(key: &str) -> &'static Setting {
+    find(key).unwrap_or_else(|| panic!("Setting {} is not in the registry", key))
+}
+
+/// The value of the variable, or the default of the setting
+fn raw(key: &str) -> String {
+    env::var(key).unwrap_or_else(|_| lookup(key).default.to_string())
+}
+
+/// Reads a setting and parses it into type `T`.
+pub fn read<T>(key: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    raw(key)
+        .parse()
+        .map_err(|_| anyhow!("Failed to parse {} env var", key))
+}
+
+/// Reads a size setting, see [`parse_size`].
+pub fn read_size(key: &str) -> Result<u64> {
+    parse_size(&raw(key)).ok_or_else(|| anyhow!("Failed to parse {} env var", key))
+}
+
+/// Reads a comma separated setting, empty items are skipped.
+pub fn read_list<T>(key: &str) -> Result<Vec<T>>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    raw(key)
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .map_err(|e| anyhow!("Failed to parse {} env var: {:?}", key, e))
+        })
+        .collect()
+}
+
+/// Returns the keys which look like settings but are unknown, e.g. misspelled ones.
+pub fn unknown_keys<I, S>(keys: I) -> Vec<S>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    keys.into_iter()
+        .filter(|key| {
+            let key = key.as_ref();
+            PREFIXES.iter().any(|prefix| key.starts_with(prefix)) && find(key).is_none()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_settings() {
+        let mut keys = HashSet::new();
+        for setting in SETTINGS {
+            assert!(keys.insert(setting.key), "{} is duplicated", setting.key);
+            let valid = match setting.kind {
+                Bool => setting.default.parse::<bool>().is_ok(),
+                Integer => setting.default.parse::<u64>().is_ok(),
+                Size => parse_size(setting.default).is_some(),
+                Cron => setting.default.split(' ').count() == 6,
+                Text | List | Secret => true,
+            };
+            assert!(valid, "the default of {} is invalid", setting.key);
+        }
+
+        // Every variable documented in `.env` is known
+        for line in include_str!("../../.env").lines() {
+            let line = line.trim_start_matches(['#', ' ']);
+            let Some((key, _)) = line.split_once('=') else {
+                continue;
+            };
+            if key.chars().all(|c| c.is_ascii_uppercase() || c == '_') && !key.starts_with("RUST_")
+            {
+                assert!(find(key).is_some(), "{} is not in the registry", key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let keys = ["HTTP_PORT", "HTTP_PROT", "HOME", "UPLOAD_PATHS"];
+        assert_eq!(unknown_keys(keys), vec!["HTTP_PROT", "UPLOAD_PATHS"]);
+    }
+}
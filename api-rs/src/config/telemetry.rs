@@ -0,0 +1,72 @@
+use crate::config::LogConfig;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Sets up logging, and the export of traces when `LOG_OTLP_ENDPOINT` is set.
+pub fn init_tracing(config: &LogConfig) {
+    let span_events = if config.log_spans {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::try_from_default_env()
+                .unwrap_or(format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
+        )
+        .with(fmt::layer().with_span_events(span_events))
+        .with(otel::layer(&config.otlp_endpoint))
+        .init();
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing::Subscriber;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    pub fn layer<S>(endpoint: &str) -> Option<impl Layer<S>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        if endpoint.is_empty() {
+            return None;
+        }
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .expect("Cannot create the OTLP exporter");
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        // Keeps the provider alive for the lifetime of the process
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod otel {
+    use tracing_subscriber::layer::Identity;
+
+    /// Traces are not exported without the `otel` feature, see `AppConfig::check`
+    pub fn layer(_endpoint: &str) -> Option<Identity> {
+        None
+    }
+}
@@ -1,13 +1,18 @@
+use crate::middleware::request_context::{current_request_context, RequestContext};
 use crate::util::extractor::Json;
+use crate::util::i18n::{translate, Locale};
+use crate::util::retry::CircuitOpen;
 use axum::extract::multipart::MultipartError;
 use axum::extract::rejection::{FormRejection, JsonRejection, QueryRejection};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use serde::Serialize;
 use sqlx::error::ErrorKind;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
+use uuid::Uuid;
 use validator::ValidationErrors;
 
 pub type ApiResult<T> = Result<T, ApiError>;
@@ -18,15 +23,28 @@ pub struct ErrorMessage {
     pub error: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// Messages of each invalid field, e.g. `{"name": ["can not be empty"]}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Identifies a server error in the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum ApiError {
     BadRequest(String),
     Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
     ServerError(String),
     TooManyRequests(String),
+    InsufficientStorage(String),
+    ServiceUnavailable(String),
 
     PathError(u16, String),
 
@@ -51,8 +69,13 @@ impl ApiError {
         match self {
             BadRequest(_) => 400,
             Unauthorized(_) => 401,
+            Forbidden(_) => 403,
             NotFound(_) => 404,
+            Conflict(_) => 409,
+            PayloadTooLarge(_) => 413,
             TooManyRequests(_) => 429,
+            ServiceUnavailable(_) => 503,
+            InsufficientStorage(_) => 507,
             PathError(code, _) => *code,
             QueryRejection(_) | JsonRejection(_) | FormRejection(_) | ValidationError(_) => 400,
             ServerError(_) | Sqlx(_) | Anyhow(_) => 500,
@@ -72,7 +95,15 @@ impl ApiError {
     fn message(&self) -> Option<String> {
         use super::ApiError::*;
         match self {
-            BadRequest(msg) | NotFound(msg) | TooManyRequests(msg) | Unauthorized(msg)
+            BadRequest(msg)
+            | NotFound(msg)
+            | Conflict(msg)
+            | PayloadTooLarge(msg)
+            | TooManyRequests(msg)
+            | InsufficientStorage(msg)
+            | ServiceUnavailable(msg)
+            | Unauthorized(msg)
+            | Forbidden(msg)
             | ServerError(msg) => Some(msg.clone()),
             PathError(_, message) => Some(message.clone()),
             QueryRejection(error) => Some(error.body_text()),
@@ -80,7 +111,7 @@ impl ApiError {
             FormRejection(error) => Some(error.body_text()),
             MultiPartError(error) => Some(error.body_text()),
             Sqlx(_) | Anyhow(_) => None,
-            ValidationError(err) => Some(err.to_string().replace('\n', "; ")),
+            ValidationError(_) => None,
             Any(msg) => msg.message.clone(),
         }
     }
@@ -90,12 +121,45 @@ impl ApiError {
     }
 
     fn to_json(&self, code: u16, error: &str, message: Option<&str>) -> Response {
+        let RequestContext {
+            request_id, locale, ..
+        } = current_request_context();
+
+        let errors = match self {
+            ApiError::ValidationError(err) => Some(field_errors(err, locale)),
+            _ => None,
+        };
+        // Keep a readable summary for clients that only show the message
+        let summary = errors.as_ref().map(|errors| {
+            errors
+                .iter()
+                .map(|(field, messages)| format!("{}: {}", field, messages.join(", ")))
+                .collect::<Vec<_>>()
+                .join("; ")
+        });
+
+        // Server errors get a unique reference, which users can report
+        let reference = (code >= 500).then(|| {
+            let reference =
+                format!("E{}", &Uuid::new_v4().simple().to_string()[..10]).to_uppercase();
+            tracing::error!(
+                "[{}] request {}: {:?}",
+                reference,
+                request_id.as_deref().unwrap_or("-"),
+                self
+            );
+            reference
+        });
+
         (
             StatusCode::from_u16(code).unwrap(),
             Json(ErrorMessage {
                 code,
-                error: error.to_string(),
-                message: message.map(String::from),
+                error: translate(locale, error).into_owned(),
+                message: summary.or_else(|| message.map(|msg| translate(locale, msg).into_owned())),
+                errors,
+                request_id,
+                reference,
             }),
         )
             .into_response()
@@ -129,10 +193,6 @@ impl IntoResponse for ApiError {
                     _ => self.to_default_json(),
                 }
             }
-            Anyhow(ref error) => {
-                tracing::error!("generic error: {:?}", error);
-                self.to_default_json()
-            }
             _ => self.to_default_json(),
         }
     }
@@ -168,7 +228,11 @@ impl From<sqlx::Error> for ApiError {
 
 impl From<anyhow::Error> for ApiError {
     fn from(err: anyhow::Error) -> Self {
-        ApiError::Anyhow(err)
+        // A service known to be down is reported as such, rather than as a bug
+        match err.downcast_ref::<CircuitOpen>() {
+            Some(open) => ApiError::ServiceUnavailable(open.to_string()),
+            None => ApiError::Anyhow(err),
+        }
     }
 }
 
@@ -202,6 +266,25 @@ impl From<ValidationErrors> for ApiError {
     }
 }
 
+/// Collects the translated messages of each invalid field,
+/// using the error code if a validator has no message.
+fn field_errors(errors: &ValidationErrors, locale: Locale) -> BTreeMap<String, Vec<String>> {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|e| match e.message {
+                    Some(ref msg) => translate(locale, msg).into_owned(),
+                    None => translate(locale, &e.code).into_owned(),
+                })
+                .collect();
+            (field.to_string(), messages)
+        })
+        .collect()
+}
+
 pub fn bad_request(msg: &str) -> ApiError {
     ApiError::BadRequest(msg.to_string())
 }
@@ -215,5 +298,32 @@ pub fn any_error(code: u16, error: &str, message: Option<&str>) -> ApiError {
         code,
         error: error.to_string(),
         message: message.map(String::from),
+        errors: None,
+        request_id: None,
+        reference: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use validator::ValidationError;
+
+    #[test]
+    fn test_field_errors() {
+        let mut errors = ValidationErrors::new();
+        errors.add(
+            "name",
+            ValidationError::new("length").with_message("can not be empty".into()),
+        );
+        errors.add("name", ValidationError::new("range"));
+        errors.add("url", ValidationError::new("url"));
+
+        let rv = field_errors(&errors, Locale::En);
+        assert_eq!(rv["name"], vec!["can not be empty", "range"]);
+        assert_eq!(rv["url"], vec!["url"]);
+
+        let rv = field_errors(&errors, Locale::Zh);
+        assert_eq!(rv["name"], vec!["不能为空", "超出范围"]);
+    }
+}
@@ -2,20 +2,32 @@ use crate::config::db::DB;
 use crate::config::rd::RD;
 use crate::config::AppConfig;
 use crate::errors::{any_error, ApiError};
-use crate::route::{post_api, post_page};
-use crate::service::search_service::FullTextSearch;
+use crate::middleware::client_info::resolve_client_info;
+use crate::middleware::log_request::log_request;
+use crate::middleware::request_context::scope_request_context;
+use crate::route::{admin_api, file_api, integration_api, post_api, post_page};
+use crate::service::asset_service::{serve_hashed, Assets};
+use crate::service::auth_service::AuthService;
+use crate::service::kv_service::KvStore;
+use crate::service::notifier_service::{notifier_from_config, Notifier};
+use crate::service::ocr_service::{ocr_engine_from_config, OcrEngine};
+use crate::service::realtime_service::RealtimeHub;
+use crate::service::scan_service::{virus_scanner_from_config, VirusScanner};
+use crate::service::search_service::{search_backend_from_config, SearchBackend};
+use crate::service::task_service::JobRegistry;
+use crate::service::url_service::UrlResolver;
 use axum::extract::DefaultBodyLimit;
 use axum::handler::HandlerWithoutStateExt;
-use axum::http::Uri;
+use axum::http::{HeaderName, HeaderValue, Uri};
 use axum::response::{IntoResponse, Response};
 use axum::Router;
-use jieba_rs::Jieba;
 use std::fs;
 use std::sync::Arc;
 use tower::ServiceBuilder;
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::services::ServeDir;
-use tower_http::trace::TraceLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::error;
 
 pub mod config;
@@ -31,23 +43,50 @@ pub mod util;
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<AppConfig>,
+    pub auth: Arc<AuthService>,
     pub db: Arc<DB>,
     pub rd: Arc<RD>,
-    pub fts: Arc<FullTextSearch>,
+    /// The store of the rate limits of the routes, Redis or one in memory in tests
+    pub kv: Arc<dyn KvStore>,
+    pub fts: Arc<dyn SearchBackend>,
+    pub ocr: Option<Arc<dyn OcrEngine>>,
+    pub realtime: Arc<RealtimeHub>,
+    pub scanner: Option<Arc<dyn VirusScanner>>,
+    pub notifier: Option<Arc<dyn Notifier>>,
+    pub jobs: Arc<JobRegistry>,
+    pub urls: Arc<UrlResolver>,
 }
 
 // Application router creation
 // Note: The order of layers is important.
 pub async fn create_app(state: AppState) -> Router {
     let config = &state.config;
-
+    let trusted_proxies = Arc::new(config.http.trusted_proxies.clone());
+
+    // Templates link to the hashed names of static files, so they can be cached forever
+    let assets = Arc::new(
+        Assets::load(&config.static_path, &config.static_url).unwrap_or_else(|e| {
+            error!("Failed to hash static files: {}", e);
+            Assets::default()
+        }),
+    );
     let static_route = Router::new().nest_service(
         &config.static_url,
-        ServeDir::new(config.static_path.clone()).not_found_service(handle_404.into_service()),
+        Router::new()
+            .fallback_service(
+                ServeDir::new(config.static_path.clone())
+                    .not_found_service(handle_404.into_service()),
+            )
+            .layer(axum::middleware::from_fn({
+                let assets = assets.clone();
+                move |req, next| serve_hashed(assets.clone(), req, next)
+            })),
     );
 
-    fs::create_dir_all(config.upload.base_path.clone())
-        .expect("Failed to create 'uploads' directory");
+    // Uploads fail without it, the other routes still work
+    if let Err(e) = fs::create_dir_all(&config.upload.base_path) {
+        error!("Failed to create 'uploads' directory: {}", e);
+    }
 
     let uploads_route = Router::new().nest_service(
         &config.upload.base_url,
@@ -56,30 +95,80 @@ pub async fn create_app(state: AppState) -> Router {
 
     // The order of the layers is important.
     // https://docs.rs/axum/latest/axum/middleware/index.html#ordering
-    let mut app = Router::new()
-        .nest("/api", post_api::create_routes(state.rd.pool.clone()))
-        .nest("/shared", post_page::create_routes())
+    let mut app = Router::new().nest(
+        "/api",
+        post_api::create_routes(state.kv.clone(), state.auth.clone()),
+    );
+
+    // The password of a demo is public, so the maintenance routes are not served
+    if !config.demo.enabled {
+        app = app.nest(
+            "/api/admin",
+            admin_api::create_routes(state.kv.clone(), state.auth.clone(), state.db.clone()),
+        );
+    }
+
+    app = app
+        .nest("/api/integrations", integration_api::create_routes())
+        .nest(
+            "/shared",
+            post_page::create_routes(assets, state.kv.clone()),
+        )
         .merge(static_route)
+        .merge(file_api::create_routes(&config.upload.base_url))
         .merge(uploads_route)
         .fallback(handle_404)
         .method_not_allowed_fallback(handle_405)
         .layer(
             ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(PropagateRequestIdLayer::x_request_id())
+                .layer(axum::middleware::from_fn(move |req, next| {
+                    resolve_client_info(trusted_proxies.clone(), req, next)
+                }))
+                .layer(axum::middleware::from_fn(scope_request_context))
                 .layer(CatchPanicLayer::custom(handle_panic))
                 // NOTE: Middleware added with Router::layer will run after routing
                 // https://stackoverflow.com/questions/75355826/route-paths-with-or-without-of-trailing-slashes-in-rust-axum
                 // https://www.matsimitsu.com/blog/2023-07-30-trailing-slashes-for-axum-routes
-                // .layer(NormalizePathLayer::trim_trailing_slash())
+                // Trailing slashes are trimmed by wrapping the router in `main`, see `http.trim_trailing_slash`
                 .layer(DefaultBodyLimit::max(config.http.max_body_size as usize))
                 .layer(config.http.cors.clone().into_layer()),
         );
 
+    // Clients can show a banner, telling users that their changes will be reset
+    if config.demo.enabled {
+        app = app.layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static("x-demo-mode"),
+            HeaderValue::from_static("true"),
+        ));
+    }
+
     if config.log.log_requests {
-        app = app.layer(TraceLayer::new_for_http());
+        let log = Arc::new(config.log.clone());
+        app = app.layer(axum::middleware::from_fn(move |req, next| {
+            log_request(log.clone(), req, next)
+        }));
     }
     app.with_state(state)
 }
 
+/// The paths of the routes served by `create_app`, besides the static files and the uploads,
+/// so that tests can probe all of them. Keep in sync with where `create_app` nests the routes.
+pub fn route_paths(state: &AppState) -> Vec<String> {
+    let config = &state.config;
+    let kv = state.kv.clone();
+
+    let mut paths = post_api::routes(kv.clone()).paths("/api");
+    if !config.demo.enabled {
+        paths.extend(admin_api::routes(kv.clone()).paths("/api/admin"));
+    }
+    paths.extend(integration_api::routes().paths("/api/integrations"));
+    paths.extend(post_page::routes(kv).paths("/shared"));
+    paths.extend(file_api::routes(&config.upload.base_url).paths(""));
+    paths
+}
+
 // Application state initialization
 // Cloning AppState is cheap because it uses Arc internally to share resources like DB and Redis connections.
 impl AppState {
@@ -87,7 +176,7 @@ impl AppState {
         let config = AppConfig::from_env();
 
         let db = Arc::new(
-            DB::new(&config.db.url, config.db.pool_size)
+            DB::new(&config.db)
                 .await
                 .expect("Cannot connect to database"),
         );
@@ -95,20 +184,31 @@ impl AppState {
         let rd = Arc::new(
             RD::new(&config.redis.url)
                 .await
-                .expect("Cannot connect to redis server"),
+                .expect("Cannot connect to redis server")
+                .with_resilience(config.redis.retry_policy(), config.redis.breaker()),
         );
 
-        let fts = Arc::new(FullTextSearch::new(
-            rd.clone(),
-            Arc::new(Jieba::new()),
-            "fts:".to_string(),
-        ));
+        let fts = search_backend_from_config(&config.search, db.clone(), rd.clone());
+
+        let auth = Arc::new(AuthService::new(config.auth.clone(), db.clone()));
+        let ocr = ocr_engine_from_config(&config.ocr);
+        let scanner = virus_scanner_from_config(&config.scan);
+        let notifier = notifier_from_config(&config.notify);
+        let urls = Arc::new(UrlResolver::new(&config.upload.base_url));
 
         AppState {
             config: Arc::new(config),
+            auth,
             db,
             fts,
             rd: rd.clone(),
+            kv: rd,
+            ocr,
+            realtime: Arc::new(RealtimeHub::default()),
+            scanner,
+            notifier,
+            jobs: Arc::new(JobRegistry::default()),
+            urls,
         }
     }
 }
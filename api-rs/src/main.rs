@@ -6,31 +6,132 @@
 #[cfg(test)]
 mod tests;
 
+use axum::extract::Request;
+use axum::ServiceExt;
+use mote::config::db::DB;
+use mote::config::telemetry::init_tracing;
+use mote::config::{AppConfig, LogConfig, ReplicaConfig};
+use mote::model::post::Post;
+use mote::service::auth_service::hash_password;
+use mote::service::check_service::{print_report, run_self_test};
+use mote::service::demo_service::{reset as reset_demo, seed, SeedOptions};
+use mote::service::event_service;
+use mote::service::replica_service::{replica_store_from_config, replicate, restore};
+use mote::service::search_service::migrate_index;
 use mote::service::task_service::start_jobs;
 use mote::util::env::load_dotenv;
 use mote::{create_app, AppState};
 use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
 use tokio::net::TcpListener;
-use tracing::debug;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{fmt, EnvFilter};
+use tower::Layer;
+use tower_http::normalize_path::NormalizePathLayer;
+use tracing::{debug, warn};
 
 #[tokio::main]
 async fn main() {
     load_dotenv();
 
-    if env::var("MOTE_PASSWORD").is_err() {
-        panic!("Environment variable 'MOTE_PASSWORD' is not set!");
+    // `mote check` validates the environment and exits, e.g. in container healthchecks
+    if matches!(env::args().nth(1).as_deref(), Some("check" | "--self-test")) {
+        let passed = print_report(&run_self_test().await);
+        std::process::exit(if passed { 0 } else { 1 });
     }
 
-    tracing_subscriber::registry()
-        .with(
-            EnvFilter::try_from_default_env()
-                .unwrap_or(format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
-        )
-        .with(fmt::layer())
-        .init();
+    // `mote hash-password` reads a password from stdin and prints the hash for MOTE_PASSWORD_HASH
+    if env::args().nth(1).as_deref() == Some("hash-password") {
+        eprint!("Password: ");
+        let mut password = String::new();
+        io::stdin()
+            .read_line(&mut password)
+            .expect("Cannot read the password");
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.is_empty() {
+            eprintln!("Usage: echo -n xxx | mote hash-password");
+            std::process::exit(2);
+        }
+        println!(
+            "{}",
+            hash_password(password).expect("Cannot hash the password")
+        );
+        return;
+    }
+
+    // `mote seed --posts 10000 --tags 200` generates fake data for development
+    let seed_options = (env::args().nth(1).as_deref() == Some("seed")).then(|| {
+        let args: Vec<String> = env::args().skip(2).collect();
+        SeedOptions::from_args(&args).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            eprintln!("Usage: mote seed [--posts N] [--tags N] [--images N]");
+            std::process::exit(2);
+        })
+    });
+
+    // `NEW_DATABASE_KEY=xxx mote rekey` encrypts the database with a new key
+    if env::args().nth(1).as_deref() == Some("rekey") {
+        let new_key = env::var("NEW_DATABASE_KEY").unwrap_or_default();
+        if new_key.is_empty() {
+            eprintln!("Usage: NEW_DATABASE_KEY=xxx mote rekey");
+            std::process::exit(2);
+        }
+        let config = AppConfig::from_env();
+        if config.db.key.is_none() {
+            eprintln!(
+                "DATABASE_KEY is not set, a plaintext database cannot be encrypted by a rekey"
+            );
+            std::process::exit(2);
+        }
+        let db = DB::new(&config.db).await.expect("Cannot open database");
+        db.rekey(&new_key).await.expect("Cannot change the key");
+        println!("The key is changed, set DATABASE_KEY to the new key before restarting");
+        return;
+    }
+
+    // `mote restore [--timestamp 2026-01-01T00:00:00Z] app.db` restores the database from its replica
+    if env::args().nth(1).as_deref() == Some("restore") {
+        let usage = "Usage: mote restore [--timestamp RFC3339] <path>";
+        let args: Vec<String> = env::args().skip(2).collect();
+        let (timestamp, path) = match args.as_slice() {
+            [path] => (None, path),
+            [flag, timestamp, path] if flag == "--timestamp" => {
+                let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Invalid timestamp: {}", e);
+                        std::process::exit(2);
+                    })
+                    .timestamp_millis();
+                (Some(timestamp), path)
+            }
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        };
+
+        let Some(store) = replica_store_from_config(&ReplicaConfig::from_env()) else {
+            eprintln!("REPLICA_URL is not set");
+            std::process::exit(2);
+        };
+        let summary = restore(store.as_ref(), Path::new(path), timestamp)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Cannot restore the database: {:#}", e);
+                std::process::exit(1);
+            });
+        println!(
+            "Restored generation {} with {} WAL segments, as of {}",
+            summary.generation,
+            summary.segments,
+            chrono::DateTime::from_timestamp_millis(summary.restored_to)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default()
+        );
+        return;
+    }
+
+    init_tracing(&LogConfig::from_env());
 
     let app_state = AppState::new().await;
 
@@ -45,6 +146,57 @@ async fn main() {
         db.migrate().await.expect("Cannot migrate database");
     }
 
+    // The seeded posts and the posts made before there were users belong to the admin
+    app_state
+        .auth
+        .ensure_admin()
+        .await
+        .expect("Cannot create the admin user");
+
+    // Older versions stored the urls of files rather than their keys
+    match Post::store_file_keys(db, &app_state.urls).await {
+        Ok(0) => {}
+        Ok(n) => debug!("Turned the file urls of {} posts into keys", n),
+        Err(e) => warn!("Cannot turn file urls into keys: {:?}", e),
+    }
+
+    match Post::derive_missing_titles(db).await {
+        Ok(0) => {}
+        Ok(n) => debug!("Derived the titles of {} posts", n),
+        Err(e) => warn!("Cannot derive the titles of posts: {:?}", e),
+    }
+
+    // Links between posts were not recorded before, a linked post could be deleted
+    match Post::backfill_post_links(db).await {
+        Ok(0) => {}
+        Ok(n) => debug!("Recorded the links of {} posts", n),
+        Err(e) => warn!("Cannot record the links of posts: {:?}", e),
+    }
+
+    if let Some(options) = seed_options {
+        seed(&app_state, &options).await.expect("Cannot seed data");
+        return;
+    }
+
+    // A demo starts with fresh sample data
+    if config.demo.enabled {
+        debug!("Resetting demo data...");
+        reset_demo(&app_state)
+            .await
+            .expect("Cannot reset demo data");
+    }
+
+    if config.replica.is_enabled() {
+        tokio::spawn(replicate(db.clone(), config.replica.clone()));
+    }
+
+    // The tokens of an index built by another analyzer would not match those of queries
+    if let Err(e) = migrate_index(&app_state, config.search.auto_rebuild).await {
+        warn!("Cannot check the version of the search index: {:#}", e);
+    }
+
+    event_service::start_consumers(&app_state);
+
     let state_clone = app_state.clone();
     tokio::spawn(async move {
         if let Err(e) = start_jobs(state_clone).await {
@@ -53,8 +205,27 @@ async fn main() {
     });
 
     let addr = format!("{}:{}", &config.http.ip, &config.http.port);
+    let trim_trailing_slash = config.http.trim_trailing_slash;
     let app = create_app(app_state).await;
     let listener = TcpListener::bind(&addr).await.unwrap();
     tracing::info!("Listening on {}", addr);
-    axum::serve(listener, app).await.unwrap()
+
+    if trim_trailing_slash {
+        // Middleware added with `Router::layer` runs after routing,
+        // so the whole router is wrapped to normalize the path before routing.
+        let app = NormalizePathLayer::trim_trailing_slash().layer(app);
+        axum::serve(
+            listener,
+            ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+        )
+        .await
+        .unwrap()
+    } else {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap()
+    }
 }
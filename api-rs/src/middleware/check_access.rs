@@ -1,17 +1,24 @@
-use crate::errors::{bad_request, ApiError, ApiResult};
-use crate::service::auth_service::AuthService;
-use axum::extract::Request;
-use axum::http::header;
+use crate::config::db::DB;
+use crate::errors::{ApiError, ApiResult};
+use crate::middleware::client_info::ClientInfo;
+use crate::model::audit::AuditLog;
+use crate::model::user::User;
+use crate::service::auth_service::{AuthService, Credential};
+use axum::extract::{OriginalUri, Request};
+use axum::http::{header, HeaderMap, HeaderName};
 use axum::middleware::Next;
 use axum::response::Response;
+use std::sync::Arc;
 
 /// Middleware function to validate tokens in incoming requests.
 ///
 /// This function checks if the request path is in the list of paths that skip token verification (`skip_paths`).
 /// If the path requires verification, it extracts the token from the `Cookie` or `Authorization` header,
-/// and checks if the token is valid using the `AuthService::is_valid_token` method.
+/// and checks if the token is valid using the `AuthService::authenticate` method.
+/// The `Credential` of a valid token and the `User` it belongs to are added to the request extensions.
 ///
 /// # Arguments
+/// * `auth` - The auth service of the app state.
 /// * `skip_paths` - A list of paths that should skip token verification.
 /// * `request` - The incoming HTTP request.
 /// * `next` - The next middleware or handler in the chain.
@@ -20,8 +27,9 @@ use axum::response::Response;
 /// * `AppResult<Response>` - Returns the response from the next middleware/handler if the token is valid or the path is skipped.
 ///   Otherwise, returns an error indicating the reason for failure (e.g., missing or unauthorized token).
 pub async fn check_access(
+    auth: Arc<AuthService>,
     skip_paths: &[&str],
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> ApiResult<Response> {
     let path = request.uri().path();
@@ -34,15 +42,120 @@ pub async fn check_access(
         return Ok(next.run(request).await);
     }
 
-    let token = get_cookie(&request, "token")
-        .or(extract_bearer(&request))
-        .ok_or(bad_request("No token provided"))?;
+    let token = request_token(&auth, &request);
+    let (user, credential) = authenticate(&auth, token).await?;
+    request.extensions_mut().insert(user);
+    request.extensions_mut().insert(credential);
 
-    if !AuthService::is_valid_token(&token) {
-        return Err(ApiError::Unauthorized("Invalid token".to_string()));
+    let response = next.run(request).await;
+    Ok(response)
+}
+
+/// The token sent with a request, and the address of the client.
+/// They are taken out of the request, which is not `Sync` and cannot be held across an await.
+fn request_token(auth: &AuthService, request: &Request) -> (Option<String>, Option<String>) {
+    let token = get_cookie(request.headers(), auth.cookie_name()).or(extract_bearer(request));
+    let ip = request
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|client| client.ip)
+        .map(|ip| ip.to_string());
+    (token, ip)
+}
+
+/// Resolves the user of a token from `request_token`
+async fn authenticate(
+    auth: &AuthService,
+    (token, ip): (Option<String>, Option<String>),
+) -> ApiResult<(User, Credential)> {
+    let token = token.ok_or(ApiError::Unauthorized("No token provided".to_string()))?;
+    auth.authenticate(&token, ip.as_deref())
+        .await
+        .ok_or(ApiError::Unauthorized("Invalid token".to_string()))
+}
+
+/// The header the admin token is sent in, it is never read from a cookie,
+/// so a page cannot make the browser of an admin call the admin routes
+const ADMIN_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-admin-token");
+
+/// Middleware function to protect the admin routes, which is stricter than `check_access`.
+///
+/// The address of the client must be in `auth.admin_allowed_ips` if it is set.
+/// If an admin token is set, it must be sent in the `X-Admin-Token` header, otherwise the login
+/// of an admin user is checked like `check_access` does. Each request and each rejected one is audited.
+///
+/// # Arguments
+/// * `auth` - The auth service of the app state.
+/// * `db` - The database the audit logs are written to.
+/// * `request` - The incoming HTTP request.
+/// * `next` - The next middleware or handler in the chain.
+pub async fn check_admin_access(
+    auth: Arc<AuthService>,
+    db: Arc<DB>,
+    mut request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    // The path without the prefix of the admin router is ambiguous in the logs
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(request.uri().path(), |uri| uri.path());
+    let target = format!("{} {}", request.method(), path);
+    let ip = request
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|client| client.ip);
+
+    if !auth.is_admin_allowed(ip) {
+        AuditLog::log(
+            &db,
+            None,
+            "admin.deny",
+            &target,
+            Some("address not allowed"),
+        )
+        .await;
+        return Err(ApiError::Forbidden(
+            "Admin routes are not allowed from this address".to_string(),
+        ));
     }
 
+    let user_id = if auth.has_admin_token() {
+        let token = request
+            .headers()
+            .get(ADMIN_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(ApiError::Unauthorized(
+                "No admin token provided".to_string(),
+            ))?;
+        if !auth.is_valid_admin_token(token) {
+            AuditLog::log(&db, None, "admin.deny", &target, Some("invalid token")).await;
+            return Err(ApiError::Unauthorized("Invalid admin token".to_string()));
+        }
+        None
+    } else {
+        let token = request_token(&auth, &request);
+        let (user, credential) = authenticate(&auth, token).await?;
+        if !user.admin {
+            AuditLog::log(
+                &db,
+                Some(user.id),
+                "admin.deny",
+                &target,
+                Some("not an admin"),
+            )
+            .await;
+            return Err(ApiError::Forbidden("Only admins can do this".to_string()));
+        }
+        let user_id = user.id;
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(credential);
+        Some(user_id)
+    };
+
     let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+    AuditLog::log(&db, user_id, "admin.request", &target, Some(&status)).await;
     Ok(response)
 }
 
@@ -55,9 +168,9 @@ fn extract_bearer(request: &Request) -> Option<String> {
     Some(token.to_string())
 }
 
-// Helper function to get a cookie by name from the request
-fn get_cookie(request: &Request, name: &str) -> Option<String> {
-    let cookie_header = request.headers().get(header::COOKIE)?;
+// Helper function to get a cookie by name from the headers of a request
+pub(crate) fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    let cookie_header = headers.get(header::COOKIE)?;
     let cookie_str = cookie_header.to_str().ok()?;
 
     cookie_str.split(';').find_map(|s| {
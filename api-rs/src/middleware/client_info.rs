@@ -0,0 +1,147 @@
+use crate::util::net::IpNet;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Where a request comes from, taking trusted reverse proxies into account
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// The address of the client, `None` if the connection info is not available, e.g. in tests
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`
+    pub scheme: String,
+    /// The host the client sent the request to
+    pub host: Option<String>,
+}
+
+impl ClientInfo {
+    /// The scheme and host the request was sent to, e.g. `https://example.com`
+    pub fn origin(&self) -> String {
+        format!(
+            "{}://{}",
+            self.scheme,
+            self.host.as_deref().unwrap_or("localhost")
+        )
+    }
+}
+
+/// Middleware function to resolve the [`ClientInfo`] of a request, and add it to the request extensions.
+///
+/// The `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers are used only if the request
+/// is sent by one of the `trusted_proxies`, since any client can set them.
+///
+/// # Arguments
+/// * `trusted_proxies` - The addresses of the reverse proxies in front of the server.
+/// * `request` - The incoming HTTP request.
+/// * `next` - The next middleware or handler in the chain.
+pub async fn resolve_client_info(
+    trusted_proxies: Arc<Vec<IpNet>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    let info = resolve(peer, request.headers(), &trusted_proxies);
+    request.extensions_mut().insert(info);
+    next.run(request).await
+}
+
+/// Finds the client behind the proxies: the last address in `X-Forwarded-For` which is not a trusted proxy,
+/// since the ones before it may have been set by the client.
+pub fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> ClientInfo {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let host = header_value(headers, header::HOST.as_str());
+
+    let Some(peer) = peer.filter(|ip| is_trusted(*ip)) else {
+        return ClientInfo {
+            ip: peer,
+            scheme: "http".to_string(),
+            host,
+        };
+    };
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut ip = peer;
+    for addr in forwarded.iter().rev() {
+        let Ok(addr) = addr.parse::<IpAddr>() else {
+            break;
+        };
+        ip = addr;
+        if !is_trusted(addr) {
+            break;
+        }
+    }
+
+    let scheme = header_value(headers, "x-forwarded-proto")
+        .map(|scheme| scheme.to_ascii_lowercase())
+        .filter(|scheme| scheme == "http" || scheme == "https")
+        .unwrap_or_else(|| "http".to_string());
+
+    ClientInfo {
+        ip: Some(ip.to_canonical()),
+        scheme,
+        host: header_value(headers, "x-forwarded-host").or(host),
+    }
+}
+
+/// The first value of a header, proxies append theirs to a list
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_resolve() {
+        let proxies: Vec<IpNet> = vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("localhost:8000"));
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"),
+        );
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert("x-forwarded-host", HeaderValue::from_static("example.com"));
+
+        let info = resolve(Some("127.0.0.1".parse().unwrap()), &headers, &proxies);
+        assert_eq!(info.ip, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(info.origin(), "https://example.com");
+
+        // The headers of untrusted peers are ignored
+        let info = resolve(Some("5.5.5.5".parse().unwrap()), &headers, &proxies);
+        assert_eq!(info.ip, Some("5.5.5.5".parse().unwrap()));
+        assert_eq!(info.origin(), "http://localhost:8000");
+
+        let info = resolve(Some("127.0.0.1".parse().unwrap()), &headers, &[]);
+        assert_eq!(info.ip, Some("127.0.0.1".parse().unwrap()));
+
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("oops, 10.0.0.2"),
+        );
+        let info = resolve(Some("127.0.0.1".parse().unwrap()), &headers, &proxies);
+        assert_eq!(info.ip, Some("10.0.0.2".parse().unwrap()));
+    }
+}
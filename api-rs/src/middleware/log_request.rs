@@ -0,0 +1,121 @@
+use crate::config::LogConfig;
+use axum::extract::Request;
+use axum::http::{HeaderMap, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info_span, Instrument};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Middleware function to log requests, like `TraceLayer`, but with the sensitive parts redacted.
+///
+/// The values of the query params in `log.redact_params` are replaced, such as search queries and tokens,
+/// and so are the headers in `log.redact_headers` when headers are logged.
+///
+/// # Arguments
+/// * `config` - The log settings.
+/// * `request` - The incoming HTTP request.
+/// * `next` - The next middleware or handler in the chain.
+pub async fn log_request(config: Arc<LogConfig>, request: Request, next: Next) -> Response {
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        uri = %redact_uri(request.uri(), &config.redact_params),
+        version = ?request.version(),
+    );
+
+    if config.log_headers {
+        span.in_scope(|| {
+            debug!(
+                headers = ?redact_headers(request.headers(), &config.redact_headers),
+                "started processing request"
+            )
+        });
+    }
+
+    let start = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let latency = start.elapsed().as_millis();
+    let status = response.status();
+
+    let latency = format!("{} ms", latency);
+    span.in_scope(|| {
+        if status.is_server_error() {
+            error!(%latency, status = status.as_u16(), "response failed");
+        } else {
+            debug!(%latency, status = status.as_u16(), "finished processing request");
+        }
+    });
+    response
+}
+
+/// Returns the path and query of the uri, with the values of the `params` replaced.
+/// Param names are matched case-insensitively.
+pub fn redact_uri(uri: &Uri, params: &[String]) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            if params.iter().any(|p| p.eq_ignore_ascii_case(name)) {
+                format!("{}={}", name, REDACTED)
+            } else {
+                pair.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", uri.path(), query)
+}
+
+/// Returns the headers as name-value pairs, with the values of the `names` replaced.
+pub fn redact_headers<'a>(headers: &'a HeaderMap, names: &[String]) -> Vec<(&'a str, &'a str)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str();
+            if names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                (name, REDACTED)
+            } else {
+                (name, value.to_str().unwrap_or("[binary]"))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, HeaderValue};
+
+    #[test]
+    fn test_redact() {
+        let params = vec!["q".to_string(), "token".to_string()];
+
+        let uri: Uri = "/api/search?q=secret%20plan&limit=10&Token=abc&token"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redact_uri(&uri, &params),
+            "/api/search?q=[REDACTED]&limit=10&Token=[REDACTED]&token=[REDACTED]"
+        );
+        let uri: Uri = "/api/get-posts".parse().unwrap();
+        assert_eq!(redact_uri(&uri, &params), "/api/get-posts");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc"),
+        );
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        assert_eq!(
+            redact_headers(&headers, &["Authorization".to_string()]),
+            vec![("authorization", REDACTED), ("accept", "*/*")]
+        );
+    }
+}
@@ -1,2 +1,5 @@
 pub mod check_access;
+pub mod client_info;
 pub mod limit_request;
+pub mod log_request;
+pub mod request_context;
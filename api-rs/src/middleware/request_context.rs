@@ -0,0 +1,59 @@
+use crate::middleware::client_info::ClientInfo;
+use crate::util::i18n::Locale;
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_http::request_id::RequestId;
+
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    /// The id set by `SetRequestIdLayer`
+    pub request_id: Option<String>,
+    /// The language negotiated from the `Accept-Language` header
+    pub locale: Locale,
+    /// The address resolved by `resolve_client_info`
+    pub client_ip: Option<String>,
+}
+
+tokio::task_local! {
+    static REQUEST_CONTEXT: RequestContext;
+}
+
+/// Middleware function to make some information of the request available while handling it,
+/// so that error responses can use it without access to the request.
+pub async fn scope_request_context(request: Request, next: Next) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .filter(|id| !id.is_empty())
+        .map(String::from);
+
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(Locale::from_accept_language)
+        .unwrap_or_default();
+
+    let client_ip = request
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|info| info.ip)
+        .map(|ip| ip.to_string());
+
+    let context = RequestContext {
+        request_id,
+        locale,
+        client_ip,
+    };
+    REQUEST_CONTEXT.scope(context, next.run(request)).await
+}
+
+/// Returns the context of the request being handled, or a default one outside of requests.
+pub fn current_request_context() -> RequestContext {
+    REQUEST_CONTEXT
+        .try_with(|ctx| ctx.clone())
+        .unwrap_or_default()
+}
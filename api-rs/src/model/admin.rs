@@ -0,0 +1,244 @@
+use crate::model::file::UploadUsage;
+use crate::model::post::FileType;
+use crate::util::retry::BreakerStatus;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+#[derive(Debug, Serialize)]
+pub struct SystemStatus {
+    pub app_name: String,
+    pub app_version: String,
+    pub uploads: UploadStatus,
+    pub search_index: IndexStatus,
+    pub redis: BreakerStatus,
+}
+
+/// What a client needs to know about the server before logging in, to adapt its UI
+#[derive(Debug, Serialize)]
+pub struct InstanceMeta {
+    pub app_name: String,
+    pub app_version: String,
+    pub features: Features,
+    /// The largest request body, and so the largest file which can be uploaded at once, in bytes
+    pub max_upload_size: u64,
+    /// The largest file which can be uploaded in chunks, in bytes
+    pub max_file_size: u64,
+    /// The image formats which are thumbnailed, e.g. `png`, others are uploaded as regular files
+    pub image_formats: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Features {
+    pub uploads: bool,
+    /// `redis` or `sqlite`
+    pub search_backend: String,
+    /// Whether the text in images and documents is searchable
+    pub ocr: bool,
+    /// Whether passkeys can be registered and log in
+    pub passkeys: bool,
+    /// Users are added by admins, there is no sign-up
+    pub registration: bool,
+    /// Whether the data is reset periodically
+    pub demo: bool,
+}
+
+/// The writes are counted since the app started
+#[derive(Debug, Serialize)]
+pub struct IndexStatus {
+    /// The number of indexed posts
+    pub docs: i64,
+    /// The version of the schema and the analyzer in use, e.g. `1:jieba`
+    pub version: String,
+    /// The version the index is built with
+    pub stored_version: String,
+    /// Whether the index should be rebuilt, because it is built with another version
+    pub outdated: bool,
+    pub rebuilding: bool,
+    /// The writes sent to the store
+    pub ops: u64,
+    /// The pipelines the writes are sent in
+    pub batches: u64,
+    /// The batches tried again after a transient error
+    pub retries: u64,
+    /// The batches which failed
+    pub failures: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptimizeResult {
+    pub size_before: u64,
+    pub size_after: u64,
+    pub wal_size_before: u64,
+    pub wal_size_after: u64,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadStatus {
+    #[serde(flatten)]
+    pub usage: UploadUsage,
+    /// 0 means unlimited
+    pub quota: u64,
+    /// The quota of each user, 0 means unlimited
+    pub user_quota: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobInfo {
+    pub name: &'static str,
+    pub cron: String,
+    pub enabled: bool,
+    pub running: bool,
+    pub last_run: Option<JobRun>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunJobRequest {
+    pub name: String,
+}
+
+/// The outcome of a run of a background job.
+#[derive(Debug, Serialize, Clone)]
+pub struct JobRun {
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub ok: bool,
+    /// What the job did, or the error if it failed
+    pub summary: String,
+}
+
+/// Inconsistencies between the posts, the tags and the upload directory.
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct OrphanReport {
+    /// Files in the upload directory not attached to any post, including the deleted ones
+    pub files: Vec<OrphanFile>,
+    /// Attachments whose files are missing from the upload directory
+    pub missing_files: Vec<MissingFile>,
+    /// Tags without posts, neither of their own nor of their descendants
+    pub empty_tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OrphanFile {
+    pub filename: String,
+    pub size: u64,
+    pub modified_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PruneUploadsRequest {
+    /// Lists the files which would be deleted without deleting them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PruneUploadsResult {
+    /// The deleted files, or those which would be deleted
+    pub files: Vec<OrphanFile>,
+    /// Their total size in bytes
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MissingFile {
+    pub post_id: i64,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportRequest {
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+/// The posts and the files taking the most space, the largest first
+#[derive(Debug, Serialize)]
+pub struct LargestReport {
+    pub posts: Vec<LargePost>,
+    pub files: Vec<LargeFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LargePost {
+    pub id: i64,
+    pub user_id: i64,
+    /// The name of the owner, `None` if the user is deleted
+    pub owner: Option<String>,
+    pub title: String,
+    /// The length of the content in bytes
+    pub size: i64,
+    pub file_count: i64,
+    pub created_at: i64,
+    pub deleted_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct LargeFile {
+    pub filename: String,
+    /// The user who uploaded the file
+    pub user_id: i64,
+    /// The name of the uploader, `None` if the user is deleted
+    pub owner: Option<String>,
+    pub original_name: String,
+    pub content_type: Option<String>,
+    pub size: i64,
+    pub created_at: i64,
+    /// The posts it is attached to, including the deleted ones
+    #[sqlx(json)]
+    pub post_ids: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct LargestRequest {
+    #[validate(range(min = 1, max = 100))]
+    #[serde(default = "default_largest_limit")]
+    pub limit: i64,
+    #[validate(range(min = 0))]
+    #[serde(default)]
+    pub offset: i64,
+    /// Deleted posts still take space until the trash is emptied
+    #[serde(default = "default_true")]
+    pub include_deleted: bool,
+    /// Only the files of a kind are listed
+    pub file_type: Option<FileType>,
+}
+
+fn default_largest_limit() -> i64 {
+    20
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The result of the last check of an external link in a shared post
+#[derive(Debug, Serialize, FromRow)]
+pub struct LinkHealth {
+    pub post_id: i64,
+    pub url: String,
+    /// The HTTP status, none if the request failed
+    pub status: Option<i64>,
+    /// Where a redirect leads to
+    pub location: Option<String>,
+    pub error: Option<String>,
+    /// Whether the link answered with a 2xx status, redirects are reported as well
+    pub ok: bool,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct LinkHealthRequest {
+    /// Lists the working links too, not only the broken and redirected ones
+    pub all: bool,
+}
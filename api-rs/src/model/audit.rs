@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct AuditLog {
+    pub id: i64,
+    /// Who made the change, none for the requests with the admin token and the jobs
+    pub user_id: Option<i64>,
+    pub action: String,
+    pub target: String,
+    pub detail: Option<String>,
+    /// The client address, see `TRUSTED_PROXIES`
+    pub ip: Option<String>,
+    pub created_at: i64,
+}
+
+/// The changes of a post since a time, repeated actions are counted once
+#[derive(Debug, Serialize, FromRow)]
+pub struct Activity {
+    /// One of `post.create`, `post.update`, `post.delete`, `post.restore` and `post.clear`
+    pub action: String,
+    pub post_id: i64,
+    pub count: i64,
+    pub last_at: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ActivityRequest {
+    #[validate(range(min = 0, message = "must be a valid timestamp"))]
+    pub since: Option<i64>,
+    #[validate(range(min = 1, max = 500))]
+    pub limit: Option<i64>,
+}
@@ -0,0 +1,153 @@
+use crate::model::user::ADMIN_USER_ID;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// The version of the backup format, a backup of a newer version cannot be imported.
+/// Version 2 adds the users, the posts and tags of version 1 belong to the admin.
+pub const BACKUP_VERSION: u32 = 2;
+
+/// The data of a backup, stored as `backup.json` in the archive next to the uploaded files.
+/// The rows are kept as they are stored, e.g. files hold their keys rather than urls.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub created_at: i64,
+    #[serde(default)]
+    pub users: Vec<BackupUser>,
+    pub posts: Vec<BackupPost>,
+    pub tags: Vec<BackupTag>,
+    pub tag_post_assoc: Vec<BackupTagPost>,
+    pub files: Vec<BackupFile>,
+    #[serde(default)]
+    pub file_uploads: Vec<BackupFileUpload>,
+}
+
+/// A user with the hash of their password, so that they can log in after a restore
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupUser {
+    pub id: i64,
+    pub name: String,
+    pub password_hash: String,
+    pub admin: bool,
+    pub created_at: i64,
+}
+
+fn admin_user_id() -> i64 {
+    ADMIN_USER_ID
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupPost {
+    pub id: i64,
+    #[serde(default = "admin_user_id")]
+    pub user_id: i64,
+    pub content: String,
+    /// The files as a JSON array
+    pub files: Option<String>,
+    pub color: Option<String>,
+    pub shared: bool,
+    pub deleted_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub parent_id: Option<i64>,
+    pub children_count: i64,
+    /// The share options as a JSON object
+    pub share_options: Option<String>,
+    pub title: Option<String>,
+    pub title_custom: bool,
+    #[serde(default)]
+    pub purge_after: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupTag {
+    pub id: i64,
+    #[serde(default = "admin_user_id")]
+    pub user_id: i64,
+    pub name: String,
+    pub sticky: bool,
+    pub sort_order: i64,
+    pub hidden: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupTagPost {
+    pub tag_id: i64,
+    pub post_id: i64,
+}
+
+/// The record of an uploaded file, including its extracted text so that it is not extracted again
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupFile {
+    pub id: String,
+    pub filename: String,
+    pub original_name: String,
+    pub content_type: Option<String>,
+    pub size: i64,
+    pub created_at: i64,
+    pub text: Option<String>,
+    #[serde(default = "admin_user_id")]
+    pub user_id: i64,
+}
+
+/// An upload of a file with the name it was uploaded with
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BackupFileUpload {
+    pub id: String,
+    pub file_id: String,
+    pub user_id: i64,
+    pub original_name: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct ExportRequest {
+    pub format: ArchiveFormat,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Detects the format from the first bytes of an archive
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"PK\x03\x04") {
+            Some(ArchiveFormat::Zip)
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// What is restored from a backup
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct ImportResult {
+    pub posts: usize,
+    pub tags: usize,
+    /// The uploaded files written to the upload directory, those which exist already are kept
+    pub files: usize,
+}
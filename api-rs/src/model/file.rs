@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+/// An uploaded file stored in the upload directory.
+/// The id is the hex encoded SHA-256 hash of the file content.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct StoredFile {
+    pub id: String,
+    pub filename: String,
+    pub original_name: String,
+    pub content_type: Option<String>,
+    pub size: i64,
+    pub created_at: i64,
+    #[serde(skip_serializing)]
+    pub text: Option<String>,
+    /// The user who uploaded the file first, whose quota it counts against
+    pub user_id: i64,
+}
+
+/// An upload of a stored file, with the name it was uploaded with.
+/// The same content uploaded again is stored once, but gets an upload of its own.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct FileUpload {
+    pub id: String,
+    /// The id of the stored file
+    pub file_id: String,
+    pub user_id: i64,
+    pub original_name: String,
+    pub created_at: i64,
+}
+
+/// Disk space used by the uploaded files.
+#[derive(Debug, Serialize)]
+pub struct UploadUsage {
+    pub files: i64,
+    pub size: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UploadFileRequest {
+    /// Chosen by the client to receive `upload_progress` events of this upload over the websocket
+    #[validate(length(min = 1, max = 64))]
+    pub token: Option<String>,
+}
+
+/// Starts a resumable upload, or resumes the one of the same file
+#[derive(Debug, Deserialize, Validate, Clone)]
+pub struct InitUploadRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub filename: String,
+    #[validate(range(min = 1))]
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// The hex encoded SHA-256 hash of the file, checked once all chunks are received
+    #[validate(length(equal = 64))]
+    pub checksum: String,
+}
+
+/// A resumable upload, kept with its chunks until it is complete
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChunkedUpload {
+    pub user_id: i64,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    pub checksum: String,
+    pub chunk_size: u64,
+}
+
+impl ChunkedUpload {
+    pub fn chunks(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+
+    /// The size of a chunk, all but the last one are of `chunk_size`
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        (self.size - index * self.chunk_size).min(self.chunk_size)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    /// The indexes of the chunks received, the others are to be sent
+    pub received: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadChunkRequest {
+    pub id: String,
+    pub index: u64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CompleteUploadRequest {
+    pub id: String,
+    /// Chosen by the client to receive `upload_progress` events of this upload over the websocket
+    #[validate(length(min = 1, max = 64))]
+    pub token: Option<String>,
+}
@@ -0,0 +1,17 @@
+use serde::Serialize;
+
+/// The reply to a Slack slash command, shown only to the user who sent it
+#[derive(Debug, Serialize)]
+pub struct SlackReply {
+    pub response_type: &'static str,
+    pub text: String,
+}
+
+impl SlackReply {
+    pub fn ephemeral(text: impl Into<String>) -> Self {
+        Self {
+            response_type: "ephemeral",
+            text: text.into(),
+        }
+    }
+}
@@ -1,3 +1,13 @@
+pub mod admin;
+pub mod audit;
+pub mod backup;
+pub mod file;
+pub mod integration;
+pub mod notification;
+pub mod passkey;
 pub mod post;
+pub mod reaction;
+pub mod session;
 pub mod tag;
+pub mod user;
 pub mod validator;
@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Notification {
+    pub id: i64,
+    /// What happened, e.g. `job.failed` or `index.rebuilt`
+    pub kind: String,
+    pub message: String,
+    pub read_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct GetNotificationsRequest {
+    #[serde(default)]
+    pub unread: bool,
+    #[validate(range(min = 1, max = 500))]
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkNotificationReadRequest {
+    /// All notifications are marked as read if it is missing
+    pub id: Option<i64>,
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Passkey {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+    /// The base64url encoded id given by the authenticator
+    pub credential_id: String,
+    pub name: String,
+    /// The COSE encoded public key
+    #[serde(skip_serializing)]
+    pub public_key: Vec<u8>,
+    /// The signature counter, which a cloned authenticator fails to increase
+    #[serde(skip_serializing)]
+    pub sign_count: i64,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// The result of `navigator.credentials.create()` or `navigator.credentials.get()`,
+/// binary fields are base64url encoded
+#[derive(Debug, Deserialize)]
+pub struct PublicKeyCredential<R> {
+    pub id: String,
+    pub response: R,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RegisterPasskeyRequest {
+    /// A name to tell the passkeys apart, such as the device
+    #[validate(length(min = 1, max = 64))]
+    pub name: String,
+    pub credential: PublicKeyCredential<AttestationResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginRequest {
+    pub credential: PublicKeyCredential<AssertionResponse>,
+}
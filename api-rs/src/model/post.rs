@@ -1,8 +1,11 @@
-use crate::model::validator::validate_date_format;
+use crate::model::validator::{validate_date_format, validate_hex_color};
+use crate::util::html::truncate_html;
 use crate::util::maybe::MaybeAbsent;
 use derive_more::Display;
-use serde::{Deserialize, Serialize};
+use serde::de::{value::StrDeserializer, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::FromRow;
+use std::collections::BTreeMap;
 use validator::Validate;
 
 // which Rust types correspond to which sqlite column types:
@@ -21,6 +24,35 @@ pub struct PostRow {
     #[serde(skip_serializing)]
     pub parent_id: Option<i64>,
     pub children_count: i64,
+    #[serde(serialize_with = "serialize_raw_json")]
+    pub share_options: Option<String>,
+    /// Derived from the first heading of the content, unless it is set by the client
+    pub title: Option<String>,
+    /// Whether the title is set by the client
+    pub title_custom: bool,
+    /// When the post in the trash is deleted permanently, if sooner than the trash is purged
+    pub purge_after: Option<i64>,
+    /// The owner, whose tags the post has
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+}
+
+impl PostRow {
+    /// Decode the attached files, which are stored as a JSON array
+    pub fn file_infos(&self) -> Vec<FileInfo> {
+        self.files
+            .as_deref()
+            .and_then(|files| serde_json::from_str(files).ok())
+            .unwrap_or_default()
+    }
+
+    /// Decode the share options, the defaults if they are not set
+    pub fn share_options(&self) -> ShareOptions {
+        self.share_options
+            .as_deref()
+            .and_then(|options| serde_json::from_str(options).ok())
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -34,7 +66,19 @@ pub struct Post {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub score: Option<f64>,
 
+    /// An excerpt around the best match of a search, set when asked for `snippets`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+
+    /// When the post or any of its children was last updated, set when ordered by `last_activity`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity_at: Option<i64>,
+
     pub tags: Vec<String>,
+
+    /// Whether the content is cut short, the full one is returned by `get-post`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
 }
 
 impl From<PostRow> for Post {
@@ -43,7 +87,75 @@ impl From<PostRow> for Post {
             row,
             parent: None,
             score: None,
+            snippet: None,
+            last_activity_at: None,
             tags: vec![],
+            truncated: false,
+        }
+    }
+}
+
+impl Post {
+    /// Cuts the content after `max_chars` chars of text, 0 means no limit.
+    pub fn truncate_content(&mut self, max_chars: usize) {
+        if max_chars == 0 {
+            return;
+        }
+        if let Some(content) = truncate_html(&self.row.content, max_chars) {
+            self.row.content = content;
+            self.truncated = true;
+        }
+    }
+
+    /// The value the posts are ordered by, which is the cursor of the next page
+    pub fn sort_key(&self, order_by: &SortingField) -> Option<i64> {
+        match order_by {
+            SortingField::CreatedAt => Some(self.row.created_at),
+            SortingField::UpdatedAt => Some(self.row.updated_at),
+            SortingField::DeletedAt => self.row.deleted_at,
+            SortingField::LastActivity => self.last_activity_at,
+        }
+    }
+}
+
+/// A post of a thread, see `get-flat-thread`
+#[derive(Debug, Serialize)]
+pub struct ThreadPost {
+    #[serde(flatten)]
+    pub post: Post,
+    /// How deep the post is nested, the root of the thread is 0
+    pub depth: usize,
+}
+
+/// The metadata of a post, to render a timeline before the content is loaded
+#[derive(Debug, Serialize, FromRow)]
+pub struct PostMeta {
+    pub id: i64,
+    pub title: Option<String>,
+    pub color: Option<String>,
+    pub shared: bool,
+    pub deleted_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub parent_id: Option<i64>,
+    pub children_count: i64,
+    pub file_count: i64,
+    #[sqlx(skip)]
+    pub tags: Vec<String>,
+    /// Like `Post::last_activity_at`
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity_at: Option<i64>,
+}
+
+impl PostMeta {
+    /// Like `Post::sort_key`
+    pub fn sort_key(&self, order_by: &SortingField) -> Option<i64> {
+        match order_by {
+            SortingField::CreatedAt => Some(self.created_at),
+            SortingField::UpdatedAt => Some(self.updated_at),
+            SortingField::DeletedAt => self.deleted_at,
+            SortingField::LastActivity => self.last_activity_at,
         }
     }
 }
@@ -69,6 +181,68 @@ pub enum SortingField {
     UpdatedAt,
     #[display("deleted_at")]
     DeletedAt,
+    /// A parent bubbles up when any of its children is updated, like the active threads of chat apps
+    #[display("last_activity")]
+    LastActivity,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchOrder {
+    /// The most relevant first
+    #[default]
+    Score,
+    /// The newest first
+    CreatedAt,
+    UpdatedAt,
+}
+
+/// A kind of attached file, by its content type
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileType {
+    Image,
+    Video,
+    Audio,
+    /// PDFs, office documents, e-books and plain text
+    Document,
+}
+
+impl FileType {
+    /// The `LIKE` patterns of the content types
+    pub fn content_type_patterns(&self) -> &'static [&'static str] {
+        match self {
+            FileType::Image => &["image/%"],
+            FileType::Video => &["video/%"],
+            FileType::Audio => &["audio/%"],
+            FileType::Document => &[
+                "application/pdf",
+                "application/msword",
+                "application/rtf",
+                "application/epub+zip",
+                "application/vnd.openxmlformats-officedocument.%",
+                "application/vnd.ms-%",
+                "application/vnd.oasis.opendocument.%",
+                "text/%",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostFields {
+    #[default]
+    All,
+    Meta,
+}
+
+/// The related data which can be skipped when listing posts, each of them costs a query
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PostInclude {
+    Tags,
+    Parent,
 }
 
 #[derive(Debug, Deserialize)]
@@ -83,50 +257,120 @@ pub struct Name {
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
+    /// The admin user logs in if it is not set, as before there were users
+    pub name: Option<String>,
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct FileInfo {
+    pub id: Option<String>,
+    /// The id of this upload of the file, `/uploads/dl/{upload_id}` serves it under its original name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_id: Option<String>,
     pub url: String,
+    pub original_name: Option<String>,
+    pub content_type: Option<String>,
     pub thumb_url: Option<String>,
     pub size: Option<u64>,
     pub width: Option<u32>,
     pub height: Option<u32>,
+    pub taken_at: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// The larger thumbnails of an image, the narrowest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<ImageVariant>,
+}
+
+/// A thumbnail of an image in another size, and possibly in another format
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImageVariant {
+    pub width: u32,
+    pub url: String,
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct SearchRequest {
     #[validate(length(min = 1, message = "can not be empty"))]
     pub query: String,
+    /// The size of a page, all results are returned at once if not set
+    #[validate(range(min = 1, max = 500))]
     pub limit: Option<usize>,
+    /// The offset of the page, which is the cursor returned with the previous one
+    #[validate(range(min = 0, max = 1_000_000))]
+    pub cursor: Option<i64>,
     pub partial: Option<bool>,
+    #[serde(default)]
+    pub order_by: SearchOrder,
+    /// Returns an excerpt around the best match of each post, in place of the marked content
+    #[serde(default)]
+    pub snippets: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct QuickSearchRequest {
+    #[validate(length(min = 1, message = "can not be empty"))]
+    pub q: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuickSearchHit {
+    pub id: i64,
+    pub title: String,
+    pub snippet: String,
 }
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct FilterPostRequest {
     pub cursor: Option<i64>,
+    /// The `cursor_id` of the previous page, which breaks the ties of posts with the same `cursor`
+    pub cursor_id: Option<i64>,
     pub deleted: bool,
     pub parent_id: Option<i64>,
-    pub color: Option<CategoryColor>,
+    /// Matches any of the colors, e.g. `color=red,blue`
+    #[serde(deserialize_with = "deserialize_comma_separated")]
+    pub color: Vec<CategoryColor>,
     pub tag: Option<String>,
     pub shared: Option<bool>,
     pub has_files: Option<bool>,
+    /// Posts with a file of the type attached, e.g. `file_type=document` for PDFs
+    pub file_type: Option<FileType>,
     pub order_by: SortingField,
     pub ascending: bool,
     pub start_date: Option<i64>,
     pub end_date: Option<i64>,
+    /// `fields=meta` returns the metadata only, without content and files
+    pub fields: PostFields,
+    /// Includes the posts of hidden tags when no tag is given
+    pub include_hidden: bool,
+    /// The related data attached to the posts, e.g. `include=tags`, `include=` for none, all by default
+    #[serde(deserialize_with = "deserialize_optional_comma_separated")]
+    pub include: Option<Vec<PostInclude>>,
+}
+
+impl FilterPostRequest {
+    pub fn includes(&self, item: PostInclude) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.contains(&item))
+    }
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct CreatePostRequest {
     #[validate(length(min = 1, message = "can not be empty"))]
     pub content: String,
+    /// Derived from the content if it is not set
+    #[validate(length(max = 200))]
+    pub title: Option<String>,
     pub files: Option<Vec<FileInfo>>,
     pub color: Option<CategoryColor>,
     pub shared: Option<bool>,
     pub parent_id: Option<i64>,
+    #[validate(range(min = 0, message = "must be a valid timestamp"))]
+    pub created_at: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -136,6 +380,10 @@ pub struct UpdatePostRequest {
     #[serde(default)]
     pub content: MaybeAbsent<String>,
 
+    /// A title sets a custom one, null derives it from the content again
+    #[serde(default)]
+    pub title: MaybeAbsent<Option<String>>,
+
     #[serde(default)]
     pub shared: MaybeAbsent<bool>,
 
@@ -145,6 +393,32 @@ pub struct UpdatePostRequest {
     pub color: MaybeAbsent<Option<CategoryColor>>,
     #[serde(default)]
     pub parent_id: MaybeAbsent<Option<i64>>,
+    #[serde(default)]
+    pub share_options: MaybeAbsent<Option<ShareOptions>>,
+}
+
+/// How a shared post is shown on its public page
+#[derive(Debug, Serialize, Deserialize, Validate, Default, Clone)]
+#[serde(default)]
+pub struct ShareOptions {
+    pub hide_date: bool,
+    pub hide_tags: bool,
+    /// The color of links and tags, e.g. `#3b82f6`
+    #[validate(custom(function = "validate_hex_color"))]
+    pub accent_color: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SyncPostRequest {
+    /// Returns the posts changed after this timestamp in milliseconds
+    #[validate(range(min = 0, message = "must be a valid timestamp"))]
+    pub since: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemovePostFileRequest {
+    pub id: i64,
+    pub url: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -152,6 +426,32 @@ pub struct DeletePostRequest {
     pub id: i64,
     #[serde(default)]
     pub hard: bool,
+    #[serde(default)]
+    pub force: bool,
+    /// Moving the post to the trash, a timestamp in milliseconds when it is deleted permanently
+    pub purge_after: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct BatchSetColorRequest {
+    #[validate(length(min = 1, max = 1000))]
+    pub ids: Vec<i64>,
+    /// None clears the color
+    pub color: Option<CategoryColor>,
+}
+
+/// The ids of the posts which are changed, those which are not found are skipped
+#[derive(Debug, Serialize)]
+pub struct BatchUpdateResponse {
+    pub ids: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SchedulePurgeRequest {
+    pub id: i64,
+    /// A timestamp in milliseconds, none to keep the post until the trash is purged
+    #[validate(range(min = 0, message = "must be a valid timestamp"))]
+    pub purge_after: Option<i64>,
 }
 
 #[derive(Debug, Deserialize, Validate)]
@@ -163,25 +463,102 @@ pub struct DateRange {
     pub offset: i32,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct DayPostsRequest {
+    #[validate(custom(function = "validate_date_format"))]
+    pub date: String,
+    /// The timezone offset in minutes, as in `DateRange`
+    pub offset: i32,
+    pub cursor: Option<i64>,
+    pub cursor_id: Option<i64>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct CreateResponse {
     pub id: i64,
     pub created_at: i64,
     pub updated_at: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<LintWarning>,
+}
+
+/// The problems found in the content of a saved post, the post is saved anyway
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LintWarning {
+    /// A link to a post which does not exist or is deleted
+    BrokenLink { post_id: i64 },
+    /// Hashtags without a name
+    EmptyHashTag { count: usize },
+    /// An image pasted into the content as a data uri, the size is in bytes
+    LargeInlineImage { size: u64 },
 }
 
 #[derive(Debug, Serialize)]
-pub struct PostPagination {
-    pub posts: Vec<Post>,
+pub struct UpdateResponse {
+    pub warnings: Vec<LintWarning>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostPagination<T = Post> {
+    pub posts: Vec<T>,
     pub cursor: i64,
+    /// The id of the last post, sent back with `cursor` for the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor_id: Option<i64>,
     pub size: i64,
+    /// Set when the requested tag has been renamed, the posts are those of the new name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_renamed_to: Option<String>,
+    /// Set when a search matches too many posts, only the likely most relevant ones are ranked
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PostStats {
     pub post_count: i64,
     pub tag_count: i64,
     pub day_count: i64,
+    pub color_counts: BTreeMap<String, i64>,
+    /// Posts of each tag, not including those of its descendants
+    pub tag_counts: BTreeMap<String, i64>,
+}
+
+/// The posts in the trash, and the bytes of the files which are attached to or embedded in them only.
+/// The same is returned when the trash is emptied, the files are deleted by the next cleanup of unattached files.
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct TrashStats {
+    pub post_count: i64,
+    pub file_size: i64,
+}
+
+/// An advisory lock of a post being edited, other editors are warned but not blocked
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PostLock {
+    /// The random id a client generates for each editor, e.g. for each tab
+    pub holder: String,
+    /// Shown to the other editors, the user agent if not given
+    pub device: Option<String>,
+    pub locked_at: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PostLockRequest {
+    pub id: i64,
+    #[validate(length(min = 1, max = 64))]
+    pub holder: String,
+    #[validate(length(max = 100))]
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostLockStatus {
+    /// Whether the lock is held by the editor of the request
+    pub acquired: bool,
+    /// The lock of the post, held by another editor if not acquired, `None` if the post is not locked
+    pub lock: Option<PostLock>,
+    pub expires_at: Option<i64>,
 }
 
 fn serialize_raw_json<S>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
@@ -197,3 +574,56 @@ where
         None => serializer.serialize_none(),
     }
 }
+
+fn deserialize_comma_separated<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = String::deserialize(deserializer)?;
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| T::deserialize(StrDeserializer::<D::Error>::new(item)))
+        .collect()
+}
+
+fn deserialize_optional_comma_separated<'de, D, T>(
+    deserializer: D,
+) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    deserialize_comma_separated(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_colors() {
+        let req: FilterPostRequest = serde_json::from_str(r#"{"color": "red, blue"}"#).unwrap();
+        assert_eq!(req.color.len(), 2);
+        assert_eq!(req.color[1].to_string(), "blue");
+
+        let req: FilterPostRequest = serde_json::from_str("{}").unwrap();
+        assert!(req.color.is_empty());
+
+        assert!(serde_json::from_str::<FilterPostRequest>(r#"{"color": "pink"}"#).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_include() {
+        let req: FilterPostRequest = serde_json::from_str("{}").unwrap();
+        assert!(req.includes(PostInclude::Tags) && req.includes(PostInclude::Parent));
+
+        let req: FilterPostRequest = serde_json::from_str(r#"{"include": "tags"}"#).unwrap();
+        assert!(req.includes(PostInclude::Tags) && !req.includes(PostInclude::Parent));
+
+        let req: FilterPostRequest = serde_json::from_str(r#"{"include": ""}"#).unwrap();
+        assert!(!req.includes(PostInclude::Tags) && !req.includes(PostInclude::Parent));
+    }
+}
@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+use validator::Validate;
+
+/// The reactor of the owner of a post, who reacts through the API
+pub const OWNER: &str = "owner";
+
+/// The cookie keeping the id of a visitor who reacts to shared posts
+pub const VISITOR_COOKIE: &str = "mote_visitor";
+
+/// The reactor of a visitor of shared posts, told apart by the id in their cookie
+pub fn visitor_reactor(id: &Uuid) -> String {
+    format!("visitor:{}", id)
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ToggleReactionRequest {
+    pub id: i64,
+    #[validate(length(min = 1, max = 16, message = "must be an emoji"))]
+    pub emoji: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SharedReactionRequest {
+    #[validate(length(min = 1, max = 16, message = "must be an emoji"))]
+    pub emoji: String,
+}
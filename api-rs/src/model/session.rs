@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// A login, the token itself is not stored
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Session {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub user_id: i64,
+    pub user_agent: Option<String>,
+    /// The client address when the session was last used, see `TRUSTED_PROXIES`
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    /// Updated at most once a minute
+    pub last_seen_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether the request is sent with the token of this session
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub id: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    /// Sent as a Bearer token by clients which do not keep cookies
+    pub token: String,
+    /// When the token expires, in milliseconds
+    pub expires_at: i64,
+    pub app_name: String,
+    pub app_version: String,
+}
@@ -1,28 +1,99 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use validator::Validate;
 
 #[derive(Debug, Serialize, FromRow)]
 pub struct Tag {
     pub id: i64,
+    #[serde(skip_serializing)]
+    pub user_id: i64,
     pub name: String,
     pub sticky: bool,
+    pub sort_order: i64,
+    pub hidden: bool,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
-#[derive(Debug, Serialize, FromRow)]
+#[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct TagWithPostCount {
     pub name: String,
     pub sticky: bool,
+    pub sort_order: i64,
+    /// Whether the tag or any of its parents is hidden
+    pub hidden: bool,
     pub post_count: i64,
 }
 
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct GetTagsRequest {
+    pub include_hidden: bool,
+    /// The tags are in their custom order without it
+    pub sort: Option<TagSort>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagSort {
+    Name,
+    /// The most used first
+    PostCount,
+    /// The tags with the newest posts first
+    Recent,
+}
+
+impl TagSort {
+    pub const ALL: [TagSort; 3] = [TagSort::Name, TagSort::PostCount, TagSort::Recent];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagSort::Name => "name",
+            TagSort::PostCount => "post_count",
+            TagSort::Recent => "recent",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RenameTagRequest {
     pub name: String,
     pub new_name: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct ReorderTagsRequest {
+    /// Tag names in the new order, the other tags keep their positions
+    #[validate(length(min = 1, message = "can not be empty"))]
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteTagRequest {
+    pub name: String,
+    #[serde(default)]
+    pub mode: DeleteTagMode,
+}
+
+/// What happens to the posts of a deleted tag, the descendant tags are deleted as well
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteTagMode {
+    /// The posts are moved to the trash, the tag disappears as it has no posts left
+    #[default]
+    TrashPosts,
+    /// The tag is removed from the posts, including their content
+    Detach,
+    /// Only the tag is deleted, the posts keep it in their content
+    DeleteTagOnly,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HideTagRequest {
+    pub name: String,
+    pub hidden: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct StickyTagRequest {
     pub name: String,
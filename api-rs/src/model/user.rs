@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use validator::Validate;
+
+/// The admin user, who is created on startup with the configured password.
+/// The posts and tags made before there were users belong to it.
+pub const ADMIN_USER_ID: i64 = 1;
+
+/// Someone who logs in, who sees only their own posts and tags.
+/// It is added to the request extensions by `check_access`.
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    /// An argon2id hash in the PHC string format
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    /// Admins can use `/api/admin` if no admin token is set, and make or restore backups
+    pub admin: bool,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateUserRequest {
+    #[validate(length(min = 1, max = 64))]
+    pub name: String,
+    #[validate(length(min = 8, max = 256))]
+    pub password: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserRequest {
+    pub id: i64,
+}
@@ -11,3 +11,29 @@ pub fn validate_date_format(date: &str) -> Result<(), ValidationError> {
         Err(error)
     }
 }
+
+/// Validate a hex color like `#3b82f6` or `#fff`
+pub fn validate_hex_color(color: &str) -> Result<(), ValidationError> {
+    let digits = color.strip_prefix('#').unwrap_or_default();
+    if matches!(digits.len(), 3 | 6) && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("invalid_color");
+        error.message = Some("must be a hex color".into());
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_hex_color() {
+        assert!(validate_hex_color("#3b82f6").is_ok());
+        assert!(validate_hex_color("#FFF").is_ok());
+        assert!(validate_hex_color("3b82f6").is_err());
+        assert!(validate_hex_color("#3b82f").is_err());
+        assert!(validate_hex_color("#fff;}").is_err());
+    }
+}
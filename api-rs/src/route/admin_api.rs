@@ -0,0 +1,265 @@
+use crate::config::db::DB;
+use crate::config::registry::{Setting, SETTINGS};
+use crate::errors::{bad_request, not_found, ApiError, ApiResult};
+use crate::middleware::check_access::check_admin_access;
+use crate::middleware::limit_request::limit_request;
+use crate::model::admin::*;
+use crate::model::audit::AuditLog;
+use crate::model::file::StoredFile;
+use crate::model::post::Post;
+use crate::model::user::{CreateUserRequest, DeleteUserRequest, User};
+use crate::route::Routes;
+use crate::service::auth_service::{hash_password, AuthService};
+use crate::service::kv_service::KvStore;
+use crate::service::notifier_service::Alert;
+use crate::service::search_service::{is_rebuilding, rebuild_index};
+use crate::service::task_service::{self, JobKind};
+use crate::service::upload_service::FileUploadService;
+use crate::service::{orphan_service, stats_service};
+use crate::util::extractor::{Json, Query, ValidatedJson, ValidatedQuery};
+use crate::util::fp::Pipe;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tracing::info;
+
+// Only one optimization may run at a time
+static OPTIMIZE_LOCK: Mutex<()> = Mutex::const_new(());
+
+pub fn create_routes(
+    kv: Arc<dyn KvStore>,
+    auth: Arc<AuthService>,
+    db: Arc<DB>,
+) -> Router<AppState> {
+    routes(kv)
+        .into_router()
+        .layer(middleware::from_fn(move |req, next| {
+            check_admin_access(auth.clone(), db.clone(), req, next)
+        }))
+}
+
+pub fn routes(kv: Arc<dyn KvStore>) -> Routes {
+    Routes::new()
+        .route("/status", get(get_status))
+        .route("/rebuild-indexes", post(rebuild_indexes))
+        .route("/jobs", get(get_jobs))
+        .route("/run-job", post(run_job))
+        .route("/config-schema", get(get_config_schema))
+        .route("/orphans", get(get_orphans))
+        .route("/prune-uploads", post(prune_uploads))
+        .route("/largest", get(get_largest))
+        .route("/link-health", get(get_link_health))
+        .route("/test-notify", post(test_notify))
+        .route("/users", get(get_users))
+        .route("/create-user", post(create_user))
+        .route("/delete-user", post(delete_user))
+        .route(
+            "/optimize",
+            post(optimize_db).layer(middleware::from_fn(move |req, next| {
+                limit_request(kv.clone(), 600, 1, req, next)
+            })),
+        )
+}
+
+/// Rebuilds the search index of all posts in the background, a notification is sent when done.
+async fn rebuild_indexes(State(state): State<AppState>) -> ApiResult<&'static str> {
+    if is_rebuilding() {
+        return Err(ApiError::Conflict(
+            "the index is already being rebuilt".to_string(),
+        ));
+    }
+    tokio::spawn(async move { rebuild_index(&state).await });
+    Ok("Indexing...")
+}
+
+async fn get_status(State(state): State<AppState>) -> ApiResult<Json<SystemStatus>> {
+    let config = &state.config;
+
+    Json(SystemStatus {
+        app_name: config.app_name.clone(),
+        app_version: config.app_version.clone(),
+        uploads: UploadStatus {
+            usage: StoredFile::usage(&state.db, None).await?,
+            quota: config.upload.quota,
+            user_quota: config.upload.user_quota,
+        },
+        search_index: state.fts.status().await?,
+        redis: state.rd.breaker.status(),
+    })
+    .pipe(Ok)
+}
+
+/// Reports the files not attached to any post, the attachments missing their files and the unused tags,
+/// as JSON or as a CSV download.
+async fn get_orphans(
+    State(state): State<AppState>,
+    Query(query): Query<ReportRequest>,
+) -> ApiResult<Response> {
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let report = orphan_service::find_orphans(&state.db, &upload_service).await?;
+
+    let response = match query.format {
+        ReportFormat::Json => Json(report).into_response(),
+        ReportFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"orphans.csv\"",
+                ),
+            ],
+            orphan_service::to_csv(&report),
+        )
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Deletes the files not used by any post which are older than `JOB_PRUNE_UPLOADS_GRACE_HOURS`,
+/// or only lists them with `dry_run`.
+async fn prune_uploads(
+    State(state): State<AppState>,
+    Query(query): Query<PruneUploadsRequest>,
+) -> ApiResult<Json<PruneUploadsResult>> {
+    let grace = Duration::hours(state.config.jobs.prune_grace_hours as i64);
+    let before = (Utc::now() - grace).timestamp_millis();
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let files =
+        orphan_service::prune_uploads(&state.db, &upload_service, before, query.dry_run).await?;
+
+    let size = files.iter().map(|file| file.size).sum();
+    if !query.dry_run {
+        let detail = format!("{} files of {} bytes", files.len(), size);
+        AuditLog::log(&state.db, None, "uploads.prune", "", Some(&detail)).await;
+    }
+    Ok(Json(PruneUploadsResult { files, size }))
+}
+
+/// Sends a notification to every notify channel, to check their settings.
+async fn test_notify(State(state): State<AppState>) -> ApiResult<StatusCode> {
+    let notifier = state
+        .notifier
+        .as_ref()
+        .ok_or(bad_request("No notify channels are configured"))?;
+    let title = format!("{}: test", state.config.app_name);
+    let alert = Alert {
+        kind: "test",
+        title: &title,
+        message: "The notify channels work.",
+    };
+    notifier
+        .send(&alert)
+        .await
+        .map_err(|e| ApiError::ServiceUnavailable(format!("{:#}", e)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the largest posts and files, to find what to delete when the disk is getting full.
+/// Both lists are paged with the same `limit` and `offset`.
+async fn get_largest(
+    State(state): State<AppState>,
+    ValidatedQuery(query): ValidatedQuery<LargestRequest>,
+) -> ApiResult<Json<LargestReport>> {
+    Json(LargestReport {
+        posts: Post::find_largest(&state.db, query.include_deleted, query.limit, query.offset)
+            .await?,
+        files: StoredFile::find_largest(&state.db, query.file_type, query.limit, query.offset)
+            .await?,
+    })
+    .pipe(Ok)
+}
+
+async fn get_users(State(state): State<AppState>) -> ApiResult<Json<Vec<User>>> {
+    Ok(Json(User::find_all(&state.db).await?))
+}
+
+/// Adds a user, who logs in with their name and password and has their own posts and tags.
+async fn create_user(
+    State(state): State<AppState>,
+    ValidatedJson(payload): ValidatedJson<CreateUserRequest>,
+) -> ApiResult<Json<User>> {
+    let hash = hash_password(&payload.password)?;
+    let user = User::create(&state.db, &payload.name, &hash, payload.admin).await?;
+    AuditLog::log(&state.db, None, "user.create", &user.name, None).await;
+    Ok(Json(user))
+}
+
+/// Deletes a user with all of their posts, the files only they used are discarded later.
+async fn delete_user(
+    State(state): State<AppState>,
+    Json(payload): Json<DeleteUserRequest>,
+) -> ApiResult<StatusCode> {
+    if !User::delete(&state.db, payload.id).await? {
+        return Err(not_found("User not found"));
+    }
+    stats_service::invalidate(&state.rd, payload.id).await;
+    let target = payload.id.to_string();
+    AuditLog::log(&state.db, None, "user.delete", &target, None).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the broken and redirected links of shared posts found by the last run of the check-links job.
+async fn get_link_health(
+    State(state): State<AppState>,
+    Query(query): Query<LinkHealthRequest>,
+) -> ApiResult<Json<Vec<LinkHealth>>> {
+    Ok(Json(LinkHealth::find_all(&state.db, query.all).await?))
+}
+
+/// Lists the environment variables read by the app, with their types, defaults and descriptions.
+async fn get_config_schema() -> Json<&'static [Setting]> {
+    Json(SETTINGS)
+}
+
+async fn get_jobs(State(state): State<AppState>) -> Json<Vec<JobInfo>> {
+    Json(state.jobs.jobs(&state.config.jobs))
+}
+
+/// Runs a job immediately, e.g. to check it without waiting for its schedule.
+/// Disabled jobs can also be run.
+async fn run_job(
+    State(state): State<AppState>,
+    Query(query): Query<RunJobRequest>,
+) -> ApiResult<Json<JobRun>> {
+    let kind = JobKind::from_name(&query.name).ok_or_else(|| not_found("Job not found"))?;
+
+    task_service::run_job(&state, kind)
+        .await
+        .ok_or_else(|| ApiError::Conflict("job is already running".to_string()))
+        .map(Json)
+}
+
+async fn optimize_db(State(state): State<AppState>) -> ApiResult<Json<OptimizeResult>> {
+    let _guard = OPTIMIZE_LOCK
+        .try_lock()
+        .map_err(|_| ApiError::Conflict("optimization is already running".to_string()))?;
+
+    let start = Instant::now();
+    let (size_before, wal_size_before) = state.db.file_sizes().await?;
+    state.db.optimize().await?;
+    let (size_after, wal_size_after) = state.db.file_sizes().await?;
+
+    let result = OptimizeResult {
+        size_before,
+        size_after,
+        wal_size_before,
+        wal_size_after,
+        elapsed_ms: start.elapsed().as_millis(),
+    };
+    info!("Database optimized: {:?}", result);
+
+    let detail = format!(
+        "size: {} -> {}, wal: {} -> {}",
+        size_before, size_after, wal_size_before, wal_size_after
+    );
+    AuditLog::log(&state.db, None, "db.optimize", "main", Some(&detail)).await;
+
+    Ok(Json(result))
+}
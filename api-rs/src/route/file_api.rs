@@ -0,0 +1,98 @@
+use crate::errors::{not_found, ApiResult};
+use crate::model::file::{FileUpload, StoredFile};
+use crate::route::Routes;
+use crate::service::upload_service::FileUploadService;
+use crate::util::extractor::Path;
+use crate::AppState;
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+pub fn create_routes(base_url: &str) -> Router<AppState> {
+    routes(base_url).into_router()
+}
+
+pub fn routes(base_url: &str) -> Routes {
+    Routes::new().route(format!("{}/dl/{{id}}", base_url), get(download_file))
+}
+
+/// Serves an upload as an attachment named after the filename it was uploaded with.
+async fn download_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> ApiResult<Response> {
+    let upload = FileUpload::find_by_id(&state.db, &id)
+        .await?
+        .ok_or_else(|| not_found("file not found"))?;
+    let stored = StoredFile::find_by_id(&state.db, &upload.file_id)
+        .await?
+        .ok_or_else(|| not_found("file not found"))?;
+
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let file = File::open(upload_service.file_path(&stored.filename))
+        .await
+        .map_err(|_| not_found("file not found"))?;
+    let size = file
+        .metadata()
+        .await
+        .context("Cannot read file metadata")?
+        .len();
+
+    let content_type = stored
+        .content_type
+        .unwrap_or("application/octet-stream".to_string());
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition(&upload.original_name),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+/// Builds an `attachment` disposition with an ASCII fallback and an RFC 5987 encoded filename.
+pub(crate) fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded = utf8_percent_encode(filename, NON_ALPHANUMERIC);
+
+    format!(r#"attachment; filename="{fallback}"; filename*=UTF-8''{encoded}"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            r#"attachment; filename="report.pdf"; filename*=UTF-8''report%2Epdf"#
+        );
+        assert_eq!(
+            content_disposition("报告 \"final\".pdf"),
+            r#"attachment; filename="__ _final_.pdf"; filename*=UTF-8''%E6%8A%A5%E5%91%8A%20%22final%22%2Epdf"#
+        );
+    }
+}
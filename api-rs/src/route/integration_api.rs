@@ -0,0 +1,96 @@
+use crate::errors::{not_found, ApiError, ApiResult};
+use crate::middleware::client_info::ClientInfo;
+use crate::model::integration::SlackReply;
+use crate::model::post::{CreatePostRequest, Post};
+use crate::model::user::User;
+use crate::route::Routes;
+use crate::service::{slack_service, stats_service};
+use crate::util::extractor::Json;
+use crate::AppState;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::routing::post;
+use axum::{Extension, Router};
+use chrono::Utc;
+
+/// The routes called by other services, which are authenticated by their own signatures
+pub fn create_routes() -> Router<AppState> {
+    routes().into_router()
+}
+
+pub fn routes() -> Routes {
+    Routes::new().route("/slack", post(slack_command))
+}
+
+/// Creates a post from the text of a Slack slash command, and replies with its link.
+/// The problems of the sender are replied to them, since Slack shows other errors as a failure.
+async fn slack_command(
+    State(state): State<AppState>,
+    Extension(client): Extension<ClientInfo>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<Json<SlackReply>> {
+    let config = &state.config.slack;
+    if config.signing_secret.is_empty() {
+        return Err(not_found("Slack integration is not enabled"));
+    }
+
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    let verified = slack_service::verify_signature(
+        &config.signing_secret,
+        header("X-Slack-Request-Timestamp"),
+        &body,
+        header("X-Slack-Signature"),
+        Utc::now().timestamp(),
+    );
+    if !verified {
+        return Err(ApiError::Unauthorized("Invalid signature".to_string()));
+    }
+
+    let form = slack_service::parse_form(&body);
+    let slack_id = form.get("user_id").map(String::as_str).unwrap_or_default();
+    let user = match config.user_name(slack_id) {
+        Some(name) => User::find_by_name(&state.db, name).await?,
+        None => None,
+    };
+    let Some(user) = user else {
+        return Ok(Json(SlackReply::ephemeral(
+            "Your Slack account cannot create posts",
+        )));
+    };
+
+    let text = form.get("text").map(String::as_str).unwrap_or_default();
+    let content = slack_service::command_to_html(text);
+    if content.is_empty() {
+        return Ok(Json(SlackReply::ephemeral(
+            "Type the text of the post after the command, with #tags if any",
+        )));
+    }
+    if content.len() as u64 > state.config.max_content_size {
+        return Ok(Json(SlackReply::ephemeral("The post is too long")));
+    }
+
+    let post = CreatePostRequest {
+        content,
+        title: None,
+        files: None,
+        color: None,
+        shared: None,
+        parent_id: None,
+        created_at: None,
+    };
+    let res = Post::create(&state.db, user.id, &post).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+
+    let link = format!("{}/p/{}", client.origin(), res.id);
+    Ok(Json(SlackReply::ephemeral(format!(
+        "Saved <{}|post {}>",
+        link, res.id
+    ))))
+}
@@ -1,2 +1,52 @@
+use crate::AppState;
+use axum::routing::MethodRouter;
+use axum::Router;
+
+pub mod admin_api;
+pub mod file_api;
+pub mod integration_api;
+pub mod passkey_api;
 pub mod post_api;
 pub mod post_page;
+pub mod realtime_api;
+
+/// The routes of a module, which are listed as well as served, so that tests can probe all of them.
+#[derive(Default)]
+pub struct Routes {
+    routes: Vec<(String, MethodRouter<AppState>)>,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, path: impl Into<String>, method_router: MethodRouter<AppState>) -> Self {
+        self.routes.push((path.into(), method_router));
+        self
+    }
+
+    pub fn merge(mut self, other: Routes) -> Self {
+        self.routes.extend(other.routes);
+        self
+    }
+
+    /// The paths of the routes, as served when they are nested under `prefix`
+    pub fn paths(&self, prefix: &str) -> Vec<String> {
+        self.routes
+            .iter()
+            .map(|(path, _)| match path.as_str() {
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                path => format!("{}{}", prefix, path),
+            })
+            .collect()
+    }
+
+    pub fn into_router(self) -> Router<AppState> {
+        self.routes
+            .into_iter()
+            .fold(Router::new(), |router, (path, method_router)| {
+                router.route(&path, method_router)
+            })
+    }
+}
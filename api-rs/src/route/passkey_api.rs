@@ -0,0 +1,192 @@
+use crate::errors::{not_found, ApiError, ApiResult};
+use crate::middleware::client_info::ClientInfo;
+use crate::middleware::limit_request::limit_request;
+use crate::model::audit::AuditLog;
+use crate::model::passkey::{Passkey, PasskeyLoginRequest, RegisterPasskeyRequest};
+use crate::model::post::Id;
+use crate::model::user::User;
+use crate::route::post_api::{session_response, user_agent};
+use crate::route::Routes;
+use crate::service::kv_service::KvStore;
+use crate::service::passkey_service::{
+    decode_base64, encode_base64, verify_assertion, verify_registration,
+};
+use crate::util::extractor::{Json, ValidatedJson};
+use crate::AppState;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{middleware, Extension};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::warn;
+
+/// How long the browser waits for the user, in milliseconds
+const CEREMONY_TIMEOUT: u64 = 5 * 60 * 1000;
+
+pub fn routes(kv: Arc<dyn KvStore>) -> Routes {
+    let login_kv = kv.clone();
+    Routes::new()
+        .route("/get-passkeys", get(get_passkeys))
+        .route("/start-passkey-registration", post(start_registration))
+        .route("/finish-passkey-registration", post(finish_registration))
+        .route("/delete-passkey", post(delete_passkey))
+        .route(
+            "/start-passkey-login",
+            post(start_login).layer(middleware::from_fn(move |req, next| {
+                limit_request(login_kv.clone(), 60, 10, req, next)
+            })),
+        )
+        .route(
+            "/finish-passkey-login",
+            post(finish_login).layer(middleware::from_fn(move |req, next| {
+                limit_request(kv.clone(), 60, 5, req, next)
+            })),
+        )
+}
+
+fn passkeys_disabled() -> ApiError {
+    ApiError::BadRequest("Passkeys are not enabled".to_string())
+}
+
+async fn get_passkeys(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<Vec<Passkey>>> {
+    Ok(Json(Passkey::find_all(&state.db.pool, user.id).await?))
+}
+
+/// The options of `navigator.credentials.create()`, the user handle is the id of the user
+async fn start_registration(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<Value>> {
+    let rp = state.auth.relying_party().ok_or_else(passkeys_disabled)?;
+    let name = &state.config.app_name;
+    let registered: Vec<Value> = Passkey::find_all(&state.db.pool, user.id)
+        .await?
+        .into_iter()
+        .map(|passkey| json!({ "type": "public-key", "id": passkey.credential_id }))
+        .collect();
+
+    Ok(Json(json!({
+        "challenge": state.auth.issue_challenge(),
+        "rp": { "id": rp.id, "name": name },
+        "user": {
+            "id": encode_base64(user.id.to_string().as_bytes()),
+            "name": user.name,
+            "displayName": format!("{} ({})", user.name, name),
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": -7 },
+            { "type": "public-key", "alg": -8 },
+            { "type": "public-key", "alg": -257 },
+        ],
+        "timeout": CEREMONY_TIMEOUT,
+        "attestation": "none",
+        "authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+        "excludeCredentials": registered,
+    })))
+}
+
+async fn finish_registration(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<RegisterPasskeyRequest>,
+) -> ApiResult<Json<Passkey>> {
+    let rp = state.auth.relying_party().ok_or_else(passkeys_disabled)?;
+    let response = &payload.credential.response;
+
+    let new = decode_base64(&response.client_data_json)
+        .and_then(|client_data| {
+            let attestation = decode_base64(&response.attestation_object)?;
+            verify_registration(&rp, &client_data, &attestation, |challenge| {
+                state.auth.take_challenge(challenge)
+            })
+        })
+        .map_err(|e| ApiError::BadRequest(format!("invalid passkey: {}", e)))?;
+
+    let passkey = Passkey::create(
+        &state.db,
+        user.id,
+        &new.credential_id,
+        &payload.name,
+        &new.public_key,
+        new.sign_count,
+    )
+    .await?;
+    let target = passkey.id.to_string();
+    AuditLog::log(&state.db, Some(user.id), "passkey.register", &target, None).await;
+    Ok(Json(passkey))
+}
+
+async fn delete_passkey(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<Id>,
+) -> ApiResult<StatusCode> {
+    if !Passkey::delete(&state.db, user.id, payload.id).await? {
+        return Err(not_found("Passkey not found"));
+    }
+    let target = payload.id.to_string();
+    AuditLog::log(&state.db, Some(user.id), "passkey.delete", &target, None).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The options of `navigator.credentials.get()`, the passkeys are discoverable so none is listed
+async fn start_login(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let rp = state.auth.relying_party().ok_or_else(passkeys_disabled)?;
+
+    Ok(Json(json!({
+        "challenge": state.auth.issue_challenge(),
+        "rpId": rp.id,
+        "timeout": CEREMONY_TIMEOUT,
+        "userVerification": "required",
+        "allowCredentials": [],
+    })))
+}
+
+/// Starts a session of the owner of the passkey like `login`, with a passkey instead of the password
+async fn finish_login(
+    State(state): State<AppState>,
+    Extension(client): Extension<ClientInfo>,
+    headers: HeaderMap,
+    Json(payload): Json<PasskeyLoginRequest>,
+) -> ApiResult<impl IntoResponse> {
+    let rp = state.auth.relying_party().ok_or_else(passkeys_disabled)?;
+    let credential = &payload.credential;
+    let invalid = || ApiError::Unauthorized("invalid passkey".to_string());
+
+    let passkey =
+        Passkey::find_by_credential_id(&state.db.pool, credential.id.trim_end_matches('='))
+            .await?
+            .ok_or_else(invalid)?;
+
+    let response = &credential.response;
+    let sign_count = decode_base64(&response.client_data_json)
+        .and_then(|client_data| {
+            let authenticator_data = decode_base64(&response.authenticator_data)?;
+            let signature = decode_base64(&response.signature)?;
+            verify_assertion(
+                &rp,
+                &passkey,
+                &client_data,
+                &authenticator_data,
+                &signature,
+                |challenge| state.auth.take_challenge(challenge),
+            )
+        })
+        .map_err(|e| {
+            warn!("Passkey {} is rejected: {}", passkey.id, e);
+            invalid()
+        })?;
+    Passkey::mark_used(&state.db, passkey.id, sign_count).await?;
+
+    let ip = client.ip.map(|ip| ip.to_string());
+    let (token, session) = state
+        .auth
+        .start_session(passkey.user_id, user_agent(&headers), ip.as_deref())
+        .await?;
+    Ok(session_response(&state, &client, token, session))
+}
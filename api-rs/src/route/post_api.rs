@@ -1,264 +1,1200 @@
-use crate::config::rd::RedisPool;
-use crate::errors::{not_found, ApiError, ApiResult};
+use crate::errors::{bad_request, not_found, ApiError, ApiResult};
 use crate::middleware::check_access::check_access;
+use crate::middleware::client_info::ClientInfo;
 use crate::middleware::limit_request::limit_request;
+use crate::model::admin::{Features, InstanceMeta};
+use crate::model::audit::{Activity, ActivityRequest, AuditLog};
+use crate::model::backup::{ArchiveFormat, ExportRequest, ImportResult};
+use crate::model::file::{
+    CompleteUploadRequest, FileUpload, InitUploadRequest, StoredFile, UploadChunkRequest,
+    UploadFileRequest, UploadSession,
+};
+use crate::model::notification::{
+    GetNotificationsRequest, MarkNotificationReadRequest, Notification,
+};
 use crate::model::post::*;
+use crate::model::reaction::{ReactionCount, ToggleReactionRequest, OWNER};
+use crate::model::session::{LoginResponse, RevokeSessionRequest, Session, SessionInfo};
 use crate::model::tag::*;
-use crate::service::auth_service::AuthService;
+use crate::model::user::User;
+use crate::route::file_api::content_disposition;
+use crate::route::{passkey_api, realtime_api, Routes};
+use crate::service::auth_service::{AuthService, Credential};
+use crate::service::kv_service::KvStore;
+use crate::service::post_service::UserPosts;
+use crate::service::realtime_service::{RealtimeEvent, UploadStage, UploadTracker};
+use crate::service::scan_service::{quarantine, ScanVerdict, VirusScanner};
+use crate::service::search_service::{rebuild_index, SearchResults};
 use crate::service::upload_service::FileUploadService;
+use crate::service::{
+    backup_service, export_service, inline_image_service, lint_service, lock_service,
+    stats_service, view_service,
+};
 use crate::util::extractor::{Json, Query, ValidatedJson, ValidatedQuery};
 use crate::util::fp::Pipe;
+use crate::util::html::{display_title, snippet, strip_tags};
+use crate::util::json_stream::JsonArray;
+use crate::util::maybe::MaybeAbsent;
 use crate::AppState;
 use anyhow::Result;
+use axum::body::{Body, Bytes};
+use axum::extract::multipart::Field;
 use axum::extract::{Multipart, State};
-use axum::http::StatusCode;
-use axum::response::Html;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{middleware, Router};
-use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use axum::{middleware, Extension, Router};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeZone, Utc};
+use futures::{Stream, TryStreamExt};
 use regex::Regex;
 use std::borrow::Cow;
 use std::collections::HashMap;
-use tracing::error;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, warn};
+use uuid::Uuid;
+use validator::Validate;
 
-pub fn create_routes(rd_pool: RedisPool) -> Router<AppState> {
-    Router::new()
+pub fn create_routes(kv: Arc<dyn KvStore>, auth: Arc<AuthService>) -> Router<AppState> {
+    routes(kv)
+        .into_router()
+        .layer(middleware::from_fn(move |req, next| {
+            check_access(
+                auth.clone(),
+                &[
+                    "/login",
+                    "/start-passkey-login",
+                    "/finish-passkey-login",
+                    "/meta",
+                ],
+                req,
+                next,
+            )
+        }))
+}
+
+pub fn routes(kv: Arc<dyn KvStore>) -> Routes {
+    Routes::new()
         .route("/get-tags", get(get_tags))
         .route("/rename-tag", post(rename_tag))
         .route("/stick-tag", post(stick_tag))
+        .route("/reorder-tags", post(reorder_tags))
+        .route("/hide-tag", post(hide_tag))
         .route("/delete-tag", post(delete_tag))
         .route("/search", get(search_posts))
+        .route("/quick-search", get(quick_search))
         .route("/get-posts", get(get_posts))
+        .route("/get-posts-by-day", get(get_posts_by_day))
         .route("/get-post", get(get_post))
+        .route("/get-flat-thread", get(get_flat_thread))
+        .route("/mark-viewed", post(mark_viewed))
+        .route("/get-recently-viewed", get(get_recently_viewed))
+        .route("/lock-post", post(lock_post))
+        .route("/renew-post-lock", post(renew_post_lock))
+        .route("/unlock-post", post(unlock_post))
+        .route("/export-posts", get(export_posts))
+        .route("/export-tag-files", get(export_tag_files))
+        .route("/export-post", get(export_post))
+        .route("/export-all-markdown", get(export_all_markdown))
+        .route("/export", get(export_backup))
+        .route("/import", post(import_backup))
+        .route("/sync-posts", get(sync_posts))
         .route("/create-post", post(create_post))
         .route("/update-post", post(update_post))
         .route("/delete-post", post(delete_post))
         .route("/restore-post", post(restore_post))
+        .route("/schedule-purge", post(schedule_purge))
+        .route("/batch-set-color", post(batch_set_color))
         .route("/clear-posts", post(clear_posts))
+        .route("/get-trash-stats", get(get_trash_stats))
+        .route("/remove-post-file", post(remove_post_file))
+        .route("/get-reactions", get(get_reactions))
+        .route("/toggle-reaction", post(toggle_reaction))
         .route("/get-overall-counts", get(get_stats))
+        .route("/get-activity", get(get_activity))
+        .route("/get-notifications", get(get_notifications))
+        .route("/mark-notification-read", post(mark_notification_read))
         .route("/get-daily-post-counts", get(get_daily_post_counts))
         .route("/upload", get(file_form).post(upload_file))
-        .route(
-            "/_dangerously_rebuild_all_indexes",
-            get(rebuild_all_indexes),
-        )
+        .route("/upload/init", post(init_upload))
+        .route("/upload/chunk", post(upload_chunk))
+        .route("/upload/complete", post(complete_upload))
+        .route("/get-sessions", get(get_sessions))
+        .route("/revoke-session", post(revoke_session))
+        .route("/refresh-token", post(refresh_token))
+        .route("/logout", post(logout))
         .route("/auth", get(|| async {}))
+        .route("/meta", get(get_meta))
+        .merge(realtime_api::routes())
+        .merge(passkey_api::routes(kv.clone()))
         .route(
             "/login",
             post(login).layer(middleware::from_fn(move |req, next| {
-                limit_request(rd_pool.clone(), 60, 5, req, next)
+                limit_request(kv.clone(), 60, 5, req, next)
             })),
         )
-        .layer(middleware::from_fn(|req, next| {
-            check_access(&["/login"], req, next)
-        }))
 }
 
-async fn login(Json(payload): Json<LoginRequest>) -> ApiResult<StatusCode> {
-    if AuthService::is_valid_token(&payload.password) {
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err(ApiError::Unauthorized("wrong password".to_string()))
+/// Describes the server, it is public so that a client can adapt the login page as well.
+async fn get_meta(State(state): State<AppState>) -> Json<InstanceMeta> {
+    let config = &state.config;
+    let mut image_formats = config.upload.image_formats.clone();
+    // HEIC images are converted into one of the other formats
+    if !config.upload.heic_converter.is_empty() && !image_formats.iter().any(|f| f == "heic") {
+        image_formats.push("heic".to_string());
     }
+
+    Json(InstanceMeta {
+        app_name: config.app_name.clone(),
+        app_version: config.app_version.clone(),
+        features: Features {
+            uploads: true,
+            search_backend: config.search.backend.clone(),
+            ocr: state.ocr.is_some(),
+            passkeys: !config.auth.passkey_rp_id.is_empty(),
+            registration: false,
+            demo: config.demo.enabled,
+        },
+        max_upload_size: config.http.max_body_size,
+        max_file_size: config.upload.max_file_size,
+        image_formats,
+    })
+}
+
+/// Starts a session, the token is set as an HttpOnly cookie and returned for other clients
+async fn login(
+    State(state): State<AppState>,
+    Extension(client): Extension<ClientInfo>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginRequest>,
+) -> ApiResult<impl IntoResponse> {
+    let ip = client.ip.map(|ip| ip.to_string());
+    let login = state
+        .auth
+        .login(
+            payload.name.as_deref(),
+            &payload.password,
+            user_agent(&headers),
+            ip.as_deref(),
+        )
+        .await?;
+    let Some((token, session)) = login else {
+        return Err(ApiError::Unauthorized("wrong name or password".to_string()));
+    };
+
+    Ok(session_response(&state, &client, token, session))
+}
+
+/// Replaces the token of the current session, so that a client which is used keeps logged in.
+/// Older clients sending the password itself get the token of a new session.
+async fn refresh_token(
+    State(state): State<AppState>,
+    Extension(client): Extension<ClientInfo>,
+    Extension(user): Extension<User>,
+    Extension(credential): Extension<Credential>,
+    headers: HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    let ip = client.ip.map(|ip| ip.to_string());
+    let (token, session) = state
+        .auth
+        .refresh_session(&user, &credential, user_agent(&headers), ip.as_deref())
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized("Session is revoked".to_string()))?;
+
+    Ok(session_response(&state, &client, token, session))
+}
+
+/// Ends the current session and removes its cookie
+async fn logout(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Extension(credential): Extension<Credential>,
+) -> ApiResult<impl IntoResponse> {
+    if let Credential::Session(session) = credential {
+        Session::delete(&state.db, user.id, session.id).await?;
+    }
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, state.auth.clear_session_cookie())],
+    ))
+}
+
+pub(crate) fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The response of a login, which sets the cookie of the session
+pub(crate) fn session_response(
+    state: &AppState,
+    client: &ClientInfo,
+    token: String,
+    session: Session,
+) -> impl IntoResponse {
+    let cookie = state.auth.session_cookie(&token, client.scheme == "https");
+    let config = &state.config;
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(LoginResponse {
+            token,
+            expires_at: session.expires_at,
+            app_name: config.app_name.clone(),
+            app_version: config.app_version.clone(),
+        }),
+    )
+}
+
+/// The sessions of the user which have not expired, to find the devices logged in
+async fn get_sessions(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Extension(credential): Extension<Credential>,
+) -> ApiResult<Json<Vec<SessionInfo>>> {
+    let current = match credential {
+        Credential::Session(session) => Some(session.id),
+        Credential::Password => None,
+    };
+    Session::find_active(&state.db.pool, user.id)
+        .await?
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: current == Some(session.id),
+            session,
+        })
+        .collect::<Vec<_>>()
+        .pipe(Json)
+        .pipe(Ok)
+}
+
+/// Logs out a device, its token cannot be used anymore
+async fn revoke_session(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<RevokeSessionRequest>,
+) -> ApiResult<StatusCode> {
+    if !Session::delete(&state.db, user.id, payload.id).await? {
+        return Err(not_found("Session not found"));
+    }
+    let target = payload.id.to_string();
+    AuditLog::log(&state.db, Some(user.id), "session.revoke", &target, None).await;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_tags(State(state): State<AppState>) -> ApiResult<Json<Vec<TagWithPostCount>>> {
-    let tags = Tag::get_all_with_post_count(&state.db).await?;
+async fn get_tags(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<GetTagsRequest>,
+) -> ApiResult<Json<Vec<TagWithPostCount>>> {
+    let key = stats_service::tags_key(user.id, query.include_hidden, query.sort);
+    let tags = state
+        .rd
+        .cached(&key, stats_service::CACHE_TTL_SECONDS, || {
+            Tag::get_all_with_post_count(&state.db, user.id, query.include_hidden, query.sort)
+        })
+        .await?;
     Ok(Json(tags))
 }
 
 async fn rename_tag(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Json(tag): Json<RenameTagRequest>,
 ) -> ApiResult<StatusCode> {
-    Tag::rename_or_merge(&state.db, &tag.name, &tag.new_name).await?;
+    Tag::rename_or_merge(&state.db, user.id, &tag.name, &tag.new_name).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
 async fn delete_tag(
     State(state): State<AppState>,
-    Json(name): Json<Name>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<DeleteTagRequest>,
 ) -> ApiResult<StatusCode> {
-    Tag::delete_associated_posts(&state.db, &name.name).await?;
+    let name = &payload.name;
+    match payload.mode {
+        DeleteTagMode::TrashPosts => Tag::delete_associated_posts(&state.db, user.id, name).await?,
+        DeleteTagMode::DeleteTagOnly => Tag::delete_only(&state.db, user.id, name).await?,
+        DeleteTagMode::Detach => Tag::detach(&state.db, user.id, name).await?,
+    }
+    stats_service::invalidate(&state.rd, user.id).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
 async fn stick_tag(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Json(tag): Json<StickyTagRequest>,
 ) -> ApiResult<StatusCode> {
-    Tag::insert_or_update(&state.db, &tag.name, tag.sticky).await?;
+    Tag::insert_or_update(&state.db, user.id, &tag.name, tag.sticky).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn hide_tag(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(tag): Json<HideTagRequest>,
+) -> ApiResult<StatusCode> {
+    Tag::set_hidden(&state.db, user.id, &tag.name, tag.hidden).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reorder_tags(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<ReorderTagsRequest>,
+) -> ApiResult<StatusCode> {
+    Tag::reorder(&state.db, user.id, &payload.names).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// A lightweight search for command palettes, returns only the titles and snippets of the top hits.
+async fn quick_search(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<QuickSearchRequest>,
+) -> ApiResult<Json<Vec<QuickSearchHit>>> {
+    let docs = UserPosts::new(&state.db.pool, user.id);
+    let SearchResults {
+        tokens,
+        hits: results,
+        ..
+    } = state
+        .fts
+        .search_among(&query.q, false, 10, Some(&docs))
+        .await?;
+    if results.is_empty() {
+        return Ok(Json(vec![]));
+    }
+
+    let ids: Vec<i64> = results.iter().map(|r| r.0).collect();
+    let rows: HashMap<i64, PostRow> = Post::find_rows_by_ids(&state.db, user.id, &ids)
+        .await?
+        .into_iter()
+        .map(|row| (row.id, row))
+        .collect();
+
+    // Keep the order of relevance
+    ids.iter()
+        .filter_map(|id| rows.get(id))
+        .map(|row| {
+            let text = strip_tags(&row.content);
+            let snippet = snippet(&text, &tokens, 80);
+            QuickSearchHit {
+                id: row.id,
+                title: display_title(row.title.as_deref(), &row.content, 50),
+                snippet: mark_tokens_in_html(&snippet, &tokens),
+            }
+        })
+        .collect::<Vec<_>>()
+        .pipe(Json)
+        .pipe(Ok)
+}
+
 async fn get_posts(
     State(state): State<AppState>,
-    Query(query): Query<FilterPostRequest>,
-) -> ApiResult<Json<PostPagination>> {
-    let posts = Post::filter_posts(&state.db, &query, 30).await?;
-    let size = posts.len() as i64;
-    let cursor = if size == 0 {
-        -1
-    } else {
-        posts.last().unwrap().row.created_at
+    Extension(user): Extension<User>,
+    Query(mut query): Query<FilterPostRequest>,
+) -> ApiResult<Response> {
+    // Old tag names in saved links and filters still work after a rename
+    let tag_renamed_to = match query.tag {
+        Some(ref tag) => Tag::resolve_rename(&state.db, user.id, tag).await?,
+        None => None,
     };
+    if tag_renamed_to.is_some() {
+        query.tag = tag_renamed_to.clone();
+    }
+
+    paginate_posts(&state, user.id, &query, tag_renamed_to).await
+}
+
+/// Returns the posts created on a local day, the day is bucketed like `get-daily-post-counts`.
+async fn get_posts_by_day(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<DayPostsRequest>,
+) -> ApiResult<Response> {
+    let start = parse_date_with_timezone(&query.date, query.offset, false)?;
+    let end = parse_date_with_timezone(&query.date, query.offset, true)?;
+
+    let filter = FilterPostRequest {
+        cursor: query.cursor,
+        cursor_id: query.cursor_id,
+        start_date: Some(start.timestamp_millis()),
+        end_date: Some(end.timestamp_millis()),
+        ..Default::default()
+    };
+    paginate_posts(&state, user.id, &filter, None).await
+}
+
+async fn paginate_posts(
+    state: &AppState,
+    user_id: i64,
+    query: &FilterPostRequest,
+    tag_renamed_to: Option<String>,
+) -> ApiResult<Response> {
+    if query.fields == PostFields::Meta {
+        let posts = Post::filter_post_metas(&state.db, user_id, query, 30).await?;
+        let cursor = posts
+            .last()
+            .and_then(|post| post.sort_key(&query.order_by))
+            .unwrap_or(-1);
+        let size = posts.len() as i64;
+        let cursor_id = posts.last().map(|post| post.id);
+        return Json(PostPagination {
+            posts,
+            cursor,
+            cursor_id,
+            size,
+            tag_renamed_to,
+            truncated: false,
+        })
+        .into_response()
+        .pipe(Ok);
+    }
+
+    let mut posts = Post::filter_posts(&state.db, user_id, query, 30).await?;
+    for post in posts.iter_mut() {
+        post.truncate_content(state.config.list_content_length);
+    }
+    let posts: Vec<Post> = posts
+        .into_iter()
+        .map(|post| state.urls.resolve_post(post))
+        .collect();
+    let size = posts.len() as i64;
+    let cursor = posts
+        .last()
+        .and_then(|post| post.sort_key(&query.order_by))
+        .unwrap_or(-1);
+    let cursor_id = posts.last().map(|post| post.row.id);
     Json(PostPagination {
         posts,
         cursor,
+        cursor_id,
         size,
+        tag_renamed_to,
+        truncated: false,
     })
+    .into_response()
     .pipe(Ok)
 }
 
-async fn get_post(State(state): State<AppState>, Query(query): Query<Id>) -> ApiResult<Json<Post>> {
-    let post = Post::find_with_parent(&state.db, query.id).await?;
-    Ok(Json(post))
+/// Downloads all posts of the user as a JSON array.
+async fn export_posts(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"posts.json\"",
+        )],
+        JsonArray(resolved_stream(&state, user.id, None)),
+    )
+}
+
+/// Downloads the attachments of the posts under a tag and its descendants as a zip, streamed while it is written.
+async fn export_tag_files(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<Name>,
+) -> ApiResult<Response> {
+    let posts = Tag::get_posts(&state.db.pool, user.id, &query.name).await?;
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let entries = export_service::attachment_entries(&upload_service, &posts);
+    if entries.is_empty() {
+        return Err(not_found("no files found under the tag"));
+    }
+
+    let filename = format!("{}.zip", query.name.replace('/', "-"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(&filename)),
+        ],
+        Body::from_stream(export_service::zip_stream(entries)),
+    )
+        .into_response())
+}
+
+/// Downloads a post as a Markdown file in a zip, with the uploaded files it links to.
+async fn export_post(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<Id>,
+) -> ApiResult<Response> {
+    let posts = Post::find_by_ids(&state.db, user.id, &[query.id]).await?;
+    let Some(post) = posts.first() else {
+        return Err(not_found("Post not found"));
+    };
+    let filename = export_service::markdown_name(&post.row).replace(".md", ".zip");
+    Ok(markdown_zip(&state, &posts, &filename))
+}
+
+/// Downloads all posts of the user as Markdown files in a zip, with the uploaded files they link to.
+async fn export_all_markdown(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Response> {
+    let posts: Vec<Post> = Post::stream_all(state.db.pool.clone(), user.id, None)
+        .try_collect()
+        .await?;
+    Ok(markdown_zip(&state, &posts, "posts-markdown.zip"))
+}
+
+fn markdown_zip(state: &AppState, posts: &[Post], filename: &str) -> Response {
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let (documents, entries) =
+        export_service::markdown_archive(&upload_service, &state.urls, posts);
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, content_disposition(filename)),
+        ],
+        Body::from_stream(export_service::archive_stream(
+            ArchiveFormat::Zip,
+            documents,
+            entries,
+        )),
+    )
+        .into_response()
+}
+
+/// Downloads a backup of the posts of all users, including the deleted ones, with their tags and uploaded files,
+/// streamed while the archive is written. Only admins can make backups.
+async fn export_backup(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<ExportRequest>,
+) -> ApiResult<Response> {
+    // The backups hold the posts of all users
+    if !user.admin {
+        return Err(ApiError::Forbidden("Only admins can do this".to_string()));
+    }
+    let backup = backup_service::read_backup(&state.db).await?;
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let stream = backup_service::export_stream(&upload_service, &backup, query.format)?;
+
+    let filename = format!(
+        "mote-backup-{}.{}",
+        Utc::now().format("%Y%m%d"),
+        query.format.extension()
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (header::CONTENT_DISPOSITION, content_disposition(&filename)),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Restores a backup made by `export_backup`, replacing all posts and tags, only admins can restore it.
+/// The search index is rebuilt in the background, a notification is sent when done.
+async fn import_backup(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    mut multipart: Multipart,
+) -> ApiResult<Json<ImportResult>> {
+    if !user.admin {
+        return Err(ApiError::Forbidden("Only admins can do this".to_string()));
+    }
+    let Some(mut field) = multipart.next_field().await? else {
+        return Err(ApiError::BadRequest("Invalid Multipart".into()));
+    };
+
+    // Kept out of the upload directory, where it would be taken for an upload
+    let archive = std::env::temp_dir().join(format!("mote-import-{}", Uuid::new_v4()));
+    let rv = match save_field(&mut field, &archive).await {
+        Ok(()) => {
+            let upload_dir = Path::new(&state.config.upload.base_path);
+            backup_service::import(&state.db, upload_dir, &archive).await
+        }
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = tokio::fs::remove_file(&archive).await {
+        warn!("Cannot remove {:?}: {}", archive, e);
+    }
+    let result = rv?;
+
+    for user in User::find_all(&state.db).await? {
+        stats_service::invalidate(&state.rd, user.id).await;
+    }
+    let detail = format!(
+        "{} posts, {} tags, {} files",
+        result.posts, result.tags, result.files
+    );
+    AuditLog::log(&state.db, Some(user.id), "backup.import", "", Some(&detail)).await;
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        if !rebuild_index(&state).await {
+            warn!("The search index is being rebuilt, rebuild it again after the import");
+        }
+    });
+
+    Ok(Json(result))
+}
+
+async fn save_field(field: &mut Field<'_>, path: &Path) -> Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Returns the posts changed after a timestamp, for clients keeping a local copy.
+/// Deleted posts are included with `deleted_at` set, but cleared posts are not.
+async fn sync_posts(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<SyncPostRequest>,
+) -> impl IntoResponse {
+    JsonArray(resolved_stream(&state, user.id, Some(query.since)))
+}
+
+/// Streams the posts of a user changed after a timestamp, or all of them, with the urls of their files resolved.
+fn resolved_stream(
+    state: &AppState,
+    user_id: i64,
+    since: Option<i64>,
+) -> impl Stream<Item = ApiResult<Post>> + Send + 'static {
+    let urls = state.urls.clone();
+    Post::stream_all(state.db.pool.clone(), user_id, since)
+        .map_ok(move |post| urls.resolve_post(post))
+}
+
+async fn get_post(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<Id>,
+) -> ApiResult<Json<Post>> {
+    let post = Post::find_with_parent(&state.db, user.id, query.id).await?;
+    Ok(Json(state.urls.resolve_post(post)))
+}
+
+/// Returns the thread of a post flattened for rendering: its ancestors, itself and its replies, with their depths.
+async fn get_flat_thread(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<Id>,
+) -> ApiResult<Json<Vec<ThreadPost>>> {
+    let thread = Post::find_flat_thread(&state.db, user.id, query.id)
+        .await?
+        .into_iter()
+        .map(|mut item| {
+            item.post = state.urls.resolve_post(item.post);
+            item
+        })
+        .collect();
+    Ok(Json(thread))
+}
+
+/// Finds an undeleted post of the user, the posts of other users are not found either
+async fn find_own_post(state: &AppState, user: &User, id: i64) -> ApiResult<PostRow> {
+    Post::find_by_id(&state.db, id)
+        .await?
+        .filter(|p| p.user_id == user.id)
+        .ok_or_else(|| not_found("Post not found"))
+}
+
+async fn mark_viewed(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<Id>,
+) -> ApiResult<StatusCode> {
+    find_own_post(&state, &user, payload.id).await?;
+
+    view_service::mark_viewed(&state.rd, user.id, payload.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Locks a post while it is edited, so that the editors on other devices can be warned.
+/// The lock is advisory, it does not block updates.
+async fn lock_post(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<PostLockRequest>,
+) -> ApiResult<Json<PostLockStatus>> {
+    find_own_post(&state, &user, payload.id).await?;
+
+    let device = payload.device.as_deref().or(user_agent(&headers));
+    lock_service::lock_post(
+        &state.rd,
+        payload.id,
+        &payload.holder,
+        device,
+        state.config.post_lock_ttl_secs * 1000,
+    )
+    .await?
+    .pipe(Json)
+    .pipe(Ok)
+}
+
+/// The heartbeat of an editor, `acquired` is false if its lock has expired or been taken.
+async fn renew_post_lock(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<PostLockRequest>,
+) -> ApiResult<Json<PostLockStatus>> {
+    find_own_post(&state, &user, payload.id).await?;
+    lock_service::renew_post_lock(
+        &state.rd,
+        payload.id,
+        &payload.holder,
+        state.config.post_lock_ttl_secs * 1000,
+    )
+    .await?
+    .pipe(Json)
+    .pipe(Ok)
+}
+
+async fn unlock_post(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<PostLockRequest>,
+) -> ApiResult<StatusCode> {
+    find_own_post(&state, &user, payload.id).await?;
+    lock_service::unlock_post(&state.rd, payload.id, &payload.holder).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the recently viewed posts, the latest first, for a "jump back in" section.
+/// Deleted posts are left out.
+async fn get_recently_viewed(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<Vec<Post>>> {
+    let ids = view_service::recently_viewed(&state.rd, user.id).await?;
+    let mut posts: HashMap<i64, Post> = Post::find_by_ids(&state.db, user.id, &ids)
+        .await?
+        .into_iter()
+        .map(|post| (post.row.id, post))
+        .collect();
+
+    let rv: Vec<Post> = ids
+        .iter()
+        .filter_map(|id| posts.remove(id))
+        .map(|mut post| {
+            post.truncate_content(state.config.list_content_length);
+            state.urls.resolve_post(post)
+        })
+        .collect();
+    Ok(Json(rv))
 }
 
 async fn search_posts(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     ValidatedQuery(query): ValidatedQuery<SearchRequest>,
 ) -> ApiResult<Json<PostPagination>> {
-    let (tokens, results) = state
+    // The results are paged by their offset. By relevance, only the hits up to the end of the page
+    // are ranked, one more tells if there is a next page, or if the cap cuts the results.
+    let max_results = state.config.search.max_results;
+    let per_page = query.limit.unwrap_or(0);
+    let offset = query.cursor.unwrap_or(0) as usize;
+    let end = match (&query.order_by, per_page) {
+        (SearchOrder::Score, 1..) => offset.saturating_add(per_page).saturating_add(1),
+        _ => 0,
+    };
+    let limit = match (max_results, end) {
+        (0, end) => end,
+        (max, 0) => max + 1,
+        (max, end) => end.min(max + 1),
+    };
+    // Only the matched posts are checked to be the user's
+    let docs = UserPosts::new(&state.db.pool, user.id);
+
+    let SearchResults {
+        tokens,
+        hits: mut results,
+        mut truncated,
+    } = state
         .fts
-        .search(
+        .search_among(
             query.query.as_str(),
             query.partial.unwrap_or(false),
-            query.limit.unwrap_or(0),
+            limit,
+            Some(&docs),
         )
         .await?;
-    if results.is_empty() {
+    if max_results > 0 && results.len() > max_results {
+        results.truncate(max_results);
+        truncated = true;
+    }
+
+    let ids: Vec<i64> = match query.order_by {
+        SearchOrder::Score => results.iter().map(|r| r.0).collect(),
+        SearchOrder::CreatedAt | SearchOrder::UpdatedAt => {
+            let ids: Vec<i64> = results.iter().map(|r| r.0).collect();
+            let updated = matches!(query.order_by, SearchOrder::UpdatedAt);
+            Post::sort_ids_by_time(&state.db, user.id, &ids, updated).await?
+        }
+    };
+    let page_end = match per_page {
+        0 => ids.len(),
+        _ => offset.saturating_add(per_page).min(ids.len()),
+    };
+    let page = ids.get(offset..page_end).unwrap_or_default();
+    let cursor = if page_end < ids.len() {
+        page_end as i64
+    } else {
+        -1
+    };
+    if page.is_empty() {
         return Ok(Json(PostPagination {
             posts: vec![],
             cursor: -1,
+            cursor_id: None,
             size: 0,
+            tag_renamed_to: None,
+            truncated,
         }));
     }
-    let id_to_score: HashMap<i64, f64> = results.into_iter().map(|r| (r.0, r.1)).collect();
-    let ids: Vec<i64> = id_to_score.keys().cloned().collect();
+    let id_to_score: HashMap<i64, f64> = results.into_iter().collect();
 
-    let mut posts = Post::find_by_ids(&state.db, &ids).await?;
-
-    for post in posts.iter_mut() {
-        let score = id_to_score[&post.row.id];
-        post.row.content = mark_tokens_in_html(&post.row.content, &tokens);
-        post.score = Some(score);
-    }
+    let mut posts: Vec<Post> = Post::find_by_ids(&state.db, user.id, page)
+        .await?
+        .into_iter()
+        .map(|mut post| {
+            let score = id_to_score[&post.row.id];
+            if query.snippets {
+                let text = strip_tags(&post.row.content);
+                post.snippet = Some(mark_tokens_in_html(&snippet(&text, &tokens, 160), &tokens));
+            } else {
+                post.row.content = mark_tokens_in_html(&post.row.content, &tokens);
+            }
+            post.truncate_content(state.config.list_content_length);
+            post.score = Some(score);
+            state.urls.resolve_post(post)
+        })
+        .collect();
 
-    posts.sort_by(|a, b| {
-        b.score
-            .partial_cmp(&a.score)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
+    // Keep the order of the page
+    let positions: HashMap<i64, usize> = page.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    posts.sort_by_key(|post| positions[&post.row.id]);
     let size = posts.len() as i64;
 
     Json(PostPagination {
         posts,
-        cursor: -1,
+        cursor,
+        cursor_id: None,
         size,
+        tag_renamed_to: None,
+        truncated,
     })
     .pipe(Ok)
 }
 
 async fn create_post(
     State(state): State<AppState>,
-    ValidatedJson(post): ValidatedJson<CreatePostRequest>,
+    Extension(user): Extension<User>,
+    ValidatedJson(mut post): ValidatedJson<CreatePostRequest>,
 ) -> ApiResult<Json<CreateResponse>> {
-    let content = post.content.clone();
-    let res = Post::create(&state.db, &post).await?;
-
-    tokio::spawn(async move {
-        let rv = state.fts.index(res.id, &content).await;
-        if rv.is_err() {
-            error!("Cannot index post: {:?}", rv);
-        }
-    });
+    post.content = extract_inline_images(&state, user.id, post.content).await?;
+    if post.content.len() as u64 > state.config.max_content_size {
+        return Err(content_too_large(&state));
+    }
+    if let Some(parent_id) = post.parent_id {
+        let max_depth = state.config.max_thread_depth;
+        Post::check_thread_depth(&state.db, None, parent_id, max_depth).await?;
+    }
+    post.files = post.files.map(|files| state.urls.to_stored_files(files));
+    let mut res = Post::create(&state.db, user.id, &post).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+    res.warnings = lint_content(&state, user.id, &post.content).await;
     Ok(Json(res))
 }
 
+/// Returns 204, or the warnings about the content if there are any.
 async fn update_post(
     State(state): State<AppState>,
-    Json(post): Json<UpdatePostRequest>,
-) -> ApiResult<StatusCode> {
+    Extension(user): Extension<User>,
+    Json(mut post): Json<UpdatePostRequest>,
+) -> ApiResult<Response> {
+    if let MaybeAbsent::Present(Some(ref options)) = post.share_options {
+        options.validate()?;
+    }
+    if let MaybeAbsent::Present(Some(ref title)) = post.title {
+        if title.chars().count() > 200 {
+            return Err(bad_request("The title is too long"));
+        }
+    }
     let record = Post::find_by_id(&state.db, post.id).await?;
 
     record
-        .filter(|p| p.deleted_at.is_none())
+        .filter(|p| p.deleted_at.is_none() && p.user_id == user.id)
         .ok_or_else(|| not_found("Post not found"))?;
 
-    Post::update(&state.db, &post).await?;
+    if let MaybeAbsent::Present(content) = post.content {
+        let content = extract_inline_images(&state, user.id, content).await?;
+        if content.len() as u64 > state.config.max_content_size {
+            return Err(content_too_large(&state));
+        }
+        post.content = MaybeAbsent::Present(content);
+    }
+    if let MaybeAbsent::Present(Some(parent_id)) = post.parent_id {
+        let max_depth = state.config.max_thread_depth;
+        Post::check_thread_depth(&state.db, Some(post.id), parent_id, max_depth).await?;
+    }
+
+    post.files = post
+        .files
+        .map(|files| files.map(|files| state.urls.to_stored_files(files)));
+    Post::update(&state.db, user.id, &post).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+
+    let warnings = match post.content {
+        MaybeAbsent::Present(ref content) => lint_content(&state, user.id, content).await,
+        MaybeAbsent::Absent => vec![],
+    };
+    if warnings.is_empty() {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(Json(UpdateResponse { warnings }).into_response())
+    }
+}
+
+/// Stores the images pasted into the content as data uris, and links the content to the stored files.
+/// Images which cannot be decoded or stored are kept inline, but a rejected upload, e.g. over the quota, fails the save.
+async fn extract_inline_images(
+    state: &AppState,
+    user_id: i64,
+    content: String,
+) -> ApiResult<String> {
+    if !state.config.extract_inline_images {
+        return Ok(content);
+    }
+    let images = inline_image_service::find_inline_images(&content);
+    if images.is_empty() {
+        return Ok(content);
+    }
 
-    if post.content.is_present() {
-        tokio::spawn(async move {
-            let rv = state.fts.reindex(post.id, post.content.get()).await;
-            if rv.is_err() {
-                error!("Cannot rebuild index: {:?}", rv);
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let mut replacements = vec![];
+    for image in images {
+        let info = match upload_service
+            .save_image(&image.data, &image.content_type)
+            .await
+        {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("Cannot extract an inline image: {:?}", e);
+                continue;
             }
-        });
+        };
+        // No client follows the progress
+        let tracker = UploadTracker::new(
+            state.realtime.clone(),
+            user_id,
+            None,
+            info.thumb_url.is_some(),
+        );
+        let info = store_upload(state, upload_service.clone(), info, &tracker).await?;
+        replacements.push((image.range, state.urls.resolve(&info.url)));
     }
+    Ok(inline_image_service::replace_ranges(&content, replacements))
+}
 
-    Ok(StatusCode::NO_CONTENT)
+/// Lints the content of a saved post, a failing lint is logged and gives no warnings.
+async fn lint_content(state: &AppState, user_id: i64, content: &str) -> Vec<LintWarning> {
+    if !state.config.lint_posts {
+        return vec![];
+    }
+    lint_service::lint(
+        &state.db,
+        user_id,
+        content,
+        state.config.max_inline_image_size,
+    )
+    .await
+    .unwrap_or_else(|e| {
+        error!("Cannot lint the post: {:?}", e);
+        vec![]
+    })
+}
+
+/// The content size is limited separately from the request body, which includes the files.
+fn content_too_large(state: &AppState) -> ApiError {
+    ApiError::PayloadTooLarge(format!(
+        "post content is too large: {} bytes at most",
+        state.config.max_content_size
+    ))
+}
+
+async fn get_reactions(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<Id>,
+) -> ApiResult<Json<Vec<ReactionCount>>> {
+    find_own_post(&state, &user, query.id).await?;
+    let counts = ReactionCount::find_by_post(&state.db, query.id).await?;
+    Ok(Json(counts))
+}
+
+async fn toggle_reaction(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<ToggleReactionRequest>,
+) -> ApiResult<Json<Vec<ReactionCount>>> {
+    find_own_post(&state, &user, payload.id).await?;
+    let counts = ReactionCount::toggle(&state.db, payload.id, &payload.emoji, OWNER).await?;
+    Ok(Json(counts))
 }
 
 async fn delete_post(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Json(payload): Json<DeletePostRequest>,
 ) -> ApiResult<StatusCode> {
     if payload.hard {
-        Post::clear(&state.db, payload.id).await?;
-
-        tokio::spawn(async move {
-            let rv = state.fts.deindex(payload.id).await;
-            if rv.is_err() {
-                error!("Cannot delete index: {:?}", rv);
+        // Only the posts in the trash are deleted permanently
+        Post::find_trashed(&state.db, user.id, payload.id)
+            .await?
+            .ok_or_else(|| not_found("Post not found"))?;
+        if !payload.force {
+            let referrers = Post::find_referrers(&state.db, payload.id).await?;
+            if !referrers.is_empty() {
+                let ids: Vec<String> = referrers.iter().map(i64::to_string).collect();
+                return Err(ApiError::Conflict(format!(
+                    "post is linked from other posts: {}",
+                    ids.join(", ")
+                )));
             }
-        });
+        }
+
+        Post::clear(&state.db, user.id, payload.id).await?;
     } else {
-        Post::delete(&state.db, payload.id).await?;
+        Post::delete(&state.db, user.id, payload.id, payload.purge_after).await?;
     }
+    stats_service::invalidate(&state.rd, user.id).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
-async fn clear_posts(State(state): State<AppState>) -> ApiResult<StatusCode> {
-    let ids = Post::clear_all(&state.db).await?;
+async fn clear_posts(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<TrashStats>> {
+    let stats = Post::clear_all(&state.db, user.id).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+    Ok(Json(stats))
+}
 
-    tokio::spawn(async move {
-        for id in ids {
-            let rv = state.fts.deindex(id).await;
-            if rv.is_err() {
-                error!("Cannot delete index: {:?}", rv);
-                break;
-            }
-        }
-    });
+async fn get_trash_stats(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<TrashStats>> {
+    Ok(Json(Post::get_trash_stats(&state.db.pool, user.id).await?))
+}
 
+async fn remove_post_file(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<RemovePostFileRequest>,
+) -> ApiResult<StatusCode> {
+    // The file is discarded later, if it is an upload of the user no post uses
+    let key = state.urls.to_key(&payload.url);
+    Post::remove_file(&state.db, user.id, payload.id, key).await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
 async fn restore_post(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Json(payload): Json<Id>,
 ) -> ApiResult<StatusCode> {
-    Post::restore(&state.db, payload.id).await?;
+    Post::restore(&state.db, user.id, payload.id).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_stats(State(state): State<AppState>) -> ApiResult<Json<PostStats>> {
-    Json(PostStats {
-        post_count: Post::get_count(&state.db).await?,
-        tag_count: Tag::get_count(&state.db).await?,
-        day_count: Post::get_active_days(&state.db).await?,
-    })
-    .pipe(Ok)
+/// Sets when a post in the trash is deleted permanently, it is done by the next run of the purge-trash job.
+async fn schedule_purge(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<SchedulePurgeRequest>,
+) -> ApiResult<StatusCode> {
+    Post::schedule_purge(&state.db, user.id, payload.id, payload.purge_after).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Recolors a selection of posts at once, e.g. all those of a filter.
+async fn batch_set_color(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<BatchSetColorRequest>,
+) -> ApiResult<Json<BatchUpdateResponse>> {
+    let ids = Post::set_color(&state.db, user.id, &payload.ids, payload.color.as_ref()).await?;
+    stats_service::invalidate(&state.rd, user.id).await;
+    Ok(Json(BatchUpdateResponse { ids }))
+}
+
+async fn get_stats(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> ApiResult<Json<PostStats>> {
+    let stats = stats_service::get_stats(&state.db, &state.rd, user.id).await?;
+    Ok(Json(stats))
+}
+
+/// Recent changes of the posts of the user, e.g. for a "recently edited" view.
+async fn get_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<ActivityRequest>,
+) -> ApiResult<Json<Vec<Activity>>> {
+    let since = query.since.unwrap_or(0);
+    let limit = query.limit.unwrap_or(100);
+    let activities = Activity::find_since(&state.db, user.id, since, limit).await?;
+    Ok(Json(activities))
+}
+
+/// Notifications are about the instance, such as failed jobs, so only admins see them.
+async fn get_notifications(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<GetNotificationsRequest>,
+) -> ApiResult<Json<Vec<Notification>>> {
+    if !user.admin {
+        return Err(ApiError::Forbidden("Only admins can do this".to_string()));
+    }
+    let limit = query.limit.unwrap_or(50);
+    let notifications = Notification::find_latest(&state.db, query.unread, limit).await?;
+    Ok(Json(notifications))
+}
+
+async fn mark_notification_read(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<MarkNotificationReadRequest>,
+) -> ApiResult<StatusCode> {
+    if !user.admin {
+        return Err(ApiError::Forbidden("Only admins can do this".to_string()));
+    }
+    Notification::mark_read(&state.db, payload.id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn get_daily_post_counts(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     ValidatedQuery(query): ValidatedQuery<DateRange>,
 ) -> ApiResult<Json<Vec<i64>>> {
     Json(
         Post::get_daily_counts(
             &state.db,
+            user.id,
             parse_date_with_timezone(&query.start_date, query.offset, false)?,
             parse_date_with_timezone(&query.end_date, query.offset, true)?,
         )
@@ -287,39 +1223,259 @@ async fn file_form() -> Html<&'static str> {
 
 async fn upload_file(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedQuery(query): ValidatedQuery<UploadFileRequest>,
     mut multipart: Multipart,
 ) -> ApiResult<Json<FileInfo>> {
     if let Some(field) = multipart.next_field().await? {
+        // Reject early if the quota is already exceeded
+        check_upload_quota(&state, user.id, 0).await?;
+
         let upload_service = FileUploadService::new(state.config.upload.clone());
-        let rv = upload_service.stream_to_file(field).await?;
-        Ok(Json(rv))
+        let info = upload_service.stream_to_file(field).await?;
+
+        let tracker = UploadTracker::new(
+            state.realtime.clone(),
+            user.id,
+            query.token,
+            info.thumb_url.is_some(),
+        );
+        let url = state.urls.resolve(&info.url);
+        tracker.report(UploadStage::Received, &url);
+
+        match store_upload(&state, upload_service, info, &tracker).await {
+            Ok(info) => Ok(Json(state.urls.resolve_file(info))),
+            Err(e) => {
+                tracker.report(UploadStage::Failed, &url);
+                Err(e)
+            }
+        }
     } else {
         Err(ApiError::BadRequest("Invalid Multipart".into()))?
     }
 }
 
-async fn rebuild_all_indexes(State(state): State<AppState>) -> ApiResult<&'static str> {
-    let posts = sqlx::query!("SELECT id, content FROM posts")
-        .fetch_all(&state.db.pool)
+/// Starts a resumable upload, or returns the chunks received of the same file if it was started before.
+async fn init_upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<InitUploadRequest>,
+) -> ApiResult<Json<UploadSession>> {
+    check_upload_quota(&state, user.id, payload.size).await?;
+
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    Ok(Json(upload_service.init_chunked(user.id, &payload).await?))
+}
+
+/// Receives a chunk of a resumable upload as the raw body.
+async fn upload_chunk(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<UploadChunkRequest>,
+    body: Bytes,
+) -> ApiResult<Json<UploadSession>> {
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let session = upload_service
+        .save_chunk(user.id, &query.id, query.index, &body)
+        .await?;
+    Ok(Json(session))
+}
+
+/// Assembles a resumable upload once all of its chunks are received, then stores it as `upload` does.
+async fn complete_upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ValidatedJson(payload): ValidatedJson<CompleteUploadRequest>,
+) -> ApiResult<Json<FileInfo>> {
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let info = upload_service
+        .complete_chunked(user.id, &payload.id)
         .await?;
 
+    let tracker = UploadTracker::new(
+        state.realtime.clone(),
+        user.id,
+        payload.token,
+        info.thumb_url.is_some(),
+    );
+    let url = state.urls.resolve(&info.url);
+    tracker.report(UploadStage::Received, &url);
+
+    match store_upload(&state, upload_service, info, &tracker).await {
+        Ok(info) => Ok(Json(state.urls.resolve_file(info))),
+        Err(e) => {
+            tracker.report(UploadStage::Failed, &url);
+            Err(e)
+        }
+    }
+}
+
+/// Records a new upload, or reuses an identical file uploaded before.
+/// Thumbnails are made and text is extracted in the background, reported over the websocket.
+async fn store_upload(
+    state: &AppState,
+    upload_service: FileUploadService,
+    mut info: FileInfo,
+    tracker: &UploadTracker,
+) -> ApiResult<FileInfo> {
+    if let Some(ref scanner) = state.scanner {
+        scan_upload(state, scanner.as_ref(), &upload_service, &info).await?;
+    }
+    let id = info.id.clone().unwrap_or_default();
+
+    if let Some(stored) = StoredFile::find_by_id(&state.db, &id).await? {
+        if upload_service.file_path(&stored.filename).exists() {
+            // The same content has been uploaded before, keep only one copy on disk
+            upload_service.discard(&info).await?;
+            let mut info = upload_service.reuse_stored(info, &stored.filename);
+            let original_name = info.original_name.as_deref();
+            let upload = FileUpload::create(
+                &state.db,
+                &id,
+                tracker.user_id,
+                original_name.unwrap_or(&stored.original_name),
+            )
+            .await?;
+            info.upload_id = Some(upload.id);
+            let url = state.urls.resolve(&info.url);
+            tracker.report(UploadStage::Stored, &url);
+            // Its thumbnail and text are ready as well
+            tracker.report(UploadStage::Indexed, &url);
+            return Ok(info);
+        }
+    }
+
+    if let Err(e) = check_upload_quota(state, tracker.user_id, info.size.unwrap_or(0)).await {
+        upload_service.discard(&info).await?;
+        return Err(e);
+    }
+
+    let filename = upload_service
+        .filename_from_url(&info.url)
+        .unwrap_or_default();
+    let original_name = info.original_name.as_deref().unwrap_or(filename);
+    StoredFile::save(
+        &state.db,
+        tracker.user_id,
+        &id,
+        filename,
+        original_name,
+        info.content_type.as_deref(),
+        info.size.unwrap_or(0) as i64,
+    )
+    .await?;
+    let upload = FileUpload::create(&state.db, &id, tracker.user_id, original_name).await?;
+    info.upload_id = Some(upload.id);
+    let url = state.urls.resolve(&info.url);
+    tracker.report(UploadStage::Stored, &url);
+
+    let (state, tracker) = (state.clone(), tracker.clone());
+    let file = info.clone();
     tokio::spawn(async move {
-        let rv = state.fts.clear_all_indexes().await;
-        if rv.is_err() {
-            error!("Cannot clear indexes: {:?}", rv);
-            return;
+        if let Some(ref thumb_url) = file.thumb_url {
+            let event = match upload_service.process_image(&file).await {
+                Ok(()) => {
+                    tracker.report(UploadStage::Thumbnailed, &url);
+                    RealtimeEvent::FileProcessed {
+                        user_id: tracker.user_id,
+                        url: url.clone(),
+                        thumb_url: state.urls.resolve(thumb_url),
+                    }
+                }
+                Err(e) => {
+                    error!("Cannot process image {}: {:?}", file.url, e);
+                    tracker.report(UploadStage::Failed, &url);
+                    state.realtime.publish(RealtimeEvent::FileProcessingFailed {
+                        user_id: tracker.user_id,
+                        url,
+                    });
+                    return;
+                }
+            };
+            state.realtime.publish(event);
         }
 
-        for post in posts {
-            let rv = state.fts.index(post.id, &post.content).await;
-            if rv.is_err() {
-                error!("Cannot rebuild index: {:?}", rv);
-                break;
+        // The text is cached, so that indexing the post it is attached to is quick
+        upload_service
+            .attachment_text(&state.db, state.ocr.as_deref(), tracker.user_id, &file)
+            .await;
+        tracker.report(UploadStage::Indexed, &url);
+    });
+    Ok(info)
+}
+
+/// Scans a new upload, infected files are moved to the quarantine directory.
+/// The results are written to the audit log.
+async fn scan_upload(
+    state: &AppState,
+    scanner: &dyn VirusScanner,
+    upload_service: &FileUploadService,
+    info: &FileInfo,
+) -> ApiResult<()> {
+    let filename = upload_service
+        .filename_from_url(&info.url)
+        .unwrap_or_default();
+    let path = upload_service.file_path(filename);
+
+    match scanner.scan(&path).await {
+        Ok(ScanVerdict::Clean) => {
+            AuditLog::log(&state.db, None, "upload.clean", filename, None).await;
+            Ok(())
+        }
+        Ok(ScanVerdict::Infected(signature)) => {
+            if let Err(e) = quarantine(&path, &state.config.scan.quarantine_path).await {
+                error!("Cannot quarantine file {}: {:?}", filename, e);
             }
+            upload_service.discard(info).await?;
+            AuditLog::log(
+                &state.db,
+                None,
+                "upload.infected",
+                filename,
+                Some(&signature),
+            )
+            .await;
+            Err(ApiError::BadRequest(format!(
+                "file is infected: {}",
+                signature
+            )))
         }
-    });
+        Err(e) => {
+            upload_service.discard(info).await?;
+            let detail = format!("{:#}", e);
+            AuditLog::log(
+                &state.db,
+                None,
+                "upload.scan_failed",
+                filename,
+                Some(&detail),
+            )
+            .await;
+            Err(ApiError::ServerError("cannot scan file".to_string()))
+        }
+    }
+}
 
-    Ok("Indexing...")
+/// Returns 507 if storing `size` more bytes would exceed the upload quota, or that of the user.
+/// A file uploaded before by anyone is reused, it counts for the user who uploaded it first.
+async fn check_upload_quota(state: &AppState, user_id: i64, size: u64) -> ApiResult<()> {
+    let config = &state.config.upload;
+    for (quota, user_id) in [(config.quota, None), (config.user_quota, Some(user_id))] {
+        if quota == 0 {
+            continue;
+        }
+
+        let usage = StoredFile::usage(&state.db, user_id).await?;
+        if (usage.size as u64)
+            .checked_add(size)
+            .is_none_or(|total| total > quota)
+        {
+            return Err(ApiError::InsufficientStorage(
+                "upload quota exceeded".to_string(),
+            ));
+        }
+    }
+    Ok(())
 }
 
 // Helper functions
@@ -1,29 +1,51 @@
-use crate::model::post::{FileInfo, PostRow};
-use crate::util::env::get_env_or;
-use crate::util::extractor::Path;
+use crate::errors::{not_found, ApiError, ApiResult};
+use crate::middleware::check_access::get_cookie;
+use crate::middleware::client_info::ClientInfo;
+use crate::middleware::limit_request::limit_request;
+use crate::model::post::{FileInfo, PostRow, ShareOptions};
+use crate::model::reaction::{
+    visitor_reactor, ReactionCount, SharedReactionRequest, VISITOR_COOKIE,
+};
+use crate::route::Routes;
+use crate::service::asset_service::{template_env, Assets};
+use crate::service::kv_service::KvStore;
+use crate::service::stats_service::{CACHE_TTL_SECONDS, SHARED_POSTS_KEY};
+use crate::util::extractor::{Json, Path, Query, ValidatedJson};
+use crate::util::html::strip_tags;
 use crate::AppState;
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{Html, IntoResponse, Response};
-use axum::routing::get;
-use axum::{Extension, Router};
+use axum::routing::{get, post};
+use axum::{middleware, Extension, Router};
 use chrono::{Local, TimeZone};
 use lazy_static::lazy_static;
-use minijinja::{context, path_loader, Environment};
+use minijinja::{context, Environment};
 use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use tracing::error;
+use uuid::Uuid;
 
 type HtmlResult = Result<Html<String>, HtmlError>;
 
-pub fn create_routes() -> Router<AppState> {
-    let mut env = Environment::new();
-    env.set_loader(path_loader("templates"));
+pub fn create_routes(assets: Arc<Assets>, kv: Arc<dyn KvStore>) -> Router<AppState> {
+    let env = template_env(assets);
+    routes(kv).into_router().layer(Extension(env))
+}
 
-    Router::new()
+pub fn routes(kv: Arc<dyn KvStore>) -> Routes {
+    Routes::new()
         .route("/", get(post_list))
         .route("/{id}", get(post_item))
-        .layer(Extension(env))
+        .route("/{id}/embed", get(post_embed))
+        .route("/{id}/oembed.json", get(post_oembed))
+        .route(
+            "/{id}/toggle-reaction",
+            post(toggle_reaction).layer(middleware::from_fn(move |req, next| {
+                limit_request(kv.clone(), 60, 30, req, next)
+            })),
+        )
 }
 
 #[derive(Debug, Serialize)]
@@ -38,6 +60,16 @@ async fn post_list(
     State(state): State<AppState>,
     Extension(env): Extension<Environment<'_>>,
 ) -> HtmlResult {
+    let html = state
+        .rd
+        .cached(SHARED_POSTS_KEY, CACHE_TTL_SECONDS, || {
+            render_post_list(&state, &env)
+        })
+        .await?;
+    Ok(Html(html))
+}
+
+async fn render_post_list(state: &AppState, env: &Environment<'_>) -> Result<String, HtmlError> {
     let posts = sqlx::query_as!(
         PostRow,
         r#"
@@ -52,22 +84,22 @@ async fn post_list(
     let mut result = Vec::new();
 
     for post in posts.iter() {
-        let (title, description) = extract_header_and_description_from_html(&post.content);
+        let (_, description) = extract_header_and_description_from_html(&post.content);
         result.push(PostMetaData {
             id: post.id,
-            title,
+            title: post.title.clone(),
             description,
             created_at: timestamp_to_local_date(post.created_at / 1000),
         })
     }
 
-    let about_url = get_env_or("ABOUT_URL", "".to_string())?;
+    let about_url = &state.config.about_url;
     let template = env.get_template("post-list.html")?;
 
-    Ok(Html(template.render(context! {
+    Ok(template.render(context! {
         about_url,
         posts => result,
-    })?))
+    })?)
 }
 
 async fn post_item(
@@ -75,7 +107,170 @@ async fn post_item(
     Path(id): Path<i64>,
     Extension(env): Extension<Environment<'_>>,
 ) -> HtmlResult {
-    let post = sqlx::query_as!(
+    let post = find_shared_post(&state, id)
+        .await?
+        .ok_or(HtmlError::NotFound)?;
+
+    let title = post.title.clone();
+    let images = resolved_images(&state, &post);
+    let reactions = ReactionCount::find_by_post(&state.db, post.id).await?;
+    let SharedContent {
+        content,
+        date,
+        options,
+    } = SharedContent::of(&post);
+
+    let about_url = &state.config.about_url;
+    let template = env.get_template("post-item.html")?;
+
+    Ok(Html(template.render(
+        context! { about_url, post, title, content, date, options, images, reactions },
+    )?))
+}
+
+/// A minimal page of a shared post, to be embedded into other sites with an iframe.
+/// It posts its height to the parent window, so that the iframe can be resized to fit.
+async fn post_embed(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Extension(env): Extension<Environment<'_>>,
+) -> HtmlResult {
+    let post = find_shared_post(&state, id)
+        .await?
+        .ok_or(HtmlError::NotFound)?;
+
+    let images = resolved_images(&state, &post);
+    let SharedContent {
+        content,
+        date,
+        options,
+    } = SharedContent::of(&post);
+
+    let template = env.get_template("post-embed.html")?;
+    Ok(Html(template.render(
+        context! { post, content, date, options, images },
+    )?))
+}
+
+/// The images of a shared post, without where they were taken, which only their owner sees.
+fn resolved_images(state: &AppState, post: &PostRow) -> Vec<FileInfo> {
+    post.file_infos()
+        .into_iter()
+        .map(|file| FileInfo {
+            latitude: None,
+            longitude: None,
+            ..state.urls.resolve_file(file)
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct OEmbedRequest {
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+/// See <https://oembed.com>
+#[derive(Debug, Serialize)]
+struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    provider_name: String,
+    provider_url: String,
+    title: Option<String>,
+    html: String,
+    width: u32,
+    height: u32,
+}
+
+async fn post_oembed(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<OEmbedRequest>,
+    Extension(client): Extension<ClientInfo>,
+) -> ApiResult<Json<OEmbed>> {
+    let post = find_shared_post(&state, id)
+        .await?
+        .ok_or_else(|| not_found("Post not found"))?;
+
+    let width = query.maxwidth.unwrap_or(EMBED_WIDTH).min(EMBED_WIDTH);
+    let height = query
+        .maxheight
+        .unwrap_or(EMBED_HEIGHT)
+        .min(embed_height(&post));
+
+    let origin = client.origin();
+    let src = format!("{}/shared/{}/embed", origin, post.id);
+    let html = format!(
+        r#"<iframe src="{src}" width="{width}" height="{height}" style="max-width: 100%; border: 0" loading="lazy" data-mote-embed="{id}"></iframe>{script}"#,
+        src = src,
+        width = width,
+        height = height,
+        id = post.id,
+        script = EMBED_RESIZE_SCRIPT,
+    );
+
+    Ok(Json(OEmbed {
+        version: "1.0",
+        kind: "rich",
+        provider_name: state.config.app_name.clone(),
+        provider_url: origin,
+        title: post.title.clone(),
+        html,
+        width,
+        height,
+    }))
+}
+
+/// Visitors of a shared post react to it without an account, each is told apart by an id kept in a cookie,
+/// which is set by the first reaction.
+async fn toggle_reaction(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Extension(client): Extension<ClientInfo>,
+    ValidatedJson(payload): ValidatedJson<SharedReactionRequest>,
+) -> ApiResult<Response> {
+    find_shared_post(&state, id)
+        .await?
+        .ok_or_else(|| not_found("Post not found"))?;
+
+    let visitor = visitor_id(&headers);
+    let id_or_new = visitor.unwrap_or_else(Uuid::new_v4);
+    let counts =
+        ReactionCount::toggle(&state.db, id, &payload.emoji, &visitor_reactor(&id_or_new)).await?;
+
+    let mut response = Json(counts).into_response();
+    if visitor.is_none() {
+        let cookie = visitor_cookie(&id_or_new, client.scheme == "https");
+        if let Ok(value) = cookie.parse() {
+            response.headers_mut().insert(header::SET_COOKIE, value);
+        }
+    }
+    Ok(response)
+}
+
+/// The id of the visitor in the cookie, a malformed one is replaced
+fn visitor_id(headers: &HeaderMap) -> Option<Uuid> {
+    get_cookie(headers, VISITOR_COOKIE).and_then(|value| Uuid::parse_str(&value).ok())
+}
+
+fn visitor_cookie(id: &Uuid, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/shared; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        VISITOR_COOKIE,
+        id,
+        VISITOR_COOKIE_TTL_SECS,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// A visitor keeps their reactions for a year
+const VISITOR_COOKIE_TTL_SECS: u64 = 365 * 24 * 3600;
+
+async fn find_shared_post(state: &AppState, id: i64) -> Result<Option<PostRow>, sqlx::Error> {
+    sqlx::query_as!(
         PostRow,
         r#"
         SELECT * FROM posts
@@ -84,26 +279,45 @@ async fn post_item(
         id
     )
     .fetch_optional(&state.db.pool)
-    .await?;
+    .await
+}
 
-    let post = post
-        .as_ref()
-        .filter(|p| p.deleted_at.is_none())
-        .ok_or(HtmlError::NotFound)?;
+/// What is shown of a shared post, according to its share options
+struct SharedContent {
+    content: String,
+    date: String,
+    options: ShareOptions,
+}
 
-    let (title, _) = extract_header_and_description_from_html(&post.content);
+impl SharedContent {
+    fn of(post: &PostRow) -> Self {
+        let options = post.share_options();
+        let content = if options.hide_tags {
+            HASH_TAG_PATTERN.replace_all(&post.content, "").into_owned()
+        } else {
+            post.content.clone()
+        };
+
+        SharedContent {
+            content,
+            date: timestamp_to_local_date(post.created_at / 1000),
+            options,
+        }
+    }
+}
 
-    let images: Vec<FileInfo> = match post.files {
-        Some(ref files) => serde_json::from_str(files).expect("JSON decode error"),
-        None => vec![],
-    };
+/// The default and max size of an embedded post in pixels
+const EMBED_WIDTH: u32 = 550;
+const EMBED_HEIGHT: u32 = 800;
 
-    let about_url = get_env_or("ABOUT_URL", "".to_string())?;
-    let template = env.get_template("post-item.html")?;
+/// Resizes the iframes of embedded posts to the heights they post, it is only added once.
+const EMBED_RESIZE_SCRIPT: &str = r#"<script>window.moteEmbed||(window.moteEmbed=1,addEventListener("message",function(e){var d=e.data;if(!d||d.type!=="mote-embed-height")return;document.querySelectorAll("iframe[data-mote-embed='"+d.id+"']").forEach(function(f){f.height=d.height})}))</script>"#;
 
-    Ok(Html(
-        template.render(context! { about_url, post, title, images })?,
-    ))
+/// Guesses the height of an embedded post before it is loaded, from its text and images.
+fn embed_height(post: &PostRow) -> u32 {
+    let lines = strip_tags(&post.content).chars().count() as u32 / 40 + 1;
+    let images = if post.file_infos().is_empty() { 0 } else { 120 };
+    80 + lines * 24 + images
 }
 
 #[derive(Debug)]
@@ -132,6 +346,12 @@ impl From<anyhow::Error> for HtmlError {
     }
 }
 
+impl From<ApiError> for HtmlError {
+    fn from(err: ApiError) -> Self {
+        HtmlError::Anyhow(err.into())
+    }
+}
+
 impl IntoResponse for HtmlError {
     fn into_response(self) -> Response {
         match self {
@@ -160,6 +380,8 @@ lazy_static! {
         Regex::new(r#"<h[1-3][^>]*>(.*?)</h[1-3]>\s*(?:<p[^>]*><strong>(.*?)</strong></p>)?"#)
             .unwrap();
     static ref STRONG_TAG_PATTERN: Regex = Regex::new(r"</?strong>").unwrap();
+    static ref HASH_TAG_PATTERN: Regex =
+        Regex::new(r#"<span class="hash-tag">#[^<]*</span>"#).unwrap();
 }
 
 fn extract_header_and_description_from_html(html: &str) -> (Option<String>, Option<String>) {
@@ -184,6 +406,32 @@ fn timestamp_to_local_date(timestamp: i64) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_visitor_id() {
+        let id = Uuid::new_v4();
+        let mut headers = HeaderMap::new();
+        assert_eq!(visitor_id(&headers), None);
+
+        headers.insert(
+            header::COOKIE,
+            format!("theme=dark; {}={}", VISITOR_COOKIE, id)
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(visitor_id(&headers), Some(id));
+
+        headers.insert(
+            header::COOKIE,
+            format!("{}=owner", VISITOR_COOKIE).parse().unwrap(),
+        );
+        assert_eq!(visitor_id(&headers), None);
+
+        let cookie = visitor_cookie(&id, true);
+        assert!(cookie.starts_with(&format!("{}={};", VISITOR_COOKIE, id)));
+        assert!(cookie.ends_with("; Secure"));
+        assert_eq!(visitor_reactor(&id), format!("visitor:{}", id));
+    }
+
     #[test]
     fn test_header_and_bold_paragraph() {
         let html = r#"
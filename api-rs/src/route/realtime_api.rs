@@ -0,0 +1,58 @@
+use crate::model::user::User;
+use crate::route::Routes;
+use crate::service::realtime_service::RealtimeEvent;
+use crate::AppState;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Extension;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tracing::error;
+
+pub fn routes() -> Routes {
+    Routes::new().route("/ws", get(subscribe))
+}
+
+async fn subscribe(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let events = state.realtime.subscribe();
+    ws.on_upgrade(move |socket| forward_events(socket, user, events))
+}
+
+/// Pushes events to the client as json text messages until either side closes,
+/// the events of other users are skipped.
+async fn forward_events(mut socket: WebSocket, user: User, mut events: Receiver<RealtimeEvent>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if !event.is_visible_to(&user) {
+                    continue;
+                }
+                let text = match serde_json::to_string(&event) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        error!("Cannot serialize event: {:?}", e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::Result;
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use minijinja::{path_loader, Environment, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The static files with the hash of their content in the names, e.g. `style.3f2a9c1b04.css`,
+/// so that browsers can cache them forever and still get the new ones after a change.
+#[derive(Debug, Default)]
+pub struct Assets {
+    /// The url prefix of static files, e.g. `/static`
+    url: String,
+    /// The path of a file relative to the static directory, to its hashed path
+    hashed: HashMap<String, String>,
+    /// The reverse of `hashed`
+    originals: HashMap<String, String>,
+}
+
+impl Assets {
+    /// Hashes the files in `path` once, they are expected not to change while the app runs.
+    pub fn load(path: &str, url: &str) -> Result<Self> {
+        let mut assets = Assets {
+            url: url.trim_end_matches('/').to_string(),
+            ..Default::default()
+        };
+
+        let mut dirs = vec![Path::new(path).to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry_path = entry?.path();
+                if entry_path.is_dir() {
+                    dirs.push(entry_path);
+                    continue;
+                }
+
+                let relative = entry_path
+                    .strip_prefix(path)?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                let hash = format!("{:x}", Sha256::digest(fs::read(&entry_path)?));
+                let hashed = hashed_name(&relative, &hash[..10]);
+                assets.originals.insert(hashed.clone(), relative.clone());
+                assets.hashed.insert(relative, hashed);
+            }
+        }
+        Ok(assets)
+    }
+
+    /// The url of a static file, with the hash if the file is known.
+    pub fn url_for(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        let path = self.hashed.get(path).map_or(path, String::as_str);
+        format!("{}/{}", self.url, path)
+    }
+
+    /// The path of the file a hashed path refers to
+    pub fn original(&self, hashed: &str) -> Option<&str> {
+        self.originals.get(hashed).map(String::as_str)
+    }
+}
+
+/// Inserts the hash before the extension, `js/app.js` to `js/app.<hash>.js`
+fn hashed_name(path: &str, hash: &str) -> String {
+    let (dir, name) = path
+        .rsplit_once('/')
+        .map_or(("", path), |(dir, name)| (dir, name));
+    let name = match name.split_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}.{}.{}", stem, hash, ext),
+        _ => format!("{}.{}", name, hash),
+    };
+    if dir.is_empty() {
+        name
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Middleware function to serve hashed paths from the static directory.
+///
+/// The request path, relative to the static url, is rewritten to the path of the original file,
+/// and the response is cached forever since the content of a hashed path never changes.
+/// Paths without a hash are served as they are, without the cache header.
+pub async fn serve_hashed(assets: Arc<Assets>, mut request: Request, next: Next) -> Response {
+    let Some(original) = assets.original(request.uri().path().trim_start_matches('/')) else {
+        return next.run(request).await;
+    };
+    if let Ok(uri) = format!("/{}", original).parse::<Uri>() {
+        *request.uri_mut() = uri;
+    }
+
+    let mut response = next.run(request).await;
+    if response.status().is_success() {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=31536000, immutable"),
+        );
+    }
+    response
+}
+
+/// The environment of the templates, with a `static_url_for("style.css")` function
+/// which returns the hashed url of a static file.
+pub fn template_env(assets: Arc<Assets>) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_loader(path_loader("templates"));
+    // Marked safe, or `/` would be escaped and break urls in scripts
+    env.add_function("static_url_for", move |path: &str| {
+        Value::from_safe_string(assets.url_for(path))
+    });
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assets() {
+        assert_eq!(hashed_name("style.css", "abc"), "style.abc.css");
+        assert_eq!(hashed_name("js/app.esm.js", "abc"), "js/app.abc.esm.js");
+        assert_eq!(hashed_name("LICENSE", "abc"), "LICENSE.abc");
+        assert_eq!(hashed_name(".hidden", "abc"), ".hidden.abc");
+
+        let dir = std::env::temp_dir().join(format!("mote-assets-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("js")).unwrap();
+        fs::write(dir.join("style.css"), "body {}").unwrap();
+        fs::write(dir.join("js/app.js"), "").unwrap();
+
+        let assets = Assets::load(&dir.to_string_lossy(), "/static/").unwrap();
+        let url = assets.url_for("style.css");
+        assert!(url.starts_with("/static/style.") && url.ends_with(".css"));
+        assert_ne!(url, "/static/style.css");
+        assert_eq!(assets.url_for("/missing.css"), "/static/missing.css");
+
+        let hashed = assets.url_for("js/app.js");
+        let hashed = hashed.trim_start_matches("/static/");
+        assert_eq!(assets.original(hashed), Some("js/app.js"));
+        assert_eq!(assets.original("js/app.js"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
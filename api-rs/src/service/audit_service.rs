@@ -0,0 +1,80 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::middleware::request_context::current_request_context;
+use crate::model::audit::{Activity, AuditLog};
+use chrono::Utc;
+use sqlx::{query, query_as, SqlitePool};
+use tracing::{error, info};
+
+impl AuditLog {
+    pub async fn record(
+        db: &DB,
+        user_id: Option<i64>,
+        action: &str,
+        target: &str,
+        detail: Option<&str>,
+    ) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let ip = current_request_context().client_ip;
+        query!(
+            "INSERT INTO audit_logs (user_id, action, target, detail, ip, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            user_id,
+            action,
+            target,
+            detail,
+            ip,
+            now,
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records an entry, failures are only logged so that they never break the request.
+    /// `user_id` is who made the change, if it is made by a user.
+    pub async fn log(
+        db: &DB,
+        user_id: Option<i64>,
+        action: &str,
+        target: &str,
+        detail: Option<&str>,
+    ) {
+        info!("audit: {} {} {}", action, target, detail.unwrap_or(""));
+        if let Err(e) = Self::record(db, user_id, action, target, detail).await {
+            error!("Cannot write audit log: {:?}", e);
+        }
+    }
+}
+
+impl Activity {
+    /// Returns the latest post actions of a user after `since`, the most recent first.
+    pub async fn find_since(
+        pool: &SqlitePool,
+        user_id: i64,
+        since: i64,
+        limit: i64,
+    ) -> ApiResult<Vec<Activity>> {
+        let activities = query_as!(
+            Activity,
+            r#"
+            SELECT action,
+                   CAST(target AS INTEGER) AS "post_id!: i64",
+                   COUNT(*) AS "count!: i64",
+                   MAX(created_at) AS "last_at!: i64"
+            FROM audit_logs
+            WHERE action LIKE 'post.%' AND user_id = ? AND created_at > ?
+            GROUP BY action, target
+            ORDER BY MAX(created_at) DESC
+            LIMIT ?
+            "#,
+            user_id,
+            since,
+            limit
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(activities)
+    }
+}
@@ -1,8 +1,657 @@
-pub struct AuthService;
+use crate::config::db::DB;
+use crate::config::AuthConfig;
+use crate::errors::ApiResult;
+use crate::model::session::Session;
+use crate::model::user::{User, ADMIN_USER_ID};
+use crate::service::passkey_service::{encode_base64, RelyingParty};
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::error;
+
+/// What the token of a request is, added to the request extensions by `check_access` next to its [`User`]
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Session(Session),
+    /// The password of the admin user itself, which cannot be revoked.
+    /// It is only accepted with `AUTH_LEGACY_PASSWORD_TOKEN`.
+    Password,
+}
+
+/// Logs in and checks the tokens of requests, with the auth config loaded on startup
+pub struct AuthService {
+    config: AuthConfig,
+    db: Arc<DB>,
+    /// The password of the admin user, set on startup by [`AuthService::ensure_admin`]
+    admin_password_hash: String,
+    /// The token of the admin routes, an admin login is accepted if not set
+    admin_token_hash: Option<String>,
+    verified: HashCache,
+    passkey_origins: Vec<String>,
+    /// The passkey challenges which are not used yet, with when they expire
+    challenges: Mutex<HashMap<String, i64>>,
+    /// The wrong passwords sent as tokens by each address
+    failures: FailureLimiter,
+}
+
+/// How long a passkey ceremony can take
+const CHALLENGE_TTL_MS: i64 = 5 * 60 * 1000;
+// Challenges are issued before login, so their number is capped
+const MAX_CHALLENGES: usize = 1000;
+/// How many wrong passwords an address can send as tokens in a window, each costs an argon2 verification
+const MAX_FAILURES: u32 = 5;
+const FAILURE_WINDOW_MS: i64 = 60 * 1000;
+// The addresses with failures are capped as well
+const MAX_FAILURE_IPS: usize = 10000;
 
 impl AuthService {
-    pub fn is_valid_token(token: &str) -> bool {
-        let password = std::env::var("MOTE_PASSWORD").expect("Password is not set");
-        token == password
+    pub fn new(config: AuthConfig, db: Arc<DB>) -> Self {
+        // The deprecated plain text password is hashed, so both are verified the same way
+        let admin_password_hash = match &config.password {
+            Some(password) if config.password_hash.is_empty() => {
+                hash_password(password).expect("Cannot hash the password")
+            }
+            _ => config.password_hash.clone(),
+        };
+
+        let passkey_origins = if config.passkey_origins.is_empty() {
+            vec![format!("https://{}", config.passkey_rp_id)]
+        } else {
+            config.passkey_origins.clone()
+        };
+
+        let admin_token_hash =
+            (!config.admin_token_hash.is_empty()).then(|| config.admin_token_hash.clone());
+
+        Self {
+            config,
+            db,
+            admin_password_hash,
+            admin_token_hash,
+            verified: HashCache::default(),
+            passkey_origins,
+            challenges: Mutex::new(HashMap::new()),
+            failures: FailureLimiter::default(),
+        }
+    }
+
+    /// Creates the admin user, or updates its name and password from the config.
+    /// It should be called on startup, after the migrations.
+    pub async fn ensure_admin(&self) -> ApiResult<User> {
+        User::ensure_admin(&self.db, &self.config.admin_name, &self.admin_password_hash).await
+    }
+
+    /// The name of the cookie holding the token
+    pub fn cookie_name(&self) -> &str {
+        &self.config.cookie_name
+    }
+
+    /// Starts a session if the password of the user is right, returns its token.
+    /// Clients which send no name log in as the admin user, as before there were users.
+    pub async fn login(
+        &self,
+        name: Option<&str>,
+        password: &str,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> ApiResult<Option<(String, Session)>> {
+        let user = match name {
+            Some(name) => User::find_by_name(&self.db.pool, name).await?,
+            None => User::find_by_id(&self.db.pool, ADMIN_USER_ID).await?,
+        };
+        match user {
+            Some(user) if self.is_valid_password(&user, password) => {
+                self.start_session(user.id, user_agent, ip).await.map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Starts a session for a user who is already verified, e.g. with a passkey.
+    pub async fn start_session(
+        &self,
+        user_id: i64,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> ApiResult<(String, Session)> {
+        Session::delete_expired(&self.db).await?;
+
+        let token = new_token();
+        let session = Session::create(
+            &self.db,
+            user_id,
+            &hash_token(&token),
+            self.session_expires_at(),
+            user_agent,
+            ip,
+        )
+        .await?;
+
+        Ok((token, session))
+    }
+
+    /// Replaces the token of the current session with a new one, which expires after a full session lifetime.
+    /// The old token cannot be used anymore. A login with the password itself gets a new session instead.
+    pub async fn refresh_session(
+        &self,
+        user: &User,
+        credential: &Credential,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> ApiResult<Option<(String, Session)>> {
+        let Credential::Session(session) = credential else {
+            return self.start_session(user.id, user_agent, ip).await.map(Some);
+        };
+
+        let token = new_token();
+        let session = Session::renew(
+            &self.db,
+            session.id,
+            &hash_token(&token),
+            self.session_expires_at(),
+        )
+        .await?;
+        Ok(session.map(|session| (token, session)))
+    }
+
+    fn session_expires_at(&self) -> i64 {
+        Utc::now().timestamp_millis() + self.config.session_ttl_secs as i64 * 1000
+    }
+
+    /// Checks the token of a request sent from `ip`, which is the token of a session, or the password
+    /// of the admin user itself as sent by older clients if `legacy_password_token` is set.
+    /// Returns whom it belongs to.
+    pub async fn authenticate(&self, token: &str, ip: Option<&str>) -> Option<(User, Credential)> {
+        let result = match Session::find_by_token_hash(&self.db.pool, &hash_token(token)).await {
+            Ok(Some(session)) => {
+                if let Err(e) = session.touch(&self.db, ip).await {
+                    error!("Cannot update session: {:?}", e);
+                }
+                User::find_by_id(&self.db.pool, session.user_id)
+                    .await
+                    .map(|user| user.map(|user| (user, Credential::Session(session))))
+            }
+            // The token of a session is not a password, which saves an argon2 verification
+            Ok(None) if !self.config.legacy_password_token || is_session_token(token) => Ok(None),
+            Ok(None) => self.authenticate_password(token, ip).await,
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| {
+            error!("Cannot find session: {:?}", e);
+            None
+        })
+    }
+
+    /// Checks the password of the admin user sent as a token, an address sending too many wrong ones is refused
+    /// for a while without checking them.
+    async fn authenticate_password(
+        &self,
+        password: &str,
+        ip: Option<&str>,
+    ) -> ApiResult<Option<(User, Credential)>> {
+        let ip = ip.unwrap_or_default();
+        if self.failures.is_limited(ip) {
+            return Ok(None);
+        }
+
+        let user = User::find_by_id(&self.db.pool, ADMIN_USER_ID).await?;
+        match user.filter(|user| self.is_valid_password(user, password)) {
+            Some(user) => Ok(Some((user, Credential::Password))),
+            None => {
+                self.failures.record(ip);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The site passkeys are registered for, `None` if passkeys are disabled
+    pub fn relying_party(&self) -> Option<RelyingParty<'_>> {
+        (!self.config.passkey_rp_id.is_empty()).then(|| RelyingParty {
+            id: &self.config.passkey_rp_id,
+            origins: &self.passkey_origins,
+        })
+    }
+
+    /// A random challenge for a passkey ceremony, valid for a few minutes
+    pub fn issue_challenge(&self) -> String {
+        let challenge = encode_base64(&rand::random::<[u8; 32]>());
+        let now = Utc::now().timestamp_millis();
+
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, expires_at| *expires_at > now);
+        if challenges.len() >= MAX_CHALLENGES {
+            // The oldest challenge is the one expiring first
+            let oldest = challenges
+                .iter()
+                .min_by_key(|(_, expires_at)| **expires_at)
+                .map(|(challenge, _)| challenge.clone());
+            if let Some(oldest) = oldest {
+                challenges.remove(&oldest);
+            }
+        }
+        challenges.insert(challenge.clone(), now + CHALLENGE_TTL_MS);
+        challenge
+    }
+
+    /// Consumes a challenge, returns whether it was issued and has not expired.
+    pub fn take_challenge(&self, challenge: &str) -> bool {
+        let now = Utc::now().timestamp_millis();
+        self.challenges
+            .lock()
+            .unwrap()
+            .remove(challenge)
+            .is_some_and(|expires_at| expires_at > now)
+    }
+
+    /// The `Set-Cookie` value which keeps the token of a session in the browser,
+    /// `secure` if the request is sent over https.
+    pub fn session_cookie(&self, token: &str, secure: bool) -> String {
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            self.config.cookie_name,
+            token,
+            self.config.session_ttl_secs,
+            if secure { "; Secure" } else { "" }
+        )
+    }
+
+    /// The `Set-Cookie` value which removes the token of a session from the browser
+    pub fn clear_session_cookie(&self) -> String {
+        format!(
+            "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+            self.config.cookie_name
+        )
+    }
+
+    /// The admin token if one is set, otherwise the routes accept the login of an admin user
+    pub fn has_admin_token(&self) -> bool {
+        self.admin_token_hash.is_some()
+    }
+
+    pub fn is_valid_admin_token(&self, token: &str) -> bool {
+        self.admin_token_hash
+            .as_ref()
+            .is_some_and(|hash| self.verified.verify(token, hash))
+    }
+
+    /// Whether the admin routes are served to a client, an unknown address is allowed
+    /// only if there is no allowlist.
+    pub fn is_admin_allowed(&self, ip: Option<IpAddr>) -> bool {
+        let allowed = &self.config.admin_allowed_ips;
+        allowed.is_empty() || ip.is_some_and(|ip| allowed.iter().any(|net| net.contains(ip)))
+    }
+
+    fn is_valid_password(&self, user: &User, password: &str) -> bool {
+        self.verified.verify(password, &user.password_hash)
+    }
+}
+
+/// The SHA-256 of the last secret which passed each argon2 hash,
+/// argon2 is too slow to run on every request
+#[derive(Default)]
+struct HashCache {
+    verified: RwLock<HashMap<String, [u8; 32]>>,
+}
+
+impl HashCache {
+    fn verify(&self, secret: &str, hash: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+        if let Some(verified) = self.verified.read().unwrap().get(hash) {
+            if constant_time_eq(verified, &digest) {
+                return true;
+            }
+        }
+
+        let valid = verify_password(secret, hash);
+        if valid {
+            self.verified
+                .write()
+                .unwrap()
+                .insert(hash.to_string(), digest);
+        }
+        valid
+    }
+}
+
+/// The failures of each address in the current window, with when it started.
+/// Clients whose address is unknown share one entry.
+#[derive(Default)]
+struct FailureLimiter {
+    failures: Mutex<HashMap<String, (u32, i64)>>,
+}
+
+impl FailureLimiter {
+    fn is_limited(&self, ip: &str) -> bool {
+        let now = Utc::now().timestamp_millis();
+        self.failures
+            .lock()
+            .unwrap()
+            .get(ip)
+            .is_some_and(|(count, start)| *count >= MAX_FAILURES && now - start < FAILURE_WINDOW_MS)
+    }
+
+    fn record(&self, ip: &str) {
+        let now = Utc::now().timestamp_millis();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_FAILURE_IPS && !failures.contains_key(ip) {
+            failures.retain(|_, (_, start)| now - *start < FAILURE_WINDOW_MS);
+            if failures.len() >= MAX_FAILURE_IPS {
+                let oldest = failures
+                    .iter()
+                    .min_by_key(|(_, (_, start))| *start)
+                    .map(|(ip, _)| ip.clone());
+                if let Some(oldest) = oldest {
+                    failures.remove(&oldest);
+                }
+            }
+        }
+
+        let entry = failures.entry(ip.to_string()).or_insert((0, now));
+        if now - entry.1 >= FAILURE_WINDOW_MS {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+    }
+}
+
+/// A random token of 256 bits, hex encoded
+fn new_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Whether a token has the shape of those from [`new_token`]
+fn is_session_token(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Sessions keep only the hash of their tokens, so a leaked database cannot be used to log in
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Hashes a password with argon2id and a random salt, in the PHC string format
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Cannot hash the password: {}", e))
+}
+
+/// Checks a password against a hash from [`hash_password`], the comparison takes constant time
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// Compares in a time which does not depend on where the first difference is,
+/// so the password cannot be guessed a byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DBConfig;
+
+    fn config(password_hash: String, password: Option<&str>) -> AuthConfig {
+        AuthConfig {
+            admin_name: "admin".to_string(),
+            password_hash,
+            password: password.map(String::from),
+            session_ttl_secs: 60,
+            legacy_password_token: false,
+            cookie_name: "token".to_string(),
+            passkey_rp_id: "example.com".to_string(),
+            passkey_origins: vec![],
+            admin_token_hash: String::new(),
+            admin_allowed_ips: vec![],
+        }
+    }
+
+    async fn auth_service(config: AuthConfig) -> AuthService {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let auth = AuthService::new(config, Arc::new(db));
+        auth.ensure_admin().await.unwrap();
+        auth
+    }
+
+    async fn admin(auth: &AuthService) -> User {
+        User::find_by_id(&auth.db.pool, ADMIN_USER_ID)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_is_valid_password() {
+        let hash = hash_password("foobar").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+
+        let auth = auth_service(config(hash, None)).await;
+        let user = admin(&auth).await;
+        assert!(user.admin);
+        for _ in 0..2 {
+            assert!(auth.is_valid_password(&user, "foobar"));
+        }
+        assert!(!auth.is_valid_password(&user, "foobaz"));
+        assert!(!auth.is_valid_password(&user, ""));
+
+        let auth = auth_service(config(String::new(), Some("foobar"))).await;
+        let user = admin(&auth).await;
+        assert!(auth.is_valid_password(&user, "foobar"));
+        assert!(!auth.is_valid_password(&user, "foo"));
+
+        assert!(!verify_password("foobar", "foobar"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_access() {
+        let auth = auth_service(config(String::new(), Some("foobar"))).await;
+        assert!(!auth.has_admin_token());
+        assert!(!auth.is_valid_admin_token("foobar"));
+        assert!(auth.is_admin_allowed(None));
+
+        let mut config = config(String::new(), Some("foobar"));
+        config.admin_token_hash = hash_password("admin").unwrap();
+        config.admin_allowed_ips = vec!["10.0.0.0/8".parse().unwrap()];
+        let auth = auth_service(config).await;
+        assert!(auth.has_admin_token());
+        assert!(auth.is_valid_admin_token("admin"));
+        assert!(!auth.is_valid_admin_token("foobar"));
+        assert!(auth.is_admin_allowed(Some("10.1.2.3".parse().unwrap())));
+        assert!(!auth.is_admin_allowed(Some("127.0.0.1".parse().unwrap())));
+        assert!(!auth.is_admin_allowed(None));
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let auth = auth_service(config(String::new(), Some("foobar"))).await;
+        assert!(auth.login(None, "foo", None, None).await.unwrap().is_none());
+
+        let (token, session) = auth
+            .login(None, "foobar", Some("curl/8.0"), Some("127.0.0.1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!((59_000..=60_000).contains(&(session.expires_at - session.created_at)));
+        assert_eq!(session.user_agent.as_deref(), Some("curl/8.0"));
+
+        let Some((user, Credential::Session(found))) = auth.authenticate(&token, Some("::1")).await
+        else {
+            panic!("the token of a session should be valid");
+        };
+        assert_eq!(found.id, session.id);
+        assert_eq!(user.id, ADMIN_USER_ID);
+        let found = Session::find_active(&auth.db.pool, ADMIN_USER_ID)
+            .await
+            .unwrap();
+        assert_eq!(found[0].ip.as_deref(), Some("::1"));
+
+        assert!(auth.authenticate("foobar", None).await.is_none());
+        assert!(auth.authenticate(&token[1..], None).await.is_none());
+
+        // The token of a refreshed session is replaced
+        let (user, credential) = auth.authenticate(&token, None).await.unwrap();
+        let (new_token, renewed) = auth
+            .refresh_session(&user, &credential, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(renewed.id, session.id);
+        assert!(auth.authenticate(&token, None).await.is_none());
+        let token = new_token;
+        assert!(auth.authenticate(&token, None).await.is_some());
+
+        assert!(!Session::delete(&auth.db, ADMIN_USER_ID + 1, session.id)
+            .await
+            .unwrap());
+        assert!(Session::delete(&auth.db, ADMIN_USER_ID, session.id)
+            .await
+            .unwrap());
+        assert!(auth.authenticate(&token, None).await.is_none());
+
+        let challenge = auth.issue_challenge();
+        assert!(auth.take_challenge(&challenge));
+        assert!(!auth.take_challenge(&challenge));
+
+        for _ in 0..=MAX_CHALLENGES {
+            auth.issue_challenge();
+        }
+        assert_eq!(auth.challenges.lock().unwrap().len(), MAX_CHALLENGES);
+        assert!(auth.take_challenge(&auth.issue_challenge()));
+        assert_eq!(
+            auth.relying_party().unwrap().origins,
+            ["https://example.com"]
+        );
+
+        assert_eq!(
+            auth.session_cookie("abc", true),
+            "token=abc; Path=/; Max-Age=60; HttpOnly; SameSite=Lax; Secure"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_legacy_password_token() {
+        let mut config = config(String::new(), Some("foobar"));
+        config.legacy_password_token = true;
+        let auth = auth_service(config).await;
+
+        let (user, credential) = auth.authenticate("foobar", None).await.unwrap();
+        assert_eq!(user.id, ADMIN_USER_ID);
+        assert!(matches!(credential, Credential::Password));
+        assert!(auth.authenticate("foobaz", None).await.is_none());
+
+        // A login with the password gets a session
+        let (token, session) = auth
+            .refresh_session(&user, &credential, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.user_id, ADMIN_USER_ID);
+        assert!(is_session_token(&token));
+
+        // An address sending wrong passwords is refused for a while, even with the right one
+        for _ in 0..MAX_FAILURES {
+            assert!(auth
+                .authenticate("foobaz", Some("10.0.0.1"))
+                .await
+                .is_none());
+        }
+        assert!(auth
+            .authenticate("foobar", Some("10.0.0.1"))
+            .await
+            .is_none());
+        assert!(auth
+            .authenticate("foobar", Some("10.0.0.2"))
+            .await
+            .is_some());
+        // The tokens of sessions are never checked as passwords
+        assert!(auth
+            .authenticate(&"0".repeat(64), Some("10.0.0.3"))
+            .await
+            .is_none());
+        assert!(!auth.failures.is_limited("10.0.0.3"));
+    }
+
+    #[tokio::test]
+    async fn test_login_users() {
+        let auth = auth_service(config(String::new(), Some("foobar"))).await;
+        let bob = User::create(&auth.db, "bob", &hash_password("bobpass").unwrap(), false)
+            .await
+            .unwrap();
+        assert!(!bob.admin);
+        assert!(User::create(&auth.db, "bob", "", false).await.is_err());
+
+        // The password of another user does not work, nor the legacy token of the admin password
+        assert!(auth
+            .login(Some("bob"), "foobar", None, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(auth
+            .login(Some("alice"), "bobpass", None, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(auth.authenticate("bobpass", None).await.is_none());
+
+        let (token, _) = auth
+            .login(Some("bob"), "bobpass", None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let (user, _) = auth.authenticate(&token, None).await.unwrap();
+        assert_eq!(user.id, bob.id);
+        assert!(Session::find_active(&auth.db.pool, ADMIN_USER_ID)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The admin keeps its id when its name is changed in the config
+        let mut config = config(String::new(), Some("foobaz"));
+        config.admin_name = "root".to_string();
+        let auth = AuthService::new(config, auth.db.clone());
+        let admin = auth.ensure_admin().await.unwrap();
+        assert_eq!((admin.id, admin.name.as_str()), (ADMIN_USER_ID, "root"));
+        assert!(auth
+            .login(Some("root"), "foobaz", None, None)
+            .await
+            .unwrap()
+            .is_some());
+
+        assert!(User::delete(&auth.db, ADMIN_USER_ID).await.is_err());
+        assert!(User::delete(&auth.db, bob.id).await.unwrap());
+        assert!(auth.authenticate(&token, None).await.is_none());
+        assert_eq!(User::find_all(&auth.db.pool).await.unwrap().len(), 1);
     }
 }
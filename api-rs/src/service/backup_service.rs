@@ -0,0 +1,639 @@
+use crate::config::db::DB;
+use crate::errors::{bad_request, ApiError, ApiResult};
+use crate::model::backup::{
+    ArchiveFormat, Backup, BackupFile, BackupFileUpload, BackupPost, BackupTag, BackupTagPost,
+    BackupUser, ImportResult, BACKUP_VERSION,
+};
+use crate::model::post::{FileInfo, Post};
+use crate::model::user::ADMIN_USER_ID;
+use crate::service::export_service::{self, ArchiveEntry, Document};
+use crate::service::post_service::extract_post_links;
+use crate::service::upload_service::{thumb_key, FileUploadService};
+use anyhow::{Context, Result};
+use async_zip::tokio::read::seek::ZipFileReader;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use futures::Stream;
+use sqlx::{query, query_as, SqlitePool};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::Mutex;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::instrument;
+
+/// The name of the data in an archive
+const BACKUP_FILE: &str = "backup.json";
+/// The directory of the uploaded files in an archive
+const UPLOADS_DIR: &str = "uploads/";
+
+// Only one import may run at a time
+static IMPORT_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Reads all users and their posts, including the deleted ones, with their tags and the records of their files.
+#[instrument(skip_all)]
+pub async fn read_backup(pool: &SqlitePool) -> ApiResult<Backup> {
+    let users = query_as!(
+        BackupUser,
+        "SELECT id, name, password_hash, admin, created_at FROM users ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+    let posts = query_as!(BackupPost, "SELECT * FROM posts ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    let tags = query_as!(BackupTag, "SELECT * FROM tags ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    let tag_post_assoc = query_as!(
+        BackupTagPost,
+        "SELECT tag_id, post_id FROM tag_post_assoc ORDER BY tag_id, post_id"
+    )
+    .fetch_all(pool)
+    .await?;
+    let files = query_as!(BackupFile, "SELECT * FROM files ORDER BY created_at, id")
+        .fetch_all(pool)
+        .await?;
+    let file_uploads = query_as!(
+        BackupFileUpload,
+        "SELECT * FROM file_uploads ORDER BY created_at, id"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        created_at: Utc::now().timestamp_millis(),
+        users,
+        posts,
+        tags,
+        tag_post_assoc,
+        files,
+        file_uploads,
+    })
+}
+
+/// Streams an archive of the backup, with the uploaded files and their thumbnails.
+pub fn export_stream(
+    upload_service: &FileUploadService,
+    backup: &Backup,
+    format: ArchiveFormat,
+) -> Result<impl Stream<Item = io::Result<Bytes>>> {
+    let document = Document {
+        name: BACKUP_FILE.to_string(),
+        data: serde_json::to_vec(backup).context("Cannot encode the backup")?,
+    };
+    let entries = upload_entries(upload_service, backup);
+    Ok(export_service::archive_stream(
+        format,
+        vec![document],
+        entries,
+    ))
+}
+
+/// Lists the files in the upload directory which are recorded or attached to posts, each once.
+fn upload_entries(upload_service: &FileUploadService, backup: &Backup) -> Vec<ArchiveEntry> {
+    let mut filenames = BTreeSet::new();
+    for file in &backup.files {
+        filenames.insert(thumb_key(&file.filename));
+        filenames.insert(file.filename.clone());
+    }
+    for post in &backup.posts {
+        let files: Vec<FileInfo> = post
+            .files
+            .as_deref()
+            .and_then(|files| serde_json::from_str(files).ok())
+            .unwrap_or_default();
+        for file in files {
+            let variants = file.variants.iter().map(|variant| variant.url.as_str());
+            for url in [Some(file.url.as_str()), file.thumb_url.as_deref()]
+                .into_iter()
+                .flatten()
+                .chain(variants)
+            {
+                filenames.extend(upload_service.filename_from_url(url).map(String::from));
+            }
+        }
+    }
+
+    filenames
+        .into_iter()
+        .filter_map(|filename| {
+            let path = upload_service.file_path(&filename);
+            let modified_at = path.metadata().ok()?.modified().ok()?;
+            Some(ArchiveEntry {
+                name: format!("{}{}", UPLOADS_DIR, filename),
+                path,
+                modified_at: DateTime::<Utc>::from(modified_at),
+            })
+        })
+        .collect()
+}
+
+/// Restores a backup archive, which replaces all users, posts, tags and records of files.
+/// The admin is kept as configured, and the other users lose their sessions and passkeys.
+///
+/// The uploaded files are written into `upload_dir` first, keeping the files which exist already,
+/// then the rows are replaced in a transaction, so that a failed import leaves the posts as they were.
+/// The links between posts are derived again, but the reactions to the replaced posts are lost.
+/// The search index is not updated, it should be rebuilt afterwards.
+#[instrument(skip_all)]
+pub async fn import(db: &DB, upload_dir: &Path, archive: &Path) -> ApiResult<ImportResult> {
+    let _guard = IMPORT_LOCK
+        .try_lock()
+        .map_err(|_| ApiError::Conflict("a backup is already being imported".to_string()))?;
+
+    let mut head = [0; 4];
+    let read = async { File::open(archive).await?.read(&mut head).await }
+        .await
+        .context("Cannot read the archive")?;
+    let format = ArchiveFormat::detect(&head[..read])
+        .ok_or_else(|| bad_request("The backup must be a zip or tar.gz archive"))?;
+
+    let backup: Backup = match read_backup_data(archive, format).await {
+        Ok(Some(data)) => serde_json::from_slice(&data)
+            .map_err(|e| bad_request(&format!("Invalid {}: {}", BACKUP_FILE, e)))?,
+        Ok(None) => return Err(bad_request(&format!("No {} in the archive", BACKUP_FILE))),
+        Err(e) => return Err(bad_request(&format!("Invalid archive: {:#}", e))),
+    };
+    if backup.version > BACKUP_VERSION {
+        return Err(bad_request(&format!(
+            "The backup is made by a newer version (format {})",
+            backup.version
+        )));
+    }
+
+    let files = extract_uploads(archive, format, upload_dir).await?;
+    restore(db, &backup).await?;
+
+    Ok(ImportResult {
+        posts: backup.posts.len(),
+        tags: backup.tags.len(),
+        files,
+    })
+}
+
+/// Replaces the rows with those of the backup, in a transaction.
+async fn restore(db: &DB, backup: &Backup) -> ApiResult<()> {
+    let mut tx = db.writer.begin().await?;
+
+    // Posts may refer to parents inserted after them
+    query!("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+    for table in [
+        "post_links",
+        "post_reactions",
+        "tag_post_assoc",
+        "tags",
+        "posts",
+        "files",
+    ] {
+        sqlx::query(&format!("DELETE FROM {}", table))
+            .execute(&mut *tx)
+            .await?;
+    }
+    for table in ["sessions", "passkeys", "users"] {
+        let column = if table == "users" { "id" } else { "user_id" };
+        sqlx::query(&format!("DELETE FROM {} WHERE {} != ?", table, column))
+            .bind(ADMIN_USER_ID)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // The name of a user may be taken by the configured admin, their posts are kept all the same
+    for user in backup.users.iter().filter(|user| user.id != ADMIN_USER_ID) {
+        query!(
+            r#"
+            INSERT INTO users (id, name, password_hash, admin, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT DO NOTHING
+            "#,
+            user.id,
+            user.name,
+            user.password_hash,
+            user.admin,
+            user.created_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for post in &backup.posts {
+        query!(
+            r#"
+            INSERT INTO posts (
+                id, user_id, content, files, color, shared, deleted_at, created_at, updated_at,
+                parent_id, children_count, share_options, title, title_custom, purge_after
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            post.id,
+            post.user_id,
+            post.content,
+            post.files,
+            post.color,
+            post.shared,
+            post.deleted_at,
+            post.created_at,
+            post.updated_at,
+            post.parent_id,
+            post.children_count,
+            post.share_options,
+            post.title,
+            post.title_custom,
+            post.purge_after,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for tag in &backup.tags {
+        query!(
+            r#"
+            INSERT INTO tags (id, user_id, name, sticky, sort_order, hidden, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            tag.id,
+            tag.user_id,
+            tag.name,
+            tag.sticky,
+            tag.sort_order,
+            tag.hidden,
+            tag.created_at,
+            tag.updated_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for assoc in &backup.tag_post_assoc {
+        query!(
+            "INSERT OR IGNORE INTO tag_post_assoc (tag_id, post_id) VALUES (?, ?)",
+            assoc.tag_id,
+            assoc.post_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for file in &backup.files {
+        query!(
+            r#"
+            INSERT INTO files (id, filename, original_name, content_type, size, created_at, text, user_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            file.id,
+            file.filename,
+            file.original_name,
+            file.content_type,
+            file.size,
+            file.created_at,
+            file.text,
+            file.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    for upload in &backup.file_uploads {
+        query!(
+            r#"
+            INSERT INTO file_uploads (id, file_id, user_id, original_name, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+            upload.id,
+            upload.file_id,
+            upload.user_id,
+            upload.original_name,
+            upload.created_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    // The files of older backups are downloaded by the hash of their content, as after the migration
+    if backup.file_uploads.is_empty() {
+        query!(
+            r#"
+            INSERT INTO file_uploads (id, file_id, user_id, original_name, created_at)
+            SELECT id, id, user_id, original_name, created_at FROM files
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // All posts exist now, so that the links to posts inserted later are kept
+    for post in &backup.posts {
+        let links = extract_post_links(&post.content);
+        Post::update_post_links(&mut tx, post.user_id, post.id, &links, true).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Reads the data of a backup, none if the archive has none.
+async fn read_backup_data(archive: &Path, format: ArchiveFormat) -> Result<Option<Vec<u8>>> {
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip =
+                ZipFileReader::with_tokio(BufReader::new(File::open(archive).await?)).await?;
+            let Some(index) = zip
+                .file()
+                .entries()
+                .iter()
+                .position(|entry| entry.filename().as_str().ok() == Some(BACKUP_FILE))
+            else {
+                return Ok(None);
+            };
+            let mut data = vec![];
+            zip.reader_with_entry(index)
+                .await?
+                .read_to_end_checked(&mut data)
+                .await?;
+            Ok(Some(data))
+        }
+        ArchiveFormat::TarGz => {
+            let archive = archive.to_path_buf();
+            tokio::task::spawn_blocking(move || {
+                let mut tar = tar::Archive::new(GzDecoder::new(std::fs::File::open(archive)?));
+                for entry in tar.entries()? {
+                    let mut entry = entry?;
+                    if entry.path()?.as_os_str() == BACKUP_FILE {
+                        let mut data = vec![];
+                        io::Read::read_to_end(&mut entry, &mut data)?;
+                        return Ok(Some(data));
+                    }
+                }
+                Ok(None)
+            })
+            .await?
+        }
+    }
+}
+
+/// Writes the uploaded files of an archive into the directory, except those which exist already.
+/// Returns how many are written.
+async fn extract_uploads(archive: &Path, format: ArchiveFormat, dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip =
+                ZipFileReader::with_tokio(BufReader::new(File::open(archive).await?)).await?;
+            let names: Vec<Option<String>> = zip
+                .file()
+                .entries()
+                .iter()
+                .map(|entry| entry.filename().as_str().ok().map(String::from))
+                .collect();
+
+            for (index, name) in names.iter().enumerate() {
+                let Some(target) = name.as_deref().and_then(|name| upload_target(dir, name)) else {
+                    continue;
+                };
+                if fs::try_exists(&target).await? {
+                    continue;
+                }
+                let temp = temp_path(&target);
+                let mut reader = zip.reader_without_entry(index).await?.compat();
+                let mut file = File::create(&temp).await?;
+                tokio::io::copy(&mut reader, &mut file)
+                    .await
+                    .with_context(|| format!("Cannot extract {:?}", target))?;
+                fs::rename(&temp, &target).await?;
+                count += 1;
+            }
+        }
+        ArchiveFormat::TarGz => {
+            let (archive, dir) = (archive.to_path_buf(), dir.to_path_buf());
+            count = tokio::task::spawn_blocking(move || -> Result<usize> {
+                let mut count = 0;
+                let mut tar = tar::Archive::new(GzDecoder::new(std::fs::File::open(archive)?));
+                for entry in tar.entries()? {
+                    let mut entry = entry?;
+                    let name = entry.path()?.to_string_lossy().into_owned();
+                    let Some(target) = upload_target(&dir, &name) else {
+                        continue;
+                    };
+                    if target.try_exists()? {
+                        continue;
+                    }
+                    let temp = temp_path(&target);
+                    io::copy(&mut entry, &mut std::fs::File::create(&temp)?)
+                        .with_context(|| format!("Cannot extract {:?}", target))?;
+                    std::fs::rename(&temp, &target)?;
+                    count += 1;
+                }
+                Ok(count)
+            })
+            .await??;
+        }
+    }
+    Ok(count)
+}
+
+/// The path to extract an entry to, if it is an uploaded file.
+/// Names which could be written out of the directory, or hidden files, are ignored.
+fn upload_target(dir: &Path, name: &str) -> Option<PathBuf> {
+    name.strip_prefix(UPLOADS_DIR)
+        .filter(|name| !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']))
+        .map(|name| dir.join(name))
+}
+
+/// Files are extracted to a temporary path first, so that a broken import leaves no partial file
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.importing", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DBConfig;
+    use crate::config::UploadConfig;
+    use crate::model::file::{FileUpload, StoredFile};
+    use crate::model::post::CreatePostRequest;
+    use futures::TryStreamExt;
+
+    async fn memory_db() -> DB {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    #[test]
+    fn test_upload_target() {
+        let dir = Path::new("/uploads");
+        assert_eq!(
+            upload_target(dir, "uploads/a.jpg"),
+            Some(PathBuf::from("/uploads/a.jpg"))
+        );
+        assert_eq!(upload_target(dir, "uploads/../a.jpg"), None);
+        assert_eq!(upload_target(dir, "uploads/.env"), None);
+        assert_eq!(upload_target(dir, "backup.json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let root = std::env::temp_dir().join("mote-backup-test");
+        let _ = fs::remove_dir_all(&root).await;
+        let (source_dir, target_dir) = (root.join("source"), root.join("target"));
+        fs::create_dir_all(&source_dir).await.unwrap();
+        fs::create_dir_all(&target_dir).await.unwrap();
+        fs::write(source_dir.join("a.txt"), "hello").await.unwrap();
+
+        let source = memory_db().await;
+        let parent = Post::create(
+            &source,
+            ADMIN_USER_ID,
+            &CreatePostRequest {
+                content: r#"<p><span class="hash-tag">#tag</span></p>"#.to_string(),
+                files: Some(vec![FileInfo {
+                    url: "a.txt".to_string(),
+                    ..Default::default()
+                }]),
+                color: None,
+                shared: None,
+                parent_id: None,
+                created_at: None,
+                title: None,
+            },
+        )
+        .await
+        .unwrap()
+        .id;
+        Post::create(
+            &source,
+            ADMIN_USER_ID,
+            &CreatePostRequest {
+                content: format!(r#"<h1>Child</h1><a href="/p/{}">parent</a>"#, parent),
+                files: None,
+                color: None,
+                shared: None,
+                parent_id: Some(parent),
+                created_at: None,
+                title: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let upload_service = FileUploadService::new(UploadConfig {
+            base_path: source_dir.to_string_lossy().into_owned(),
+            base_url: "/uploads".to_string(),
+            thumb_width: 0,
+            animated_thumb: false,
+            thumb_max_frames: 0,
+            image_formats: vec![],
+            exif_location: false,
+            variant_widths: vec![],
+            variant_format: String::new(),
+            heic_converter: String::new(),
+            heic_format: String::new(),
+            quota: 0,
+            user_quota: 0,
+            chunk_path: String::new(),
+            chunk_size: 0,
+            max_file_size: 0,
+        });
+
+        // The same content uploaded again keeps the name of each upload
+        let first = FileUpload::create(&source, "hash", ADMIN_USER_ID, "first.txt").await;
+        assert!(first.is_err());
+        StoredFile::save(
+            &source,
+            ADMIN_USER_ID,
+            "hash",
+            "a.txt",
+            "first.txt",
+            None,
+            5,
+        )
+        .await
+        .unwrap();
+        let first = FileUpload::create(&source, "hash", ADMIN_USER_ID, "first.txt")
+            .await
+            .unwrap();
+        let file = StoredFile::save(
+            &source,
+            ADMIN_USER_ID,
+            "hash",
+            "a.txt",
+            "second.txt",
+            None,
+            5,
+        )
+        .await
+        .unwrap();
+        assert_eq!(file.original_name, "first.txt");
+        let second = FileUpload::create(&source, "hash", ADMIN_USER_ID, "second.txt")
+            .await
+            .unwrap();
+        let backup = read_backup(&source.pool).await.unwrap();
+
+        for format in [ArchiveFormat::Zip, ArchiveFormat::TarGz] {
+            let chunks: Vec<Bytes> = export_stream(&upload_service, &backup, format)
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            let archive = root.join(format!("backup.{}", format.extension()));
+            fs::write(&archive, chunks.concat()).await.unwrap();
+            let _ = fs::remove_file(target_dir.join("a.txt")).await;
+
+            let target = memory_db().await;
+            let result = import(&target, &target_dir, &archive).await.unwrap();
+            assert_eq!(
+                result,
+                ImportResult {
+                    posts: 2,
+                    tags: 1,
+                    files: 1
+                }
+            );
+            assert_eq!(
+                fs::read_to_string(target_dir.join("a.txt")).await.unwrap(),
+                "hello"
+            );
+
+            let restored = read_backup(&target.pool).await.unwrap();
+            assert_eq!(restored.posts[1].parent_id, Some(parent));
+            assert_eq!(restored.posts[1].title.as_deref(), Some("Child"));
+            assert_eq!(restored.tag_post_assoc.len(), 1);
+            for (upload, name) in [(&first, "first.txt"), (&second, "second.txt")] {
+                let found = FileUpload::find_by_id(&target.pool, &upload.id)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(found.original_name, name);
+            }
+            assert_eq!(
+                Post::find_referrers(&target.pool, parent)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+
+        let invalid = root.join("invalid.zip");
+        fs::write(&invalid, "not an archive").await.unwrap();
+        assert!(matches!(
+            import(&memory_db().await, &target_dir, &invalid).await,
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+}
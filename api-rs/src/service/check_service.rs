@@ -0,0 +1,204 @@
+use crate::config::db::DB;
+use crate::config::rd::RD;
+use crate::config::{AppConfig, ConfigIssues, DBConfig, ReplicaConfig};
+use crate::service::asset_service::{template_env, Assets};
+use crate::service::replica_service::replica_store_from_config;
+use crate::service::search_service::Tokenizer;
+use anyhow::{anyhow, Context, Result};
+use jieba_rs::Jieba;
+use minijinja::context;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use uuid::Uuid;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct CheckResult {
+    pub name: &'static str,
+    pub error: Option<String>,
+    /// Problems which do not fail the check, e.g. of optional features
+    pub warnings: Vec<String>,
+    pub elapsed: Duration,
+}
+
+/// Checks that the app can start and serve requests with the current environment,
+/// without changing any data. Used by `mote check`.
+pub async fn run_self_test() -> Vec<CheckResult> {
+    let config = AppConfig::from_env_unchecked();
+    let ConfigIssues { errors, warnings } = config.check();
+
+    let mut config_result = run("config", async { check_config(errors) }).await;
+    config_result.warnings = warnings;
+
+    vec![
+        config_result,
+        run("database", check_migrations(&config.db)).await,
+        run("redis", check_redis(&config.redis.url)).await,
+        run("replica", check_replica(&config.replica)).await,
+        run("uploads", async { check_uploads(&config.upload.base_path) }).await,
+        run("tokenizer", async { check_tokenizer() }).await,
+        run("templates", async {
+            check_templates(&config.static_path, &config.static_url)
+        })
+        .await,
+    ]
+}
+
+/// Prints a readable report, returns false if any check failed.
+pub fn print_report(results: &[CheckResult]) -> bool {
+    for result in results {
+        match result.error {
+            None => println!("[ OK ] {} ({} ms)", result.name, result.elapsed.as_millis()),
+            Some(ref error) => println!("[FAIL] {}: {}", result.name, error),
+        }
+        for warning in &result.warnings {
+            println!("[WARN] {}: {}", result.name, warning);
+        }
+    }
+
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    if failed == 0 {
+        println!("All {} checks passed", results.len());
+    } else {
+        println!("{} of {} checks failed", failed, results.len());
+    }
+    failed == 0
+}
+
+async fn run(name: &'static str, check: impl Future<Output = Result<()>>) -> CheckResult {
+    let start = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{:#}", e)),
+        Err(_) => Some(format!("timed out after {:?}", CHECK_TIMEOUT)),
+    };
+
+    CheckResult {
+        name,
+        error,
+        warnings: vec![],
+        elapsed: start.elapsed(),
+    }
+}
+
+fn check_config(errors: Vec<String>) -> Result<()> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(errors.join("; ")))
+    }
+}
+
+/// Runs the pending migrations against a copy of the database.
+async fn check_migrations(config: &DBConfig) -> Result<()> {
+    let db = DB::new(config).await.context("Cannot open database")?;
+    let copy = env::temp_dir().join(format!("mote-check-{}.db", Uuid::new_v4()));
+
+    let rv = async {
+        sqlx::query("VACUUM INTO ?")
+            .bind(copy.to_string_lossy())
+            .execute(&db.pool)
+            .await
+            .context("Cannot copy database")?;
+
+        // The copy is encrypted with the same key
+        let copy_db = DB::new(&DBConfig {
+            url: format!("sqlite://{}", copy.display()),
+            ..config.clone()
+        })
+        .await?;
+        let rv = copy_db.migrate().await.context("Migration failed");
+        copy_db.pool.close().await;
+        copy_db.writer.close().await;
+        rv
+    }
+    .await;
+
+    db.pool.close().await;
+    db.writer.close().await;
+    for suffix in ["", "-wal", "-shm"] {
+        fs::remove_file(format!("{}{}", copy.display(), suffix)).ok();
+    }
+    rv
+}
+
+async fn check_redis(url: &str) -> Result<()> {
+    let rd = RD::new(url).await.context("Cannot connect to redis")?;
+    let key = format!("self-test:{}", Uuid::new_v4());
+    rd.set(&key, "ok", Some(10)).await?;
+    let value: Option<String> = rd.get(&key).await?;
+    rd.del(&key).await?;
+
+    match value.as_deref() {
+        Some("ok") => Ok(()),
+        _ => Err(anyhow!("Unexpected value read back: {:?}", value)),
+    }
+}
+
+/// Lists the replica, which checks the credentials without writing anything
+async fn check_replica(config: &ReplicaConfig) -> Result<()> {
+    match replica_store_from_config(config) {
+        Some(store) => store
+            .list("")
+            .await
+            .map(|_| ())
+            .context("Cannot list the replica"),
+        None => Ok(()),
+    }
+}
+
+fn check_uploads(base_path: &str) -> Result<()> {
+    fs::create_dir_all(base_path).context("Cannot create upload directory")?;
+
+    let path = Path::new(base_path).join(format!(".self-test-{}", Uuid::new_v4()));
+    fs::write(&path, b"ok").context("Upload directory is not writable")?;
+    let content = fs::read(&path);
+    fs::remove_file(&path).ok();
+
+    if content? == b"ok" {
+        Ok(())
+    } else {
+        Err(anyhow!("Unexpected content read back"))
+    }
+}
+
+fn check_tokenizer() -> Result<()> {
+    let tokens = Jieba::new().analyze("Hello, 全文搜索!");
+    if tokens.contains(&"hello".to_string()) && tokens.contains(&"搜索".to_string()) {
+        Ok(())
+    } else {
+        Err(anyhow!("Unexpected tokens: {:?}", tokens))
+    }
+}
+
+fn check_templates(static_path: &str, static_url: &str) -> Result<()> {
+    let assets = Assets::load(static_path, static_url).context("Cannot hash static files")?;
+    let env = template_env(Arc::new(assets));
+
+    env.get_template("post-list.html")?.render(context! {
+        about_url => "",
+        posts => vec![context! { id => 1, title => "Title", description => "Description", created_at => "2024-01-01" }],
+    })?;
+    let images = vec![context! { url => "/uploads/a.png", width => 1, height => 1 }];
+    let options = context! { hide_date => false, hide_tags => false, accent_color => "#3b82f6" };
+    env.get_template("post-item.html")?.render(context! {
+        about_url => "",
+        post => context! { id => 1, content => "<p>Content</p>" },
+        title => "Title",
+        content => "<p>Content</p>",
+        date => "2024-01-01",
+        options => options.clone(),
+        images => images.clone(),
+    })?;
+    env.get_template("post-embed.html")?.render(context! {
+        post => context! { id => 1 },
+        content => "<p>Content</p>",
+        date => "2024-01-01",
+        options,
+        images,
+    })?;
+    Ok(())
+}
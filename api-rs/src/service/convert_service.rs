@@ -0,0 +1,74 @@
+use crate::config::UploadConfig;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::process::Command;
+
+/// Converts images that browsers cannot display, such as HEIC photos taken by iPhones.
+/// The output format is decided by the extension of `dest`.
+pub trait ImageConverter: Send + Sync {
+    fn convert<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Creates the HEIC converter selected by the config, or `None` if conversion is disabled.
+pub fn heic_converter_from_config(config: &UploadConfig) -> Option<Arc<dyn ImageConverter>> {
+    if config.heic_converter.is_empty() {
+        None
+    } else {
+        Some(Arc::new(CommandConverter {
+            command: config.heic_converter.clone(),
+        }))
+    }
+}
+
+/// Runs an external converter as `command <src> <dest>`,
+/// e.g. `heif-convert` of libheif or `magick` of ImageMagick.
+pub struct CommandConverter {
+    command: String,
+}
+
+impl ImageConverter for CommandConverter {
+    fn convert<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let output = Command::new(&self.command)
+                .arg(src)
+                .arg(dest)
+                .output()
+                .await
+                .with_context(|| format!("Cannot run {}", self.command))?;
+
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "{} failed: {}",
+                    self.command,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Checks the content type, or the extension since browsers may not recognize HEIC files.
+pub fn is_heic(content_type: &str, filename: &str) -> bool {
+    let content_type = content_type.to_lowercase();
+    let filename = filename.to_lowercase();
+    content_type.starts_with("image/heic")
+        || content_type.starts_with("image/heif")
+        || filename.ends_with(".heic")
+        || filename.ends_with(".heif")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_heic() {
+        assert!(is_heic("image/heic", "IMG_0001.HEIC"));
+        assert!(is_heic("image/heif-sequence", "photo"));
+        assert!(is_heic("application/octet-stream", "IMG_0001.HEIC"));
+        assert!(!is_heic("image/jpeg", "IMG_0001.jpg"));
+    }
+}
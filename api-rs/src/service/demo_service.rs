@@ -0,0 +1,365 @@
+use crate::model::post::{CategoryColor, CreatePostRequest, FileInfo, Post};
+use crate::model::user::ADMIN_USER_ID;
+use crate::service::stats_service;
+use crate::AppState;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+use tokio::{fs, task};
+use tracing::info;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+const SENTENCES: &[&str] = &[
+    "Finished the first draft of the design doc, the storage part still needs work.",
+    "Read two chapters before bed, the author explains consensus better than the paper.",
+    "Tried the new coffee place near the station, good beans but too loud.",
+    "Remember to renew the passport before the trip in spring.",
+    "A small idea: keep a list of questions instead of answers.",
+    "Refactored the parser, it is shorter and the error messages are clearer.",
+    "Walked along the river after work, the cherry trees are about to bloom.",
+    "The meeting could have been an email, again.",
+    "今天把书架整理了一遍，发现好几本还没读完的书。",
+    "周末去爬山，山顶的风很大，但是视野很好。",
+    "读完了《百年孤独》，结尾比想象中还要安静。",
+    "学习一门新的语言，最难的是坚持每天练习。",
+    "晚饭做了番茄炒蛋，简单但是很好吃。",
+    "项目的第一个版本终于上线了，接下来要处理用户反馈。",
+    "下雨天适合在家写代码，顺便听听音乐。",
+    "想法：每周写一篇总结，记录做了什么和学到了什么。",
+];
+
+// Words to generate more varied text, so that search behaves like on real data
+const WORDS: &[&str] = &[
+    "storage", "index", "query", "cache", "latency", "deploy", "review", "release", "budget",
+    "garden", "weekend", "coffee", "train", "morning", "music", "window", "recipe", "bread",
+    "novel", "chapter", "essay", "notebook", "habit", "plan", "draft", "idea", "question",
+    "mountain", "river", "city", "market", "museum", "ticket", "photo", "letter", "friend",
+    "quiet", "early", "slow", "bright", "simple", "careful", "small", "useful", "strange", "write",
+    "read", "fix", "measure", "cook", "visit", "forget", "finish", "start", "try",
+];
+
+const PHRASES: &[&str] = &[
+    "数据库",
+    "索引",
+    "缓存",
+    "周末",
+    "咖啡",
+    "早晨",
+    "音乐",
+    "窗外",
+    "面包",
+    "小说",
+    "笔记",
+    "习惯",
+    "计划",
+    "草稿",
+    "问题",
+    "山顶",
+    "河边",
+    "城市",
+    "市场",
+    "博物馆",
+    "照片",
+    "朋友",
+    "安静",
+    "简单",
+    "认真",
+    "有用",
+    "奇怪",
+    "写作",
+    "阅读",
+    "修复",
+    "做饭",
+    "拜访",
+    "完成",
+];
+
+const TAGS: &[&str] = &[
+    "reading",
+    "work",
+    "work/design",
+    "work/meeting",
+    "ideas",
+    "travel",
+    "food",
+    "code",
+    "code/rust",
+    "读书",
+    "生活",
+    "旅行",
+    "学习",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct SeedOptions {
+    pub posts: usize,
+    pub tags: usize,
+    pub images: usize,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        SeedOptions {
+            posts: 1000,
+            tags: 50,
+            images: 20,
+        }
+    }
+}
+
+impl SeedOptions {
+    /// Parses options like `--posts 10000 --tags 200 --images 50`.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = SeedOptions::default();
+        let mut args = args.iter();
+
+        while let Some(name) = args.next() {
+            let value = args
+                .next()
+                .ok_or(format!("Missing value of {}", name))?
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid value of {}", name))?;
+
+            match name.as_str() {
+                "--posts" => options.posts = value,
+                "--tags" => options.tags = value,
+                "--images" => options.images = value,
+                _ => return Err(format!("Unknown option {}", name)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// A small data set, which makes the demo look lived-in
+    pub fn demo() -> Self {
+        SeedOptions {
+            posts: 60,
+            tags: TAGS.len(),
+            images: 6,
+        }
+    }
+}
+
+/// Generates sample posts with tags and images, and indexes them for search.
+/// Posts are spread over the past year, some of them are replies to earlier ones.
+pub async fn seed(state: &AppState, options: &SeedOptions) -> Result<()> {
+    let mut rng = StdRng::from_entropy();
+    let tags = tag_names(options.tags);
+    let images = generate_images(state, options.images).await?;
+
+    let now = Utc::now().timestamp_millis();
+    let mut ids: Vec<i64> = Vec::with_capacity(options.posts);
+
+    for i in 0..options.posts {
+        // Older posts first, so that replies are newer than their parents
+        let days_ago = (options.posts - i) as i64 * 365 / options.posts.max(1) as i64;
+        let created_at = now - days_ago * DAY_MS - rng.gen_range(0..DAY_MS);
+
+        let tag_count = rng.gen_range(0..=2);
+        let post_tags: Vec<&str> = tags
+            .choose_multiple(&mut rng, tag_count)
+            .map(String::as_str)
+            .collect();
+        let content = generate_content(&mut rng, &post_tags);
+
+        let image_count = rng.gen_range(1..=3);
+        let files = (!images.is_empty() && rng.gen_bool(0.15)).then(|| {
+            images
+                .choose_multiple(&mut rng, image_count)
+                .cloned()
+                .collect()
+        });
+        let color = match rng.gen_range(0..10) {
+            0 => Some(CategoryColor::Red),
+            1 => Some(CategoryColor::Blue),
+            2 => Some(CategoryColor::Green),
+            _ => None,
+        };
+        let parent_id =
+            (!ids.is_empty() && rng.gen_bool(0.1)).then(|| *ids.choose(&mut rng).unwrap());
+
+        let post = CreatePostRequest {
+            content,
+            files,
+            color,
+            shared: Some(rng.gen_bool(0.1)),
+            parent_id,
+            created_at: Some(created_at),
+            title: None,
+        };
+
+        // The fake posts belong to the admin
+        let rv = Post::create(&state.db, ADMIN_USER_ID, &post).await?;
+        state.fts.index(rv.id, &post.content).await?;
+        ids.push(rv.id);
+
+        if ids.len().is_multiple_of(1000) {
+            info!("Seeded {} of {} posts", ids.len(), options.posts);
+        }
+    }
+
+    info!("Seeded {} posts and {} images", ids.len(), images.len());
+    Ok(())
+}
+
+/// Removes all posts, tags and uploaded files, then seeds the demo data again.
+pub async fn reset(state: &AppState) -> Result<()> {
+    let mut tx = state.db.writer.begin().await?;
+    for table in [
+        "post_reactions",
+        "tag_post_assoc",
+        "tag_renames",
+        "post_links",
+        "posts",
+        "tags",
+        "files",
+    ] {
+        sqlx::query(&format!("DELETE FROM {}", table))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    state.fts.clear_all_indexes().await?;
+
+    let upload_path = &state.config.upload.base_path;
+    if Path::new(upload_path).exists() {
+        fs::remove_dir_all(upload_path)
+            .await
+            .context("Cannot clear upload directory")?;
+    }
+    fs::create_dir_all(upload_path).await?;
+
+    seed(state, &SeedOptions::demo()).await?;
+    stats_service::invalidate(&state.rd, ADMIN_USER_ID).await;
+    Ok(())
+}
+
+/// Returns `count` tag names, the built-in ones first.
+fn tag_names(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| match TAGS.get(i) {
+            Some(tag) => tag.to_string(),
+            None => format!("topic-{}", i - TAGS.len() + 1),
+        })
+        .collect()
+}
+
+/// Generates a few paragraphs in the format of the editor, with the tags in the last one.
+fn generate_content(rng: &mut impl Rng, tags: &[&str]) -> String {
+    let mut paragraphs: Vec<String> = (0..rng.gen_range(1..=3))
+        .map(|_| format!("<p>{}</p>", generate_sentence(rng)))
+        .collect();
+
+    if !tags.is_empty() {
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|tag| format!(r#"<span class="hash-tag">#{}</span>"#, tag))
+            .collect();
+        paragraphs.push(format!("<p>{}</p>", tags.join(" ")));
+    }
+    paragraphs.concat()
+}
+
+/// Picks one of the sample sentences, or strings random English or Chinese words together.
+fn generate_sentence(rng: &mut impl Rng) -> String {
+    match rng.gen_range(0..3) {
+        0 => SENTENCES.choose(rng).unwrap().to_string(),
+        1 => {
+            let words: Vec<&str> = (0..rng.gen_range(5..20))
+                .map(|_| *WORDS.choose(rng).unwrap())
+                .collect();
+            let mut sentence = words.join(" ");
+            sentence[..1].make_ascii_uppercase();
+            sentence + "."
+        }
+        _ => {
+            let phrases: Vec<&str> = (0..rng.gen_range(4..12))
+                .map(|_| *PHRASES.choose(rng).unwrap())
+                .collect();
+            phrases.concat() + "。"
+        }
+    }
+}
+
+/// Draws gradient images into the upload directory.
+async fn generate_images(state: &AppState, count: usize) -> Result<Vec<FileInfo>> {
+    let config = &state.config.upload;
+    fs::create_dir_all(&config.base_path).await?;
+
+    let mut images = Vec::with_capacity(count);
+    for i in 0..count {
+        let filename = format!("demo-{}.png", i + 1);
+        let path = Path::new(&config.base_path).join(&filename);
+        let (width, height) = (640, 480);
+
+        let (from, to) = (random_color(), random_color());
+        let draw_path = path.clone();
+        task::spawn_blocking(move || {
+            RgbImage::from_fn(width, height, |x, y| {
+                let t = (x + y) as f32 / (width + height) as f32;
+                Rgb([0, 1, 2].map(|c| (from[c] as f32 * (1.0 - t) + to[c] as f32 * t) as u8))
+            })
+            .save_with_format(draw_path, ImageFormat::Png)
+        })
+        .await??;
+
+        images.push(FileInfo {
+            url: filename.clone(),
+            original_name: Some(filename.clone()),
+            content_type: Some("image/png".to_string()),
+            thumb_url: Some(filename),
+            size: Some(fs::metadata(&path).await?.len()),
+            width: Some(width),
+            height: Some(height),
+            ..Default::default()
+        });
+    }
+    Ok(images)
+}
+
+fn random_color() -> [u8; 3] {
+    rand::thread_rng().gen()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_content() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let content = generate_content(&mut rng, &["work/design", "读书"]);
+        assert!(content.starts_with("<p>"));
+        assert!(content.ends_with(
+            r##"<p><span class="hash-tag">#work/design</span> <span class="hash-tag">#读书</span></p>"##
+        ));
+
+        assert_eq!(tag_names(2), vec!["reading", "work"]);
+        assert_eq!(tag_names(TAGS.len() + 1).last().unwrap(), "topic-1");
+    }
+
+    #[test]
+    fn test_seed_options_from_args() {
+        let args: Vec<String> = ["--posts", "10000", "--tags", "200"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            SeedOptions::from_args(&args).unwrap(),
+            SeedOptions {
+                posts: 10000,
+                tags: 200,
+                images: 20,
+            }
+        );
+
+        assert!(SeedOptions::from_args(&["--posts".to_string()]).is_err());
+        assert!(SeedOptions::from_args(&["--users".to_string(), "1".to_string()]).is_err());
+    }
+}
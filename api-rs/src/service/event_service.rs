@@ -0,0 +1,390 @@
+use crate::config::db::DB;
+use crate::model::audit::AuditLog;
+use crate::model::file::{FileUpload, StoredFile};
+use crate::model::post::{FileInfo, Post};
+use crate::service::realtime_service::{PostChange, RealtimeEvent};
+use crate::service::search_service::index_post;
+use crate::service::upload_service::FileUploadService;
+use crate::AppState;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::RwLock;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+/// Changes of posts, tags and files, emitted by the services once they are committed.
+/// The side effects, such as indexing and auditing, are done by the consumers.
+/// `user_id` is the owner of the post or the tag.
+#[derive(Debug, Clone)]
+pub enum AppEvent {
+    PostCreated {
+        id: i64,
+        user_id: i64,
+    },
+    /// `reindex` is set if the content or the files are changed
+    PostUpdated {
+        id: i64,
+        user_id: i64,
+        reindex: bool,
+    },
+    /// The post is moved to the trash
+    PostDeleted {
+        id: i64,
+        user_id: i64,
+    },
+    PostRestored {
+        id: i64,
+        user_id: i64,
+    },
+    /// The post is deleted permanently
+    PostCleared {
+        id: i64,
+        user_id: i64,
+    },
+    /// A file is removed from a post, it may still be attached to other posts
+    FileDetached {
+        post_id: i64,
+        user_id: i64,
+        file: Box<FileInfo>,
+    },
+    TagRenamed {
+        user_id: i64,
+        name: String,
+        new_name: String,
+    },
+    TagDeleted {
+        user_id: i64,
+        name: String,
+    },
+}
+
+lazy_static! {
+    // Each consumer has an unbounded queue, since a dropped event would leave the index
+    // or the upload directory out of date, e.g. when all posts are cleared at once
+    static ref CONSUMERS: RwLock<Vec<mpsc::UnboundedSender<AppEvent>>> = RwLock::new(vec![]);
+}
+
+/// Sends an event to the consumers, it is dropped if none is started.
+pub fn emit(event: AppEvent) {
+    debug!(?event, "app event");
+    let mut closed = false;
+    for consumer in CONSUMERS.read().unwrap().iter() {
+        closed |= consumer.send(event.clone()).is_err();
+    }
+    if closed {
+        CONSUMERS
+            .write()
+            .unwrap()
+            .retain(|consumer| !consumer.is_closed());
+    }
+}
+
+/// Receives the events emitted from now on, none of them is dropped.
+pub fn subscribe() -> mpsc::UnboundedReceiver<AppEvent> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    CONSUMERS.write().unwrap().push(sender);
+    receiver
+}
+
+/// Starts the consumers of the events, each with its own queue, so that a slow one does not hold up the others.
+/// It should be called before requests are served, the events emitted before are missed.
+pub fn start_consumers(state: &AppState) {
+    consume("search index", state, update_index);
+    consume("audit log", state, log_activity);
+    consume("realtime", state, broadcast_change);
+    consume("file cleanup", state, discard_detached_file);
+}
+
+fn consume<F, Fut>(name: &'static str, state: &AppState, handler: F)
+where
+    F: Fn(AppState, AppEvent) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    let mut receiver = subscribe();
+    let state = state.clone();
+
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            if let Err(e) = handler(state.clone(), event).await {
+                error!("The {} consumer failed: {:?}", name, e);
+            }
+        }
+    });
+}
+
+async fn update_index(state: AppState, event: AppEvent) -> Result<()> {
+    match event {
+        AppEvent::PostCreated { id, .. }
+        | AppEvent::PostUpdated {
+            id, reindex: true, ..
+        }
+        | AppEvent::FileDetached { post_id: id, .. } => {
+            // A post cleared in the meantime needs no index
+            if let Some(row) = Post::find_by_id(&state.db, id).await? {
+                index_post(&state, &row).await?;
+            }
+        }
+        AppEvent::PostCleared { id, .. } => state.fts.deindex(id).await?,
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn log_activity(state: AppState, event: AppEvent) -> Result<()> {
+    let (user_id, action, target, detail) = match event {
+        AppEvent::PostCreated { id, user_id } => (user_id, "post.create", id.to_string(), None),
+        AppEvent::PostUpdated { id, user_id, .. } => (user_id, "post.update", id.to_string(), None),
+        AppEvent::PostDeleted { id, user_id } => (user_id, "post.delete", id.to_string(), None),
+        AppEvent::PostRestored { id, user_id } => (user_id, "post.restore", id.to_string(), None),
+        AppEvent::PostCleared { id, user_id } => (user_id, "post.clear", id.to_string(), None),
+        AppEvent::TagRenamed {
+            user_id,
+            name,
+            new_name,
+        } => (user_id, "tag.rename", name, Some(new_name)),
+        AppEvent::TagDeleted { user_id, name } => (user_id, "tag.delete", name, None),
+        AppEvent::FileDetached { .. } => return Ok(()),
+    };
+    AuditLog::log(&state.db, Some(user_id), action, &target, detail.as_deref()).await;
+    Ok(())
+}
+
+/// Tells the clients of the owner on other devices to refresh the changed posts.
+async fn broadcast_change(state: AppState, event: AppEvent) -> Result<()> {
+    let (id, user_id, change) = match event {
+        AppEvent::PostCreated { id, user_id } => (id, user_id, PostChange::Created),
+        AppEvent::PostUpdated { id, user_id, .. }
+        | AppEvent::FileDetached {
+            post_id: id,
+            user_id,
+            ..
+        } => (id, user_id, PostChange::Updated),
+        AppEvent::PostDeleted { id, user_id } => (id, user_id, PostChange::Deleted),
+        AppEvent::PostRestored { id, user_id } => (id, user_id, PostChange::Restored),
+        AppEvent::PostCleared { id, user_id } => (id, user_id, PostChange::Cleared),
+        AppEvent::TagRenamed { .. } | AppEvent::TagDeleted { .. } => return Ok(()),
+    };
+    state.realtime.publish(RealtimeEvent::PostChanged {
+        id,
+        user_id,
+        change,
+    });
+    Ok(())
+}
+
+/// Removes a detached file from the upload directory, unless it is still used.
+async fn discard_detached_file(state: AppState, event: AppEvent) -> Result<()> {
+    let AppEvent::FileDetached { user_id, file, .. } = event else {
+        return Ok(());
+    };
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    discard_upload(&state.db, &upload_service, user_id, &file).await?;
+    Ok(())
+}
+
+/// Removes the upload of a user detached from a post, and the stored file once no upload of it is left.
+/// The file is found by its records, the info sent by the client only names it: the upload must be one
+/// of the user, and the file is kept while any post has it attached or links to it.
+/// Returns whether the stored file is removed.
+async fn discard_upload(
+    db: &DB,
+    upload_service: &FileUploadService,
+    user_id: i64,
+    file: &FileInfo,
+) -> Result<bool> {
+    let Some(filename) = upload_service.filename_from_url(&file.url) else {
+        return Ok(false);
+    };
+    let Some(stored) = StoredFile::find_by_filename(db, filename).await? else {
+        return Ok(false);
+    };
+    // The files uploaded before the uploads were recorded have one named by their hash
+    let upload_id = file.upload_id.as_deref().unwrap_or(&stored.id);
+    let uploads = FileUpload::find_by_file(db, &stored.id).await?;
+    if !uploads
+        .iter()
+        .any(|upload| upload.id == upload_id && upload.user_id == user_id)
+    {
+        warn!(
+            "User {} has no upload {} of {}",
+            user_id, upload_id, filename
+        );
+        return Ok(false);
+    }
+    if Post::is_file_attached(db, &stored.filename).await?
+        || Post::is_file_linked(db, &stored.filename).await?
+    {
+        return Ok(false);
+    }
+
+    FileUpload::delete(db, upload_id).await?;
+    if uploads.iter().any(|upload| upload.id != upload_id) {
+        return Ok(false);
+    }
+    // The thumbnails are named after the stored file, not after the info sent
+    let stored_info = upload_service.reuse_stored(file.clone(), &stored.filename);
+    upload_service.discard(&stored_info).await?;
+    StoredFile::delete(db, &stored.id).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DBConfig, UploadConfig};
+    use crate::model::post::CreatePostRequest;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_emit() {
+        let mut first = subscribe();
+        let mut second = subscribe();
+
+        // More events than a bounded queue would hold, none of them is dropped
+        for id in 0..5000 {
+            emit(AppEvent::PostCreated {
+                id: -id,
+                user_id: -1,
+            });
+        }
+        // Other tests may emit events too
+        let count = |receiver: &mut mpsc::UnboundedReceiver<AppEvent>| {
+            let mut count = 0;
+            while let Ok(event) = receiver.try_recv() {
+                if matches!(event, AppEvent::PostCreated { user_id: -1, .. }) {
+                    count += 1;
+                }
+            }
+            count
+        };
+        assert_eq!(count(&mut first), 5000);
+        assert_eq!(count(&mut second), 5000);
+
+        // A dropped receiver is no longer sent to
+        drop(first);
+        emit(AppEvent::PostCreated { id: 0, user_id: -1 });
+        assert_eq!(count(&mut second), 1);
+    }
+
+    async fn memory_db() -> DB {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_discard_upload() {
+        let db = memory_db().await;
+        let mut config = UploadConfig::from_env();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-discard-{}", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::create_dir_all(&config.base_path).unwrap();
+        let base_path = config.base_path.clone();
+        let upload_service = FileUploadService::new(config);
+
+        // A file of its own for each case, uploaded by user 1
+        let upload = |filename: &'static str| {
+            let db = &db;
+            let base_path = &base_path;
+            async move {
+                std::fs::write(Path::new(base_path).join(filename), filename).unwrap();
+                StoredFile::save(db, 1, filename, filename, filename, None, 1)
+                    .await
+                    .unwrap();
+                let upload = FileUpload::create(db, filename, 1, filename).await.unwrap();
+                FileInfo {
+                    url: filename.to_string(),
+                    upload_id: Some(upload.id),
+                    ..Default::default()
+                }
+            }
+        };
+        let create_post = |content: &str, files: Option<Vec<FileInfo>>| CreatePostRequest {
+            content: content.to_string(),
+            title: None,
+            files,
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: None,
+        };
+        let exists = |filename: &str| Path::new(&base_path).join(filename).exists();
+
+        // Another user cannot remove the file, even with its upload id
+        let file = upload("a.txt").await;
+        assert!(!discard_upload(&db, &upload_service, 2, &file)
+            .await
+            .unwrap());
+        assert!(exists("a.txt"));
+        assert!(discard_upload(&db, &upload_service, 1, &file)
+            .await
+            .unwrap());
+        assert!(!exists("a.txt"));
+        assert!(StoredFile::find_by_id(&db.pool, "a.txt")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Nor by naming it with an upload of theirs of another file
+        let file = upload("b.txt").await;
+        std::fs::write(Path::new(&base_path).join("x.txt"), "x").unwrap();
+        StoredFile::save(&db, 2, "x.txt", "x.txt", "x.txt", None, 1)
+            .await
+            .unwrap();
+        let theirs = FileUpload::create(&db, "x.txt", 2, "x.txt").await.unwrap();
+        let forged = FileInfo {
+            upload_id: Some(theirs.id),
+            ..file.clone()
+        };
+        assert!(!discard_upload(&db, &upload_service, 2, &forged)
+            .await
+            .unwrap());
+        assert!(exists("b.txt"));
+
+        // A file linked from the content of a post is kept
+        let file = upload("c.png").await;
+        Post::create(&db, 1, &create_post(r#"<img src="/uploads/c.png">"#, None))
+            .await
+            .unwrap();
+        assert!(!discard_upload(&db, &upload_service, 1, &file)
+            .await
+            .unwrap());
+        assert!(exists("c.png"));
+
+        // A file still attached to another post is kept
+        let file = upload("d.txt").await;
+        Post::create(&db, 1, &create_post("<p>d</p>", Some(vec![file.clone()])))
+            .await
+            .unwrap();
+        assert!(!discard_upload(&db, &upload_service, 1, &file)
+            .await
+            .unwrap());
+        assert!(exists("d.txt"));
+
+        // A file uploaded by another user too is kept for them, the upload of the user is removed
+        let file = upload("e.txt").await;
+        let other = FileUpload::create(&db, "e.txt", 2, "e.txt").await.unwrap();
+        assert!(!discard_upload(&db, &upload_service, 1, &file)
+            .await
+            .unwrap());
+        assert!(exists("e.txt"));
+        let uploads = FileUpload::find_by_file(&db.pool, "e.txt").await.unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].id, other.id);
+
+        std::fs::remove_dir_all(&base_path).ok();
+    }
+}
@@ -0,0 +1,360 @@
+use crate::model::backup::ArchiveFormat;
+use crate::model::post::{Post, PostRow};
+use crate::service::upload_service::FileUploadService;
+use crate::service::url_service::UrlResolver;
+use crate::util::html::unescape;
+use anyhow::{Context, Result};
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use futures::stream::{self, Stream, StreamExt};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+use tokio_util::io::{ReaderStream, SyncIoBridge};
+use tracing::warn;
+
+/// The directory of the attachments of the Markdown files
+const ATTACHMENTS_DIR: &str = "attachments/";
+
+lazy_static! {
+    static ref CONTENT_URL: Regex = Regex::new(r#"(?:src|href)="([^"]+)""#).unwrap();
+}
+
+/// A file held in memory, put into an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// A file put into an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    /// The name in the archive
+    pub name: String,
+    pub path: PathBuf,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// Lists the uploaded files attached to the posts, each file once, named after their original names.
+/// Files which are not uploaded to this server are skipped.
+pub fn attachment_entries(
+    upload_service: &FileUploadService,
+    posts: &[PostRow],
+) -> Vec<ArchiveEntry> {
+    let mut attachments = Attachments::new(upload_service, "");
+    for post in posts {
+        for file in post.file_infos() {
+            attachments.add(&file.url, file.original_name.as_deref(), post.created_at);
+        }
+    }
+    attachments.entries
+}
+
+/// The uploaded files put into an archive, each file once, named after their original names
+pub struct Attachments<'a> {
+    upload_service: &'a FileUploadService,
+    /// The directory of the files in the archive, e.g. `attachments/`
+    dir: &'a str,
+    pub entries: Vec<ArchiveEntry>,
+    names: HashSet<String>,
+    /// The names in the archive by the stored filenames
+    by_filename: HashMap<String, String>,
+}
+
+impl<'a> Attachments<'a> {
+    pub fn new(upload_service: &'a FileUploadService, dir: &'a str) -> Self {
+        Self {
+            upload_service,
+            dir,
+            entries: vec![],
+            names: HashSet::new(),
+            by_filename: HashMap::new(),
+        }
+    }
+
+    /// Adds the file of the url, unless it is not uploaded to this server or is added already
+    pub fn add(&mut self, url: &str, original_name: Option<&str>, created_at: i64) {
+        let Some(filename) = self.upload_service.filename_from_url(url) else {
+            return;
+        };
+        if self.by_filename.contains_key(filename) {
+            return;
+        }
+
+        let name = format!(
+            "{}{}",
+            self.dir,
+            unique_name(
+                &mut self.names,
+                &sanitize_name(original_name.unwrap_or(filename))
+            )
+        );
+        self.by_filename.insert(filename.to_string(), name.clone());
+        self.entries.push(ArchiveEntry {
+            name,
+            path: self.upload_service.file_path(filename),
+            modified_at: DateTime::from_timestamp_millis(created_at).unwrap_or_default(),
+        });
+    }
+
+    /// Returns the name of the file of the url in the archive, if it is added
+    pub fn name_of(&self, url: &str) -> Option<&str> {
+        self.upload_service
+            .filename_from_url(url)
+            .and_then(|filename| self.by_filename.get(filename))
+            .map(String::as_str)
+    }
+}
+
+/// The Markdown files of the posts, with the uploaded files they link to under `attachments/`.
+/// The links to the files are relative, the other uploaded files are linked to with their urls.
+pub fn markdown_archive(
+    upload_service: &FileUploadService,
+    urls: &UrlResolver,
+    posts: &[Post],
+) -> (Vec<Document>, Vec<ArchiveEntry>) {
+    let mut attachments = Attachments::new(upload_service, ATTACHMENTS_DIR);
+    for post in posts {
+        for file in post.row.file_infos() {
+            attachments.add(
+                &file.url,
+                file.original_name.as_deref(),
+                post.row.created_at,
+            );
+        }
+        // Images pasted into the content are uploaded as well
+        for caps in CONTENT_URL.captures_iter(&post.row.content) {
+            let url = unescape(&caps[1]);
+            if urls.to_key(&url) != url {
+                attachments.add(&url, None, post.row.created_at);
+            }
+        }
+    }
+
+    let link = |url: &str| match attachments.name_of(url) {
+        Some(name) => name.to_string(),
+        None => urls.resolve(url),
+    };
+    let documents = posts
+        .iter()
+        .map(|post| Document {
+            name: markdown_name(&post.row),
+            data: post.to_markdown(&link).into_bytes(),
+        })
+        .collect();
+    (documents, attachments.entries)
+}
+
+/// The name of the Markdown file of a post, e.g. `2024-12-03-42.md`
+pub fn markdown_name(post: &PostRow) -> String {
+    let date = DateTime::from_timestamp_millis(post.created_at).unwrap_or_default();
+    format!("{}-{}.md", date.format("%Y-%m-%d"), post.id)
+}
+
+/// Streams a zip of the files, while it is written.
+///
+/// The files are stored without compression, since attachments such as photos and PDFs are compressed already.
+/// A file which cannot be opened is skipped, any other error aborts the stream.
+pub fn zip_stream(entries: Vec<ArchiveEntry>) -> impl Stream<Item = io::Result<Bytes>> {
+    archive_stream(ArchiveFormat::Zip, vec![], entries)
+}
+
+/// Like `zip_stream`, but in either format, with documents held in memory put before the files.
+pub fn archive_stream(
+    format: ArchiveFormat,
+    documents: Vec<Document>,
+    entries: Vec<ArchiveEntry>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let task = tokio::spawn(async move {
+        match format {
+            ArchiveFormat::Zip => write_zip(writer, documents, entries).await,
+            ArchiveFormat::TarGz => {
+                // The tar crate writes synchronously
+                let mut writer = SyncIoBridge::new(writer);
+                tokio::task::spawn_blocking(move || {
+                    write_tar_gz(&mut writer, documents, entries)?;
+                    writer.shutdown()?;
+                    Ok(())
+                })
+                .await?
+            }
+        }
+    });
+
+    // The reader ends when the writer is dropped, then the result of writing is checked
+    let result = stream::once(task).filter_map(|rv| async move {
+        match rv {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(io::Error::other(format!("{:#}", e)))),
+            Err(e) => Some(Err(io::Error::other(e))),
+        }
+    });
+    ReaderStream::new(reader).chain(result)
+}
+
+async fn write_zip(
+    writer: DuplexStream,
+    documents: Vec<Document>,
+    entries: Vec<ArchiveEntry>,
+) -> Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    for document in documents {
+        let builder = ZipEntryBuilder::new(document.name.into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&Utc::now()))
+            .unix_permissions(0o644);
+        zip.write_entry_whole(builder, &document.data).await?;
+    }
+
+    for entry in entries {
+        let mut file = match File::open(&entry.path).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Cannot add {:?} to the archive: {}", entry.path, e);
+                continue;
+            }
+        };
+
+        let builder = ZipEntryBuilder::new(entry.name.into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&entry.modified_at))
+            .unix_permissions(0o644);
+        let mut entry_writer = zip.write_entry_stream(builder).await?.compat_write();
+        tokio::io::copy(&mut file, &mut entry_writer)
+            .await
+            .context("Cannot write the archive")?;
+        entry_writer.into_inner().close().await?;
+    }
+
+    zip.close().await?.into_inner().shutdown().await?;
+    Ok(())
+}
+
+fn write_tar_gz(
+    writer: impl Write,
+    documents: Vec<Document>,
+    entries: Vec<ArchiveEntry>,
+) -> Result<()> {
+    let mut tar = tar::Builder::new(GzEncoder::new(writer, flate2::Compression::default()));
+
+    let header = |size: u64, modified_at: DateTime<Utc>| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(modified_at.timestamp().max(0) as u64);
+        header
+    };
+
+    for document in documents {
+        let mut header = header(document.data.len() as u64, Utc::now());
+        tar.append_data(&mut header, &document.name, document.data.as_slice())
+            .context("Cannot write the archive")?;
+    }
+
+    for entry in entries {
+        let file = match std::fs::File::open(&entry.path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Cannot add {:?} to the archive: {}", entry.path, e);
+                continue;
+            }
+        };
+        let mut header = header(file.metadata()?.len(), entry.modified_at);
+        tar.append_data(&mut header, &entry.name, file)
+            .context("Cannot write the archive")?;
+    }
+
+    tar.into_inner()?.finish()?.flush()?;
+    Ok(())
+}
+
+/// Keeps the last component of a path, so that files are not extracted out of the target directory
+fn sanitize_name(name: &str) -> String {
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('.')
+        .trim();
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Appends a number to a name which is taken, e.g. `receipt (2).pdf`
+fn unique_name(names: &mut HashSet<String>, name: &str) -> String {
+    if names.insert(name.to_string()) {
+        return name.to_string();
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    (2..)
+        .map(|n| format!("{} ({}){}", stem, n, ext))
+        .find(|candidate| names.insert(candidate.clone()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_zip::tokio::read::seek::ZipFileReader;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("C:\\photos\\.a.jpg"), "a.jpg");
+        assert_eq!(sanitize_name("/"), "file");
+
+        let mut names = HashSet::new();
+        assert_eq!(unique_name(&mut names, "a.pdf"), "a.pdf");
+        assert_eq!(unique_name(&mut names, "a.pdf"), "a (2).pdf");
+        assert_eq!(unique_name(&mut names, "a.pdf"), "a (3).pdf");
+        assert_eq!(unique_name(&mut names, "README"), "README");
+        assert_eq!(unique_name(&mut names, "README"), "README (2)");
+    }
+
+    #[tokio::test]
+    async fn test_zip_stream() {
+        let dir = std::env::temp_dir().join("mote-export-test");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("a.txt"), "hello").await.unwrap();
+
+        let entry = |name: &str, file: &str| ArchiveEntry {
+            name: name.to_string(),
+            path: dir.join(file),
+            modified_at: Utc::now(),
+        };
+        let entries = vec![entry("a.txt", "a.txt"), entry("missing.txt", "missing.txt")];
+        let chunks: Vec<Bytes> = zip_stream(entries).try_collect().await.unwrap();
+        let bytes = chunks.concat();
+
+        let mut reader = ZipFileReader::with_tokio(io::Cursor::new(bytes))
+            .await
+            .unwrap();
+        assert_eq!(reader.file().entries().len(), 1);
+        let mut text = String::new();
+        reader
+            .reader_with_entry(0)
+            .await
+            .unwrap()
+            .read_to_string_checked(&mut text)
+            .await
+            .unwrap();
+        assert_eq!(text, "hello");
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::task;
+
+/// Upper bound of the text taken from a single attachment, in chars
+const MAX_TEXT_LEN: usize = 200_000;
+
+/// Extensions of attachments that are indexed as plain text
+const TEXT_EXTENSIONS: [&str; 6] = ["txt", "md", "markdown", "csv", "log", "json"];
+
+/// Extracts searchable text from an attachment, based on its extension.
+/// Returns `None` if the type of the file is not supported.
+pub async fn extract_text_from_file(path: &Path) -> Result<Option<String>> {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let text = if ext == "pdf" {
+        let path = PathBuf::from(path);
+        // Parsing pdf is CPU bound, so keep it off the async runtime
+        task::spawn_blocking(move || -> Result<String> {
+            let bytes = std::fs::read(&path)?;
+            pdf_extract::extract_text_from_mem(&bytes).context("Cannot extract text from pdf")
+        })
+        .await??
+    } else if TEXT_EXTENSIONS.contains(&ext.as_str()) {
+        String::from_utf8_lossy(&fs::read(path).await?).into_owned()
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(truncate_chars(&text, MAX_TEXT_LEN).to_string()))
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => &text[..idx],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_chars() {
+        assert_eq!(truncate_chars("hello", 10), "hello");
+        assert_eq!(truncate_chars("hello", 2), "he");
+        assert_eq!(truncate_chars("你好世界", 3), "你好世");
+    }
+}
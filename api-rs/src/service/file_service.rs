@@ -0,0 +1,260 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::model::admin::LargeFile;
+use crate::model::file::{FileUpload, StoredFile, UploadUsage};
+use crate::model::post::FileType;
+use chrono::Utc;
+use sqlx::{query, query_as, QueryBuilder, Sqlite, SqlitePool};
+use uuid::Uuid;
+
+impl StoredFile {
+    pub async fn find_by_id(pool: &SqlitePool, id: &str) -> ApiResult<Option<StoredFile>> {
+        let file = query_as!(StoredFile, "SELECT * FROM files WHERE id = ?", id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(file)
+    }
+
+    pub async fn find_by_filename(
+        pool: &SqlitePool,
+        filename: &str,
+    ) -> ApiResult<Option<StoredFile>> {
+        let file = query_as!(
+            StoredFile,
+            "SELECT * FROM files WHERE filename = ?",
+            filename
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    /// Get a stored file by its filename, if the user uploaded it
+    pub async fn find_owned(
+        pool: &SqlitePool,
+        user_id: i64,
+        filename: &str,
+    ) -> ApiResult<Option<StoredFile>> {
+        let file = query_as!(
+            StoredFile,
+            r#"
+            SELECT * FROM files f
+            WHERE f.filename = ? AND (f.user_id = ? OR EXISTS (
+                SELECT 1 FROM file_uploads u WHERE u.file_id = f.id AND u.user_id = ?
+            ))
+            "#,
+            filename,
+            user_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    /// Records a stored file, replacing the record of a previous file with the same content.
+    /// The name it was first uploaded with is kept, each upload records its own, see [`FileUpload`].
+    pub async fn save(
+        db: &DB,
+        user_id: i64,
+        id: &str,
+        filename: &str,
+        original_name: &str,
+        content_type: Option<&str>,
+        size: i64,
+    ) -> ApiResult<StoredFile> {
+        let now = Utc::now().timestamp_millis();
+
+        let file = query_as!(
+            StoredFile,
+            r#"
+            INSERT INTO files (id, filename, original_name, content_type, size, created_at, user_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                filename = excluded.filename,
+                content_type = excluded.content_type,
+                size = excluded.size,
+                created_at = excluded.created_at
+            RETURNING *
+            "#,
+            id,
+            filename,
+            original_name,
+            content_type,
+            size,
+            now,
+            user_id,
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(file)
+    }
+
+    /// Get the files uploaded before the given time which are neither attached to any post,
+    /// nor linked from the content of one, as the images extracted from it are.
+    pub async fn find_unattached(pool: &SqlitePool, before: i64) -> ApiResult<Vec<StoredFile>> {
+        let files = query_as!(
+            StoredFile,
+            r#"
+            SELECT * FROM files f
+            WHERE f.created_at < ? AND NOT EXISTS (
+                SELECT 1 FROM posts p, json_each(p.files) j
+                WHERE json_extract(j.value, '$.url') = f.filename
+            ) AND NOT EXISTS (
+                SELECT 1 FROM posts p WHERE instr(p.content, '/' || f.filename) > 0
+            )
+            "#,
+            before
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(files)
+    }
+
+    pub async fn delete(db: &DB, id: &str) -> ApiResult<()> {
+        query!("DELETE FROM files WHERE id = ?", id)
+            .execute(&db.writer)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the records of a file whose content was removed from the upload directory.
+    pub async fn delete_by_filename(db: &DB, filename: &str) -> ApiResult<()> {
+        query!("DELETE FROM files WHERE filename = ?", filename)
+            .execute(&db.writer)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The disk space used by the files uploaded by a user, or by all of them if `user_id` is `None`.
+    pub async fn usage(pool: &SqlitePool, user_id: Option<i64>) -> ApiResult<UploadUsage> {
+        let usage = query_as!(
+            UploadUsage,
+            r#"
+            SELECT COUNT(*) AS "files!: i64", COALESCE(SUM(size), 0) AS "size!: i64" FROM files
+            WHERE ? IS NULL OR user_id = ?
+            "#,
+            user_id,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(usage)
+    }
+
+    /// Get the largest files with the posts they are attached to, optionally only those of a kind.
+    pub async fn find_largest(
+        pool: &SqlitePool,
+        file_type: Option<FileType>,
+        limit: i64,
+        offset: i64,
+    ) -> ApiResult<Vec<LargeFile>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            r#"
+            SELECT f.filename, f.user_id, u.name AS owner, f.original_name, f.content_type, f.size,
+                f.created_at,
+                (
+                    SELECT json_group_array(p.id) FROM posts p, json_each(p.files) j
+                    WHERE json_extract(j.value, '$.url') = f.filename
+                ) AS post_ids
+            FROM files f
+            LEFT JOIN users u ON u.id = f.user_id
+            WHERE 1 = 1
+            "#,
+        );
+        if let Some(file_type) = file_type {
+            builder.push(" AND (");
+            let mut separated = builder.separated(" OR ");
+            for pattern in file_type.content_type_patterns() {
+                separated
+                    .push("f.content_type LIKE ")
+                    .push_bind_unseparated(*pattern);
+            }
+            builder.push(")");
+        }
+        builder
+            .push(" ORDER BY f.size DESC, f.filename LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+
+        let files = builder.build_query_as().fetch_all(pool).await?;
+        Ok(files)
+    }
+
+    pub async fn set_text(db: &DB, id: &str, text: &str) -> ApiResult<()> {
+        query!("UPDATE files SET text = ? WHERE id = ?", text, id)
+            .execute(&db.writer)
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl FileUpload {
+    /// Records an upload of a stored file under the name it was uploaded with.
+    pub async fn create(
+        db: &DB,
+        file_id: &str,
+        user_id: i64,
+        original_name: &str,
+    ) -> ApiResult<FileUpload> {
+        let id = Uuid::new_v4().simple().to_string();
+        let now = Utc::now().timestamp_millis();
+
+        let upload = query_as!(
+            FileUpload,
+            r#"
+            INSERT INTO file_uploads (id, file_id, user_id, original_name, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING *
+            "#,
+            id,
+            file_id,
+            user_id,
+            original_name,
+            now,
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(upload)
+    }
+
+    pub async fn find_by_id(pool: &SqlitePool, id: &str) -> ApiResult<Option<FileUpload>> {
+        let upload = query_as!(FileUpload, "SELECT * FROM file_uploads WHERE id = ?", id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(upload)
+    }
+
+    /// Get the uploads of a stored file, by any user
+    pub async fn find_by_file(pool: &SqlitePool, file_id: &str) -> ApiResult<Vec<FileUpload>> {
+        let uploads = query_as!(
+            FileUpload,
+            "SELECT * FROM file_uploads WHERE file_id = ? ORDER BY created_at",
+            file_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(uploads)
+    }
+
+    pub async fn delete(db: &DB, id: &str) -> ApiResult<()> {
+        query!("DELETE FROM file_uploads WHERE id = ?", id)
+            .execute(&db.writer)
+            .await?;
+
+        Ok(())
+    }
+}
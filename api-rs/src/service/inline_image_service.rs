@@ -0,0 +1,63 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::ops::Range;
+
+lazy_static! {
+    /// The source of an image pasted as a data uri, with its content type and its base64 data
+    pub static ref INLINE_IMAGE: Regex =
+        Regex::new(r#"src="(data:(image/[\w.+-]+);base64,([A-Za-z0-9+/=\s]*))""#).unwrap();
+}
+
+/// An image found in the content of a post
+#[derive(Debug, PartialEq)]
+pub struct InlineImage {
+    /// The range of the data uri in the content
+    pub range: Range<usize>,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Finds and decodes the images pasted into the content as data uris, those which cannot be decoded are skipped.
+pub fn find_inline_images(content: &str) -> Vec<InlineImage> {
+    INLINE_IMAGE
+        .captures_iter(content)
+        .filter_map(|cap| {
+            let data: String = cap[3].chars().filter(|c| !c.is_whitespace()).collect();
+            Some(InlineImage {
+                range: cap.get(1)?.range(),
+                content_type: cap[2].to_lowercase(),
+                data: STANDARD.decode(data).ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Replaces the ranges of the content, which must not overlap.
+pub fn replace_ranges(content: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    replacements.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut content = content.to_string();
+    for (range, value) in replacements {
+        content.replace_range(range, &value);
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_inline_images() {
+        let content = r#"<p><img src="data:image/PNG;base64,aGVs
+            bG8="> <img src="data:image/gif;base64,!!"> <img src="/a.png"></p>"#;
+        let images = find_inline_images(content);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].content_type, "image/png");
+        assert_eq!(images[0].data, b"hello");
+
+        let replaced = replace_ranges(content, vec![(images[0].range.clone(), "/x.png".into())]);
+        assert!(replaced.starts_with(r#"<p><img src="/x.png"> <img src="data:image/gif"#));
+    }
+}
@@ -0,0 +1,425 @@
+use crate::config::rd::RD;
+use anyhow::{anyhow, Context, Result};
+use bb8::RunError;
+use futures::future::BoxFuture;
+use redis::ExistenceCheck::NX;
+use redis::SetExpiry::EX;
+use redis::{ErrorKind, RedisError, SetOptions};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A write of a batch, see `KvStore::apply`.
+#[derive(Debug, Clone)]
+pub enum KvOp {
+    Set(String, String),
+    Del(String),
+    Incr(String, i64),
+    SAdd(String, String),
+    SRem(String, String),
+}
+
+/// The key-value operations used by the full-text search and rate limiting.
+/// Redis is used in production, and an in-memory store in tests.
+pub trait KvStore: Send + Sync {
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    fn mget<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>>;
+
+    /// Returns the members of each set, in the order of `keys`.
+    fn smembers_many<'a>(
+        &'a self,
+        keys: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<HashSet<String>>>>;
+
+    /// Returns the size of each set, in the order of `keys`.
+    fn scard_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<usize>>>;
+
+    /// Returns whether each of `members` is in the set, in the order of `members`.
+    fn sismember_many<'a>(
+        &'a self,
+        key: &'a str,
+        members: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<bool>>>;
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>>;
+
+    fn del_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<()>>;
+
+    /// Applies the writes atomically.
+    fn apply(&self, ops: Vec<KvOp>) -> BoxFuture<'_, Result<()>>;
+
+    /// Increments a counter which expires `expires` seconds after it is created,
+    /// returns the new value.
+    fn incr_with_expiry<'a>(&'a self, key: &'a str, expires: u64) -> BoxFuture<'a, Result<u64>>;
+}
+
+impl KvStore for RD {
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(RD::exists(self, key))
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(RD::get(self, key))
+    }
+
+    fn mget<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        Box::pin(async move {
+            if keys.is_empty() {
+                return Ok(vec![]);
+            }
+            RD::mget(self, keys).await
+        })
+    }
+
+    fn smembers_many<'a>(
+        &'a self,
+        keys: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<HashSet<String>>>> {
+        Box::pin(self.read_pipeline(move |pipe| {
+            for key in keys {
+                pipe.smembers(key);
+            }
+        }))
+    }
+
+    fn scard_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<usize>>> {
+        Box::pin(self.read_pipeline(move |pipe| {
+            for key in keys {
+                pipe.scard(key);
+            }
+        }))
+    }
+
+    fn sismember_many<'a>(
+        &'a self,
+        key: &'a str,
+        members: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<bool>>> {
+        Box::pin(async move {
+            if members.is_empty() {
+                return Ok(vec![]);
+            }
+            // SMISMEMBER needs Redis 6.2
+            self.read_pipeline(move |pipe| {
+                for member in members {
+                    pipe.sismember(key, member);
+                }
+            })
+            .await
+        })
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(RD::keys(self, format!("{}*", prefix)))
+    }
+
+    fn del_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if keys.is_empty() {
+                return Ok(());
+            }
+            RD::del(self, keys).await
+        })
+    }
+
+    fn apply(&self, ops: Vec<KvOp>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.pipeline(move |pipe| {
+            for op in ops {
+                match op {
+                    KvOp::Set(key, value) => pipe.set(key, value),
+                    KvOp::Del(key) => pipe.del(key),
+                    KvOp::Incr(key, delta) => pipe.incr(key, delta),
+                    KvOp::SAdd(key, member) => pipe.sadd(key, member),
+                    KvOp::SRem(key, member) => pipe.srem(key, member),
+                };
+            }
+        }))
+    }
+
+    fn incr_with_expiry<'a>(&'a self, key: &'a str, expires: u64) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            let rv: [u64; 1] = self
+                .pipeline(|pipe| {
+                    pipe.set_options(
+                        key,
+                        0,
+                        SetOptions::default()
+                            .with_expiration(EX(expires))
+                            .conditional_set(NX),
+                    )
+                    .ignore()
+                    .incr(key, 1);
+                })
+                .await
+                .context("Redis Error")?;
+            Ok(rv[0])
+        })
+    }
+}
+
+/// Whether an error of a store may go away if the operation is tried again,
+/// such as a dropped connection, a timeout, or Redis loading its data.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    let err = match err.downcast_ref::<RunError<RedisError>>() {
+        Some(RunError::TimedOut) => return true,
+        Some(RunError::User(e)) => Some(e),
+        None => err.downcast_ref::<RedisError>(),
+    };
+    err.is_some_and(|e| {
+        e.is_io_error()
+            || e.is_timeout()
+            || e.is_connection_dropped()
+            || e.is_connection_refusal()
+            || matches!(e.kind(), ErrorKind::TryAgain | ErrorKind::BusyLoadingError)
+    })
+}
+
+#[derive(Debug, Clone)]
+enum MemoryValue {
+    String(String),
+    Set(HashSet<String>),
+}
+
+type Entries = HashMap<String, (MemoryValue, Option<Instant>)>;
+
+/// An in-memory store for tests, which need no Redis server.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<Entries>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f` with the entries after removing the expired ones.
+    fn with_entries<T>(&self, f: impl FnOnce(&mut Entries) -> Result<T>) -> Result<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, (_, expires_at)| expires_at.is_none_or(|t| t > now));
+        f(&mut entries)
+    }
+}
+
+fn get_string(entries: &Entries, key: &str) -> Result<Option<String>> {
+    match entries.get(key) {
+        None => Ok(None),
+        Some((MemoryValue::String(value), _)) => Ok(Some(value.clone())),
+        Some(_) => Err(anyhow!("Wrong type of key `{}`", key)),
+    }
+}
+
+fn get_set<'a>(entries: &'a mut Entries, key: &str) -> Result<&'a mut HashSet<String>> {
+    let (value, _) = entries
+        .entry(key.to_string())
+        .or_insert((MemoryValue::Set(HashSet::new()), None));
+    match value {
+        MemoryValue::Set(set) => Ok(set),
+        _ => Err(anyhow!("Wrong type of key `{}`", key)),
+    }
+}
+
+fn incr(entries: &mut Entries, key: &str, delta: i64) -> Result<i64> {
+    let value = get_string(entries, key)?
+        .map(|v| v.parse::<i64>())
+        .transpose()
+        .context("Value is not an integer")?
+        .unwrap_or(0)
+        + delta;
+    let expires_at = entries.get(key).and_then(|(_, t)| *t);
+    entries.insert(
+        key.to_string(),
+        (MemoryValue::String(value.to_string()), expires_at),
+    );
+    Ok(value)
+}
+
+/// Returns the members of a set, missing keys are empty sets like in Redis.
+fn members(entries: &Entries, key: &str) -> Result<HashSet<String>> {
+    match entries.get(key) {
+        None => Ok(HashSet::new()),
+        Some((MemoryValue::Set(set), _)) => Ok(set.clone()),
+        Some(_) => Err(anyhow!("Wrong type of key `{}`", key)),
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move { self.with_entries(|entries| Ok(entries.contains_key(key))) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move { self.with_entries(|entries| get_string(entries, key)) })
+    }
+
+    fn mget<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        Box::pin(async move {
+            self.with_entries(|entries| keys.iter().map(|key| get_string(entries, key)).collect())
+        })
+    }
+
+    fn smembers_many<'a>(
+        &'a self,
+        keys: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<HashSet<String>>>> {
+        Box::pin(async move {
+            self.with_entries(|entries| keys.iter().map(|key| members(entries, key)).collect())
+        })
+    }
+
+    fn scard_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<Vec<usize>>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                keys.iter()
+                    .map(|key| members(entries, key).map(|set| set.len()))
+                    .collect()
+            })
+        })
+    }
+
+    fn sismember_many<'a>(
+        &'a self,
+        key: &'a str,
+        members: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<bool>>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                let set = self::members(entries, key)?;
+                Ok(members.iter().map(|member| set.contains(member)).collect())
+            })
+        })
+    }
+
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                Ok(entries
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .cloned()
+                    .collect())
+            })
+        })
+    }
+
+    fn del_many<'a>(&'a self, keys: &'a [String]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                for key in keys {
+                    entries.remove(key);
+                }
+                Ok(())
+            })
+        })
+    }
+
+    fn apply(&self, ops: Vec<KvOp>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                // Work on a copy, so that nothing is changed if any of the writes fails
+                let mut copy = entries.clone();
+                for op in ops {
+                    match op {
+                        KvOp::Set(key, value) => {
+                            copy.insert(key, (MemoryValue::String(value), None));
+                        }
+                        KvOp::Del(key) => {
+                            copy.remove(&key);
+                        }
+                        KvOp::Incr(key, delta) => {
+                            incr(&mut copy, &key, delta)?;
+                        }
+                        KvOp::SAdd(key, member) => {
+                            get_set(&mut copy, &key)?.insert(member);
+                        }
+                        KvOp::SRem(key, member) => {
+                            let set = get_set(&mut copy, &key)?;
+                            set.remove(&member);
+                            if set.is_empty() {
+                                copy.remove(&key);
+                            }
+                        }
+                    }
+                }
+                *entries = copy;
+                Ok(())
+            })
+        })
+    }
+
+    fn incr_with_expiry<'a>(&'a self, key: &'a str, expires: u64) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            self.with_entries(|entries| {
+                if !entries.contains_key(key) {
+                    let expires_at = Instant::now() + Duration::from_secs(expires);
+                    entries.insert(
+                        key.to_string(),
+                        (MemoryValue::String("0".to_string()), Some(expires_at)),
+                    );
+                }
+                Ok(incr(entries, key, 1)? as u64)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new();
+        store
+            .apply(vec![
+                KvOp::Set("a".to_string(), "1".to_string()),
+                KvOp::Incr("a".to_string(), 2),
+                KvOp::SAdd("s".to_string(), "x".to_string()),
+                KvOp::SAdd("s".to_string(), "y".to_string()),
+                KvOp::SRem("s".to_string(), "x".to_string()),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(store.get("a").await.unwrap(), Some("3".to_string()));
+        let keys = ["s".to_string(), "t".to_string()];
+        assert_eq!(store.scard_many(&keys).await.unwrap(), vec![1, 0]);
+        let members = ["x".to_string(), "y".to_string()];
+        assert_eq!(
+            store.sismember_many("s", &members).await.unwrap(),
+            vec![false, true]
+        );
+
+        // A failed batch changes nothing
+        let rv = store
+            .apply(vec![
+                KvOp::Del("s".to_string()),
+                KvOp::SAdd("a".to_string(), "x".to_string()),
+            ])
+            .await;
+        assert!(rv.is_err());
+        assert!(store.exists("s").await.unwrap());
+
+        assert_eq!(store.incr_with_expiry("rate", 60).await.unwrap(), 1);
+        assert_eq!(store.incr_with_expiry("rate", 60).await.unwrap(), 2);
+
+        let mut keys = store.keys_with_prefix("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "rate", "s"]);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_transient(&RedisError::from(reset).into()));
+        assert!(is_transient(&RunError::<RedisError>::TimedOut.into()));
+
+        let wrong_type = RedisError::from((ErrorKind::TypeError, "WRONGTYPE"));
+        assert!(!is_transient(&wrong_type.into()));
+        assert!(!is_transient(&anyhow!("Token frequency not found")));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::model::admin::LinkHealth;
+use crate::util::html::unescape;
+use crate::util::net::is_global;
+use anyhow::Result;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::LOCATION;
+use reqwest::{redirect, Client, StatusCode, Url};
+use sqlx::{query, query_as, SqlitePool};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::instrument;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many links are requested at the same time
+const CONCURRENCY: usize = 8;
+
+lazy_static! {
+    static ref EXTERNAL_LINK_PATTERN: Regex =
+        Regex::new(r#"<a\s[^>]*?href="(https?://[^"]+)""#).unwrap();
+}
+
+impl LinkHealth {
+    /// The links found by the last check, only the broken and redirected ones unless `all` is set
+    pub async fn find_all(pool: &SqlitePool, all: bool) -> ApiResult<Vec<LinkHealth>> {
+        let links = query_as!(
+            LinkHealth,
+            r#"
+            SELECT post_id, url, status, location, error, ok, checked_at
+            FROM link_health
+            WHERE ? OR NOT ok
+            ORDER BY post_id, url
+            "#,
+            all
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(links)
+    }
+}
+
+/// The outcome of requesting a link
+struct LinkCheck {
+    status: Option<i64>,
+    location: Option<String>,
+    error: Option<String>,
+}
+
+impl LinkCheck {
+    fn ok(&self) -> bool {
+        matches!(self.status, Some(200..=299))
+    }
+}
+
+/// Checks the external links of the shared posts, and replaces the results of the last check.
+/// Each url is requested once, and redirects are not followed.
+/// Returns how many links are checked, and how many of them are broken or redirected.
+#[instrument(skip_all)]
+pub async fn check_shared_links(db: &DB) -> Result<(usize, usize)> {
+    let posts = query!("SELECT id, content FROM posts WHERE shared AND deleted_at IS NULL")
+        .fetch_all(&db.pool)
+        .await?;
+    let links: Vec<(i64, String)> = posts
+        .iter()
+        .flat_map(|post| {
+            extract_external_links(&post.content)
+                .into_iter()
+                .map(move |url| (post.id, url))
+        })
+        .collect();
+
+    let client = link_client()?;
+    // The urls are owned by the requests, borrowing them makes the future of the job not `Send`
+    let urls: BTreeSet<String> = links.iter().map(|(_, url)| url.clone()).collect();
+    let checks: HashMap<String, LinkCheck> = stream::iter(urls)
+        .map(|url| {
+            let client = client.clone();
+            async move {
+                let check = check_link(&client, &url).await;
+                (url, check)
+            }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+
+    let now = Utc::now().timestamp_millis();
+    let mut tx = db.writer.begin().await?;
+    query!("DELETE FROM link_health").execute(&mut *tx).await?;
+    for (post_id, url) in links.iter() {
+        let check = &checks[url];
+        let ok = check.ok();
+        // The post may have been deleted during the check
+        query!(
+            r#"
+            INSERT INTO link_health (post_id, url, status, location, error, ok, checked_at)
+            SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7
+            WHERE EXISTS (SELECT 1 FROM posts WHERE id = ?1)
+            "#,
+            post_id,
+            url,
+            check.status,
+            check.location,
+            check.error,
+            ok,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let broken = links.iter().filter(|(_, url)| !checks[url].ok()).count();
+    Ok((links.len(), broken))
+}
+
+/// The client of the checks, which connects to the public addresses only
+fn link_client() -> reqwest::Result<Client> {
+    Client::builder()
+        .redirect(redirect::Policy::none())
+        .timeout(CHECK_TIMEOUT)
+        .user_agent(concat!("mote/", env!("CARGO_PKG_VERSION")))
+        .dns_resolver(Arc::new(GlobalResolver))
+        .build()
+}
+
+/// Resolves a host to its public addresses, so that a link put in a post cannot reach the services
+/// of the server or its network, e.g. Redis at `127.0.0.1:6379` or the metadata at `169.254.169.254`.
+/// The addresses are checked where they are connected to, a host cannot resolve to another one later.
+struct GlobalResolver;
+
+impl Resolve for GlobalResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_global(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The address of a link whose host is one, which is connected to without being resolved
+fn literal_ip(url: &str) -> Option<IpAddr> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    // An IPv6 address is bracketed in a url
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Requests a link with HEAD, or with GET if the server does not answer HEAD requests
+async fn check_link(client: &Client, url: &str) -> LinkCheck {
+    if let Some(ip) = literal_ip(url).filter(|ip| !is_global(*ip)) {
+        return LinkCheck {
+            status: None,
+            location: None,
+            error: Some(format!("{} is not a public address", ip)),
+        };
+    }
+
+    let mut result = client.head(url).send().await;
+    if let Ok(ref response) = result {
+        if matches!(
+            response.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED | StatusCode::FORBIDDEN
+        ) {
+            result = client.get(url).send().await;
+        }
+    }
+
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            let location = status
+                .is_redirection()
+                .then(|| response.headers().get(LOCATION)?.to_str().ok())
+                .flatten()
+                .map(String::from);
+            LinkCheck {
+                status: Some(status.as_u16() as i64),
+                location,
+                error: None,
+            }
+        }
+        Err(e) => LinkCheck {
+            status: None,
+            location: None,
+            error: Some(format!("{:#}", anyhow::Error::from(e))),
+        },
+    }
+}
+
+/// The http(s) links of a post, decoded
+pub fn extract_external_links(content: &str) -> BTreeSet<String> {
+    EXTERNAL_LINK_PATTERN
+        .captures_iter(content)
+        .map(|caps| unescape(&caps[1]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_private_links_are_not_requested() {
+        let client = link_client().unwrap();
+        for url in [
+            "http://127.0.0.1:6379/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]:8000/",
+            "http://[::ffff:10.0.0.1]/",
+        ] {
+            let check = check_link(&client, url).await;
+            assert!(
+                check.error.unwrap().ends_with("is not a public address"),
+                "{}",
+                url
+            );
+        }
+
+        let check = check_link(&client, "http://localhost:6379/").await;
+        assert_eq!(check.status, None);
+        assert!(check
+            .error
+            .unwrap()
+            .contains("localhost has no public address"));
+    }
+
+    #[test]
+    fn test_extract_external_links() {
+        let content = r#"<p><a href="https://example.com/a?x=1&amp;y=2">a</a>
+            <a target="_blank" href="http://example.org">b</a> <a href="/p/12">post</a>
+            <img src="https://example.com/c.png"><a href="http://example.org">again</a></p>"#;
+        assert_eq!(
+            extract_external_links(content),
+            BTreeSet::from([
+                "http://example.org".to_string(),
+                "https://example.com/a?x=1&y=2".to_string(),
+            ])
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::errors::ApiResult;
+use crate::model::post::{LintWarning, Post};
+use crate::service::inline_image_service::INLINE_IMAGE;
+use crate::service::post_service::extract_post_links;
+use lazy_static::lazy_static;
+use regex::Regex;
+use sqlx::SqlitePool;
+
+lazy_static! {
+    static ref HASH_TAG: Regex = Regex::new(r#"<span class="hash-tag">([^<]*)</span>"#).unwrap();
+}
+
+/// Finds the problems of the content of a post which do not prevent it from being saved.
+/// Inline images are reported if they are larger than `max_inline_image_size` bytes,
+/// and links to posts which are not of the user are reported as broken.
+pub async fn lint(
+    pool: &SqlitePool,
+    user_id: i64,
+    content: &str,
+    max_inline_image_size: u64,
+) -> ApiResult<Vec<LintWarning>> {
+    let mut warnings = lint_text(content, max_inline_image_size);
+
+    let mut ids: Vec<i64> = extract_post_links(content).into_iter().collect();
+    if !ids.is_empty() {
+        ids.sort();
+        let found = Post::find_rows_by_ids(pool, user_id, &ids).await?;
+        for id in ids {
+            if !found
+                .iter()
+                .any(|row| row.id == id && row.deleted_at.is_none())
+            {
+                warnings.push(LintWarning::BrokenLink { post_id: id });
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// The checks which need no database
+fn lint_text(content: &str, max_inline_image_size: u64) -> Vec<LintWarning> {
+    let mut warnings = vec![];
+
+    let empty_tags = HASH_TAG
+        .captures_iter(content)
+        .filter(|cap| cap[1].trim().trim_start_matches('#').trim().is_empty())
+        .count();
+    if empty_tags > 0 {
+        warnings.push(LintWarning::EmptyHashTag { count: empty_tags });
+    }
+
+    for cap in INLINE_IMAGE.captures_iter(content) {
+        // The decoded size, without decoding it
+        let size = cap[3]
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .count() as u64
+            * 3
+            / 4;
+        if size > max_inline_image_size {
+            warnings.push(LintWarning::LargeInlineImage { size });
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_text() {
+        let content = r#"<p><span class="hash-tag">#</span> <span class="hash-tag">#ok</span>
+            <span class="hash-tag"> </span>
+            <img src="data:image/png;base64,AAAAAAAA"> <img src="data:image/png;base64,AAAA"></p>"#;
+        assert_eq!(
+            lint_text(content, 3),
+            [
+                LintWarning::EmptyHashTag { count: 2 },
+                LintWarning::LargeInlineImage { size: 6 }
+            ]
+        );
+        assert!(lint_text("<p>fine</p>", 0).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::rd::RD;
+use crate::model::post::{PostLock, PostLockStatus};
+use anyhow::Result;
+use chrono::Utc;
+
+fn lock_key(post_id: i64) -> String {
+    format!("post-lock:{}", post_id)
+}
+
+/// Locks a post for an editor, or renews the lock it already holds.
+/// If another editor holds the lock, it is returned without being acquired.
+pub async fn lock_post(
+    rd: &RD,
+    post_id: i64,
+    holder: &str,
+    device: Option<&str>,
+    ttl_ms: u64,
+) -> Result<PostLockStatus> {
+    let key = lock_key(post_id);
+
+    // Tried again once, in case the lock expires while it is checked
+    for _ in 0..2 {
+        let lock = PostLock {
+            holder: holder.to_string(),
+            device: device.map(String::from),
+            locked_at: Utc::now().timestamp_millis(),
+        };
+        if rd
+            .try_lock(&key, &serde_json::to_string(&lock)?, ttl_ms)
+            .await?
+        {
+            return Ok(held(lock, ttl_ms));
+        }
+
+        let status = renew_post_lock(rd, post_id, holder, ttl_ms).await?;
+        if status.lock.is_some() {
+            return Ok(status);
+        }
+    }
+    Ok(unlocked())
+}
+
+/// Extends the lock of an editor, which must be locked again if it has expired.
+pub async fn renew_post_lock(
+    rd: &RD,
+    post_id: i64,
+    holder: &str,
+    ttl_ms: u64,
+) -> Result<PostLockStatus> {
+    let key = lock_key(post_id);
+    let Some(value) = rd.get::<String, _>(&key).await? else {
+        return Ok(unlocked());
+    };
+    let lock: PostLock = serde_json::from_str(&value)?;
+
+    if lock.holder != holder {
+        let ttl: i64 = rd.pttl(&key).await?;
+        return Ok(PostLockStatus {
+            acquired: false,
+            lock: Some(lock),
+            expires_at: (ttl > 0).then(|| Utc::now().timestamp_millis() + ttl),
+        });
+    }
+
+    if rd.extend_lock(&key, &value, ttl_ms).await? {
+        Ok(held(lock, ttl_ms))
+    } else {
+        Ok(unlocked())
+    }
+}
+
+/// Releases the lock of an editor, the lock of another one is kept.
+pub async fn unlock_post(rd: &RD, post_id: i64, holder: &str) -> Result<()> {
+    let key = lock_key(post_id);
+    if let Some(value) = rd.get::<String, _>(&key).await? {
+        let lock: PostLock = serde_json::from_str(&value)?;
+        if lock.holder == holder {
+            rd.unlock(&key, &value).await?;
+        }
+    }
+    Ok(())
+}
+
+fn held(lock: PostLock, ttl_ms: u64) -> PostLockStatus {
+    PostLockStatus {
+        acquired: true,
+        lock: Some(lock),
+        expires_at: Some(Utc::now().timestamp_millis() + ttl_ms as i64),
+    }
+}
+
+fn unlocked() -> PostLockStatus {
+    PostLockStatus {
+        acquired: false,
+        lock: None,
+        expires_at: None,
+    }
+}
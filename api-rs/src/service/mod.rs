@@ -1,7 +1,37 @@
+pub mod asset_service;
+pub mod audit_service;
 pub mod auth_service;
+pub mod backup_service;
+pub mod check_service;
+pub mod convert_service;
+pub mod demo_service;
+pub mod event_service;
+pub mod export_service;
+pub mod extract_service;
+pub mod file_service;
+pub mod inline_image_service;
+pub mod kv_service;
+pub mod link_service;
+pub mod lint_service;
+pub mod lock_service;
+pub mod notification_service;
+pub mod notifier_service;
+pub mod ocr_service;
+pub mod orphan_service;
+pub mod passkey_service;
 pub mod post_service;
+pub mod reaction_service;
+pub mod realtime_service;
 pub mod redis_service;
+pub mod replica_service;
+pub mod scan_service;
 pub mod search_service;
+pub mod session_service;
+pub mod slack_service;
+pub mod stats_service;
 pub mod tag_service;
 pub mod task_service;
 pub mod upload_service;
+pub mod url_service;
+pub mod user_service;
+pub mod view_service;
@@ -0,0 +1,101 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::model::notification::Notification;
+use crate::service::notifier_service::Alert;
+use crate::service::realtime_service::RealtimeEvent;
+use crate::AppState;
+use chrono::Utc;
+use sqlx::{query, query_as, SqlitePool};
+use tracing::error;
+
+impl Notification {
+    pub async fn create(db: &DB, kind: &str, message: &str) -> ApiResult<Notification> {
+        let now = Utc::now().timestamp_millis();
+        let notification = query_as!(
+            Notification,
+            r#"
+            INSERT INTO notifications (kind, message, created_at) VALUES (?, ?, ?)
+            RETURNING *
+            "#,
+            kind,
+            message,
+            now,
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(notification)
+    }
+
+    /// Returns the latest notifications, the most recent first.
+    pub async fn find_latest(
+        pool: &SqlitePool,
+        unread: bool,
+        limit: i64,
+    ) -> ApiResult<Vec<Notification>> {
+        let notifications = query_as!(
+            Notification,
+            r#"
+            SELECT id AS "id!", kind, message, read_at, created_at
+            FROM notifications
+            WHERE NOT ? OR read_at IS NULL
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?
+            "#,
+            unread,
+            limit
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(notifications)
+    }
+
+    /// Marks a notification as read, or all of them if `id` is `None`.
+    pub async fn mark_read(db: &DB, id: Option<i64>) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        query!(
+            r#"
+            UPDATE notifications SET read_at = ?
+            WHERE read_at IS NULL AND (? IS NULL OR id = ?)
+            "#,
+            now,
+            id,
+            id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Saves a notification and pushes it to the connected clients,
+/// and sends it to the notify channels if its kind is configured.
+/// Failures are only logged, like those of the operation being reported.
+pub async fn notify(state: &AppState, kind: &str, message: &str) {
+    match Notification::create(&state.db, kind, message).await {
+        Ok(notification) => state
+            .realtime
+            .publish(RealtimeEvent::Notification(notification)),
+        Err(e) => error!("Cannot save notification: {:?}", e),
+    }
+
+    if let Some(notifier) = state.notifier.clone() {
+        if state.config.notify.is_sent(kind) {
+            let title = format!("{}: {}", state.config.app_name, kind);
+            let (kind, message) = (kind.to_string(), message.to_string());
+            // Sending may take a while, the caller should not wait for it
+            tokio::spawn(async move {
+                let alert = Alert {
+                    kind: &kind,
+                    title: &title,
+                    message: &message,
+                };
+                if let Err(e) = notifier.send(&alert).await {
+                    error!("{:#}", e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::config::NotifyConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use chrono::Utc;
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+/// A notification sent out of the app.
+#[derive(Debug, Serialize)]
+pub struct Alert<'a> {
+    pub kind: &'a str,
+    pub title: &'a str,
+    pub message: &'a str,
+}
+
+/// Sends notifications to a channel outside the app, e.g. email.
+pub trait Notifier: Send + Sync {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Creates a notifier sending to all the channels of the config, or `None` if there are none.
+pub fn notifier_from_config(config: &NotifyConfig) -> Option<Arc<dyn Notifier>> {
+    let client = reqwest::Client::builder()
+        .timeout(SEND_TIMEOUT)
+        .build()
+        .unwrap();
+
+    let channels: Vec<(String, Box<dyn Notifier>)> = config
+        .channels
+        .iter()
+        .filter_map(|channel| {
+            let notifier: Box<dyn Notifier> = match channel.as_str() {
+                "smtp" => Box::new(SmtpNotifier::from_config(config)),
+                "ntfy" => {
+                    let (server, topic) = config.ntfy_topic()?;
+                    Box::new(NtfyNotifier {
+                        client: client.clone(),
+                        server: server.to_string(),
+                        topic: topic.to_string(),
+                        token: config.ntfy_token.clone(),
+                    })
+                }
+                "webhook" => Box::new(WebhookNotifier {
+                    client: client.clone(),
+                    url: config.webhook_url.clone(),
+                }),
+                "telegram" => Box::new(TelegramNotifier {
+                    client: client.clone(),
+                    bot_token: config.telegram_bot_token.clone(),
+                    chat_id: config.telegram_chat_id.clone(),
+                }),
+                _ => return None,
+            };
+            Some((channel.clone(), notifier))
+        })
+        .collect();
+
+    (!channels.is_empty()).then(|| Arc::new(Channels(channels)) as Arc<dyn Notifier>)
+}
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends to every channel at once, failing if any of them fails.
+struct Channels(Vec<(String, Box<dyn Notifier>)>);
+
+impl Notifier for Channels {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let results = join_all(self.0.iter().map(|(_, notifier)| notifier.send(alert))).await;
+            let errors: Vec<String> = self
+                .0
+                .iter()
+                .zip(results)
+                .filter_map(|((name, _), result)| {
+                    result.err().map(|e| format!("{}: {:#}", name, e))
+                })
+                .collect();
+
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(anyhow!("Cannot send notification to {}", errors.join("; ")))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SmtpSecurity {
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// Plain text upgraded with `STARTTLS`, usually on port 587
+    StartTls,
+    None,
+}
+
+/// Sends emails with a minimal SMTP client, logging in with `AUTH PLAIN` if there is a user.
+pub struct SmtpNotifier {
+    host: String,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    fn from_config(config: &NotifyConfig) -> Self {
+        SmtpNotifier {
+            host: config.smtp_host.clone(),
+            port: config.smtp_port,
+            security: match config.smtp_security.as_str() {
+                "tls" => SmtpSecurity::Tls,
+                "none" => SmtpSecurity::None,
+                _ => SmtpSecurity::StartTls,
+            },
+            credentials: (!config.smtp_username.is_empty())
+                .then(|| (config.smtp_username.clone(), config.smtp_password.clone())),
+            from: config.smtp_from.clone(),
+            to: config.smtp_to.clone(),
+        }
+    }
+
+    async fn deliver(&self, email: &str) -> Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .context("Cannot connect to the SMTP server")?;
+
+        match self.security {
+            SmtpSecurity::Tls => {
+                let mut client = SmtpClient::new(self.tls_connect(stream).await?);
+                client.reply(220).await?;
+                self.transact(client, email).await
+            }
+            SmtpSecurity::StartTls => {
+                let mut client = SmtpClient::new(stream);
+                client.reply(220).await?;
+                client.command(&format!("EHLO {}", EHLO_NAME), 250).await?;
+                client.command("STARTTLS", 220).await?;
+                let stream = self.tls_connect(client.into_inner()).await?;
+                self.transact(SmtpClient::new(stream), email).await
+            }
+            SmtpSecurity::None => {
+                let mut client = SmtpClient::new(stream);
+                client.reply(220).await?;
+                self.transact(client, email).await
+            }
+        }
+    }
+
+    async fn tls_connect<S>(&self, stream: S) -> Result<tokio_native_tls::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+        connector
+            .connect(&self.host, stream)
+            .await
+            .context("TLS handshake with the SMTP server failed")
+    }
+
+    /// Logs in and sends the email once the server has greeted.
+    async fn transact<S>(&self, mut client: SmtpClient<S>, email: &str) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        client.command(&format!("EHLO {}", EHLO_NAME), 250).await?;
+        if let Some((ref username, ref password)) = self.credentials {
+            let token = BASE64_STANDARD.encode(format!("\0{}\0{}", username, password));
+            client
+                .command(&format!("AUTH PLAIN {}", token), 235)
+                .await
+                .context("SMTP login failed")?;
+        }
+
+        client
+            .command(&format!("MAIL FROM:<{}>", self.from), 250)
+            .await?;
+        for to in self.to.iter() {
+            client.command(&format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        client.command("DATA", 354).await?;
+        // The body is base64, so no line of it can be a single dot
+        client.command(&format!("{}\r\n.", email), 250).await?;
+
+        // The email is accepted, the server may hang up without replying
+        let _ = client.command("QUIT", 221).await;
+        Ok(())
+    }
+}
+
+const EHLO_NAME: &str = "localhost";
+
+impl Notifier for SmtpNotifier {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let email = format_email(&self.from, &self.to, alert);
+            tokio::time::timeout(SEND_TIMEOUT, self.deliver(&email))
+                .await
+                .context("The SMTP server timed out")?
+        })
+    }
+}
+
+/// Formats a plain text email, with the headers encoded for non-ASCII titles.
+fn format_email(from: &str, to: &[String], alert: &Alert) -> String {
+    let subject = if alert.title.is_ascii() {
+        alert.title.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(alert.title))
+    };
+    let body = BASE64_STANDARD.encode(alert.message);
+    let body: Vec<&str> = body
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+        from,
+        to.join(", "),
+        subject,
+        Utc::now().to_rfc2822(),
+        body.join("\r\n")
+    )
+}
+
+struct SmtpClient<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpClient<S> {
+    fn new(stream: S) -> Self {
+        SmtpClient {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn command(&mut self, line: &str, expected: u16) -> Result<String> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.reply(expected).await
+    }
+
+    /// Reads a reply, which spans lines like `250-first` until one like `250 last`.
+    async fn reply(&mut self, expected: u16) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("The SMTP server closed the connection");
+            }
+            let line = line.trim_end();
+            let (code, rest) = line
+                .split_at_checked(3)
+                .ok_or(anyhow!("Invalid SMTP reply: {}", line))?;
+            text.push_str(rest.get(1..).unwrap_or_default());
+
+            if !rest.starts_with('-') {
+                if code.parse::<u16>().ok() != Some(expected) {
+                    bail!("The SMTP server replied: {}", line);
+                }
+                return Ok(text);
+            }
+            text.push('\n');
+        }
+    }
+}
+
+/// Publishes to a topic of an ntfy server, e.g. ntfy.sh.
+pub struct NtfyNotifier {
+    client: reqwest::Client,
+    server: String,
+    topic: String,
+    token: Option<String>,
+}
+
+impl Notifier for NtfyNotifier {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Published as json, since headers cannot hold non-ASCII titles
+            let mut request = self.client.post(&self.server).json(&json!({
+                "topic": self.topic,
+                "title": alert.title,
+                "message": alert.message,
+                "tags": [alert.kind],
+            }));
+            if let Some(ref token) = self.token {
+                request = request.bearer_auth(token);
+            }
+            request.send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+/// Posts the alert as json, like `{"kind": "...", "title": "...", "message": "..."}`.
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Notifier for WebhookNotifier {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(alert)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+/// Sends messages to a chat with the Telegram bot API.
+pub struct TelegramNotifier {
+    client: reqwest::Client,
+    bot_token: String,
+    chat_id: String,
+}
+
+impl Notifier for TelegramNotifier {
+    fn send<'a>(&'a self, alert: &'a Alert<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let url = format!("https://api.telegram.org/bot{}/sendMessage", self.bot_token);
+            // The url holds the token, keep it out of the errors
+            self.client
+                .post(url)
+                .json(&json!({
+                    "chat_id": self.chat_id,
+                    "text": format!("{}\n\n{}", alert.title, alert.message),
+                }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.without_url())?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_format_email() {
+        let alert = Alert {
+            kind: "job.failed",
+            title: "备忘: job.failed",
+            message: "Job backup failed",
+        };
+        let email = format_email("mote@example.com", &["a@example.com".to_string()], &alert);
+        let (headers, body) = email.split_once("\r\n\r\n").unwrap();
+
+        assert!(headers.contains("To: a@example.com\r\n"));
+        assert!(headers.contains(&format!(
+            "Subject: =?UTF-8?B?{}?=\r\n",
+            BASE64_STANDARD.encode("备忘: job.failed")
+        )));
+        assert_eq!(
+            BASE64_STANDARD.decode(body).unwrap(),
+            b"Job backup failed".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smtp_notifier() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // A server accepting everything, which records the session
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut session = String::new();
+            stream.write_all(b"220 smtp.example.com\r\n").await.unwrap();
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                session.push_str(&line);
+                let reply: &[u8] = match line.trim_end() {
+                    line if line.starts_with("EHLO") => {
+                        b"250-smtp.example.com\r\n250 AUTH PLAIN\r\n"
+                    }
+                    line if line.starts_with("AUTH") => b"235 OK\r\n",
+                    "DATA" => b"354 Go ahead\r\n",
+                    "." => b"250 Queued\r\n",
+                    "QUIT" => b"221 Bye\r\n",
+                    _ => continue_or_ok(&session),
+                };
+                stream.write_all(reply).await.unwrap();
+                if line.trim_end() == "QUIT" {
+                    break;
+                }
+            }
+            session
+        });
+
+        let notifier = SmtpNotifier {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            credentials: Some(("mote".to_string(), "secret".to_string())),
+            from: "mote@example.com".to_string(),
+            to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+        };
+        let alert = Alert {
+            kind: "job.failed",
+            title: "mote: job.failed",
+            message: "Job backup failed",
+        };
+        notifier.send(&alert).await.unwrap();
+
+        let session = server.await.unwrap();
+        assert!(session.contains(&format!(
+            "AUTH PLAIN {}\r\n",
+            BASE64_STANDARD.encode("\0mote\0secret")
+        )));
+        assert!(session.contains("MAIL FROM:<mote@example.com>\r\n"));
+        assert!(session.contains("RCPT TO:<a@example.com>\r\nRCPT TO:<b@example.com>\r\n"));
+        assert!(session.contains("Subject: mote: job.failed\r\n"));
+        assert!(session.ends_with(".\r\nQUIT\r\n"));
+    }
+
+    /// Lines of the email are not replied to until the final dot.
+    fn continue_or_ok(session: &str) -> &'static [u8] {
+        if session.contains("DATA\r\n") {
+            b""
+        } else {
+            b"250 OK\r\n"
+        }
+    }
+
+    #[test]
+    fn test_notifier_from_config() {
+        let mut config = NotifyConfig::from_env();
+        config.channels = vec![];
+        assert!(notifier_from_config(&config).is_none());
+
+        config.channels = vec!["ntfy".to_string()];
+        config.ntfy_url = "https://ntfy.sh".to_string();
+        assert!(notifier_from_config(&config).is_none());
+        config.ntfy_url = "https://ntfy.sh/mote-alerts".to_string();
+        assert_eq!(
+            config.ntfy_topic(),
+            Some(("https://ntfy.sh", "mote-alerts"))
+        );
+        assert!(notifier_from_config(&config).is_some());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::OcrConfig;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Recognizes the text in an image, so that screenshots can be searched.
+pub trait OcrEngine: Send + Sync {
+    fn recognize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<String>>;
+}
+
+/// Creates the OCR engine selected by the config, or `None` if OCR is disabled.
+/// An image taking longer than the timeout to recognize fails, so that it does not hold up indexing.
+pub fn ocr_engine_from_config(config: &OcrConfig) -> Option<Arc<dyn OcrEngine>> {
+    let timeout = Duration::from_secs(config.timeout_secs);
+    match config.provider.as_str() {
+        "tesseract" => Some(Arc::new(TesseractOcr {
+            command: config.tesseract_path.clone(),
+            languages: config.languages.clone(),
+            timeout,
+        })),
+        "http" => Some(Arc::new(HttpOcr {
+            client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
+            url: config.api_url.clone(),
+            api_key: config.api_key.clone(),
+        })),
+        _ => None,
+    }
+}
+
+/// Runs the `tesseract` binary, which prints the recognized text to stdout.
+pub struct TesseractOcr {
+    command: String,
+    languages: String,
+    timeout: Duration,
+}
+
+impl OcrEngine for TesseractOcr {
+    fn recognize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            // The process is killed when the timeout drops it
+            let output = Command::new(&self.command)
+                .arg(path)
+                .arg("stdout")
+                .args(["-l", &self.languages])
+                .kill_on_drop(true)
+                .output();
+            let output = tokio::time::timeout(self.timeout, output)
+                .await
+                .map_err(|_| anyhow!("tesseract timed out after {:?}", self.timeout))?
+                .context("Cannot run tesseract")?;
+
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "tesseract failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+    }
+}
+
+/// Posts the raw image to an external OCR service, which responds with `{"text": "..."}`.
+pub struct HttpOcr {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpOcrResponse {
+    text: String,
+}
+
+impl OcrEngine for HttpOcr {
+    fn recognize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let bytes = tokio::fs::read(path).await?;
+
+            let mut request = self.client.post(&self.url).body(bytes);
+            if let Some(ref key) = self.api_key {
+                request = request.bearer_auth(key);
+            }
+
+            let response: HttpOcrResponse = request
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .context("Invalid response of OCR service")?;
+            Ok(response.text)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves one request with the given response, returns the request received
+    async fn serve_once(listener: TcpListener, response: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.parse().unwrap());
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    fn http_ocr(url: String, timeout: Duration) -> HttpOcr {
+        HttpOcr {
+            client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
+            url,
+            api_key: Some("secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_ocr() {
+        let image = std::env::temp_dir().join(format!("mote-ocr-{}.png", uuid::Uuid::new_v4()));
+        std::fs::write(&image, b"image").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ocr", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(
+            listener,
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 17\r\n\r\n{\"text\":\"hello\"}\n",
+        ));
+        let ocr = http_ocr(url, Duration::from_secs(5));
+        assert_eq!(ocr.recognize(&image).await.unwrap(), "hello");
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /ocr "));
+        assert!(request.contains("authorization: Bearer secret\r\n"));
+        assert!(request.ends_with("\r\n\r\nimage"));
+
+        // A service which never responds times out
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ocr", listener.local_addr().unwrap());
+        let ocr = http_ocr(url, Duration::from_millis(200));
+        let rv = tokio::time::timeout(Duration::from_secs(5), ocr.recognize(&image)).await;
+        assert!(rv.expect("the request should time out").is_err());
+        drop(listener);
+
+        std::fs::remove_file(&image).ok();
+    }
+
+    #[tokio::test]
+    async fn test_tesseract_ocr() {
+        let missing = TesseractOcr {
+            command: "/nonexistent/tesseract".to_string(),
+            languages: "eng".to_string(),
+            timeout: Duration::from_secs(5),
+        };
+        let err = missing.recognize(Path::new("a.png")).await.unwrap_err();
+        assert!(err.to_string().contains("Cannot run tesseract"));
+
+        // A command which hangs is killed, `sh` runs the "image" as a script
+        let script = std::env::temp_dir().join(format!("mote-ocr-{}.sh", uuid::Uuid::new_v4()));
+        std::fs::write(&script, "sleep 30\n").unwrap();
+        let hanging = TesseractOcr {
+            command: "sh".to_string(),
+            languages: "eng".to_string(),
+            timeout: Duration::from_millis(200),
+        };
+        let started = std::time::Instant::now();
+        let err = hanging.recognize(&script).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        std::fs::remove_file(&script).ok();
+    }
+}
@@ -0,0 +1,301 @@
+use crate::config::db::DB;
+use crate::model::admin::{MissingFile, OrphanFile, OrphanReport};
+use crate::model::file::StoredFile;
+use crate::model::post::{FileInfo, Post};
+use crate::model::tag::Tag;
+use crate::service::upload_service::{variant_source, FileUploadService};
+use anyhow::{Context, Result};
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+/// Compares the attachments of the posts with the files in the upload directory, and finds the unused tags.
+pub async fn find_orphans(
+    pool: &SqlitePool,
+    upload_service: &FileUploadService,
+) -> Result<OrphanReport> {
+    let disk_files = list_files(upload_service.file_path("")).await?;
+    let post_files = Post::get_all_files(pool).await?;
+    let report = compare(upload_service, disk_files, &post_files);
+
+    // Images extracted from the content are linked from it, and so are their thumbnails
+    let mut files = Vec::with_capacity(report.files.len());
+    for file in report.files {
+        let name = variant_source(&file.filename)
+            .or_else(|| file.filename.strip_prefix("thumb_"))
+            .unwrap_or(&file.filename);
+        if !Post::is_file_linked(pool, name).await? {
+            files.push(file);
+        }
+    }
+
+    Ok(OrphanReport {
+        files,
+        empty_tags: Tag::find_empty(pool).await?,
+        ..report
+    })
+}
+
+/// Deletes the files in the upload directory which are neither attached to a post nor linked from one,
+/// and were modified before `before`, so that a file being attached is kept. A thumbnail is deleted
+/// once its file is. Returns the deleted files, or those which would be deleted with `dry_run`.
+pub async fn prune_uploads(
+    db: &DB,
+    upload_service: &FileUploadService,
+    before: i64,
+    dry_run: bool,
+) -> Result<Vec<OrphanFile>> {
+    let mut files = find_orphans(db, upload_service).await?.files;
+    files.retain(|file| file.modified_at < before);
+    if dry_run {
+        return Ok(files);
+    }
+
+    for file in files.iter() {
+        match fs::remove_file(upload_service.file_path(&file.filename)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Cannot delete {}", file.filename));
+            }
+            _ => {}
+        }
+        StoredFile::delete_by_filename(db, &file.filename).await?;
+    }
+    Ok(files)
+}
+
+/// Lists the regular files in the upload directory, hidden files are skipped.
+async fn list_files(dir: impl AsRef<std::path::Path>) -> Result<Vec<OrphanFile>> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .context("Cannot read the upload directory")?;
+    let mut files = vec![];
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        if !metadata.is_file() || filename.starts_with('.') {
+            continue;
+        }
+
+        let modified_at = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        files.push(OrphanFile {
+            filename,
+            size: metadata.len(),
+            modified_at,
+        });
+    }
+    Ok(files)
+}
+
+/// A file is referenced by the url or the thumbnail url of an attachment.
+/// Only the files are checked for missing attachments, since thumbnails are generated after the upload.
+fn compare(
+    upload_service: &FileUploadService,
+    mut disk_files: Vec<OrphanFile>,
+    post_files: &[(i64, Vec<FileInfo>)],
+) -> OrphanReport {
+    let on_disk: HashSet<String> = disk_files.iter().map(|f| f.filename.clone()).collect();
+    let mut referenced = HashSet::new();
+    let mut missing_files = vec![];
+
+    for (post_id, files) in post_files {
+        for file in files {
+            if let Some(filename) = upload_service.filename_from_url(&file.url) {
+                referenced.insert(filename);
+                if !on_disk.contains(filename) {
+                    missing_files.push(MissingFile {
+                        post_id: *post_id,
+                        url: file.url.clone(),
+                    });
+                }
+            }
+            if let Some(thumb) = file.thumb_url.as_deref() {
+                referenced.extend(upload_service.filename_from_url(thumb));
+            }
+            for variant in file.variants.iter() {
+                referenced.extend(upload_service.filename_from_url(&variant.url));
+            }
+        }
+    }
+
+    disk_files.retain(|f| !referenced.contains(f.filename.as_str()));
+    disk_files.sort_by(|a, b| a.filename.cmp(&b.filename));
+    OrphanReport {
+        files: disk_files,
+        missing_files,
+        empty_tags: vec![],
+    }
+}
+
+/// Writes the report as a CSV with the columns `kind,name,post_id,size`.
+pub fn to_csv(report: &OrphanReport) -> String {
+    let mut csv = String::from("kind,name,post_id,size\n");
+
+    for file in &report.files {
+        csv += &format!("file,{},,{}\n", csv_field(&file.filename), file.size);
+    }
+    for file in &report.missing_files {
+        csv += &format!("missing_file,{},{},\n", csv_field(&file.url), file.post_id);
+    }
+    for tag in &report.empty_tags {
+        csv += &format!("empty_tag,{},,\n", csv_field(tag));
+    }
+    csv
+}
+
+/// Quotes a field containing a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DBConfig, UploadConfig};
+    use uuid::Uuid;
+
+    fn file(url: &str, thumb_url: Option<&str>) -> FileInfo {
+        FileInfo {
+            url: url.to_string(),
+            thumb_url: thumb_url.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut config = UploadConfig::from_env();
+        config.base_url = "/uploads".to_string();
+        let upload_service = FileUploadService::new(config);
+
+        let disk_file = |name: &str| OrphanFile {
+            filename: name.to_string(),
+            size: 1,
+            modified_at: 0,
+        };
+        let disk_files = ["a.jpg", "thumb_a.jpg", "b.pdf", "thumb_c.jpg", "c.jpg"]
+            .map(disk_file)
+            .into();
+        let post_files = vec![
+            (
+                1,
+                vec![file("/uploads/a.jpg", Some("/uploads/thumb_a.jpg"))],
+            ),
+            (
+                2,
+                vec![
+                    file("/uploads/c.jpg", None),
+                    file("/uploads/gone.png", Some("/uploads/thumb_gone.png")),
+                    file("https://example.com/x.png", None),
+                ],
+            ),
+        ];
+
+        let report = compare(&upload_service, disk_files, &post_files);
+        let names: Vec<_> = report.files.iter().map(|f| f.filename.as_str()).collect();
+        assert_eq!(names, ["b.pdf", "thumb_c.jpg"]);
+        assert_eq!(
+            report.missing_files,
+            [MissingFile {
+                post_id: 2,
+                url: "/uploads/gone.png".to_string()
+            }]
+        );
+
+        let report = OrphanReport {
+            empty_tags: vec!["a,b".to_string()],
+            ..report
+        };
+        assert_eq!(
+            to_csv(&report),
+            "kind,name,post_id,size\nfile,b.pdf,,1\nfile,thumb_c.jpg,,1\n\
+             missing_file,/uploads/gone.png,2,\nempty_tag,\"a,b\",,\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune_uploads() {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+
+        let mut config = UploadConfig::from_env();
+        config.base_url = "/uploads".to_string();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-prune-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&config.base_path).await.unwrap();
+        let upload_service = FileUploadService::new(config.clone());
+        for name in [
+            "a.jpg",
+            "thumb_a.jpg",
+            "b.pdf",
+            "c.png",
+            "thumb_c.png",
+            "d.txt",
+        ] {
+            fs::write(upload_service.file_path(name), b"x")
+                .await
+                .unwrap();
+        }
+
+        // One file is attached, another one is linked from the content
+        let files = serde_json::to_string(&[file("a.jpg", Some("thumb_a.jpg"))]).unwrap();
+        sqlx::query(
+            "INSERT INTO posts (content, files, created_at, updated_at) VALUES (?, ?, 0, 0)",
+        )
+        .bind(r#"<p><img src="/uploads/c.png"></p>"#)
+        .bind(files)
+        .execute(&db.writer)
+        .await
+        .unwrap();
+
+        // The files modified after the given time are kept
+        let pruned = prune_uploads(&db, &upload_service, 0, false).await.unwrap();
+        assert!(pruned.is_empty());
+
+        let before = i64::MAX;
+        let names = |files: Vec<OrphanFile>| -> Vec<String> {
+            files.into_iter().map(|f| f.filename).collect()
+        };
+        let pruned = prune_uploads(&db, &upload_service, before, true)
+            .await
+            .unwrap();
+        assert_eq!(names(pruned), ["b.pdf", "d.txt"]);
+        assert!(upload_service.file_path("b.pdf").exists());
+
+        let pruned = prune_uploads(&db, &upload_service, before, false)
+            .await
+            .unwrap();
+        assert_eq!(names(pruned), ["b.pdf", "d.txt"]);
+        assert!(!upload_service.file_path("b.pdf").exists());
+        assert!(upload_service.file_path("thumb_c.png").exists());
+        assert!(prune_uploads(&db, &upload_service, before, false)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&config.base_path).await.unwrap();
+    }
+}
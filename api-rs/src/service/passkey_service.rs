@@ -0,0 +1,448 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::model::passkey::Passkey;
+use crate::util::cbor::{self, Value};
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, SqlitePool};
+
+impl Passkey {
+    pub async fn create(
+        db: &DB,
+        user_id: i64,
+        credential_id: &str,
+        name: &str,
+        public_key: &[u8],
+        sign_count: u32,
+    ) -> ApiResult<Passkey> {
+        let now = Utc::now().timestamp_millis();
+        let passkey = query_as!(
+            Passkey,
+            r#"
+            INSERT INTO passkeys (user_id, credential_id, name, public_key, sign_count, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            RETURNING id, user_id, credential_id, name, public_key, sign_count, created_at, last_used_at
+            "#,
+            user_id,
+            credential_id,
+            name,
+            public_key,
+            sign_count,
+            now
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(passkey)
+    }
+
+    pub async fn find_all(pool: &SqlitePool, user_id: i64) -> ApiResult<Vec<Passkey>> {
+        let passkeys = query_as!(
+            Passkey,
+            r#"
+            SELECT id AS "id!", user_id, credential_id, name, public_key, sign_count, created_at, last_used_at
+            FROM passkeys
+            WHERE user_id = ?
+            ORDER BY id
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(passkeys)
+    }
+
+    pub async fn find_by_credential_id(
+        pool: &SqlitePool,
+        credential_id: &str,
+    ) -> ApiResult<Option<Passkey>> {
+        let passkey = query_as!(
+            Passkey,
+            r#"
+            SELECT id AS "id!", user_id, credential_id, name, public_key, sign_count, created_at, last_used_at
+            FROM passkeys
+            WHERE credential_id = ?
+            "#,
+            credential_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(passkey)
+    }
+
+    /// Records a login with the new signature counter.
+    pub async fn mark_used(db: &DB, id: i64, sign_count: u32) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        query!(
+            "UPDATE passkeys SET sign_count = ?, last_used_at = ? WHERE id = ?",
+            sign_count,
+            now,
+            id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes a passkey of a user, returns whether it exists.
+    pub async fn delete(db: &DB, user_id: i64, id: i64) -> ApiResult<bool> {
+        let result = query!(
+            "DELETE FROM passkeys WHERE id = ? AND user_id = ?",
+            id,
+            user_id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Who the passkeys are for, see `AuthConfig::passkey_rp_id`
+pub struct RelyingParty<'a> {
+    pub id: &'a str,
+    pub origins: &'a [String],
+}
+
+/// A passkey which passed the checks of a registration
+#[derive(Debug)]
+pub struct NewPasskey {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Authenticator data flags, see https://www.w3.org/TR/webauthn-2/#flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Decodes base64url, with or without padding, as browsers and libraries differ.
+pub fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(text.trim_end_matches('='))
+        .map_err(|_| anyhow!("Invalid base64url data"))
+}
+
+pub fn encode_base64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Checks the client data of a ceremony, `take_challenge` consumes the challenge
+/// so that a response cannot be replayed.
+fn check_client_data(
+    rp: &RelyingParty,
+    json: &[u8],
+    kind: &str,
+    take_challenge: impl FnOnce(&str) -> bool,
+) -> Result<()> {
+    let client: ClientData = serde_json::from_slice(json)?;
+    if client.kind != kind {
+        bail!("Unexpected ceremony: {}", client.kind);
+    }
+    if !take_challenge(client.challenge.trim_end_matches('=')) {
+        bail!("Unknown or expired challenge");
+    }
+    if !rp.origins.contains(&client.origin) {
+        bail!("Unexpected origin: {}", client.origin);
+    }
+    Ok(())
+}
+
+/// Checks the authenticator data which is common to both ceremonies,
+/// returns the flags and the signature counter.
+fn check_authenticator_data(rp: &RelyingParty, data: &[u8]) -> Result<(u8, u32)> {
+    if data.len() < 37 {
+        bail!("Authenticator data is too short");
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        bail!("The passkey is for another site");
+    }
+    let flags = data[32];
+    // A passkey replaces the password, so the user must be verified with a PIN or biometrics
+    if flags & USER_PRESENT == 0 || flags & USER_VERIFIED == 0 {
+        bail!("The user is not verified");
+    }
+    let sign_count = u32::from_be_bytes(data[33..37].try_into()?);
+    Ok((flags, sign_count))
+}
+
+/// Verifies the response of `navigator.credentials.create()`. Attestation statements are not checked,
+/// passkeys are trusted on first use as in most sites.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+    take_challenge: impl FnOnce(&str) -> bool,
+) -> Result<NewPasskey> {
+    check_client_data(rp, client_data_json, "webauthn.create", take_challenge)?;
+
+    let (attestation, _) = cbor::decode(attestation_object)?;
+    let data = attestation
+        .get_text("authData")
+        .and_then(Value::as_bytes)
+        .ok_or_else(|| anyhow!("Missing authenticator data"))?;
+    let (flags, sign_count) = check_authenticator_data(rp, data)?;
+    if flags & ATTESTED_CREDENTIAL == 0 {
+        bail!("Missing credential");
+    }
+
+    // The aaguid of 16 bytes, the length of the credential id, the credential id and the public key
+    let rest = data
+        .get(37 + 16..)
+        .filter(|rest| rest.len() >= 2)
+        .ok_or_else(|| anyhow!("Authenticator data is too short"))?;
+    let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    let credential_id = rest
+        .get(2..2 + id_len)
+        .ok_or_else(|| anyhow!("Authenticator data is too short"))?;
+    let key = &rest[2 + id_len..];
+    let (_, key_len) = cbor::decode(key)?;
+    let public_key = key[..key_len].to_vec();
+    PublicKey::parse(&public_key)?;
+
+    Ok(NewPasskey {
+        credential_id: encode_base64(credential_id),
+        public_key,
+        sign_count,
+    })
+}
+
+/// Verifies the response of `navigator.credentials.get()` against the stored passkey,
+/// returns the new signature counter.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    passkey: &Passkey,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    take_challenge: impl FnOnce(&str) -> bool,
+) -> Result<u32> {
+    check_client_data(rp, client_data_json, "webauthn.get", take_challenge)?;
+    let (_, sign_count) = check_authenticator_data(rp, authenticator_data)?;
+
+    // Authenticators which do not count signatures always send 0
+    if (sign_count != 0 || passkey.sign_count != 0) && sign_count as i64 <= passkey.sign_count {
+        bail!("The signature counter did not increase, the passkey may be cloned");
+    }
+
+    let message = [authenticator_data, &Sha256::digest(client_data_json)].concat();
+    PublicKey::parse(&passkey.public_key)?.verify(&message, signature)?;
+    Ok(sign_count)
+}
+
+// COSE algorithms, key types and curves, from the IANA registry
+const COSE_ES256: i128 = -7;
+const COSE_EDDSA: i128 = -8;
+const COSE_RS256: i128 = -257;
+const COSE_KTY_OKP: i128 = 1;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_KTY_RSA: i128 = 3;
+const COSE_CRV_P256: i128 = 1;
+const COSE_CRV_ED25519: i128 = 6;
+
+/// A COSE public key of the algorithms browsers ask for by default
+enum PublicKey {
+    /// ES256, an uncompressed P-256 point
+    Es256(Vec<u8>),
+    /// RS256
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+    /// EdDSA with Ed25519
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    fn parse(cose: &[u8]) -> Result<Self> {
+        let (key, _) = cbor::decode(cose)?;
+        let bytes = |label: i128| {
+            key.get_int(label)
+                .and_then(Value::as_bytes)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| anyhow!("Missing key parameter {}", label))
+        };
+
+        let int = |label: i128| key.get_int(label).and_then(Value::as_int);
+
+        // The key type (1) and the curve (-1) must be those of the algorithm (3)
+        match (int(3), int(1)) {
+            (Some(COSE_ES256), Some(COSE_KTY_EC2)) => {
+                if int(-1) != Some(COSE_CRV_P256) {
+                    bail!("Unsupported curve of ES256 key: {:?}", int(-1));
+                }
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    bail!("Invalid P-256 key");
+                }
+                Ok(PublicKey::Es256([&[4u8][..], &x, &y].concat()))
+            }
+            (Some(COSE_RS256), Some(COSE_KTY_RSA)) => Ok(PublicKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            (Some(COSE_EDDSA), Some(COSE_KTY_OKP)) => {
+                if int(-1) != Some(COSE_CRV_ED25519) {
+                    bail!("Unsupported curve of EdDSA key: {:?}", int(-1));
+                }
+                let x = bytes(-2)?;
+                if x.len() != 32 {
+                    bail!("Invalid Ed25519 key");
+                }
+                Ok(PublicKey::Ed25519(x))
+            }
+            (alg, kty) => bail!("Unsupported key algorithm {:?} of type {:?}", alg, kty),
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> Result<()> {
+        match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, sig)
+            }
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                message,
+                sig,
+            ),
+            PublicKey::Ed25519(key) => {
+                UnparsedPublicKey::new(&signature::ED25519, key).verify(message, sig)
+            }
+        }
+        .map_err(|_| anyhow!("Invalid signature"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    const CHALLENGE: &str = "c2VjcmV0";
+
+    fn client_data(kind: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"https://example.com"}}"#,
+            kind, CHALLENGE
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_ceremonies() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = pair.public_key().as_ref();
+
+        // {1: 2, 3: -7, -1: 1, -2: x, -3: y}
+        let cose = [
+            &[0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21, 0x58, 0x20][..],
+            &point[1..33],
+            &[0x22, 0x58, 0x20],
+            &point[33..],
+        ]
+        .concat();
+        let rp_hash = Sha256::digest(b"example.com");
+        let auth_data = [
+            &rp_hash[..],
+            &[
+                USER_PRESENT | USER_VERIFIED | ATTESTED_CREDENTIAL,
+                0,
+                0,
+                0,
+                0,
+            ],
+            &[0; 16],
+            &[0, 4, 1, 2, 3, 4],
+            &cose,
+        ]
+        .concat();
+        // {"fmt": "none", "attStmt": {}, "authData": auth_data}
+        let attestation = [
+            &[0xa3, 0x63][..],
+            b"fmt",
+            &[0x64],
+            b"none",
+            &[0x67],
+            b"attStmt",
+            &[0xa0, 0x68],
+            b"authData",
+            &[0x59],
+            &(auth_data.len() as u16).to_be_bytes(),
+            &auth_data,
+        ]
+        .concat();
+
+        assert!(PublicKey::parse(&cose).is_ok());
+        // The same key with the P-384 curve, or as an OKP key, is rejected
+        let mut other_curve = cose.clone();
+        other_curve[6] = 0x02;
+        assert!(PublicKey::parse(&other_curve).is_err());
+        let mut other_type = cose.clone();
+        other_type[2] = 0x01;
+        assert!(PublicKey::parse(&other_type).is_err());
+
+        let origins = vec!["https://example.com".to_string()];
+        let rp = RelyingParty {
+            id: "example.com",
+            origins: &origins,
+        };
+        let created = client_data("webauthn.create");
+        let new = verify_registration(&rp, &created, &attestation, |c| c == CHALLENGE).unwrap();
+        assert_eq!(decode_base64(&new.credential_id).unwrap(), [1, 2, 3, 4]);
+        assert!(verify_registration(&rp, &created, &attestation, |_| false).is_err());
+
+        let other = RelyingParty {
+            id: "example.org",
+            origins: &origins,
+        };
+        assert!(verify_registration(&other, &created, &attestation, |_| true).is_err());
+
+        let passkey = Passkey {
+            id: 1,
+            user_id: 1,
+            credential_id: new.credential_id,
+            name: "test".to_string(),
+            public_key: new.public_key,
+            sign_count: 0,
+            created_at: 0,
+            last_used_at: None,
+        };
+        let auth_data = [&rp_hash[..], &[USER_PRESENT | USER_VERIFIED, 0, 0, 0, 7]].concat();
+        let got = client_data("webauthn.get");
+        let message = [&auth_data[..], &Sha256::digest(&got)].concat();
+        let sig = pair.sign(&rng, &message).unwrap();
+
+        let count =
+            verify_assertion(&rp, &passkey, &got, &auth_data, sig.as_ref(), |_| true).unwrap();
+        assert_eq!(count, 7);
+        assert!(
+            verify_assertion(&rp, &passkey, &created, &auth_data, sig.as_ref(), |_| true).is_err()
+        );
+        assert!(verify_assertion(&rp, &passkey, &got, &auth_data, &[0; 64], |_| true).is_err());
+
+        let used = Passkey {
+            sign_count: 7,
+            ..passkey
+        };
+        assert!(verify_assertion(&rp, &used, &got, &auth_data, sig.as_ref(), |_| true).is_err());
+    }
+}
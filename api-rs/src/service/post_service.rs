@@ -1,16 +1,50 @@
-use crate::errors::{ApiError, ApiResult};
+use crate::config::db::DB;
+use crate::errors::{bad_request, not_found, ApiError, ApiResult};
+use crate::model::admin::LargePost;
 use crate::model::post::{
-    CreatePostRequest, CreateResponse, FilterPostRequest, Post, PostRow, UpdatePostRequest,
+    CategoryColor, CreatePostRequest, CreateResponse, FileInfo, FilterPostRequest, Post,
+    PostInclude, PostMeta, PostRow, SortingField, ThreadPost, TrashStats, UpdatePostRequest,
 };
 use crate::model::tag::Tag;
+use crate::service::event_service::{emit, AppEvent};
+use crate::service::search_service::DocFilter;
+use crate::service::url_service::UrlResolver;
+use crate::util::html::{derive_title, display_title};
+use crate::util::markdown::{self, html_to_markdown};
+use crate::util::maybe::MaybeAbsent;
 use chrono::{DateTime, FixedOffset, Utc};
+use futures::future::BoxFuture;
+use futures::stream::{self, Stream, TryStreamExt};
 use regex::Regex;
-use sqlx::{query, query_as, QueryBuilder, Sqlite, SqlitePool, Transaction};
-use std::collections::{HashMap, HashSet};
+use sqlx::{
+    query, query_as, query_scalar, QueryBuilder, Sqlite, SqliteConnection, SqlitePool, Transaction,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::instrument;
+
+const STREAM_BATCH_SIZE: i64 = 200;
+/// The most ids bound to a query looking posts up by id
+const ID_CHUNK_SIZE: usize = 500;
+/// Walking up or down a thread stops this deep, in case the posts form a cycle
+const MAX_THREAD_WALK: i64 = 1000;
+
+/// A post with the time of its last activity, which is only read when the posts are ordered by it
+#[derive(sqlx::FromRow)]
+struct ActivityRow {
+    #[sqlx(flatten)]
+    row: PostRow,
+    #[sqlx(default)]
+    last_activity_at: Option<i64>,
+}
 
 impl Post {
-    pub async fn find_with_parent(pool: &SqlitePool, id: i64) -> ApiResult<Post> {
-        let row = Post::find_by_id(pool, id).await?.ok_or(post_not_found())?;
+    /// Finds an undeleted post of a user, with its parent.
+    #[instrument(skip(pool))]
+    pub async fn find_with_parent(pool: &SqlitePool, user_id: i64, id: i64) -> ApiResult<Post> {
+        let row = Post::find_by_id(pool, id)
+            .await?
+            .filter(|row| row.user_id == user_id)
+            .ok_or(post_not_found())?;
         let mut post = Post::from(row);
 
         if let Some(parent_id) = post.row.parent_id {
@@ -24,6 +58,7 @@ impl Post {
         Ok(post)
     }
 
+    #[instrument(skip(pool))]
     pub async fn find_by_id(pool: &SqlitePool, id: i64) -> ApiResult<Option<PostRow>> {
         Ok(sqlx::query_as!(
             PostRow,
@@ -34,30 +69,158 @@ impl Post {
         .await?)
     }
 
-    pub async fn find_by_ids(pool: &SqlitePool, ids: &[i64]) -> ApiResult<Vec<Post>> {
-        let ids = serde_json::to_string(&ids).unwrap();
-        let rows = sqlx::query_as!(
+    /// Finds a post of a user in the trash, which can be deleted permanently.
+    #[instrument(skip(pool))]
+    pub async fn find_trashed(
+        pool: &SqlitePool,
+        user_id: i64,
+        id: i64,
+    ) -> ApiResult<Option<PostRow>> {
+        Ok(sqlx::query_as!(
             PostRow,
-            r#"
-            SELECT *
-            FROM posts
-            WHERE id IN (SELECT value FROM json_each(?1))
-            AND deleted_at IS NULL
-            "#,
-            ids,
+            "SELECT * FROM posts WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+            id,
+            user_id
         )
-        .fetch_all(pool)
-        .await?;
+        .fetch_optional(pool)
+        .await?)
+    }
+
+    /// Finds the undeleted posts of a user, the ids of other posts are skipped.
+    #[instrument(skip_all, fields(count = ids.len()))]
+    pub async fn find_by_ids(pool: &SqlitePool, user_id: i64, ids: &[i64]) -> ApiResult<Vec<Post>> {
+        let rows = Self::find_rows_by_ids(pool, user_id, ids).await?;
 
         // Convert rows to Post structs
         let mut posts: Vec<Post> = rows.into_iter().map(Post::from).collect();
 
-        Self::attach_parents(pool, &mut posts).await?;
+        Self::attach_parents(pool, user_id, &mut posts).await?;
         Self::attach_tags(pool, &mut posts).await?;
 
         Ok(posts)
     }
 
+    /// Like `find_by_ids`, but without the parents and tags.
+    /// Many ids are looked up in chunks, so that a query never binds a huge list.
+    #[instrument(skip_all, fields(count = ids.len(), chunks = ids.len().div_ceil(ID_CHUNK_SIZE)))]
+    pub async fn find_rows_by_ids(
+        pool: &SqlitePool,
+        user_id: i64,
+        ids: &[i64],
+    ) -> ApiResult<Vec<PostRow>> {
+        let mut rows = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let ids = serde_json::to_string(chunk).unwrap();
+            let chunk_rows = sqlx::query_as!(
+                PostRow,
+                r#"
+                SELECT *
+                FROM posts
+                WHERE id IN (SELECT value FROM json_each(?1))
+                AND user_id = ?2 AND deleted_at IS NULL
+                "#,
+                ids,
+                user_id,
+            )
+            .fetch_all(pool)
+            .await?;
+            rows.extend(chunk_rows);
+        }
+
+        Ok(rows)
+    }
+
+    /// Streams all posts of a user, or with `since` the posts created, updated or deleted after it,
+    /// including the deleted ones so that clients can remove them.
+    /// Posts are read in batches by id, so that neither all posts are held in memory,
+    /// nor a read transaction is kept open while a slow client downloads them.
+    pub fn stream_all(
+        pool: SqlitePool,
+        user_id: i64,
+        since: Option<i64>,
+    ) -> impl Stream<Item = ApiResult<Post>> + Send + 'static {
+        stream::try_unfold(Some(0), move |last_id| {
+            let pool = pool.clone();
+            async move {
+                let Some(last_id) = last_id else {
+                    return Ok::<_, ApiError>(None);
+                };
+
+                let rows = sqlx::query_as!(
+                    PostRow,
+                    r#"
+                    SELECT *
+                    FROM posts
+                    WHERE id > ?1 AND user_id = ?4
+                    AND CASE WHEN ?2 IS NULL
+                        THEN deleted_at IS NULL
+                        ELSE updated_at > ?2 OR deleted_at > ?2
+                    END
+                    ORDER BY id
+                    LIMIT ?3
+                    "#,
+                    last_id,
+                    since,
+                    STREAM_BATCH_SIZE,
+                    user_id,
+                )
+                .fetch_all(&pool)
+                .await?;
+
+                if rows.is_empty() {
+                    return Ok(None);
+                }
+                // A short batch is the last one
+                let next_id =
+                    (rows.len() as i64 == STREAM_BATCH_SIZE).then(|| rows.last().unwrap().id);
+
+                let mut posts: Vec<Post> = rows.into_iter().map(Post::from).collect();
+                Self::attach_tags(&pool, &mut posts).await?;
+
+                Ok(Some((stream::iter(posts.into_iter().map(Ok)), next_id)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Orders the ids by the creation time of their posts, or the update time if `updated`,
+    /// the newest first. The ids of deleted posts are dropped.
+    #[instrument(skip_all, fields(count = ids.len()))]
+    pub async fn sort_ids_by_time(
+        pool: &SqlitePool,
+        user_id: i64,
+        ids: &[i64],
+        updated: bool,
+    ) -> ApiResult<Vec<i64>> {
+        let mut rows = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let ids = serde_json::to_string(chunk).unwrap();
+            let chunk_rows = sqlx::query!(
+                r#"
+                SELECT id, created_at, updated_at
+                FROM posts
+                WHERE id IN (SELECT value FROM json_each(?1))
+                AND user_id = ?2 AND deleted_at IS NULL
+                "#,
+                ids,
+                user_id,
+            )
+            .fetch_all(pool)
+            .await?;
+            rows.extend(chunk_rows);
+        }
+
+        rows.sort_by_key(|row| {
+            let time = if updated {
+                row.updated_at
+            } else {
+                row.created_at
+            };
+            std::cmp::Reverse((time, row.id))
+        });
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
     #[allow(dead_code)]
     pub async fn find_children(pool: &SqlitePool, parent_id: i64) -> ApiResult<Vec<PostRow>> {
         Ok(sqlx::query_as!(
@@ -69,13 +232,14 @@ impl Post {
         .await?)
     }
 
-    pub async fn get_count(pool: &SqlitePool) -> ApiResult<i64> {
+    pub async fn get_count(pool: &SqlitePool, user_id: i64) -> ApiResult<i64> {
         let result = sqlx::query!(
             r#"
             SELECT COUNT(*) as count
             FROM posts
-            WHERE deleted_at IS NULL
-            "#
+            WHERE user_id = ? AND deleted_at IS NULL
+            "#,
+            user_id
         )
         .fetch_one(pool)
         .await?;
@@ -83,13 +247,14 @@ impl Post {
         Ok(result.count)
     }
 
-    pub async fn get_active_days(pool: &SqlitePool) -> ApiResult<i64> {
+    pub async fn get_active_days(pool: &SqlitePool, user_id: i64) -> ApiResult<i64> {
         let result = sqlx::query!(
             r#"
             SELECT COUNT(DISTINCT date(created_at / 1000, 'unixepoch')) as count
             FROM posts
-            WHERE deleted_at IS NULL
-            "#
+            WHERE user_id = ? AND deleted_at IS NULL
+            "#,
+            user_id
         )
         .fetch_one(pool)
         .await?;
@@ -97,9 +262,33 @@ impl Post {
         Ok(result.count)
     }
 
+    /// Get the number of posts of each color
+    pub async fn get_color_counts(
+        pool: &SqlitePool,
+        user_id: i64,
+    ) -> ApiResult<BTreeMap<String, i64>> {
+        let counts = sqlx::query!(
+            r#"
+            SELECT color as "color!", COUNT(*) as "count!: i64"
+            FROM posts
+            WHERE user_id = ? AND deleted_at IS NULL AND color IS NOT NULL
+            GROUP BY color
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.color, row.count))
+        .collect();
+
+        Ok(counts)
+    }
+
     /// Get daily post counts within a date range
     pub async fn get_daily_counts(
         pool: &SqlitePool,
+        user_id: i64,
         start_date: DateTime<FixedOffset>,
         end_date: DateTime<FixedOffset>,
     ) -> ApiResult<Vec<i64>> {
@@ -111,22 +300,23 @@ impl Post {
         // Query daily counts
         let counts: HashMap<i64, i64> = sqlx::query!(
             r#"
-            SELECT (created_at + ?) / ? as local_day, COUNT(*) as count
+            SELECT (created_at + ?) / ? as "local_day!: i64", COUNT(*) as "count!: i64"
             FROM posts
-            WHERE deleted_at IS NULL
+            WHERE user_id = ? AND deleted_at IS NULL
                 AND created_at BETWEEN ? AND ?
-            GROUP BY local_day
-            ORDER BY local_day
+            GROUP BY 1
+            ORDER BY 1
             "#,
             offset_ms,
             day_ms,
+            user_id,
             start_ts,
             end_ts,
         )
         .fetch_all(pool)
         .await?
         .into_iter()
-        .map(|row| (row.local_day.unwrap(), row.count))
+        .map(|row| (row.local_day, row.count))
         .collect();
 
         // Calculate the start and end of local days
@@ -139,32 +329,108 @@ impl Post {
             .collect())
     }
 
+    #[instrument(skip_all)]
     pub async fn filter_posts(
         pool: &SqlitePool,
+        user_id: i64,
         options: &FilterPostRequest,
         per_page: i64,
     ) -> ApiResult<Vec<Post>> {
-        let mut builder = if options.tag.is_some() {
-            QueryBuilder::<Sqlite>::new(
-                r#"
-            SELECT DISTINCT p.* FROM posts p
-            INNER JOIN tag_post_assoc tp ON p.id = tp.post_id
-            INNER JOIN tags t ON tp.tag_id = t.id
-            "#,
-            )
-        } else {
-            QueryBuilder::<Sqlite>::new("SELECT p.* FROM posts p")
-        };
+        let mut posts = Self::filter_query("p.*", user_id, options, per_page)
+            .build_query_as::<ActivityRow>()
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| Post {
+                last_activity_at: row.last_activity_at,
+                ..Post::from(row.row)
+            })
+            .collect::<Vec<_>>();
+
+        if options.includes(PostInclude::Parent) {
+            Self::attach_parents(pool, user_id, &mut posts).await?;
+        }
+        if options.includes(PostInclude::Tags) {
+            Self::attach_tags(pool, &mut posts).await?;
+        }
+
+        Ok(posts)
+    }
+
+    /// Like `filter_posts`, but only reads the metadata columns.
+    #[instrument(skip_all)]
+    pub async fn filter_post_metas(
+        pool: &SqlitePool,
+        user_id: i64,
+        options: &FilterPostRequest,
+        per_page: i64,
+    ) -> ApiResult<Vec<PostMeta>> {
+        let columns = r#"
+            p.id, p.title, p.color, p.shared, p.deleted_at, p.created_at, p.updated_at,
+            p.parent_id, p.children_count,
+            COALESCE(json_array_length(p.files), 0) AS file_count
+        "#;
+        let mut posts = Self::filter_query(columns, user_id, options, per_page)
+            .build_query_as::<PostMeta>()
+            .fetch_all(pool)
+            .await?;
+
+        if options.includes(PostInclude::Tags) {
+            let ids: Vec<i64> = posts.iter().map(|post| post.id).collect();
+            let mut tags = Self::find_tags_of(pool, &ids).await?;
+            for post in posts.iter_mut() {
+                post.tags = tags.remove(&post.id).unwrap_or_default();
+            }
+        }
+
+        Ok(posts)
+    }
 
-        builder.push(" WHERE 1 = 1 ");
+    fn filter_query<'a>(
+        columns: &str,
+        user_id: i64,
+        options: &'a FilterPostRequest,
+        per_page: i64,
+    ) -> QueryBuilder<'a, Sqlite> {
+        let order_by = order_expression(&options.order_by);
+        let mut builder = QueryBuilder::<Sqlite>::new(match options.order_by {
+            SortingField::LastActivity => {
+                format!("SELECT {columns}, {order_by} AS last_activity_at FROM posts p")
+            }
+            _ => format!("SELECT {columns} FROM posts p"),
+        });
+
+        builder.push(" WHERE p.user_id = ").push_bind(user_id);
 
-        // Tag filter
+        // Tag filter, EXISTS needs no DISTINCT for posts with several matching tags
         if let Some(ref tag) = options.tag {
-            builder.push(" AND (t.name = ").push_bind(tag);
+            builder.push(
+                r#"
+                AND EXISTS (
+                    SELECT 1 FROM tag_post_assoc tp
+                    INNER JOIN tags t ON tp.tag_id = t.id
+                    WHERE tp.post_id = p.id AND (t.name = "#,
+            );
+            builder.push_bind(tag);
             builder
                 .push(" OR t.name LIKE ")
                 .push_bind(format!("{}/%", tag));
-            builder.push(" ) ");
+            builder.push(" )) ");
+        }
+
+        // Posts of hidden tags are only shown in the timelines of these tags
+        if options.tag.is_none() && !options.include_hidden {
+            builder.push(
+                r#"
+                AND NOT EXISTS (
+                    SELECT 1 FROM tag_post_assoc hp
+                    INNER JOIN tags ht ON hp.tag_id = ht.id
+                    INNER JOIN tags h ON h.hidden AND h.user_id = ht.user_id
+                        AND (ht.name = h.name OR ht.name LIKE h.name || '/%')
+                    WHERE hp.post_id = p.id
+                )
+                "#,
+            );
         }
 
         // Handle deleted filter
@@ -180,8 +446,13 @@ impl Post {
         }
 
         // Color filter
-        if let Some(ref color) = options.color {
-            builder.push(" AND p.color = ").push_bind(color.to_string());
+        if !options.color.is_empty() {
+            builder.push(" AND p.color IN (");
+            let mut separated = builder.separated(", ");
+            for color in &options.color {
+                separated.push_bind(color.to_string());
+            }
+            separated.push_unseparated(") ");
         }
 
         // Date range filters
@@ -207,43 +478,68 @@ impl Post {
             });
         }
 
-        let order_by = format!("p.{}", options.order_by);
+        // File type filter, the content types recorded on upload are preferred to those sent by clients
+        if let Some(file_type) = options.file_type {
+            builder.push(
+                r#"
+                AND EXISTS (
+                    SELECT 1 FROM json_each(p.files) j
+                    LEFT JOIN files f ON f.id = json_extract(j.value, '$.id')
+                    WHERE "#,
+            );
+            let mut separated = builder.separated(" OR ");
+            for pattern in file_type.content_type_patterns() {
+                separated
+                    .push("COALESCE(f.content_type, json_extract(j.value, '$.content_type')) LIKE ")
+                    .push_bind_unseparated(*pattern);
+            }
+            separated.push_unseparated(") ");
+        }
 
-        // Cursor based pagination
-        if let Some(cursor) = options.cursor {
-            builder
-                .push(format!(
-                    " AND {} {} ",
-                    order_by,
-                    if options.ascending { ">" } else { "<" }
-                ))
-                .push_bind(cursor);
+        // Cursor based pagination, posts with the same sort key are ordered by their ids
+        let operator = if options.ascending { ">" } else { "<" };
+        match (options.cursor, options.cursor_id) {
+            (Some(cursor), Some(cursor_id)) => {
+                builder
+                    .push(format!(" AND ({order_by}, p.id) {operator} ("))
+                    .push_bind(cursor)
+                    .push(", ")
+                    .push_bind(cursor_id)
+                    .push(") ");
+            }
+            (Some(cursor), None) => {
+                builder
+                    .push(format!(" AND {order_by} {operator} "))
+                    .push_bind(cursor);
+            }
+            _ => {}
         }
 
         let direction = if options.ascending { "ASC" } else { "DESC" };
 
-        builder.push(format!(" ORDER BY {order_by} {direction} LIMIT {per_page}"));
-
-        // Execute query and process results
-        let mut posts = builder
-            .build_query_as::<PostRow>()
-            .fetch_all(pool)
-            .await?
-            .into_iter()
-            .map(Post::from)
-            .collect::<Vec<_>>();
-
-        Self::attach_parents(pool, &mut posts).await?;
-        Self::attach_tags(pool, &mut posts).await?;
-
-        Ok(posts)
+        // The limit is bound, so that the statement is cached for any page size
+        builder.push(format!(
+            " ORDER BY {order_by} {direction}, p.id {direction} LIMIT "
+        ));
+        builder.push_bind(per_page);
+        builder
     }
 
-    pub async fn create(pool: &SqlitePool, post: &CreatePostRequest) -> ApiResult<CreateResponse> {
+    #[instrument(skip_all)]
+    pub async fn create(
+        db: &DB,
+        user_id: i64,
+        post: &CreatePostRequest,
+    ) -> ApiResult<CreateResponse> {
         let now = Utc::now().timestamp_millis();
+        // A post can be backdated, e.g. to the time when its photos were taken
+        let created_at = post.created_at.unwrap_or(now);
 
         // Start transaction
-        let mut tx = pool.begin().await?;
+        let mut tx = db.writer.begin().await?;
+        if let Some(parent_id) = post.parent_id {
+            Post::check_parent(&mut tx, user_id, parent_id).await?;
+        }
 
         let files = post
             .files
@@ -251,24 +547,30 @@ impl Post {
             .map(|files| serde_json::to_value(files).unwrap());
         let color = post.color.as_ref().map(|color| color.to_string());
         let shared = post.shared.unwrap_or(false);
+        let custom_title = custom_title(post.title.as_deref());
+        let title_custom = custom_title.is_some();
+        let title = custom_title.or_else(|| derive_title(&post.content));
 
         // Insert the post
         let result = sqlx::query!(
             r#"
         INSERT INTO posts (
             content, files, color, shared,
-            parent_id, created_at, updated_at, children_count
+            parent_id, created_at, updated_at, children_count, title, title_custom, user_id
         )
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
             post.content,
             files,
             color,
             shared,
             post.parent_id,
-            now,
+            created_at,
             now,
             0,
+            title,
+            title_custom,
+            user_id,
         )
         .execute(&mut *tx)
         .await?;
@@ -280,29 +582,54 @@ impl Post {
         let mut tags = Vec::new();
 
         for tag_name in hash_tags {
-            let tag = Tag::find_or_create(&mut tx, &tag_name).await?;
+            let tag = Tag::find_or_create(&mut tx, user_id, &tag_name).await?;
             tags.push(tag);
         }
         // Update post-tag associations
         Post::update_post_tag_assoc(&mut tx, post_id, &tags, true).await?;
 
+        // Record links to other posts
+        let links = extract_post_links(&post.content);
+        Post::update_post_links(&mut tx, user_id, post_id, &links, true).await?;
+
         // Update children count if parent exists
         if let Some(parent_id) = post.parent_id {
             Post::update_children_count(&mut tx, parent_id, true).await?;
         }
 
         tx.commit().await?;
+        emit(AppEvent::PostCreated {
+            id: post_id,
+            user_id,
+        });
 
         Ok(CreateResponse {
             id: post_id,
-            created_at: now,
+            created_at,
             updated_at: now,
+            warnings: vec![],
         })
     }
 
-    pub async fn update(pool: &SqlitePool, post: &UpdatePostRequest) -> ApiResult<()> {
+    #[instrument(skip_all, fields(id = post.id))]
+    pub async fn update(db: &DB, user_id: i64, post: &UpdatePostRequest) -> ApiResult<()> {
         let now = Utc::now().timestamp_millis();
 
+        // Resetting the title derives it from the content, which may not be changed
+        let content = match (&post.title, &post.content) {
+            (_, MaybeAbsent::Present(content)) => Some(content.clone()),
+            (MaybeAbsent::Present(None), MaybeAbsent::Absent) => {
+                query_scalar!(
+                    "SELECT content FROM posts WHERE id = ? AND user_id = ?",
+                    post.id,
+                    user_id
+                )
+                .fetch_optional(&db.pool)
+                .await?
+            }
+            _ => None,
+        };
+
         let mut builder = QueryBuilder::<Sqlite>::new("UPDATE posts SET ");
 
         builder.push("updated_at = ").push_bind(now);
@@ -312,6 +639,25 @@ impl Post {
             builder.push("content = ").push_bind(content);
         });
 
+        match (&post.title, content) {
+            (MaybeAbsent::Present(title), content) => {
+                let custom_title = custom_title(title.as_deref());
+                let title_custom = custom_title.is_some();
+                let title =
+                    custom_title.or_else(|| content.and_then(|content| derive_title(&content)));
+                builder.push(", title = ").push_bind(title);
+                builder.push(", title_custom = ").push_bind(title_custom);
+            }
+            // A custom title is kept when the content is changed
+            (MaybeAbsent::Absent, Some(content)) => {
+                builder
+                    .push(", title = CASE WHEN title_custom THEN title ELSE ")
+                    .push_bind(derive_title(&content))
+                    .push(" END");
+            }
+            (MaybeAbsent::Absent, None) => {}
+        }
+
         post.shared.if_present(|shared| {
             builder.push(", ");
             builder.push("shared = ").push_bind(shared);
@@ -331,6 +677,15 @@ impl Post {
             );
         });
 
+        post.share_options.if_present(|options| {
+            builder.push(", ");
+            builder.push("share_options = ").push_bind(
+                options
+                    .as_ref()
+                    .map(|options| serde_json::to_string(options).unwrap()),
+            );
+        });
+
         post.color.if_present(|color| {
             builder.push(", ");
             builder
@@ -339,16 +694,18 @@ impl Post {
         });
 
         builder.push(" WHERE id = ").push_bind(post.id);
+        builder.push(" AND user_id = ").push_bind(user_id);
 
-        let mut tx = pool.begin().await?;
+        let mut tx = db.writer.begin().await?;
 
         if post.parent_id.is_present() {
             let old_parent_id = query!(
                 r#"
                 SELECT parent_id FROM posts
-                WHERE id = ?
+                WHERE id = ? AND user_id = ?
                 "#,
-                post.id
+                post.id,
+                user_id
             )
             .fetch_optional(&mut *tx)
             .await?
@@ -356,6 +713,9 @@ impl Post {
             .parent_id;
 
             let parent_id = *post.parent_id.get();
+            if let Some(parent_id) = parent_id {
+                Post::check_parent(&mut tx, user_id, parent_id).await?;
+            }
 
             match (old_parent_id, parent_id) {
                 (Some(old_parent_id), None) => {
@@ -376,31 +736,45 @@ impl Post {
             let mut tags = Vec::new();
 
             for tag_name in hash_tags {
-                let tag = Tag::find_or_create(&mut tx, &tag_name).await?;
+                let tag = Tag::find_or_create(&mut tx, user_id, &tag_name).await?;
                 tags.push(tag);
             }
             // Update post-tag associations
             Post::update_post_tag_assoc(&mut tx, post.id, &tags, false).await?;
+
+            // Record links to other posts
+            let links = extract_post_links(post.content.get());
+            Post::update_post_links(&mut tx, user_id, post.id, &links, false).await?;
         }
 
         tx.commit().await?;
+        emit(AppEvent::PostUpdated {
+            id: post.id,
+            user_id,
+            reindex: post.content.is_present() || post.files.is_present(),
+        });
         Ok(())
     }
 
-    pub async fn delete(pool: &SqlitePool, id: i64) -> ApiResult<()> {
-        let mut tx = pool.begin().await?;
+    /// Moves a post to the trash, it is deleted permanently after `purge_after` if it is set,
+    /// otherwise when the trash is purged.
+    #[instrument(skip(db))]
+    pub async fn delete(db: &DB, user_id: i64, id: i64, purge_after: Option<i64>) -> ApiResult<()> {
+        let mut tx = db.writer.begin().await?;
 
         let now = Utc::now().timestamp_millis();
         let post = sqlx::query_as!(
             PostRow,
             r#"
             UPDATE posts
-            SET deleted_at = ?
-            WHERE id = ? AND deleted_at IS NULL
+            SET deleted_at = ?, purge_after = ?
+            WHERE id = ? AND user_id = ? AND deleted_at IS NULL
             RETURNING *
             "#,
             now,
-            id
+            purge_after,
+            id,
+            user_id
         )
         .fetch_optional(&mut *tx)
         .await?
@@ -411,21 +785,24 @@ impl Post {
         }
 
         tx.commit().await?;
+        emit(AppEvent::PostDeleted { id, user_id });
         Ok(())
     }
 
-    pub async fn restore(pool: &SqlitePool, id: i64) -> ApiResult<()> {
-        let mut tx = pool.begin().await?;
+    #[instrument(skip(db))]
+    pub async fn restore(db: &DB, user_id: i64, id: i64) -> ApiResult<()> {
+        let mut tx = db.writer.begin().await?;
 
         let post = query_as!(
             PostRow,
             r#"
             UPDATE posts
-            SET deleted_at = NULL
-            WHERE id = ? AND deleted_at IS NOT NULL
+            SET deleted_at = NULL, purge_after = NULL
+            WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL
             RETURNING *
             "#,
-            id
+            id,
+            user_id
         )
         .fetch_optional(&mut *tx)
         .await?
@@ -436,152 +813,746 @@ impl Post {
         }
 
         tx.commit().await?;
+        emit(AppEvent::PostRestored { id, user_id });
+        Ok(())
+    }
+
+    /// Changes when a post in the trash is deleted permanently, none to wait until the trash is purged.
+    #[instrument(skip(db))]
+    pub async fn schedule_purge(
+        db: &DB,
+        user_id: i64,
+        id: i64,
+        purge_after: Option<i64>,
+    ) -> ApiResult<()> {
+        let rv = sqlx::query!(
+            r#"
+            UPDATE posts
+            SET purge_after = ?
+            WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL
+            "#,
+            purge_after,
+            id,
+            user_id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        if rv.rows_affected() == 0 {
+            return Err(post_not_found());
+        }
         Ok(())
     }
 
-    pub async fn clear(pool: &SqlitePool, id: i64) -> ApiResult<()> {
-        sqlx::query!(
+    /// Sets the color of the undeleted posts of a user in one statement, returns the ids of those changed.
+    #[instrument(skip(db, ids), fields(count = ids.len()))]
+    pub async fn set_color(
+        db: &DB,
+        user_id: i64,
+        ids: &[i64],
+        color: Option<&CategoryColor>,
+    ) -> ApiResult<Vec<i64>> {
+        let now = Utc::now().timestamp_millis();
+        let ids = serde_json::to_string(ids).unwrap();
+        let color = color.map(|color| color.to_string());
+        let mut ids = query_scalar!(
+            r#"
+            UPDATE posts
+            SET color = ?, updated_at = ?
+            WHERE id IN (SELECT value FROM json_each(?)) AND user_id = ? AND deleted_at IS NULL
+            RETURNING id
+            "#,
+            color,
+            now,
+            ids,
+            user_id
+        )
+        .fetch_all(&db.writer)
+        .await?;
+
+        ids.sort_unstable();
+        for id in ids.iter() {
+            emit(AppEvent::PostUpdated {
+                id: *id,
+                user_id,
+                reindex: false,
+            });
+        }
+        Ok(ids)
+    }
+
+    #[instrument(skip(db))]
+    pub async fn clear(db: &DB, user_id: i64, id: i64) -> ApiResult<()> {
+        let rv = sqlx::query!(
             r#"
             DELETE FROM posts
-            WHERE id = ? AND deleted_at IS NOT NULL
+            WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL
             "#,
-            id
+            id,
+            user_id
         )
-        .execute(pool)
+        .execute(&db.writer)
         .await?;
 
+        if rv.rows_affected() > 0 {
+            emit(AppEvent::PostCleared { id, user_id });
+        }
         Ok(())
     }
 
-    pub async fn clear_all(pool: &SqlitePool) -> ApiResult<Vec<i64>> {
+    /// Empties the trash of a user, returns what is purged.
+    #[instrument(skip(db))]
+    pub async fn clear_all(db: &DB, user_id: i64) -> ApiResult<TrashStats> {
+        let mut tx = db.writer.begin().await?;
+        let stats = Self::trash_stats(&mut tx, user_id).await?;
+
         let deleted_ids = sqlx::query!(
             r#"
             DELETE FROM posts
-            WHERE deleted_at IS NOT NULL
+            WHERE user_id = ? AND deleted_at IS NOT NULL
             RETURNING id
-            "#
+            "#,
+            user_id
         )
-        .fetch_all(pool)
+        .fetch_all(&mut *tx)
         .await?
         .into_iter()
         .map(|x| x.id)
-        .collect();
+        .collect::<Vec<_>>();
+        tx.commit().await?;
 
-        Ok(deleted_ids)
+        for id in deleted_ids.iter() {
+            emit(AppEvent::PostCleared { id: *id, user_id });
+        }
+        Ok(TrashStats {
+            post_count: deleted_ids.len() as i64,
+            ..stats
+        })
     }
 
-    async fn update_post_tag_assoc(
-        tx: &mut Transaction<'_, Sqlite>,
-        post_id: i64,
-        tags: &[Tag],
-        is_new_post: bool,
-    ) -> ApiResult<()> {
-        // Remove old relationships if not a new post
-        if !is_new_post {
-            sqlx::query!(
-                r#"
-                DELETE FROM tag_post_assoc
-                WHERE post_id = ?
-                "#,
-                post_id
-            )
-            .execute(&mut **tx)
-            .await?;
-        }
+    pub async fn get_trash_stats(pool: &SqlitePool, user_id: i64) -> ApiResult<TrashStats> {
+        let mut conn = pool.acquire().await?;
+        Self::trash_stats(&mut conn, user_id).await
+    }
 
-        // Insert new relationships
-        for tag in tags {
-            sqlx::query!(
-                r#"
-                INSERT INTO tag_post_assoc (post_id, tag_id)
-                VALUES (?, ?)
-                "#,
-                post_id,
-                tag.id
-            )
-            .execute(&mut **tx)
-            .await?;
-        }
+    /// A file is freed if a trashed post of the user uses it and no other post does, of any user
+    async fn trash_stats(conn: &mut SqliteConnection, user_id: i64) -> ApiResult<TrashStats> {
+        let stats = query_as!(
+            TrashStats,
+            r#"
+            SELECT
+                (
+                    SELECT COUNT(*) FROM posts WHERE user_id = ?1 AND deleted_at IS NOT NULL
+                ) AS "post_count!: i64",
+                (
+                    SELECT COALESCE(SUM(f.size), 0) FROM files f
+                    WHERE EXISTS (
+                        SELECT 1 FROM posts p
+                        WHERE p.user_id = ?1 AND p.deleted_at IS NOT NULL AND (
+                            instr(p.content, '/' || f.filename) > 0 OR EXISTS (
+                                SELECT 1 FROM json_each(p.files) j
+                                WHERE json_extract(j.value, '$.url') = f.filename
+                            )
+                        )
+                    ) AND NOT EXISTS (
+                        SELECT 1 FROM posts p
+                        WHERE (p.deleted_at IS NULL OR p.user_id != ?1) AND (
+                            instr(p.content, '/' || f.filename) > 0 OR EXISTS (
+                                SELECT 1 FROM json_each(p.files) j
+                                WHERE json_extract(j.value, '$.url') = f.filename
+                            )
+                        )
+                    )
+                ) AS "file_size!: i64"
+            "#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
 
-        Ok(())
+        Ok(stats)
     }
 
-    async fn update_children_count(
-        tx: &mut Transaction<'_, Sqlite>,
-        parent_id: i64,
-        increment: bool,
-    ) -> ApiResult<()> {
-        let sql = if increment {
-            "UPDATE posts SET children_count = children_count + 1 WHERE id = $1"
+    /// Removes one attachment from a post of a user, returns the removed file.
+    #[instrument(skip(db))]
+    pub async fn remove_file(db: &DB, user_id: i64, id: i64, url: &str) -> ApiResult<FileInfo> {
+        let mut tx = db.writer.begin().await?;
+
+        let row = query_as!(
+            PostRow,
+            "SELECT * FROM posts WHERE id = ? AND user_id = ? AND deleted_at IS NULL",
+            id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(post_not_found())?;
+
+        let mut files = row.file_infos();
+        let index = files
+            .iter()
+            .position(|file| file.url == url)
+            .ok_or(not_found("File not found"))?;
+        let removed = files.remove(index);
+
+        let files = if files.is_empty() {
+            None
         } else {
-            "UPDATE posts SET children_count = children_count - 1 WHERE id = $1"
+            Some(serde_json::to_string(&files).unwrap())
         };
+        let now = Utc::now().timestamp_millis();
 
-        sqlx::query(sql).bind(parent_id).execute(&mut **tx).await?;
+        query!(
+            "UPDATE posts SET files = ?, updated_at = ? WHERE id = ?",
+            files,
+            now,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
 
-        Ok(())
+        tx.commit().await?;
+        emit(AppEvent::FileDetached {
+            post_id: id,
+            user_id,
+            file: Box::new(removed.clone()),
+        });
+        Ok(removed)
     }
 
-    async fn attach_tags(pool: &SqlitePool, posts: &mut Vec<Post>) -> ApiResult<()> {
-        if posts.is_empty() {
-            return Ok(());
-        }
-
-        let post_ids: Vec<i64> = posts.iter().map(|post| post.row.id).collect();
-        let post_ids = serde_json::to_string(&post_ids).unwrap();
-
-        let rows = sqlx::query!(
+    /// Checks if any post, including the deleted ones, still has the file attached
+    pub async fn is_file_attached(pool: &SqlitePool, url: &str) -> ApiResult<bool> {
+        let attached = query_scalar!(
             r#"
-            SELECT tp.post_id, tags.name as "tag_name!"
-            FROM tag_post_assoc as tp
-            INNER JOIN tags ON tp.tag_id = tags.id
-            WHERE tp.post_id IN (SELECT value FROM json_each(?1))
+            SELECT EXISTS (
+                SELECT 1 FROM posts p, json_each(p.files) f
+                WHERE json_extract(f.value, '$.url') = ?
+            ) AS "attached!: bool"
             "#,
-            post_ids
+            url
         )
-        .fetch_all(pool)
+        .fetch_one(pool)
         .await?;
 
-        let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
-        for row in rows {
-            tags.entry(row.post_id).or_default().push(row.tag_name);
-        }
+        Ok(attached)
+    }
 
-        for post in posts {
-            post.tags = tags.get(&post.row.id).cloned().unwrap_or_default();
-        }
+    /// Get the posts with the longest content, the deleted ones are included if `include_deleted`.
+    pub async fn find_largest(
+        pool: &SqlitePool,
+        include_deleted: bool,
+        limit: i64,
+        offset: i64,
+    ) -> ApiResult<Vec<LargePost>> {
+        let posts = query!(
+            r#"
+            SELECT
+                p.id,
+                p.user_id,
+                u.name AS "owner?",
+                p.title,
+                substr(p.content, 1, 1000) AS "head!: String",
+                length(CAST(p.content AS BLOB)) AS "size!: i64",
+                COALESCE(json_array_length(p.files), 0) AS "file_count!: i64",
+                p.created_at,
+                p.deleted_at
+            FROM posts p
+            LEFT JOIN users u ON u.id = p.user_id
+            WHERE ? OR p.deleted_at IS NULL
+            ORDER BY 6 DESC, p.id
+            LIMIT ? OFFSET ?
+            "#,
+            include_deleted,
+            limit,
+            offset
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| LargePost {
+            id: row.id,
+            user_id: row.user_id,
+            owner: row.owner,
+            title: display_title(row.title.as_deref(), &row.head, 50),
+            size: row.size,
+            file_count: row.file_count,
+            created_at: row.created_at,
+            deleted_at: row.deleted_at,
+        })
+        .collect();
+
+        Ok(posts)
+    }
+
+    /// Turns the file urls stored by older versions into keys, see `UrlResolver`.
+    /// It is done on startup and does nothing once they are all turned, returns the count of changed posts.
+    pub async fn store_file_keys(db: &DB, urls: &UrlResolver) -> ApiResult<u64> {
+        let prefix = format!("{}/", urls.base_url());
+        let mut tx = db.writer.begin().await?;
+
+        let rows = query!(
+            r#"
+            SELECT p.id, p.files FROM posts p
+            WHERE EXISTS (
+                SELECT 1 FROM json_each(p.files) f
+                WHERE substr(json_extract(f.value, '$.url'), 1, length(?1)) = ?1
+            )
+            "#,
+            prefix
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut count = 0;
+        for row in rows {
+            let Some(files) = row
+                .files
+                .as_deref()
+                .and_then(|files| serde_json::from_str::<Vec<FileInfo>>(files).ok())
+            else {
+                continue;
+            };
+            let files = serde_json::to_string(&urls.to_stored_files(files)).unwrap();
+            query!("UPDATE posts SET files = ? WHERE id = ?", files, row.id)
+                .execute(&mut *tx)
+                .await?;
+            count += 1;
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Derives the titles of the posts saved before posts had titles, returns how many are set.
+    pub async fn derive_missing_titles(db: &DB) -> ApiResult<u64> {
+        let mut tx = db.writer.begin().await?;
+
+        let rows = query!(
+            r#"
+            SELECT id, content FROM posts
+            WHERE title IS NULL AND NOT title_custom
+            AND (content LIKE '%<h1%' OR content LIKE '%<h2%' OR content LIKE '%<h3%')
+            "#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut count = 0;
+        for row in rows {
+            let Some(title) = derive_title(&row.content) else {
+                continue;
+            };
+            query!("UPDATE posts SET title = ? WHERE id = ?", title, row.id)
+                .execute(&mut *tx)
+                .await?;
+            count += 1;
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Records the links of the posts saved before links were recorded, returns how many posts with links are found.
+    /// It is done once, as marked in the `backfills` table, and only the posts which may link to others are read.
+    pub async fn backfill_post_links(db: &DB) -> ApiResult<u64> {
+        let mut tx = db.writer.begin().await?;
+
+        let pending = query!("DELETE FROM backfills WHERE name = 'post_links'")
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if pending == 0 {
+            return Ok(0);
+        }
+
+        let rows = query!(
+            r#"
+            SELECT id, user_id, content FROM posts p
+            WHERE instr(content, '/p/') > 0
+            AND NOT EXISTS (SELECT 1 FROM post_links l WHERE l.source_id = p.id)
+            "#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut count = 0;
+        for row in rows {
+            let links = extract_post_links(&row.content);
+            if links.is_empty() {
+                continue;
+            }
+            Self::update_post_links(&mut tx, row.user_id, row.id, &links, true).await?;
+            count += 1;
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Checks if any post, including the deleted ones, links to the file from its content
+    pub async fn is_file_linked(pool: &SqlitePool, filename: &str) -> ApiResult<bool> {
+        let path = format!("/{}", filename);
+        let linked = query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM posts WHERE instr(content, ?) > 0) AS "linked!: bool""#,
+            path
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(linked)
+    }
+
+    /// Get the attachments of all posts, including the deleted ones
+    pub async fn get_all_files(pool: &SqlitePool) -> ApiResult<Vec<(i64, Vec<FileInfo>)>> {
+        let files = query!("SELECT id, files FROM posts WHERE files IS NOT NULL ORDER BY id")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let files = row
+                    .files
+                    .as_deref()
+                    .and_then(|files| serde_json::from_str(files).ok())
+                    .unwrap_or_default();
+                (row.id, files)
+            })
+            .collect();
+
+        Ok(files)
+    }
+
+    /// Get the ids of undeleted posts that link to the given post
+    pub async fn find_referrers(pool: &SqlitePool, id: i64) -> ApiResult<Vec<i64>> {
+        let ids = sqlx::query!(
+            r#"
+            SELECT l.source_id
+            FROM post_links l
+            INNER JOIN posts p ON l.source_id = p.id
+            WHERE l.target_id = ? AND p.deleted_at IS NULL
+            ORDER BY l.source_id
+            "#,
+            id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.source_id)
+        .collect();
+
+        Ok(ids)
+    }
+
+    pub(crate) async fn update_post_links(
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        post_id: i64,
+        target_ids: &HashSet<i64>,
+        is_new_post: bool,
+    ) -> ApiResult<()> {
+        // Remove old links if not a new post
+        if !is_new_post {
+            sqlx::query!(
+                r#"
+                DELETE FROM post_links
+                WHERE source_id = ?
+                "#,
+                post_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        // Insert new links, ignoring self-references and posts that do not exist or are of other users
+        for target_id in target_ids.iter().filter(|&&id| id != post_id) {
+            sqlx::query!(
+                r#"
+                INSERT OR IGNORE INTO post_links (source_id, target_id)
+                SELECT ?, id FROM posts WHERE id = ? AND user_id = ?
+                "#,
+                post_id,
+                target_id,
+                user_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn update_post_tag_assoc(
+        tx: &mut Transaction<'_, Sqlite>,
+        post_id: i64,
+        tags: &[Tag],
+        is_new_post: bool,
+    ) -> ApiResult<()> {
+        // Remove old relationships if not a new post
+        if !is_new_post {
+            sqlx::query!(
+                r#"
+                DELETE FROM tag_post_assoc
+                WHERE post_id = ?
+                "#,
+                post_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        // Insert new relationships
+        for tag in tags {
+            sqlx::query!(
+                r#"
+                INSERT INTO tag_post_assoc (post_id, tag_id)
+                VALUES (?, ?)
+                "#,
+                post_id,
+                tag.id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// A post can only be a reply to another post of the same user
+    async fn check_parent(
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        parent_id: i64,
+    ) -> ApiResult<()> {
+        let owner = query_scalar!("SELECT user_id FROM posts WHERE id = ?", parent_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+        if owner != Some(user_id) {
+            return Err(not_found("parent post not found"));
+        }
+        Ok(())
+    }
+
+    /// The ids of the post and its ancestors, the post first
+    async fn find_ancestor_ids(pool: &SqlitePool, id: i64) -> ApiResult<Vec<i64>> {
+        let ids = query_scalar!(
+            r#"
+            WITH RECURSIVE ancestors(id, parent_id, depth) AS (
+                SELECT id, parent_id, 0 FROM posts WHERE id = ?1
+                UNION ALL
+                SELECT p.id, p.parent_id, a.depth + 1
+                FROM posts p INNER JOIN ancestors a ON p.id = a.parent_id
+                WHERE a.depth < ?2
+            )
+            SELECT id as "id!: i64" FROM ancestors ORDER BY depth
+            "#,
+            id,
+            MAX_THREAD_WALK
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(ids)
+    }
+
+    /// The ids of the undeleted descendants of a post, with the ids of their parents and their depths below the post
+    async fn find_descendant_ids(pool: &SqlitePool, id: i64) -> ApiResult<Vec<(i64, i64, i64)>> {
+        let rows = query!(
+            r#"
+            WITH RECURSIVE descendants(id, parent_id, depth) AS (
+                SELECT id, parent_id, 0 FROM posts WHERE id = ?1
+                UNION ALL
+                SELECT p.id, p.parent_id, d.depth + 1
+                FROM posts p INNER JOIN descendants d ON p.parent_id = d.id
+                WHERE p.deleted_at IS NULL AND d.depth < ?2
+            )
+            SELECT id as "id!: i64", parent_id as "parent_id!: i64", depth as "depth!: i64"
+            FROM descendants WHERE depth > 0
+            "#,
+            id,
+            MAX_THREAD_WALK
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.id, row.parent_id, row.depth))
+            .collect())
+    }
+
+    /// Checks that a post, or a new one if `id` is not set, can be a reply to `parent_id`:
+    /// it is not put under itself or its replies, and its thread is at most `max_depth` deep if it is not 0.
+    #[instrument(skip(pool))]
+    pub async fn check_thread_depth(
+        pool: &SqlitePool,
+        id: Option<i64>,
+        parent_id: i64,
+        max_depth: usize,
+    ) -> ApiResult<()> {
+        let ancestors = Self::find_ancestor_ids(pool, parent_id).await?;
+        if id.is_some_and(|id| ancestors.contains(&id)) {
+            return Err(bad_request("A post cannot reply to itself or its replies"));
+        }
+        if max_depth == 0 {
+            return Ok(());
+        }
+
+        // The replies of the post are moved along with it
+        let height = match id {
+            Some(id) => Self::find_descendant_ids(pool, id)
+                .await?
+                .iter()
+                .map(|(_, _, depth)| *depth as usize)
+                .max()
+                .unwrap_or(0),
+            None => 0,
+        };
+        if ancestors.len() + height > max_depth {
+            return Err(bad_request(&format!(
+                "Replies cannot be nested more than {} levels deep",
+                max_depth
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the ancestors of a post from the root of its thread, the post, and its replies depth-first,
+    /// the replies to a post in the order they are created. Deleted posts are left out with their replies.
+    #[instrument(skip(pool))]
+    pub async fn find_flat_thread(
+        pool: &SqlitePool,
+        user_id: i64,
+        id: i64,
+    ) -> ApiResult<Vec<ThreadPost>> {
+        let mut ancestors = Self::find_ancestor_ids(pool, id).await?;
+        ancestors.reverse();
+        let descendants = Self::find_descendant_ids(pool, id).await?;
+
+        let ids: Vec<i64> = ancestors
+            .iter()
+            .copied()
+            .chain(descendants.iter().map(|(id, _, _)| *id))
+            .collect();
+        let mut posts: Vec<Post> = Self::find_rows_by_ids(pool, user_id, &ids)
+            .await?
+            .into_iter()
+            .map(Post::from)
+            .collect();
+        if !posts.iter().any(|post| post.row.id == id) {
+            return Err(post_not_found());
+        }
+        Self::attach_tags(pool, &mut posts).await?;
+        let mut posts: HashMap<i64, Post> = posts.into_iter().map(|p| (p.row.id, p)).collect();
+
+        let mut thread = vec![];
+        for ancestor in ancestors.iter() {
+            if let Some(post) = posts.remove(ancestor) {
+                let depth = thread.len();
+                thread.push(ThreadPost { post, depth });
+            }
+        }
+
+        let mut replies: HashMap<i64, Vec<&Post>> = HashMap::new();
+        for (id, parent_id, _) in descendants.iter() {
+            if let Some(post) = posts.get(id) {
+                replies.entry(*parent_id).or_default().push(post);
+            }
+        }
+        for posts in replies.values_mut() {
+            // Popped from the end of the stack below, so the newest go first onto it
+            posts.sort_by_key(|post| std::cmp::Reverse((post.row.created_at, post.row.id)));
+        }
+        let base = thread.len() - 1;
+        let mut stack: Vec<(i64, usize)> = replies
+            .get(&id)
+            .map(|posts| posts.iter().map(|p| (p.row.id, base + 1)).collect())
+            .unwrap_or_default();
+        let mut order = vec![];
+        while let Some((id, depth)) = stack.pop() {
+            order.push((id, depth));
+            if let Some(posts) = replies.get(&id) {
+                stack.extend(posts.iter().map(|p| (p.row.id, depth + 1)));
+            }
+        }
+        for (id, depth) in order {
+            if let Some(post) = posts.remove(&id) {
+                thread.push(ThreadPost { post, depth });
+            }
+        }
+        Ok(thread)
+    }
+
+    async fn update_children_count(
+        tx: &mut Transaction<'_, Sqlite>,
+        parent_id: i64,
+        increment: bool,
+    ) -> ApiResult<()> {
+        let sql = if increment {
+            "UPDATE posts SET children_count = children_count + 1 WHERE id = $1"
+        } else {
+            "UPDATE posts SET children_count = children_count - 1 WHERE id = $1"
+        };
+
+        sqlx::query(sql).bind(parent_id).execute(&mut **tx).await?;
 
         Ok(())
     }
 
-    async fn attach_parents(pool: &SqlitePool, posts: &mut [Post]) -> ApiResult<()> {
+    async fn attach_tags(pool: &SqlitePool, posts: &mut Vec<Post>) -> ApiResult<()> {
+        if posts.is_empty() {
+            return Ok(());
+        }
+
+        let post_ids: Vec<i64> = posts.iter().map(|post| post.row.id).collect();
+        let mut tags = Self::find_tags_of(pool, &post_ids).await?;
+
+        for post in posts {
+            post.tags = tags.remove(&post.row.id).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the tag names of each post.
+    async fn find_tags_of(pool: &SqlitePool, ids: &[i64]) -> ApiResult<HashMap<i64, Vec<String>>> {
+        let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let post_ids = serde_json::to_string(chunk).unwrap();
+            let rows = sqlx::query!(
+                r#"
+                SELECT tp.post_id, tags.name as "tag_name!"
+                FROM tag_post_assoc as tp
+                INNER JOIN tags ON tp.tag_id = tags.id
+                WHERE tp.post_id IN (SELECT value FROM json_each(?1))
+                "#,
+                post_ids
+            )
+            .fetch_all(pool)
+            .await?;
+
+            for row in rows {
+                tags.entry(row.post_id).or_default().push(row.tag_name);
+            }
+        }
+
+        Ok(tags)
+    }
+
+    async fn attach_parents(pool: &SqlitePool, user_id: i64, posts: &mut [Post]) -> ApiResult<()> {
         // Early return if posts is empty
         if posts.is_empty() {
             return Ok(());
         }
 
         // Collect parent IDs
-        let parent_ids: Vec<i64> = posts.iter().filter_map(|post| post.row.parent_id).collect();
+        let mut parent_ids: Vec<i64> = posts.iter().filter_map(|post| post.row.parent_id).collect();
+        parent_ids.sort_unstable();
+        parent_ids.dedup();
 
         if parent_ids.is_empty() {
             return Ok(());
         }
 
-        let parent_ids = serde_json::to_string(&parent_ids).unwrap();
-
         // Find all parent posts
-        let parent_rows = sqlx::query_as!(
-            PostRow,
-            r#"
-            SELECT *
-            FROM posts
-            WHERE id IN (SELECT value FROM json_each(?1))
-            AND deleted_at IS NULL
-            "#,
-            parent_ids
-        )
-        .fetch_all(pool)
-        .await?;
+        let parent_rows = Self::find_rows_by_ids(pool, user_id, &parent_ids).await?;
 
         // Create a map of parent posts
         let parents: HashMap<i64, Post> = parent_rows
@@ -600,9 +1571,101 @@ impl Post {
 
         Ok(())
     }
+
+    /// Converts the post into a Markdown document, with its metadata as front matter and its files listed at the end.
+    /// `link` rewrites the urls of links, images and files, e.g. to the paths of the files in an archive.
+    pub fn to_markdown(&self, link: &dyn Fn(&str) -> String) -> String {
+        let row = &self.row;
+        let date = |ts: i64| {
+            DateTime::from_timestamp_millis(ts)
+                .unwrap_or_default()
+                .to_rfc3339()
+        };
+        // JSON strings are valid in YAML
+        let quote = |text: &str| serde_json::to_string(text).unwrap();
+
+        let mut front = vec![format!("id: {}", row.id)];
+        if let Some(ref title) = row.title {
+            front.push(format!("title: {}", quote(title)));
+        }
+        front.push(format!("created_at: {}", date(row.created_at)));
+        front.push(format!("updated_at: {}", date(row.updated_at)));
+        if let Some(ref color) = row.color {
+            front.push(format!("color: {}", color));
+        }
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self.tags.iter().map(|tag| quote(tag)).collect();
+            front.push(format!("tags: [{}]", tags.join(", ")));
+        }
+
+        let mut md = format!(
+            "---\n{}\n---\n\n{}",
+            front.join("\n"),
+            html_to_markdown(&row.content, link)
+        );
+
+        let files = row.file_infos();
+        if !files.is_empty() {
+            md.push('\n');
+        }
+        for file in files {
+            let name = markdown::escape(file.original_name.as_deref().unwrap_or(&file.url));
+            let url = markdown::link_destination(&link(&file.url));
+            let is_image = file
+                .content_type
+                .as_deref()
+                .is_some_and(|t| t.starts_with("image/"));
+            if is_image {
+                md.push_str(&format!("![{}]({})\n", name, url));
+            } else {
+                md.push_str(&format!("- [{}]({})\n", name, url));
+            }
+        }
+        md
+    }
 }
 
 // Helper functions
+
+/// A blank title counts as not set
+fn custom_title(title: Option<&str>) -> Option<String> {
+    title
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(String::from)
+}
+
+/// The undeleted posts of a user, which their searches find
+pub struct UserPosts<'a> {
+    pool: &'a SqlitePool,
+    user_id: i64,
+}
+
+impl<'a> UserPosts<'a> {
+    pub fn new(pool: &'a SqlitePool, user_id: i64) -> Self {
+        Self { pool, user_id }
+    }
+}
+
+impl DocFilter for UserPosts<'_> {
+    fn retain<'a>(&'a self, ids: &'a [i64]) -> BoxFuture<'a, anyhow::Result<HashSet<i64>>> {
+        Box::pin(async move {
+            let ids = serde_json::to_string(ids)?;
+            let kept = query_scalar!(
+                r#"
+                SELECT id FROM posts
+                WHERE user_id = ? AND deleted_at IS NULL AND id IN (SELECT value FROM json_each(?))
+                "#,
+                self.user_id,
+                ids
+            )
+            .fetch_all(self.pool)
+            .await?;
+            Ok(kept.into_iter().collect())
+        })
+    }
+}
+
 fn extract_hash_tags(content: &str) -> HashSet<String> {
     let re = Regex::new(r#"<span class="hash-tag">#(.+?)</span>"#).unwrap();
     re.captures_iter(content)
@@ -611,6 +1674,774 @@ fn extract_hash_tags(content: &str) -> HashSet<String> {
         .collect()
 }
 
+/// Extract the ids of posts referenced by internal links such as `<a href="/p/42">`,
+/// links to other sites such as `https://example.com/p/42` are not internal.
+pub(crate) fn extract_post_links(content: &str) -> HashSet<i64> {
+    let re = Regex::new(r#"href="/p/(\d+)/?""#).unwrap();
+    re.captures_iter(content)
+        .filter_map(|cap| cap.get(1))
+        .filter_map(|m| m.as_str().parse().ok())
+        .collect()
+}
+
+/// The expression the posts are ordered by. The last activity of a post is its last update,
+/// or that of its undeleted children if later.
+fn order_expression(order_by: &SortingField) -> &'static str {
+    match order_by {
+        SortingField::CreatedAt => "p.created_at",
+        SortingField::UpdatedAt => "p.updated_at",
+        SortingField::DeletedAt => "p.deleted_at",
+        SortingField::LastActivity => {
+            "MAX(p.updated_at, COALESCE((SELECT MAX(c.updated_at) FROM posts c \
+             WHERE c.parent_id = p.id AND c.deleted_at IS NULL), 0))"
+        }
+    }
+}
+
 fn post_not_found() -> ApiError {
     ApiError::NotFound("post not found".to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DBConfig;
+    use crate::model::file::StoredFile;
+    use crate::model::post::FileType;
+    use crate::model::user::{User, ADMIN_USER_ID};
+    use serde_json::json;
+
+    #[test]
+    fn test_extract_post_links() {
+        let content = r#"<p>See <a href="/p/12">this</a>, <a href="https://example.com/p/7/">that</a>
+            and <a href="/p/12">again</a>, but not <a href="/p/abc">this</a>.</p>"#;
+        assert_eq!(extract_post_links(content), HashSet::from([12]));
+        let content = r#"<a href="https://example.com/p/7/">a</a> <a href="//example.com/p/8">b</a>
+            <a href="/p/9/">c</a>"#;
+        assert_eq!(extract_post_links(content), HashSet::from([9]));
+        assert!(extract_post_links("<p>no links</p>").is_empty());
+    }
+
+    async fn memory_db() -> DB {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_threads() {
+        let db = memory_db().await;
+        let reply = |parent_id, created_at| CreatePostRequest {
+            content: "<p>post</p>".to_string(),
+            files: None,
+            color: None,
+            shared: None,
+            parent_id,
+            created_at: Some(created_at),
+            title: None,
+        };
+        let create = |parent_id, created_at| {
+            let db = &db;
+            async move {
+                Post::create(db, ADMIN_USER_ID, &reply(parent_id, created_at))
+                    .await
+                    .unwrap()
+                    .id
+            }
+        };
+        // root -> a -> b, and root -> c, with c created before a
+        let root = create(None, 1).await;
+        let a = create(Some(root), 3).await;
+        let b = create(Some(a), 4).await;
+        let c = create(Some(root), 2).await;
+
+        let thread = Post::find_flat_thread(&db.pool, ADMIN_USER_ID, a)
+            .await
+            .unwrap();
+        let flat: Vec<(i64, usize)> = thread.iter().map(|t| (t.post.row.id, t.depth)).collect();
+        assert_eq!(flat, vec![(root, 0), (a, 1), (b, 2)]);
+        let thread = Post::find_flat_thread(&db.pool, ADMIN_USER_ID, root)
+            .await
+            .unwrap();
+        let flat: Vec<(i64, usize)> = thread.iter().map(|t| (t.post.row.id, t.depth)).collect();
+        assert_eq!(flat, vec![(root, 0), (c, 1), (a, 1), (b, 2)]);
+        assert!(Post::find_flat_thread(&db.pool, ADMIN_USER_ID + 1, root)
+            .await
+            .is_err());
+
+        // b is 2 levels deep, a reply to it would be 3
+        assert!(Post::check_thread_depth(&db.pool, None, b, 3).await.is_ok());
+        assert!(Post::check_thread_depth(&db.pool, None, b, 2)
+            .await
+            .is_err());
+        assert!(Post::check_thread_depth(&db.pool, None, b, 0).await.is_ok());
+        // Moving a under c makes b 3 levels deep
+        assert!(Post::check_thread_depth(&db.pool, Some(a), c, 3)
+            .await
+            .is_ok());
+        assert!(Post::check_thread_depth(&db.pool, Some(a), c, 2)
+            .await
+            .is_err());
+        // A post cannot be moved under its own replies
+        assert!(Post::check_thread_depth(&db.pool, Some(a), b, 0)
+            .await
+            .is_err());
+        assert!(Post::check_thread_depth(&db.pool, Some(a), a, 0)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_color() {
+        let db = memory_db().await;
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let post = CreatePostRequest {
+                content: "<p>post</p>".to_string(),
+                files: None,
+                color: None,
+                shared: None,
+                parent_id: None,
+                created_at: None,
+                title: None,
+            };
+            ids.push(Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id);
+        }
+        Post::delete(&db, ADMIN_USER_ID, ids[2], None)
+            .await
+            .unwrap();
+
+        let changed = Post::set_color(&db, ADMIN_USER_ID, &ids, Some(&CategoryColor::Red))
+            .await
+            .unwrap();
+        assert_eq!(changed, vec![ids[0], ids[1]]);
+        let colors = query_scalar!("SELECT color FROM posts ORDER BY id")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            colors,
+            vec![Some("red".to_string()), Some("red".to_string()), None]
+        );
+
+        // Other users cannot recolor the posts
+        assert!(Post::set_color(&db, ADMIN_USER_ID + 1, &ids, None)
+            .await
+            .unwrap()
+            .is_empty());
+        Post::set_color(&db, ADMIN_USER_ID, &ids[..1], None)
+            .await
+            .unwrap();
+        let color = query_scalar!("SELECT color FROM posts WHERE id = ?", ids[0])
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(color, None);
+    }
+
+    #[tokio::test]
+    async fn test_sort_ids_by_time() {
+        let db = memory_db().await;
+        let mut ids = vec![];
+        for created_at in [3000, 1000, 2000] {
+            let post = CreatePostRequest {
+                content: "<p>post</p>".to_string(),
+                files: None,
+                color: None,
+                shared: None,
+                parent_id: None,
+                created_at: Some(created_at),
+                title: None,
+            };
+            ids.push(Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id);
+        }
+        Post::delete(&db, ADMIN_USER_ID, ids[0], None)
+            .await
+            .unwrap();
+
+        let sorted = Post::sort_ids_by_time(&db.pool, ADMIN_USER_ID, &ids, false)
+            .await
+            .unwrap();
+        assert_eq!(sorted, vec![ids[2], ids[1]]);
+        assert_eq!(
+            UserPosts::new(&db.pool, ADMIN_USER_ID)
+                .retain(&ids)
+                .await
+                .unwrap(),
+            HashSet::from([ids[1], ids[2]])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_order_by_last_activity() {
+        let db = memory_db().await;
+        let create = |parent_id| CreatePostRequest {
+            content: "<p>post</p>".to_string(),
+            files: None,
+            color: None,
+            shared: None,
+            parent_id,
+            created_at: None,
+            title: None,
+        };
+        let mut ids = vec![];
+        for parent_id in [None, None, None] {
+            let id = Post::create(&db, ADMIN_USER_ID, &create(parent_id))
+                .await
+                .unwrap()
+                .id;
+            ids.push(id);
+        }
+        let child = Post::create(&db, ADMIN_USER_ID, &create(Some(ids[0])))
+            .await
+            .unwrap()
+            .id;
+        for (id, updated_at) in [
+            (ids[0], 1000),
+            (ids[1], 2000),
+            (ids[2], 3000),
+            (child, 4000),
+        ] {
+            sqlx::query!(
+                "UPDATE posts SET updated_at = ? WHERE id = ?",
+                updated_at,
+                id
+            )
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        }
+
+        let mut options = FilterPostRequest {
+            order_by: SortingField::LastActivity,
+            ..Default::default()
+        };
+        let posts = Post::filter_posts(&db.pool, ADMIN_USER_ID, &options, 2)
+            .await
+            .unwrap();
+        // The parent of the latest child comes first, as recent as the child
+        let mut first: Vec<i64> = posts.iter().map(|p| p.row.id).collect();
+        first.sort();
+        assert_eq!(first, vec![ids[0], child]);
+        assert!(posts.iter().all(|p| p.last_activity_at == Some(4000)));
+
+        // A page ending between the parent and the child, which are as recent, skips neither
+        options.cursor = posts[0].sort_key(&options.order_by);
+        options.cursor_id = Some(posts[0].row.id);
+        let posts = Post::filter_post_metas(&db.pool, ADMIN_USER_ID, &options, 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            posts.iter().map(|p| p.id).collect::<Vec<_>>(),
+            vec![ids[0], ids[2], ids[1]]
+        );
+        assert_eq!(posts[1].last_activity_at, Some(3000));
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_file_type() {
+        let db = memory_db().await;
+        let create = |files: serde_json::Value| CreatePostRequest {
+            content: "<p>post</p>".to_string(),
+            files: serde_json::from_value(files).unwrap(),
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: None,
+            title: None,
+        };
+
+        // The content type of the stored file is preferred to the one sent by the client
+        StoredFile::save(&db, 1, "a", "a.pdf", "a.pdf", Some("application/pdf"), 1)
+            .await
+            .unwrap();
+        let pdf = json!([{"id": "a", "url": "/uploads/a.pdf", "content_type": "application/octet-stream"}]);
+        let pdf = Post::create(&db, ADMIN_USER_ID, &create(pdf))
+            .await
+            .unwrap()
+            .id;
+        let image = json!([{"id": "b", "url": "/uploads/b.png", "content_type": "image/png"}]);
+        let image = Post::create(&db, ADMIN_USER_ID, &create(image))
+            .await
+            .unwrap()
+            .id;
+        Post::create(&db, ADMIN_USER_ID, &create(json!(null)))
+            .await
+            .unwrap();
+
+        let filter = |file_type| {
+            let db = &db;
+            async move {
+                let options = FilterPostRequest {
+                    file_type: Some(file_type),
+                    ..Default::default()
+                };
+                Post::filter_posts(&db.pool, ADMIN_USER_ID, &options, 10)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|p| p.row.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(filter(FileType::Document).await, vec![pdf]);
+        assert_eq!(filter(FileType::Image).await, vec![image]);
+        assert!(filter(FileType::Video).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_by_ids_in_chunks() {
+        let db = memory_db().await;
+        let mut ids = vec![];
+        for i in 0..ID_CHUNK_SIZE + 10 {
+            let post = CreatePostRequest {
+                content: format!(r#"<p><span class="hash-tag">#tag{}</span></p>"#, i % 3),
+                files: None,
+                color: None,
+                shared: None,
+                parent_id: ids.first().copied(),
+                created_at: None,
+                title: None,
+            };
+            ids.push(Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id);
+        }
+        // Unknown ids are ignored
+        ids.push(-1);
+
+        let posts = Post::find_by_ids(&db.pool, ADMIN_USER_ID, &ids)
+            .await
+            .unwrap();
+        assert_eq!(posts.len(), ID_CHUNK_SIZE + 10);
+        assert!(posts.iter().all(|p| p.tags.len() == 1));
+        let last = posts.iter().find(|p| p.row.id == ids[ID_CHUNK_SIZE + 9]);
+        assert_eq!(last.unwrap().parent.as_ref().unwrap().row.id, ids[0]);
+    }
+
+    #[tokio::test]
+    async fn test_find_largest() {
+        let db = memory_db().await;
+        let file = |url: &str| FileInfo {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        let mut ids = vec![];
+        for (content, files) in [
+            ("<h1>Short</h1>", None),
+            (
+                "<h1>Long</h1><p>lorem ipsum dolor</p>",
+                Some(vec![file("a.pdf")]),
+            ),
+        ] {
+            let post = CreatePostRequest {
+                content: content.to_string(),
+                files,
+                color: None,
+                shared: None,
+                parent_id: None,
+                created_at: None,
+                title: None,
+            };
+            ids.push(Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id);
+        }
+        Post::delete(&db, ADMIN_USER_ID, ids[1], None)
+            .await
+            .unwrap();
+
+        let posts = Post::find_largest(&db.pool, true, 10, 0).await.unwrap();
+        assert_eq!(
+            posts.iter().map(|p| p.id).collect::<Vec<_>>(),
+            [ids[1], ids[0]]
+        );
+        assert_eq!(posts[0].title, "Long");
+        assert_eq!(posts[0].file_count, 1);
+        assert_eq!(posts[0].user_id, ADMIN_USER_ID);
+        let posts = Post::find_largest(&db.pool, false, 10, 0).await.unwrap();
+        assert_eq!(posts.len(), 1);
+
+        StoredFile::save(&db, 1, "1", "a.pdf", "a.pdf", Some("application/pdf"), 200)
+            .await
+            .unwrap();
+        StoredFile::save(&db, 2, "2", "b.jpg", "b.jpg", Some("image/jpeg"), 100)
+            .await
+            .unwrap();
+        let usage = StoredFile::usage(&db.pool, Some(2)).await.unwrap();
+        assert_eq!((usage.files, usage.size), (1, 100));
+        let usage = StoredFile::usage(&db.pool, None).await.unwrap();
+        assert_eq!((usage.files, usage.size), (2, 300));
+        let files = StoredFile::find_largest(&db.pool, None, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(files[0].filename, "a.pdf");
+        assert_eq!(files[0].post_ids, [ids[1]]);
+        assert!(files[1].post_ids.is_empty());
+        assert_eq!((files[0].user_id, files[1].user_id), (1, 2));
+
+        let files = StoredFile::find_largest(&db.pool, Some(FileType::Image), 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        let files = StoredFile::find_largest(&db.pool, None, 10, 1)
+            .await
+            .unwrap();
+        assert_eq!(files[0].filename, "b.jpg");
+    }
+
+    #[tokio::test]
+    async fn test_trash_stats() {
+        let db = memory_db().await;
+        let create = |content: &str, files: &[&str]| CreatePostRequest {
+            content: content.to_string(),
+            files: Some(
+                files
+                    .iter()
+                    .map(|url| FileInfo {
+                        url: url.to_string(),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: None,
+            title: None,
+        };
+        for (id, filename, size) in [("1", "a.pdf", 200), ("2", "b.jpg", 100), ("3", "c.png", 50)] {
+            StoredFile::save(&db, 1, id, filename, filename, None, size)
+                .await
+                .unwrap();
+        }
+
+        // b.jpg is still embedded in a post which is not trashed
+        let trashed = [
+            create(r#"<p><img src="/uploads/c.png"></p>"#, &["a.pdf", "b.jpg"]),
+            create("<p>empty</p>", &[]),
+        ];
+        for post in trashed {
+            let id = Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id;
+            Post::delete(&db, ADMIN_USER_ID, id, None).await.unwrap();
+        }
+        Post::create(
+            &db,
+            ADMIN_USER_ID,
+            &create(r#"<p><img src="/uploads/b.jpg"></p>"#, &[]),
+        )
+        .await
+        .unwrap();
+
+        let expected = TrashStats {
+            post_count: 2,
+            file_size: 250,
+        };
+        assert_eq!(
+            Post::get_trash_stats(&db.pool, ADMIN_USER_ID)
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(Post::clear_all(&db, ADMIN_USER_ID).await.unwrap(), expected);
+        assert_eq!(
+            Post::get_trash_stats(&db.pool, ADMIN_USER_ID)
+                .await
+                .unwrap(),
+            TrashStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schedule_purge() {
+        let db = memory_db().await;
+        let post = CreatePostRequest {
+            content: "<p>secret</p>".to_string(),
+            files: None,
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: None,
+            title: None,
+        };
+        let id = Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id;
+        let purge_after = |pool: SqlitePool| async move {
+            query_scalar!("SELECT purge_after FROM posts WHERE id = ?", id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        // Only the posts in the trash are purged
+        assert!(matches!(
+            Post::schedule_purge(&db, ADMIN_USER_ID, id, Some(1)).await,
+            Err(ApiError::NotFound(_))
+        ));
+        Post::delete(&db, ADMIN_USER_ID, id, Some(100))
+            .await
+            .unwrap();
+        assert_eq!(purge_after(db.pool.clone()).await, Some(100));
+        Post::schedule_purge(&db, ADMIN_USER_ID, id, None)
+            .await
+            .unwrap();
+        assert_eq!(purge_after(db.pool.clone()).await, None);
+
+        Post::schedule_purge(&db, ADMIN_USER_ID, id, Some(200))
+            .await
+            .unwrap();
+        Post::restore(&db, ADMIN_USER_ID, id).await.unwrap();
+        assert_eq!(purge_after(db.pool.clone()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_title() {
+        let db = memory_db().await;
+        let create = |content: &str, title: Option<&str>| CreatePostRequest {
+            content: content.to_string(),
+            files: None,
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: None,
+            title: title.map(String::from),
+        };
+        let update = |value: serde_json::Value| -> UpdatePostRequest {
+            serde_json::from_value(value).unwrap()
+        };
+        let title = |id| {
+            let db = &db;
+            async move { Post::find_by_id(&db.pool, id).await.unwrap().unwrap().title }
+        };
+
+        let derived = Post::create(
+            &db,
+            ADMIN_USER_ID,
+            &create("<h2>First</h2><p>text</p>", None),
+        )
+        .await
+        .unwrap()
+        .id;
+        assert_eq!(title(derived).await.as_deref(), Some("First"));
+        let custom = Post::create(
+            &db,
+            ADMIN_USER_ID,
+            &create("<h2>First</h2>", Some(" Mine ")),
+        )
+        .await
+        .unwrap()
+        .id;
+        assert_eq!(title(custom).await.as_deref(), Some("Mine"));
+        let blank = Post::create(&db, ADMIN_USER_ID, &create("<p>text</p>", Some(" ")))
+            .await
+            .unwrap()
+            .id;
+        assert_eq!(title(blank).await, None);
+
+        // Changing the content derives the title again, unless it is a custom one
+        for id in [derived, custom] {
+            Post::update(
+                &db,
+                ADMIN_USER_ID,
+                &update(json!({"id": id, "content": "<h1>Second</h1>"})),
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(title(derived).await.as_deref(), Some("Second"));
+        assert_eq!(title(custom).await.as_deref(), Some("Mine"));
+
+        Post::update(
+            &db,
+            ADMIN_USER_ID,
+            &update(json!({"id": custom, "title": null})),
+        )
+        .await
+        .unwrap();
+        assert_eq!(title(custom).await.as_deref(), Some("Second"));
+        Post::update(
+            &db,
+            ADMIN_USER_ID,
+            &update(json!({"id": derived, "title": "Other"})),
+        )
+        .await
+        .unwrap();
+        assert_eq!(title(derived).await.as_deref(), Some("Other"));
+    }
+
+    #[tokio::test]
+    async fn test_tag_sort() {
+        use crate::model::tag::{Tag, TagSort};
+
+        let db = memory_db().await;
+        for (tags, created_at) in [
+            (["b", "a/x"], 3),
+            (["b", "c"], 1),
+            (["c", "c"], 2),
+            (["c", "c"], 4),
+        ] {
+            let post = CreatePostRequest {
+                content: tags
+                    .map(|tag| format!(r#"<span class="hash-tag">#{}</span>"#, tag))
+                    .concat(),
+                files: None,
+                color: None,
+                shared: None,
+                parent_id: None,
+                created_at: Some(created_at),
+                title: None,
+            };
+            Post::create(&db, ADMIN_USER_ID, &post).await.unwrap();
+        }
+
+        let names = |sort| {
+            let db = &db;
+            async move {
+                Tag::get_all_with_post_count(&db.pool, ADMIN_USER_ID, false, sort)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|t| t.name)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(names(Some(TagSort::Name)).await, ["a/x", "b", "c"]);
+        assert_eq!(names(Some(TagSort::PostCount)).await, ["c", "b", "a/x"]);
+        // Ties are ordered by name
+        assert_eq!(names(Some(TagSort::Recent)).await, ["c", "a/x", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_posts_of_users() {
+        let db = memory_db().await;
+        User::ensure_admin(&db, "admin", "hash").await.unwrap();
+        let bob = User::create(&db, "bob", "hash", false).await.unwrap().id;
+        let create = |content: String, parent_id| CreatePostRequest {
+            content,
+            files: None,
+            color: None,
+            shared: None,
+            parent_id,
+            created_at: None,
+            title: None,
+        };
+        let tagged = r#"<p><span class="hash-tag">#work</span></p>"#.to_string();
+        let mine = Post::create(&db, ADMIN_USER_ID, &create(tagged.clone(), None))
+            .await
+            .unwrap()
+            .id;
+        let theirs = Post::create(&db, bob, &create(tagged, None))
+            .await
+            .unwrap()
+            .id;
+
+        // The same tag name is a different tag for each user
+        for user_id in [ADMIN_USER_ID, bob] {
+            let tags = Tag::get_all_with_post_count(&db.pool, user_id, false, None)
+                .await
+                .unwrap();
+            assert_eq!(tags.len(), 1);
+            assert_eq!(tags[0].post_count, 1);
+        }
+        assert!(Post::find_by_ids(&db.pool, bob, &[mine])
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            UserPosts::new(&db.pool, bob)
+                .retain(&[mine, theirs])
+                .await
+                .unwrap(),
+            HashSet::from([theirs])
+        );
+
+        // Other users can neither change the post, nor reply to it, nor link to it
+        assert!(matches!(
+            Post::delete(&db, bob, mine, None).await,
+            Err(ApiError::NotFound(_))
+        ));
+        assert!(matches!(
+            Post::create(&db, bob, &create("<p>reply</p>".to_string(), Some(mine))).await,
+            Err(ApiError::NotFound(_))
+        ));
+        let link = format!(r#"<p><a href="/p/{}">link</a></p>"#, mine);
+        Post::create(&db, bob, &create(link, None)).await.unwrap();
+        assert!(Post::find_referrers(&db.pool, mine)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The links of the posts saved before links were recorded are found again, for their own user only
+        let link = format!(r#"<p><a href="/p/{}">link</a></p>"#, mine);
+        let referrer = Post::create(&db, ADMIN_USER_ID, &create(link, None))
+            .await
+            .unwrap()
+            .id;
+        query!("DELETE FROM post_links")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(Post::find_referrers(&db.pool, mine)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(Post::backfill_post_links(&db).await.unwrap(), 2);
+        // It is done once
+        query!("DELETE FROM post_links")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(Post::backfill_post_links(&db).await.unwrap(), 0);
+        query!("INSERT INTO backfills (name) VALUES ('post_links')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(Post::backfill_post_links(&db).await.unwrap(), 2);
+        assert_eq!(
+            Post::find_referrers(&db.pool, mine).await.unwrap(),
+            vec![referrer]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_to_markdown() {
+        let db = memory_db().await;
+        let post = CreatePostRequest {
+            content: r#"<h1>Trip</h1><p><span class="hash-tag">#travel</span> <img src="/uploads/b.png"></p>"#
+                .to_string(),
+            files: Some(vec![FileInfo {
+                url: "a.pdf".to_string(),
+                original_name: Some("plan (1).pdf".to_string()),
+                ..Default::default()
+            }]),
+            color: None,
+            shared: None,
+            parent_id: None,
+            created_at: Some(0),
+            title: None,
+        };
+        let id = Post::create(&db, ADMIN_USER_ID, &post).await.unwrap().id;
+        let post = Post::find_by_ids(&db.pool, ADMIN_USER_ID, &[id])
+            .await
+            .unwrap()
+            .remove(0);
+
+        let md = post.to_markdown(&|url| format!("files/{}", url.trim_start_matches("/uploads/")));
+        assert_eq!(
+            md,
+            format!(
+                "---\nid: {}\ntitle: \"Trip\"\ncreated_at: 1970-01-01T00:00:00+00:00\nupdated_at: {}\ntags: [\"travel\"]\n---\n\n\
+                 # Trip\n\n#travel ![](files/b.png)\n\n- [plan (1).pdf](files/a.pdf)\n",
+                id,
+                DateTime::from_timestamp_millis(post.row.updated_at)
+                    .unwrap()
+                    .to_rfc3339()
+            )
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use crate::config::db::DB;
+use crate::errors::{not_found, ApiResult};
+use crate::model::reaction::ReactionCount;
+use chrono::Utc;
+use sqlx::{query, query_as, SqlitePool};
+
+impl ReactionCount {
+    /// Adds the reaction if the reactor has not reacted with the emoji yet, otherwise removes it.
+    /// Returns the counts after the change.
+    pub async fn toggle(
+        db: &DB,
+        post_id: i64,
+        emoji: &str,
+        reactor: &str,
+    ) -> ApiResult<Vec<ReactionCount>> {
+        let now = Utc::now().timestamp_millis();
+        let mut tx = db.writer.begin().await?;
+
+        let exists = query!(
+            "SELECT id FROM posts WHERE id = ? AND deleted_at IS NULL",
+            post_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if exists.is_none() {
+            return Err(not_found("post not found"));
+        }
+
+        let removed = query!(
+            "DELETE FROM post_reactions WHERE post_id = ? AND emoji = ? AND reactor = ?",
+            post_id,
+            emoji,
+            reactor
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        if removed == 0 {
+            query!(
+                r#"
+                INSERT INTO post_reactions (post_id, emoji, reactor, created_at)
+                VALUES (?, ?, ?, ?)
+                "#,
+                post_id,
+                emoji,
+                reactor,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Self::find_by_post(db, post_id).await
+    }
+
+    /// Returns the count of each emoji, the earliest used first.
+    pub async fn find_by_post(pool: &SqlitePool, post_id: i64) -> ApiResult<Vec<ReactionCount>> {
+        let counts = query_as!(
+            ReactionCount,
+            r#"
+            SELECT emoji, COUNT(*) AS "count!: i64"
+            FROM post_reactions
+            WHERE post_id = ?
+            GROUP BY emoji
+            ORDER BY MIN(created_at)
+            "#,
+            post_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(counts)
+    }
+}
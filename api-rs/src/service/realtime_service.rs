@@ -0,0 +1,235 @@
+use crate::model::notification::Notification;
+use crate::model::user::User;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events pushed to connected clients. `user_id` is the user who uploaded the file
+/// or who owns the post, the event is sent to the clients of that user only.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RealtimeEvent {
+    /// The thumbnail of an uploaded image is ready.
+    FileProcessed {
+        #[serde(skip)]
+        user_id: i64,
+        url: String,
+        thumb_url: String,
+    },
+    /// The uploaded image could not be processed, it has no thumbnail.
+    FileProcessingFailed {
+        #[serde(skip)]
+        user_id: i64,
+        url: String,
+    },
+    /// A notification is created, e.g. a job failed. It is sent to admins only.
+    Notification(Notification),
+    /// A post is changed, possibly on another device.
+    PostChanged {
+        id: i64,
+        #[serde(skip)]
+        user_id: i64,
+        change: PostChange,
+    },
+    /// A step of processing an upload is done, sent only if the upload has a token.
+    /// `step` counts up to `steps`, the upload is done when they are equal.
+    UploadProgress {
+        #[serde(skip)]
+        user_id: i64,
+        token: String,
+        stage: UploadStage,
+        step: u8,
+        steps: u8,
+        url: String,
+    },
+}
+
+impl RealtimeEvent {
+    /// Whether the event is sent to the clients of the user.
+    pub fn is_visible_to(&self, user: &User) -> bool {
+        match self {
+            RealtimeEvent::FileProcessed { user_id, .. }
+            | RealtimeEvent::FileProcessingFailed { user_id, .. }
+            | RealtimeEvent::PostChanged { user_id, .. }
+            | RealtimeEvent::UploadProgress { user_id, .. } => *user_id == user.id,
+            RealtimeEvent::Notification(_) => user.admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStage {
+    /// The file is saved in the upload directory
+    Received,
+    /// The file is recorded, or an identical one is reused
+    Stored,
+    /// The thumbnail of an image is made
+    Thumbnailed,
+    /// The text of the file is extracted for search
+    Indexed,
+    /// The upload is rejected or could not be processed
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostChange {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Cleared,
+}
+
+/// Broadcasts events to every connected client.
+pub struct RealtimeHub {
+    sender: broadcast::Sender<RealtimeEvent>,
+}
+
+impl RealtimeHub {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Sends an event to all subscribers, it is dropped if nobody is listening.
+    pub fn publish(&self, event: RealtimeEvent) {
+        self.sender.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RealtimeEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Reports the processing of an upload to the client which sent its token.
+#[derive(Clone)]
+pub struct UploadTracker {
+    hub: Arc<RealtimeHub>,
+    /// The user who uploads the file
+    pub user_id: i64,
+    token: Option<String>,
+    steps: u8,
+}
+
+impl UploadTracker {
+    pub fn new(
+        hub: Arc<RealtimeHub>,
+        user_id: i64,
+        token: Option<String>,
+        has_thumbnail: bool,
+    ) -> Self {
+        let steps = if has_thumbnail { 4 } else { 3 };
+        Self {
+            hub,
+            user_id,
+            token,
+            steps,
+        }
+    }
+
+    pub fn report(&self, stage: UploadStage, url: &str) {
+        let Some(ref token) = self.token else {
+            return;
+        };
+        let step = match stage {
+            UploadStage::Received => 1,
+            UploadStage::Stored => 2,
+            UploadStage::Thumbnailed => 3,
+            UploadStage::Indexed | UploadStage::Failed => self.steps,
+        };
+        self.hub.publish(RealtimeEvent::UploadProgress {
+            user_id: self.user_id,
+            token: token.clone(),
+            stage,
+            step,
+            steps: self.steps,
+            url: url.to_string(),
+        });
+    }
+}
+
+impl Default for RealtimeHub {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let hub = RealtimeHub::default();
+        hub.publish(RealtimeEvent::FileProcessingFailed {
+            user_id: 1,
+            url: "/a".into(),
+        });
+
+        let mut rx = hub.subscribe();
+        hub.publish(RealtimeEvent::FileProcessed {
+            user_id: 1,
+            url: "/a".into(),
+            thumb_url: "/thumb_a".into(),
+        });
+
+        let event = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(event["type"], "file_processed");
+        assert_eq!(event["thumb_url"], "/thumb_a");
+        assert!(event.get("user_id").is_none());
+    }
+
+    #[test]
+    fn test_is_visible_to() {
+        let user = |id, admin| User {
+            id,
+            name: String::new(),
+            password_hash: String::new(),
+            admin,
+            created_at: 0,
+        };
+        let event = RealtimeEvent::UploadProgress {
+            user_id: 1,
+            token: "t1".into(),
+            stage: UploadStage::Received,
+            step: 1,
+            steps: 3,
+            url: "/a".into(),
+        };
+        assert!(event.is_visible_to(&user(1, false)));
+        assert!(!event.is_visible_to(&user(2, true)));
+
+        let event = RealtimeEvent::Notification(Notification {
+            id: 1,
+            kind: "job.failed".into(),
+            message: String::new(),
+            read_at: None,
+            created_at: 0,
+        });
+        assert!(event.is_visible_to(&user(2, true)));
+        assert!(!event.is_visible_to(&user(1, false)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_tracker() {
+        let hub = Arc::new(RealtimeHub::default());
+        let mut rx = hub.subscribe();
+
+        UploadTracker::new(hub.clone(), 1, None, true).report(UploadStage::Received, "/a");
+        let tracker = UploadTracker::new(hub.clone(), 1, Some("t1".into()), false);
+        tracker.report(UploadStage::Stored, "/a");
+        tracker.report(UploadStage::Indexed, "/a");
+
+        let event = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(event["type"], "upload_progress");
+        assert_eq!(event["token"], "t1");
+        assert_eq!(event["stage"], "stored");
+        assert_eq!(event["step"], 2);
+
+        let event = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(event["step"], event["steps"]);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,10 +1,15 @@
 use crate::config::rd::RD;
+use crate::service::kv_service::is_transient;
+use crate::util::retry::CircuitOpen;
 use bb8::PooledConnection;
 use bb8_redis::RedisConnectionManager;
-use redis::{AsyncCommands, FromRedisValue, Pipeline, ToRedisArgs};
+use redis::{AsyncCommands, FromRedisValue, Pipeline, Script, ToRedisArgs};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::future::Future;
 use std::hash::Hash;
+use tracing::warn;
 
 impl RD {
     pub async fn get_connection(
@@ -14,25 +19,82 @@ impl RD {
         Ok(conn)
     }
 
+    /// Runs an operation, and tries it again after a transient error if it is idempotent.
+    /// While the circuit breaker is open, it fails at once without calling Redis.
+    async fn call<T, F, Fut>(&self, idempotent: bool, op: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if !self.breaker.allows() {
+            return Err(CircuitOpen { name: "Redis" }.into());
+        }
+
+        let retries = if idempotent { self.retry.retries } else { 0 };
+        let mut retry = 0;
+        loop {
+            match op().await {
+                Err(e) if is_transient(&e) => {
+                    if retry < retries {
+                        retry += 1;
+                        warn!("Redis error, retrying ({}/{}): {:#}", retry, retries, e);
+                        tokio::time::sleep(self.retry.delay(retry)).await;
+                        continue;
+                    }
+                    self.breaker.record_failure();
+                    return Err(e);
+                }
+                // Any other error is a reply, Redis is reachable
+                rv => {
+                    self.breaker.record_success();
+                    return rv;
+                }
+            }
+        }
+    }
+
     pub async fn exists<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<bool> {
-        let mut conn = self.get_connection().await?;
-        let exists: bool = conn.exists(key).await?;
-        Ok(exists)
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let exists: bool = conn.exists(key).await?;
+            Ok(exists)
+        })
+        .await
     }
 
     pub async fn scard<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<usize> {
-        let mut conn = self.get_connection().await?;
-        let count: usize = conn.scard(key).await?;
-        Ok(count)
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let count: usize = conn.scard(key).await?;
+            Ok(count)
+        })
+        .await
+    }
+
+    /// Returns the remaining time to live of a key in milliseconds, negative if it has no expiration or does not exist.
+    pub async fn pttl<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<i64> {
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let ttl: i64 = conn.pttl(key).await?;
+            Ok(ttl)
+        })
+        .await
     }
 
     pub async fn get<T, K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<Option<T>>
     where
         T: FromRedisValue,
     {
-        let mut conn = self.get_connection().await?;
-        let value: Option<T> = conn.get(key).await?;
-        Ok(value)
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let value: Option<T> = conn.get(key).await?;
+            Ok(value)
+        })
+        .await
     }
 
     pub async fn get_object<T, K: ToRedisArgs + Send + Sync>(
@@ -42,8 +104,7 @@ impl RD {
     where
         T: for<'de> Deserialize<'de>,
     {
-        let mut conn = self.get_connection().await?;
-        let json: Option<String> = conn.get(key).await?;
+        let json: Option<String> = self.get(key).await?;
         match json {
             Some(data) => Ok(Some(serde_json::from_str(&data)?)),
             None => Ok(None),
@@ -54,9 +115,13 @@ impl RD {
         &self,
         key: K,
     ) -> anyhow::Result<Vec<Option<T>>> {
-        let mut conn = self.get_connection().await?;
-        let values: Vec<Option<T>> = conn.mget(key).await?;
-        Ok(values)
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let values: Vec<Option<T>> = conn.mget(key).await?;
+            Ok(values)
+        })
+        .await
     }
 
     pub async fn mget_object<T, K: ToRedisArgs + Send + Sync>(
@@ -66,8 +131,7 @@ impl RD {
     where
         T: for<'de> Deserialize<'de>,
     {
-        let mut conn = self.get_connection().await?;
-        let json: Vec<Option<String>> = conn.mget(key).await?;
+        let json: Vec<Option<String>> = self.mget(key).await?;
         let mut result: Vec<Option<T>> = Vec::new();
 
         for opt_string in json {
@@ -80,7 +144,6 @@ impl RD {
         Ok(result)
     }
 
-    //noinspection DuplicatedCode
     pub async fn set<T, K: ToRedisArgs + Send + Sync>(
         &self,
         key: K,
@@ -90,16 +153,19 @@ impl RD {
     where
         T: ToRedisArgs + Send + Sync,
     {
-        let mut conn = self.get_connection().await?;
-        if let Some(expire) = expire_seconds {
-            conn.set_ex::<_, _, ()>(key, value, expire).await?;
-        } else {
-            conn.set::<_, _, ()>(key, value).await?;
-        }
-        Ok(())
+        let (key, value) = (&key, &value);
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            if let Some(expire) = expire_seconds {
+                conn.set_ex::<_, _, ()>(key, value, expire).await?;
+            } else {
+                conn.set::<_, _, ()>(key, value).await?;
+            }
+            Ok(())
+        })
+        .await
     }
 
-    //noinspection DuplicatedCode
     pub async fn set_object<T, K: ToRedisArgs + Send + Sync>(
         &self,
         key: K,
@@ -109,26 +175,52 @@ impl RD {
     where
         T: Serialize,
     {
-        let mut conn = self.get_connection().await?;
         let json = serde_json::to_string(value)?;
-        if let Some(expire) = expire_seconds {
-            conn.set_ex::<_, _, ()>(key, json, expire).await?;
-        } else {
-            conn.set::<_, _, ()>(key, json).await?;
+        self.set(key, json, expire_seconds).await
+    }
+
+    /// Returns the cached value of the key, or fetches it and caches it as JSON for `ttl_seconds`.
+    /// Redis errors are logged and the value is fetched instead, so that the cache never fails a request.
+    /// The errors of fetching are returned, and nothing is cached then.
+    pub async fn cached<T, E, F, Fut>(&self, key: &str, ttl_seconds: u64, fetch: F) -> Result<T, E>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        match self.get_object::<T, _>(key).await {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => {}
+            Err(e) => warn!("Cannot read the cache of {}: {:?}", key, e),
+        }
+
+        let value = fetch().await?;
+        if let Err(e) = self.set_object(key, &value, Some(ttl_seconds)).await {
+            warn!("Cannot cache {}: {:?}", key, e);
         }
-        Ok(())
+        Ok(value)
     }
 
+    /// Not tried again, since an increment may be applied before the connection drops
     pub async fn incr<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<i64> {
-        let mut conn = self.get_connection().await?;
-        let value: i64 = conn.incr(key, 1).await?;
-        Ok(value)
+        let key = &key;
+        self.call(false, || async move {
+            let mut conn = self.get_connection().await?;
+            let value: i64 = conn.incr(key, 1).await?;
+            Ok(value)
+        })
+        .await
     }
 
+    /// Not tried again, see `incr`
     pub async fn decr<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<i64> {
-        let mut conn = self.get_connection().await?;
-        let value: i64 = conn.decr(key, 1).await?;
-        Ok(value)
+        let key = &key;
+        self.call(false, || async move {
+            let mut conn = self.get_connection().await?;
+            let value: i64 = conn.decr(key, 1).await?;
+            Ok(value)
+        })
+        .await
     }
 
     pub async fn smembers<T, K: ToRedisArgs + Send + Sync>(
@@ -138,37 +230,151 @@ impl RD {
     where
         T: FromRedisValue + Hash + Eq,
     {
-        let mut conn = self.get_connection().await?;
-        let members: HashSet<T> = conn.smembers(key).await?;
-        Ok(members)
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let members: HashSet<T> = conn.smembers(key).await?;
+            Ok(members)
+        })
+        .await
+    }
+
+    pub async fn lrange<T, K: ToRedisArgs + Send + Sync>(
+        &self,
+        key: K,
+        start: isize,
+        stop: isize,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: FromRedisValue,
+    {
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let values: Vec<T> = conn.lrange(key, start, stop).await?;
+            Ok(values)
+        })
+        .await
     }
 
     pub async fn keys<K: ToRedisArgs + Send + Sync>(
         &self,
         pattern: K,
     ) -> anyhow::Result<Vec<String>> {
-        let mut conn = self.get_connection().await?;
-        let keys: Vec<String> = conn.keys(pattern).await?;
-        Ok(keys)
+        let pattern = &pattern;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let keys: Vec<String> = conn.keys(pattern).await?;
+            Ok(keys)
+        })
+        .await
     }
 
     pub async fn del<K: ToRedisArgs + Send + Sync>(&self, key: K) -> anyhow::Result<()> {
-        let mut conn = self.get_connection().await?;
-        conn.del::<_, ()>(key).await?;
-        Ok(())
+        let key = &key;
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            conn.del::<_, ()>(key).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the key to the token if it does not exist, expiring after `ttl_ms` milliseconds.
+    /// Returns whether the lock is acquired.
+    /// Not tried again, a lock set before the connection drops would look held by someone else.
+    pub async fn try_lock(&self, key: &str, token: &str, ttl_ms: u64) -> anyhow::Result<bool> {
+        self.call(false, || async move {
+            let mut conn = self.get_connection().await?;
+            let rv: Option<String> = redis::cmd("SET")
+                .arg(key)
+                .arg(token)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl_ms)
+                .query_async(&mut *conn)
+                .await?;
+            Ok(rv.is_some())
+        })
+        .await
+    }
+
+    /// Extends the expiration of a lock, returns false if it is no longer held with the token.
+    pub async fn extend_lock(&self, key: &str, token: &str, ttl_ms: u64) -> anyhow::Result<bool> {
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            let extended: i64 = Script::new(
+                r#"
+                if redis.call("GET", KEYS[1]) == ARGV[1] then
+                    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+                end
+                return 0
+                "#,
+            )
+            .key(key)
+            .arg(token)
+            .arg(ttl_ms)
+            .invoke_async(&mut *conn)
+            .await?;
+            Ok(extended == 1)
+        })
+        .await
     }
 
+    /// Releases a lock, unless it has expired and been acquired by someone else.
+    pub async fn unlock(&self, key: &str, token: &str) -> anyhow::Result<()> {
+        self.call(true, || async move {
+            let mut conn = self.get_connection().await?;
+            Script::new(
+                r#"
+                if redis.call("GET", KEYS[1]) == ARGV[1] then
+                    return redis.call("DEL", KEYS[1])
+                end
+                return 0
+                "#,
+            )
+            .key(key)
+            .arg(token)
+            .invoke_async::<()>(&mut *conn)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Runs the commands atomically, they are not tried again since they may not be idempotent.
     pub async fn pipeline<T, F>(&self, callback: F) -> anyhow::Result<T>
     where
         F: FnOnce(&mut Pipeline),
         T: FromRedisValue,
     {
-        let mut conn = self.get_connection().await?;
+        self.run_pipeline(false, callback).await
+    }
+
+    /// Like `pipeline`, but tried again after a transient error, the commands must only read.
+    pub async fn read_pipeline<T, F>(&self, callback: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Pipeline),
+        T: FromRedisValue,
+    {
+        self.run_pipeline(true, callback).await
+    }
+
+    async fn run_pipeline<T, F>(&self, idempotent: bool, callback: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Pipeline),
+        T: FromRedisValue,
+    {
         let mut pipe = redis::pipe();
-        let pipe = pipe.atomic();
-        callback(pipe);
-        let rv = pipe.query_async(&mut *conn).await;
+        pipe.atomic();
+        callback(&mut pipe);
 
-        rv.map_err(anyhow::Error::from)
+        let pipe = &pipe;
+        self.call(idempotent, || async move {
+            let mut conn = self.get_connection().await?;
+            let rv = pipe.query_async(&mut *conn).await?;
+            Ok(rv)
+        })
+        .await
     }
 }
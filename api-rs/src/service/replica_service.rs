@@ -0,0 +1,618 @@
+use crate::config::db::DB;
+use crate::config::ReplicaConfig;
+use crate::util::s3::S3Client;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::BoxFuture;
+use sqlx::SqliteConnection;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::{fs, time};
+use tracing::{error, info, warn};
+
+const WAL_HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+
+/// Where the replica is kept. Keys are paths separated by slashes:
+///
+/// - `{generation}/snapshot.db.gz`, the database when the generation starts
+/// - `{generation}/wal/{index}-{timestamp}.wal.gz`, the frames committed since the last segment
+///
+/// A generation is named after the time of its snapshot in milliseconds, padded to sort by time.
+pub trait ReplicaStore: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// Returns the keys starting with `prefix`, sorted.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>>;
+}
+
+/// Creates the store of `REPLICA_URL`, or `None` if the replication is disabled.
+pub fn replica_store_from_config(config: &ReplicaConfig) -> Option<Arc<dyn ReplicaStore>> {
+    if let Some(path) = config.url.strip_prefix("s3://") {
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        Some(Arc::new(S3Store {
+            client: S3Client::new(
+                &config.endpoint,
+                bucket,
+                &config.region,
+                &config.access_key_id,
+                &config.secret_access_key,
+            ),
+            prefix: prefix.trim_matches('/').to_string(),
+        }))
+    } else {
+        config.url.strip_prefix("file://").map(|path| {
+            Arc::new(FileStore {
+                root: PathBuf::from(path),
+            }) as Arc<dyn ReplicaStore>
+        })
+    }
+}
+
+pub struct S3Store {
+    client: S3Client,
+    prefix: String,
+}
+
+impl S3Store {
+    fn object_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+}
+
+impl ReplicaStore for S3Store {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.client.put_object(&self.object_key(key), data).await })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move { self.client.get_object(&self.object_key(key)).await })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async move {
+            let root = self.object_key("");
+            let keys = self.client.list_objects(&self.object_key(prefix)).await?;
+            Ok(keys
+                .into_iter()
+                .filter_map(|key| key.strip_prefix(&root).map(String::from))
+                .collect())
+        })
+    }
+}
+
+/// A directory, e.g. on a mounted network drive
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl ReplicaStore for FileStore {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            // Written under another name first, so a crash never leaves a partial object
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, data).await?;
+            fs::rename(&tmp, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move { Ok(fs::read(self.root.join(key)).await?) })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async move {
+            let mut keys = vec![];
+            let mut dirs = vec![self.root.clone()];
+            while let Some(dir) = dirs.pop() {
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let path = entry.path();
+                    if entry.file_type().await?.is_dir() {
+                        dirs.push(path);
+                    } else if path.extension().is_some_and(|ext| ext == "gz") {
+                        let key = path
+                            .strip_prefix(&self.root)?
+                            .to_string_lossy()
+                            .replace('\\', "/");
+                        if key.starts_with(prefix) {
+                            keys.push(key);
+                        }
+                    }
+                }
+            }
+            keys.sort();
+            Ok(keys)
+        })
+    }
+}
+
+/// How far a generation is replicated
+struct Position {
+    generation: String,
+    started_at: i64,
+    /// The index of the next segment
+    index: u32,
+    /// Where the frames which are not shipped yet start in the WAL file, 0 for a new WAL
+    offset: usize,
+    /// The salts of the WAL header, which change when the WAL restarts
+    salts: Option<[u8; 8]>,
+}
+
+/// Ships the WAL of the database to a [`ReplicaStore`], like Litestream.
+///
+/// SQLite does not checkpoint the WAL by itself while the database is replicated,
+/// see `DBConfig::auto_checkpoint`. The replicator checkpoints it after its frames are read,
+/// holding the only writer connection, so no frame is written back before it is shipped.
+/// If the WAL is checkpointed by someone else, e.g. `/api/admin/optimize`, a new generation starts.
+pub struct Replicator {
+    db: Arc<DB>,
+    store: Arc<dyn ReplicaStore>,
+    path: String,
+    checkpoint_size: usize,
+    snapshot_interval_ms: i64,
+    position: Option<Position>,
+}
+
+impl Replicator {
+    pub async fn new(
+        db: Arc<DB>,
+        store: Arc<dyn ReplicaStore>,
+        config: &ReplicaConfig,
+    ) -> Result<Self> {
+        let path = db
+            .path()
+            .await?
+            .ok_or_else(|| anyhow!("An in-memory database cannot be replicated"))?;
+
+        Ok(Self {
+            db,
+            store,
+            path,
+            checkpoint_size: config.checkpoint_size as usize,
+            snapshot_interval_ms: config.snapshot_interval_secs as i64 * 1000,
+            position: None,
+        })
+    }
+
+    /// Ships the frames committed since the last sync, starting a generation if it is time to.
+    /// The generation is abandoned if the frames cannot be shipped, and a new one starts next time.
+    pub async fn sync(&mut self) -> Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let position = match self.position.take() {
+            Some(position) if now - position.started_at < self.snapshot_interval_ms => position,
+            _ => self.snapshot().await?,
+        };
+        self.position = self.ship(position).await?;
+        Ok(())
+    }
+
+    /// Starts a generation with a copy of the database, taken after the WAL is checkpointed.
+    async fn snapshot(&self) -> Result<Position> {
+        let data = {
+            let mut conn = self.db.writer.acquire().await?;
+            if !checkpoint(&mut conn).await? {
+                bail!("The WAL cannot be checkpointed for a snapshot, the database is busy");
+            }
+            fs::read(&self.path).await?
+        };
+
+        let started_at = Utc::now().timestamp_millis();
+        let generation = format!("{:013}", started_at);
+        let size = data.len();
+        self.store
+            .put(&format!("{}/snapshot.db.gz", generation), gzip(&data)?)
+            .await
+            .context("Cannot upload the snapshot")?;
+        info!(
+            "[Replica] Generation {} started, {} bytes",
+            generation, size
+        );
+
+        Ok(Position {
+            generation,
+            started_at,
+            index: 0,
+            offset: 0,
+            salts: None,
+        })
+    }
+
+    async fn ship(&self, mut position: Position) -> Result<Option<Position>> {
+        let segment = {
+            let mut conn = self.db.writer.acquire().await?;
+            let wal = match fs::read(format!("{}-wal", self.path)).await {
+                Ok(wal) => wal,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e.into()),
+            };
+
+            let Some(frames) = committed_frames(&wal, position.offset, position.salts) else {
+                warn!("[Replica] The WAL is checkpointed by someone else, a new generation starts");
+                return Ok(None);
+            };
+            let segment = frames.segment(&wal);
+            position.offset = frames.end;
+            position.salts = frames.salts;
+
+            if wal.len() >= self.checkpoint_size && checkpoint(&mut conn).await? {
+                position.offset = 0;
+                position.salts = None;
+            }
+            segment
+        };
+
+        if let Some(segment) = segment {
+            let key = format!(
+                "{}/wal/{:08}-{:013}.wal.gz",
+                position.generation,
+                position.index,
+                Utc::now().timestamp_millis()
+            );
+            // The frames may be checkpointed already, the generation cannot go on without them
+            self.store
+                .put(&key, gzip(&segment)?)
+                .await
+                .with_context(|| format!("Cannot upload {}", key))?;
+            position.index += 1;
+        }
+        Ok(Some(position))
+    }
+}
+
+/// Runs the replication until the app exits, if `REPLICA_URL` is set.
+pub async fn replicate(db: Arc<DB>, config: ReplicaConfig) {
+    let Some(store) = replica_store_from_config(&config) else {
+        return;
+    };
+    let mut replicator = match Replicator::new(db, store, &config).await {
+        Ok(replicator) => replicator,
+        Err(e) => {
+            error!("[Replica] Cannot start: {:?}", e);
+            return;
+        }
+    };
+
+    info!("[Replica] Replicating to {}", config.url);
+    let mut interval = time::interval(std::time::Duration::from_secs(config.sync_interval_secs));
+    loop {
+        interval.tick().await;
+        if let Err(e) = replicator.sync().await {
+            error!("[Replica] Cannot sync: {:?}", e);
+        }
+    }
+}
+
+/// Checkpoints the WAL and truncates it, returns false if some readers still use it.
+async fn checkpoint(conn: &mut SqliteConnection) -> Result<bool> {
+    let (busy, _, _): (i64, i64, i64) = sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(conn)
+        .await?;
+    Ok(busy == 0)
+}
+
+/// The committed frames of a WAL file which are not shipped yet
+#[derive(Debug, PartialEq)]
+struct Frames {
+    start: usize,
+    end: usize,
+    salts: Option<[u8; 8]>,
+}
+
+impl Frames {
+    /// The WAL header followed by the frames, `None` if there are no frames.
+    fn segment(&self, wal: &[u8]) -> Option<Vec<u8>> {
+        (self.end > self.start)
+            .then(|| [&wal[..WAL_HEADER_SIZE], &wal[self.start..self.end]].concat())
+    }
+}
+
+/// Finds the frames after `offset` up to the last commit, a transaction is never shipped in part.
+/// Returns `None` if the WAL has restarted since `offset`, which loses the frames in between.
+///
+/// The frames of an older WAL left in the file have other salts, so the frames are read
+/// until one with other salts, without verifying their checksums.
+fn committed_frames(wal: &[u8], offset: usize, salts: Option<[u8; 8]>) -> Option<Frames> {
+    if wal.len() < WAL_HEADER_SIZE {
+        // The header is written with the first frame after a truncation
+        return (offset == 0).then_some(Frames {
+            start: 0,
+            end: 0,
+            salts: None,
+        });
+    }
+
+    let header_salts: [u8; 8] = wal[16..24].try_into().unwrap();
+    if salts.is_some_and(|salts| salts != header_salts) || offset > wal.len() {
+        return None;
+    }
+
+    let page_size = u32::from_be_bytes(wal[8..12].try_into().unwrap()) as usize;
+    let start = offset.max(WAL_HEADER_SIZE);
+    let (mut pos, mut end) = (start, start);
+    while pos + FRAME_HEADER_SIZE + page_size <= wal.len() {
+        let header = &wal[pos..pos + FRAME_HEADER_SIZE];
+        if header[8..16] != header_salts {
+            break;
+        }
+        pos += FRAME_HEADER_SIZE + page_size;
+        // The size of the database after a commit, 0 for the other frames
+        if header[4..8] != [0; 4] {
+            end = pos;
+        }
+    }
+
+    Some(Frames {
+        start,
+        end,
+        salts: Some(header_salts),
+    })
+}
+
+/// Writes the pages of a segment into a database file, a transaction at a time.
+fn apply_segment(db: &mut Vec<u8>, segment: &[u8]) -> Result<()> {
+    if segment.len() < WAL_HEADER_SIZE {
+        bail!("The WAL segment is truncated");
+    }
+    let page_size = u32::from_be_bytes(segment[8..12].try_into().unwrap()) as usize;
+
+    let mut pages = vec![];
+    let mut pos = WAL_HEADER_SIZE;
+    while pos + FRAME_HEADER_SIZE + page_size <= segment.len() {
+        let header = &segment[pos..pos + FRAME_HEADER_SIZE];
+        let page_number = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let commit_size = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        let data = FRAME_HEADER_SIZE + pos;
+        pages.push((page_number, &segment[data..data + page_size]));
+        pos = data + page_size;
+
+        if commit_size > 0 {
+            db.resize(commit_size * page_size, 0);
+            for (page_number, page) in pages.drain(..) {
+                let offset = (page_number - 1) * page_size;
+                if let Some(target) = db.get_mut(offset..offset + page_size) {
+                    target.copy_from_slice(page);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What [`restore`] did
+#[derive(Debug)]
+pub struct RestoreSummary {
+    pub generation: String,
+    pub segments: usize,
+    /// When the last segment applied was shipped, in milliseconds
+    pub restored_to: i64,
+}
+
+/// Writes the database as it was at `timestamp` in milliseconds, or the latest one,
+/// to `path`, which must not exist. It is as recent as the last segment shipped before then.
+pub async fn restore(
+    store: &dyn ReplicaStore,
+    path: &Path,
+    timestamp: Option<i64>,
+) -> Result<RestoreSummary> {
+    if fs::try_exists(path).await? {
+        bail!("{} exists already", path.display());
+    }
+    let timestamp = timestamp.unwrap_or(i64::MAX);
+
+    let keys = store.list("").await?;
+    let generation = keys
+        .iter()
+        .filter_map(|key| key.strip_suffix("/snapshot.db.gz"))
+        .filter(|generation| generation.parse::<i64>().is_ok_and(|t| t <= timestamp))
+        .max()
+        .ok_or_else(|| anyhow!("No snapshot is found before the time"))?
+        .to_string();
+
+    // `{index}-{timestamp}.wal.gz`, the indexes are padded so the keys sort in order
+    let wal_prefix = format!("{}/wal/", generation);
+    let segments: Vec<(&String, i64)> = keys
+        .iter()
+        .filter_map(|key| {
+            let name = key.strip_prefix(&wal_prefix)?.strip_suffix(".wal.gz")?;
+            let (_, shipped_at) = name.split_once('-')?;
+            Some((key, shipped_at.parse().ok()?))
+        })
+        .take_while(|(_, shipped_at)| *shipped_at <= timestamp)
+        .collect();
+
+    let mut db = gunzip(&store.get(&format!("{}/snapshot.db.gz", generation)).await?)?;
+    let mut restored_to = generation.parse()?;
+    for (key, shipped_at) in &segments {
+        let segment = gunzip(&store.get(key).await?)?;
+        apply_segment(&mut db, &segment).with_context(|| format!("Cannot apply {}", key))?;
+        restored_to = *shipped_at;
+    }
+
+    fs::write(path, db).await?;
+    Ok(RestoreSummary {
+        generation,
+        segments: segments.len(),
+        restored_to,
+    })
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = vec![];
+    GzDecoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DBConfig;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_committed_frames() {
+        let page_size = 16;
+        let mut wal = vec![0u8; WAL_HEADER_SIZE];
+        wal[8..12].copy_from_slice(&(page_size as u32).to_be_bytes());
+        wal[16..24].copy_from_slice(b"saltsalt");
+        let frame = |page: u32, commit: u32, salts: &[u8]| {
+            let mut frame = vec![0u8; FRAME_HEADER_SIZE + page_size];
+            frame[0..4].copy_from_slice(&page.to_be_bytes());
+            frame[4..8].copy_from_slice(&commit.to_be_bytes());
+            frame[8..16].copy_from_slice(salts);
+            frame[FRAME_HEADER_SIZE..].fill(page as u8);
+            frame
+        };
+        let frame_size = FRAME_HEADER_SIZE + page_size;
+
+        assert_eq!(committed_frames(&[], 0, None).unwrap().end, 0);
+        assert!(committed_frames(&[], 100, Some(*b"saltsalt")).is_none());
+
+        // A transaction of two pages, and one which is not committed
+        wal.extend(frame(1, 0, b"saltsalt"));
+        wal.extend(frame(2, 2, b"saltsalt"));
+        wal.extend(frame(1, 0, b"saltsalt"));
+        let frames = committed_frames(&wal, 0, None).unwrap();
+        assert_eq!(
+            frames,
+            Frames {
+                start: WAL_HEADER_SIZE,
+                end: WAL_HEADER_SIZE + 2 * frame_size,
+                salts: Some(*b"saltsalt"),
+            }
+        );
+        let segment = frames.segment(&wal).unwrap();
+        assert!(committed_frames(&wal, frames.end, Some(*b"oldsalts")).is_none());
+
+        // Frames of an older WAL are left out
+        wal.extend(frame(3, 3, b"saltsalt"));
+        wal.extend(frame(4, 4, b"oldsalts"));
+        let frames = committed_frames(&wal, frames.end, frames.salts).unwrap();
+        assert_eq!(frames.end, WAL_HEADER_SIZE + 4 * frame_size);
+
+        let mut db = vec![9u8; page_size * 3];
+        apply_segment(&mut db, &segment).unwrap();
+        assert_eq!(db.len(), 2 * page_size);
+        assert_eq!(db[0], 1);
+        assert_eq!(db[page_size], 2);
+
+        apply_segment(&mut db, &frames.segment(&wal).unwrap()).unwrap();
+        assert_eq!(db.len(), 3 * page_size);
+        assert_eq!(db[0], 1);
+        assert_eq!(db[2 * page_size], 3);
+    }
+
+    #[tokio::test]
+    async fn test_replicate_and_restore() {
+        let dir = std::env::temp_dir().join(format!("mote-replica-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_config = DBConfig {
+            url: format!("sqlite://{}?mode=rwc", dir.join("app.db").display()),
+            pool_size: 2,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: false,
+        };
+        let replica_config = ReplicaConfig {
+            url: format!("file://{}", dir.join("replica").display()),
+            endpoint: String::new(),
+            region: String::new(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            sync_interval_secs: 1,
+            checkpoint_size: 64 * 1024,
+            snapshot_interval_secs: 3600,
+        };
+
+        let db = Arc::new(DB::new(&db_config).await.unwrap());
+        db.migrate().await.unwrap();
+        let store = replica_store_from_config(&replica_config).unwrap();
+        let mut replicator = Replicator::new(db.clone(), store.clone(), &replica_config)
+            .await
+            .unwrap();
+
+        let insert = |name: String| {
+            let db = db.clone();
+            async move {
+                sqlx::query(
+                    "INSERT INTO tags (user_id, name, created_at, updated_at) VALUES (1, ?, 0, 0)",
+                )
+                .bind(name)
+                .execute(&db.writer)
+                .await
+                .unwrap();
+            }
+        };
+        insert("before".to_string()).await;
+        replicator.sync().await.unwrap();
+
+        // Enough rows to checkpoint the WAL in between
+        for i in 0..200 {
+            insert(format!("tag-{}-{}", i, "x".repeat(500))).await;
+            if i % 50 == 0 {
+                replicator.sync().await.unwrap();
+            }
+        }
+        replicator.sync().await.unwrap();
+        let position = replicator.position.as_ref().unwrap();
+        assert!(position.index > 1);
+
+        let restored = dir.join("restored.db");
+        let summary = restore(store.as_ref(), &restored, None).await.unwrap();
+        assert_eq!(summary.generation, position.generation);
+        assert!(restore(store.as_ref(), &restored, None).await.is_err());
+
+        let restored_db = DB::new(&DBConfig {
+            url: format!("sqlite://{}", restored.display()),
+            ..db_config.clone()
+        })
+        .await
+        .unwrap();
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM tags")
+            .fetch_one(&restored_db.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 201);
+        let (check,): (String,) = sqlx::query_as("PRAGMA integrity_check")
+            .fetch_one(&restored_db.pool)
+            .await
+            .unwrap();
+        assert_eq!(check, "ok");
+
+        // Only the snapshot is restored before the first segment
+        let early = dir.join("early.db");
+        let summary = restore(store.as_ref(), &early, Some(summary.restored_to - 1))
+            .await
+            .unwrap();
+        assert!(summary.segments < position.index as usize);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use crate::config::ScanConfig;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Debug, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// The name of the detected signature
+    Infected(String),
+}
+
+/// Scans uploaded files for malware before they are stored.
+pub trait VirusScanner: Send + Sync {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<ScanVerdict>>;
+}
+
+/// Creates the scanner selected by the config, or `None` if scanning is disabled.
+pub fn virus_scanner_from_config(config: &ScanConfig) -> Option<Arc<dyn VirusScanner>> {
+    match config.provider.as_str() {
+        "clamd" => Some(Arc::new(ClamdScanner {
+            addr: config.clamd_addr.clone(),
+        })),
+        "http" => Some(Arc::new(HttpScanner {
+            client: reqwest::Client::new(),
+            url: config.api_url.clone(),
+            api_key: config.api_key.clone(),
+        })),
+        _ => None,
+    }
+}
+
+/// Moves a file into the quarantine directory, returns its new path.
+pub async fn quarantine(path: &Path, dir: &str) -> Result<PathBuf> {
+    let filename = path.file_name().ok_or(anyhow!("Invalid path"))?;
+    fs::create_dir_all(dir).await?;
+    let dest = Path::new(dir).join(filename);
+
+    // Renaming fails across file systems, fall back to copying
+    if fs::rename(path, &dest).await.is_err() {
+        fs::copy(path, &dest).await?;
+        fs::remove_file(path).await?;
+    }
+    Ok(dest)
+}
+
+/// Streams the file to a clamd daemon with the `INSTREAM` command.
+pub struct ClamdScanner {
+    addr: String,
+}
+
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+impl VirusScanner for ClamdScanner {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<ScanVerdict>> {
+        Box::pin(async move {
+            let mut file = fs::File::open(path).await?;
+            let mut stream = TcpStream::connect(&self.addr)
+                .await
+                .context("Cannot connect to clamd")?;
+
+            stream.write_all(b"zINSTREAM\0").await?;
+            let mut buf = vec![0; CLAMD_CHUNK_SIZE];
+            loop {
+                let n = file.read(&mut buf).await?;
+                stream.write_all(&(n as u32).to_be_bytes()).await?;
+                if n == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..n]).await?;
+            }
+
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await?;
+            parse_clamd_reply(&String::from_utf8_lossy(&reply))
+        })
+    }
+}
+
+/// Parses replies like `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_clamd_reply(reply: &str) -> Result<ScanVerdict> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply
+        .strip_prefix("stream: ")
+        .ok_or(anyhow!("Unexpected reply of clamd: {}", reply))?;
+
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else {
+        Err(anyhow!("clamd failed: {}", result))
+    }
+}
+
+/// Posts the raw file to an external scanner,
+/// which responds with `{"infected": true, "signature": "..."}`.
+pub struct HttpScanner {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpScanResponse {
+    infected: bool,
+    signature: Option<String>,
+}
+
+impl VirusScanner for HttpScanner {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<ScanVerdict>> {
+        Box::pin(async move {
+            let bytes = fs::read(path).await?;
+
+            let mut request = self.client.post(&self.url).body(bytes);
+            if let Some(ref key) = self.api_key {
+                request = request.bearer_auth(key);
+            }
+
+            let response: HttpScanResponse = request
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .context("Invalid response of scan service")?;
+
+            Ok(if response.infected {
+                ScanVerdict::Infected(response.signature.unwrap_or("unknown".to_string()))
+            } else {
+                ScanVerdict::Clean
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(
+            parse_clamd_reply("stream: OK\0").unwrap(),
+            ScanVerdict::Clean
+        );
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Signature FOUND\0").unwrap(),
+            ScanVerdict::Infected("Eicar-Signature".to_string())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
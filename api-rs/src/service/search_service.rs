@@ -1,12 +1,44 @@
-use crate::config::rd::RD;
+use crate::config::db::DB;
+use crate::config::SearchConfig;
+use crate::model::admin::IndexStatus;
+use crate::model::post::PostRow;
+use crate::service::kv_service::{is_transient, KvOp, KvStore};
+use crate::service::notification_service;
+use crate::service::upload_service::FileUploadService;
+use crate::AppState;
 use anyhow::{Context, Result};
+use futures::future::BoxFuture;
 use jieba_rs::Jieba;
 use lazy_static::lazy_static;
 use regex::Regex;
+use rust_stemmers::{Algorithm, Stemmer};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
+use sqlx::{Sqlite, Transaction};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, instrument, warn};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// At most this many writes are sent to Redis in one pipeline
+const BATCH_SIZE: usize = 1000;
+/// A batch is tried this many times in all on transient errors
+const MAX_ATTEMPTS: u32 = 3;
+/// Doubled after each attempt
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The signature of the default analyzer
+const DEFAULT_ANALYZER: &str = "jieba";
+/// Bump it when the keys, the stored positions or the scoring change, so that indexes are rebuilt
+const SCHEMA_VERSION: u32 = 3;
+/// The version of the indexes built before it was recorded
+const LEGACY_VERSION: &str = "1:jieba";
+/// The score of a doc is raised by at most this ratio when the tokens of the query are next to each other
+const PROXIMITY_WEIGHT: f64 = 0.5;
 
 lazy_static! {
     static ref PUNCTUATION: Regex =
@@ -24,6 +56,11 @@ lazy_static! {
 pub trait Tokenizer: Send + Sync {
     fn cut<'a>(&self, text: &'a str) -> Vec<&'a str>;
 
+    /// Identifies how texts are analyzed, the index must be rebuilt when it changes
+    fn signature(&self) -> String {
+        DEFAULT_ANALYZER.to_string()
+    }
+
     fn analyze(&self, text: &str) -> Vec<String> {
         let text = HTML_TAG.replace_all(text, " ");
 
@@ -46,36 +83,506 @@ impl Tokenizer for Jieba {
     }
 }
 
+/// Normalizes the tokens of another tokenizer: folds the diacritics of Latin letters,
+/// so that "café" matches "cafe", and reduces Latin words to their stems, so that "running" matches "run".
+pub struct Normalizer<T> {
+    inner: T,
+    fold_diacritics: bool,
+    stemmer: Option<(Algorithm, Stemmer)>,
+}
+
+impl<T: Tokenizer> Normalizer<T> {
+    pub fn new(inner: T, fold_diacritics: bool, stemmer: Option<Algorithm>) -> Self {
+        Self {
+            inner,
+            fold_diacritics,
+            stemmer: stemmer.map(|algorithm| (algorithm, Stemmer::create(algorithm))),
+        }
+    }
+}
+
+impl<T: Tokenizer> Tokenizer for Normalizer<T> {
+    fn cut<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.inner.cut(text)
+    }
+
+    fn analyze(&self, text: &str) -> Vec<String> {
+        let mut tokens = if self.fold_diacritics {
+            self.inner.analyze(&fold_diacritics(text))
+        } else {
+            self.inner.analyze(text)
+        };
+
+        if let Some((_, ref stemmer)) = self.stemmer {
+            for token in tokens.iter_mut() {
+                if token.chars().all(is_latin_letter) {
+                    *token = stemmer.stem(token).into_owned();
+                }
+            }
+        }
+        tokens
+    }
+
+    fn signature(&self) -> String {
+        let mut signature = self.inner.signature();
+        if self.fold_diacritics {
+            signature.push_str("+fold");
+        }
+        if let Some((algorithm, _)) = self.stemmer {
+            signature.push_str(&format!("+stem:{:?}", algorithm).to_lowercase());
+        }
+        signature
+    }
+}
+
+fn is_latin_letter(c: char) -> bool {
+    // Basic Latin to Latin Extended-B
+    c.is_alphabetic() && (c as u32) < 0x250
+}
+
+/// Removes the combining marks following Latin letters, e.g. "Crème brûlée" becomes "Creme brulee".
+/// The marks of other scripts are kept, such as the dakuten of Japanese kana.
+pub fn fold_diacritics(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    let mut after_latin = false;
+    for c in text.nfd() {
+        if is_combining_mark(c) {
+            if !after_latin {
+                folded.push(c);
+            }
+        } else {
+            after_latin = is_latin_letter(c);
+            folded.push(c);
+        }
+    }
+    folded.nfc().collect()
+}
+
+/// A term of a query as written, see [`parse_query`]
+#[derive(Debug, PartialEq)]
+pub enum QueryTerm {
+    Word(String),
+    /// Quoted words, which are matched next to each other where the index keeps the positions of tokens
+    Phrase(String),
+}
+
+/// A query of the search, whose terms are all required unless joined by `OR`
+#[derive(Debug, Default, PartialEq)]
+pub struct ParsedQuery {
+    /// Each group is required, matched by any of its terms
+    pub groups: Vec<Vec<QueryTerm>>,
+    /// The docs matching any of these terms are left out
+    pub excluded: Vec<QueryTerm>,
+}
+
+/// Parses the query language of the search:
+///
+/// - `rust async` requires both words
+/// - `"rust async"` is a phrase
+/// - `rust OR go` requires either of them, `OR` binds closer than the spaces
+/// - `rust -async` leaves out the docs containing `async`, phrases can be excluded too
+pub fn parse_query(text: &str) -> ParsedQuery {
+    let mut query = ParsedQuery::default();
+    let mut chars = text.chars().peekable();
+    // Whether the last required term is followed by `OR`
+    let mut or = false;
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let excluded = c == '-';
+        if excluded {
+            chars.next();
+        }
+
+        // `take_while` consumes the closing quote or the whitespace after a word as well
+        let term = if chars.peek() == Some(&'"') {
+            chars.next();
+            QueryTerm::Phrase(chars.by_ref().take_while(|&c| c != '"').collect())
+        } else {
+            let word: String = chars.by_ref().take_while(|c| !c.is_whitespace()).collect();
+            if word == "OR" && !excluded {
+                or = !query.groups.is_empty();
+                continue;
+            }
+            QueryTerm::Word(word)
+        };
+        if matches!(&term, QueryTerm::Word(text) | QueryTerm::Phrase(text) if text.trim().is_empty())
+        {
+            continue;
+        }
+
+        if excluded {
+            // An excluded term cannot be an alternative
+            query.excluded.push(term);
+            or = false;
+            continue;
+        }
+        match query.groups.last_mut() {
+            Some(group) if or => group.push(term),
+            _ => query.groups.push(vec![term]),
+        }
+        or = false;
+    }
+    query
+}
+
+/// A term of a query analyzed into tokens, which are all required.
+/// Each token is followed by its synonyms, except those of phrases.
+#[derive(Debug)]
+struct Term {
+    tokens: Vec<Vec<String>>,
+    phrase: bool,
+}
+
+impl Term {
+    /// Whether a doc contains the term, given the docs of each token
+    fn matched_by(&self, id: i64, docs: &HashMap<&String, HashSet<i64>>) -> bool {
+        self.tokens.iter().all(|alternatives| {
+            alternatives
+                .iter()
+                .any(|token| docs.get(token).is_some_and(|docs| docs.contains(&id)))
+        })
+    }
+
+    /// Whether a phrase has more than one token, so that their positions matter
+    fn ordered(&self) -> bool {
+        self.phrase && self.tokens.len() > 1
+    }
+
+    /// Whether the tokens of a phrase are next to each other in a doc, always true for a word.
+    /// A doc indexed without positions is matched by a phrase whose tokens it contains.
+    fn in_order(&self, positions: Option<&TokenPositions>) -> bool {
+        if !self.ordered() {
+            return true;
+        }
+        let Some(positions) = positions else {
+            return true;
+        };
+        let Some(lists) = self
+            .tokens
+            .iter()
+            .map(|alternatives| positions.0.get(&alternatives[0]))
+            .collect::<Option<Vec<&Positions>>>()
+        else {
+            return false;
+        };
+        if lists.iter().any(|p| matches!(p, Positions::Count(_))) {
+            return true;
+        }
+        lists[0].list().iter().any(|&start| {
+            lists
+                .iter()
+                .enumerate()
+                .skip(1)
+                .all(|(i, p)| p.list().binary_search(&(start + i)).is_ok())
+        })
+    }
+}
+
+#[derive(Debug)]
+struct AnalyzedQuery {
+    groups: Vec<Vec<Term>>,
+    excluded: Vec<Term>,
+}
+
+impl AnalyzedQuery {
+    fn new(tokenizer: &dyn Tokenizer, synonyms: &HashMap<String, Vec<String>>, text: &str) -> Self {
+        let analyze = |term: &QueryTerm| {
+            let (text, phrase) = match term {
+                QueryTerm::Word(text) => (text, false),
+                QueryTerm::Phrase(text) => (text, true),
+            };
+            let tokens: Vec<Vec<String>> = tokenizer
+                .analyze(text)
+                .iter()
+                .map(|token| match phrase {
+                    true => vec![token.clone()],
+                    false => expand_token(synonyms, token),
+                })
+                .collect();
+            (!tokens.is_empty()).then_some(Term { tokens, phrase })
+        };
+
+        let parsed = parse_query(text);
+        Self {
+            groups: parsed
+                .groups
+                .iter()
+                .map(|group| group.iter().filter_map(analyze).collect::<Vec<_>>())
+                .filter(|group| !group.is_empty())
+                .collect(),
+            excluded: parsed.excluded.iter().filter_map(analyze).collect(),
+        }
+    }
+
+    /// Keeps the tokens for which `f` is true, the terms left without tokens are dropped
+    fn retain_tokens(&mut self, f: impl Fn(&str) -> bool) {
+        let retain = |terms: &mut Vec<Term>| {
+            for term in terms.iter_mut() {
+                term.tokens.retain(|alternatives| f(&alternatives[0]));
+            }
+            terms.retain(|term| !term.tokens.is_empty());
+        };
+        for group in self.groups.iter_mut() {
+            retain(group);
+        }
+        self.groups.retain(|group| !group.is_empty());
+        retain(&mut self.excluded);
+    }
+
+    /// The tokens of the required terms, as written in the query
+    fn tokens(&self) -> Vec<String> {
+        self.groups
+            .iter()
+            .flatten()
+            .flat_map(|term| {
+                term.tokens
+                    .iter()
+                    .map(|alternatives| alternatives[0].clone())
+            })
+            .collect()
+    }
+
+    /// The distinct tokens of the required terms, each followed by its synonyms
+    fn terms(&self) -> Vec<Vec<String>> {
+        let mut terms: Vec<Vec<String>> = vec![];
+        for alternatives in self
+            .groups
+            .iter()
+            .flatten()
+            .flat_map(|term| term.tokens.iter())
+        {
+            if !terms.iter().any(|t| t[0] == alternatives[0]) {
+                terms.push(alternatives.clone());
+            }
+        }
+        terms
+    }
+}
+
+/// The positions of the tokens of a doc, in the tokens returned by the tokenizer
 #[derive(Debug, Serialize, Deserialize)]
-struct TokenFrequency(HashMap<String, usize>);
+struct TokenPositions(HashMap<String, Positions>);
+
+/// The positions of a token in a doc, or only the number of them in the indexes of schema version 1,
+/// which are still searched until they are rebuilt
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Positions {
+    List(Vec<usize>),
+    Count(usize),
+}
+
+impl Positions {
+    fn frequency(&self) -> usize {
+        match self {
+            Positions::List(positions) => positions.len(),
+            Positions::Count(count) => *count,
+        }
+    }
+
+    fn list(&self) -> &[usize] {
+        match self {
+            Positions::List(positions) => positions,
+            Positions::Count(_) => &[],
+        }
+    }
+}
+
+impl TokenPositions {
+    fn new(tokens: &[String]) -> Self {
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, token) in tokens.iter().enumerate() {
+            positions.entry(token.clone()).or_default().push(i);
+        }
+        Self(
+            positions
+                .into_iter()
+                .map(|(token, list)| (token, Positions::List(list)))
+                .collect(),
+        )
+    }
+}
+
+/// The results of a search
+#[derive(Debug, Default)]
+pub struct SearchResults {
+    /// The tokens of the query
+    pub tokens: Vec<String>,
+    /// The ids of the matched docs and their scores, the most relevant first
+    pub hits: Vec<(i64, f64)>,
+    /// Whether some docs are not ranked, because too many docs match or ranking them takes too long
+    pub truncated: bool,
+}
+
+/// Restricts a search to some of the docs, e.g. the undeleted posts of a user.
+/// Only the docs matching the query are checked, so the docs need not be listed beforehand.
+pub trait DocFilter: Send + Sync {
+    /// Returns those of `ids` which may be found
+    fn retain<'a>(&'a self, ids: &'a [i64]) -> BoxFuture<'a, Result<HashSet<i64>>>;
+}
+
+/// A set of docs lists those which may be found
+impl DocFilter for HashSet<i64> {
+    fn retain<'a>(&'a self, ids: &'a [i64]) -> BoxFuture<'a, Result<HashSet<i64>>> {
+        Box::pin(async move { Ok(ids.iter().filter(|id| self.contains(id)).copied().collect()) })
+    }
+}
+
+/// Where the search index is kept, Redis or SQLite, see [`search_backend_from_config`].
+/// The ids of the docs are those of the posts.
+pub trait SearchBackend: Send + Sync {
+    fn indexed(&self, id: i64) -> BoxFuture<'_, Result<bool>>;
+
+    fn get_doc_count(&self) -> BoxFuture<'_, Result<i64>>;
+
+    /// Indexes the text of a doc, replacing that of the doc if it is indexed
+    fn index<'a>(&'a self, id: i64, text: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    fn deindex(&self, id: i64) -> BoxFuture<'_, Result<()>>;
+
+    /// Searches the docs containing all tokens of the query, or any of them if `partial`,
+    /// among the docs kept by `filter` if it is set, e.g. the posts of a user.
+    fn search_among<'a>(
+        &'a self,
+        query: &'a str,
+        partial: bool,
+        limit: usize,
+        filter: Option<&'a dyn DocFilter>,
+    ) -> BoxFuture<'a, Result<SearchResults>>;
+
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        partial: bool,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<SearchResults>> {
+        self.search_among(query, partial, limit, None)
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<IndexStatus>>;
+
+    /// The version of the index, made of the schema version and the signature of the analyzer
+    fn version(&self) -> String;
+
+    /// The version the index is built with
+    fn stored_version(&self) -> BoxFuture<'_, Result<String>>;
+
+    /// Whether the index should be rebuilt, e.g. after stemming is enabled
+    fn is_outdated(&self) -> BoxFuture<'_, Result<bool>>;
+
+    /// Records the version the index is built with.
+    fn mark_version(&self) -> BoxFuture<'_, Result<()>>;
+
+    fn clear_all_indexes(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Creates the backend of `SEARCH_BACKEND`, whose texts are analyzed as configured.
+pub fn search_backend_from_config(
+    config: &SearchConfig,
+    db: Arc<DB>,
+    kv: Arc<dyn KvStore>,
+) -> Arc<dyn SearchBackend> {
+    let synonyms = match config.synonyms_path.as_str() {
+        "" => vec![],
+        path => parse_synonyms(&fs::read_to_string(path).expect("Cannot read the synonyms file")),
+    };
+    let tokenizer = Arc::new(Normalizer::new(
+        Jieba::new(),
+        config.fold_diacritics,
+        config.stemmer(),
+    ));
+
+    match config.backend.as_str() {
+        "sqlite" => Arc::new(SqliteSearch::new(db, tokenizer).with_synonyms(&synonyms)),
+        _ => Arc::new(
+            FullTextSearch::new(kv, tokenizer, "fts:".to_string())
+                .with_limits(config.max_candidates, config.timeout())
+                .with_synonyms(&synonyms),
+        ),
+    }
+}
 
 pub struct FullTextSearch {
-    rd: Arc<RD>,
+    kv: Arc<dyn KvStore>,
     tokenizer: Arc<dyn Tokenizer>,
     key_prefix: String,
+    /// At most this many matched docs are ranked, 0 means unlimited
+    max_candidates: usize,
+    /// How long ranking may take before the docs are ordered by the number of matched tokens
+    timeout: Option<Duration>,
+    /// The synonyms of each token, which are searched as well
+    synonyms: HashMap<String, Vec<String>>,
+    metrics: IndexMetrics,
+}
+
+/// Counts the writes to the index
+#[derive(Debug, Default)]
+struct IndexMetrics {
+    ops: AtomicU64,
+    batches: AtomicU64,
+    retries: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl IndexMetrics {
+    fn record_batch(&self, ops: usize) {
+        self.ops.fetch_add(ops as u64, Relaxed);
+        self.batches.fetch_add(1, Relaxed);
+    }
 }
 
 impl FullTextSearch {
-    pub fn new(rd: Arc<RD>, tokenizer: Arc<dyn Tokenizer>, key_prefix: String) -> Self {
+    pub fn new(kv: Arc<dyn KvStore>, tokenizer: Arc<dyn Tokenizer>, key_prefix: String) -> Self {
         Self {
-            rd,
+            kv,
             tokenizer,
             key_prefix,
+            max_candidates: 0,
+            timeout: None,
+            synonyms: HashMap::new(),
+            metrics: IndexMetrics::default(),
         }
     }
 
+    /// Limits the cost of broad queries, whose tokens match a large part of the docs.
+    pub fn with_limits(mut self, max_candidates: usize, timeout: Option<Duration>) -> Self {
+        self.max_candidates = max_candidates;
+        self.timeout = timeout;
+        self
+    }
+
+    /// Makes the terms of each group match one another, see [`parse_synonyms`].
+    /// The terms are analyzed like queries, those of more than one token are ignored.
+    pub fn with_synonyms(mut self, groups: &[Vec<String>]) -> Self {
+        self.synonyms = analyze_synonyms(self.tokenizer.as_ref(), groups);
+        self
+    }
+
     pub async fn indexed(&self, id: i64) -> Result<bool> {
-        self.rd.exists(self.doc_tokens_key(id)).await
+        self.kv.exists(&self.doc_tokens_key(id)).await
     }
 
+    /// The number of docs is that of the set of their ids, so that a retried write cannot count a doc twice
     pub async fn get_doc_count(&self) -> Result<i64> {
-        let count: Option<String> = self.rd.get(self.doc_count_key()).await?;
+        let keys = [self.doc_ids_key()];
+        let count = self.kv.scard_many(&keys).await?[0];
+        if count > 0 {
+            return Ok(count as i64);
+        }
+
+        // The indexes of schema version 2 and older only keep a counter, they are rebuilt as outdated
+        let count = self.kv.get(&self.legacy_doc_count_key()).await?;
         count
             .unwrap_or("0".to_string())
             .parse::<i64>()
             .context("Failed to parse doc count")
     }
 
+    #[instrument(skip(self, text))]
     pub async fn index(&self, id: i64, text: &str) -> Result<()> {
         if self.indexed(id).await? {
             // a recursive async fn call must introduce indirection,
@@ -88,25 +595,23 @@ impl FullTextSearch {
             return Ok(());
         }
 
-        let token_frequency = count_frequencies(&tokens);
-        let freq_json = serde_json::to_string(&TokenFrequency(token_frequency))?;
+        let positions_json = serde_json::to_string(&TokenPositions::new(&tokens))?;
 
         let token_set = tokens.into_iter().collect::<HashSet<String>>();
 
-        let _: () = self
-            .rd
-            .pipeline(|pipe| {
-                pipe.set(self.doc_tokens_key(id), freq_json);
-                pipe.incr(self.doc_count_key(), 1);
-                for token in token_set.iter() {
-                    pipe.sadd(self.token_docs_key(token), id);
-                }
-            })
-            .await?;
+        let mut ops = vec![];
+        for token in token_set.iter() {
+            ops.push(KvOp::SAdd(self.token_docs_key(token), id.to_string()));
+        }
+        // The doc is indexed when its tokens are written, see `write`
+        ops.push(KvOp::Set(self.doc_tokens_key(id), positions_json));
+        ops.push(KvOp::SAdd(self.doc_ids_key(), id.to_string()));
+        self.write(ops).await?;
 
         Ok(())
     }
 
+    #[instrument(skip(self, text))]
     pub async fn reindex(&self, id: i64, text: &str) -> Result<()> {
         if !self.indexed(id).await? {
             return Box::pin(self.index(id, text)).await;
@@ -117,150 +622,343 @@ impl FullTextSearch {
             return self.deindex(id).await;
         }
 
-        let old_freq: TokenFrequency = self
-            .rd
-            .get_object(self.doc_tokens_key(id))
+        let old_positions = self
+            .get_token_positions(id)
             .await?
-            .ok_or(anyhow::anyhow!("Token frequency of doc `{}` not found", id))?;
+            .ok_or(anyhow::anyhow!("Token positions of doc `{}` not found", id))?;
 
-        let new_freq = count_frequencies(&new_tokens);
-        let freq_json = serde_json::to_string(&TokenFrequency(new_freq))?;
+        let positions_json = serde_json::to_string(&TokenPositions::new(&new_tokens))?;
 
-        let old_token_set = old_freq.0.keys().collect::<HashSet<_>>();
+        let old_token_set = old_positions.0.keys().collect::<HashSet<_>>();
         let new_token_set = new_tokens.iter().collect::<HashSet<_>>();
         let tokens_to_remove = old_token_set.difference(&new_token_set).collect::<Vec<_>>();
         let tokens_to_add = new_token_set.difference(&old_token_set).collect::<Vec<_>>();
 
-        let _: () = self
-            .rd
-            .pipeline(|pipe| {
-                pipe.set(self.doc_tokens_key(id), freq_json);
-                for token in tokens_to_remove {
-                    pipe.srem(self.token_docs_key(token), id);
-                }
-                for token in tokens_to_add {
-                    pipe.sadd(self.token_docs_key(token), id);
-                }
-            })
-            .await?;
+        let mut ops = vec![];
+        for token in tokens_to_remove {
+            ops.push(KvOp::SRem(self.token_docs_key(token), id.to_string()));
+        }
+        for token in tokens_to_add {
+            ops.push(KvOp::SAdd(self.token_docs_key(token), id.to_string()));
+        }
+        ops.push(KvOp::Set(self.doc_tokens_key(id), positions_json));
+        self.write(ops).await?;
 
         Ok(())
     }
 
+    #[instrument(skip(self))]
     pub async fn deindex(&self, id: i64) -> Result<()> {
-        let token_freq: TokenFrequency = self
-            .rd
-            .get_object(self.doc_tokens_key(id))
+        let token_positions = self
+            .get_token_positions(id)
             .await?
-            .ok_or(anyhow::anyhow!("Token frequency of doc `{}` not found", id))?;
+            .ok_or(anyhow::anyhow!("Token positions of doc `{}` not found", id))?;
 
-        let token_set = token_freq.0.keys().collect::<HashSet<_>>();
+        let token_set = token_positions.0.keys().collect::<HashSet<_>>();
 
-        let _: () = self
-            .rd
-            .pipeline(|pipe| {
-                pipe.del(self.doc_tokens_key(id));
-                pipe.decr(self.doc_count_key(), 1);
-                for token in token_set.iter() {
-                    pipe.srem(self.token_docs_key(token), id);
-                }
-            })
-            .await?;
+        let mut ops = vec![];
+        for token in token_set.iter() {
+            ops.push(KvOp::SRem(self.token_docs_key(token), id.to_string()));
+        }
+        // Deleted at last, so that a failed deindex can be tried again
+        ops.push(KvOp::Del(self.doc_tokens_key(id)));
+        ops.push(KvOp::SRem(self.doc_ids_key(), id.to_string()));
+        self.write(ops).await?;
 
         Ok(())
     }
 
-    pub async fn search(
+    /// Searches the docs containing all tokens of the query, or any of them if `partial`.
+    /// Terms joined by `OR` and excluded terms are honored, see [`parse_query`].
+    ///
+    /// If more than `max_candidates` docs match, only those matching the most tokens are ranked,
+    /// the newer ones first when they match as many. If ranking exceeds the timeout, the docs are
+    /// scored by the ratio of matched tokens instead. Either way the results are marked as truncated.
+    pub async fn search(&self, query: &str, partial: bool, limit: usize) -> Result<SearchResults> {
+        self.search_among(query, partial, limit, None).await
+    }
+
+    /// Like `search`, but only the docs kept by `filter` are matched if it is set, e.g. the posts of a user.
+    #[instrument(skip(self, filter))]
+    pub async fn search_among(
         &self,
         query: &str,
         partial: bool,
         limit: usize,
-    ) -> Result<(Vec<String>, Vec<(i64, f64)>)> {
-        let tokens = self.tokenizer.analyze(query);
+        filter: Option<&dyn DocFilter>,
+    ) -> Result<SearchResults> {
+        let query = AnalyzedQuery::new(self.tokenizer.as_ref(), &self.synonyms, query);
+        let tokens = query.tokens();
         if tokens.is_empty() {
-            return Ok((tokens, vec![]));
+            return Ok(SearchResults::default());
         }
 
-        // Retrieve the document IDs containing the query term
-        let doc_sets: Vec<HashSet<String>> = self
-            .rd
-            .pipeline(|pipe| {
-                for token in tokens.iter() {
-                    pipe.smembers(self.token_docs_key(token));
-                }
-            })
+        // A token of the query is matched by itself or any of its synonyms
+        let terms = query.terms();
+
+        let required: HashSet<&String> = terms.iter().flatten().collect();
+        let excluded_tokens = query
+            .excluded
+            .iter()
+            .flat_map(|t| t.tokens.iter().flatten());
+        let all_tokens: Vec<&String> = required
+            .iter()
+            .copied()
+            .chain(excluded_tokens)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let token_docs = self
+            .find_token_docs(&query, &required, all_tokens, partial)
             .await?;
 
-        let ids: HashSet<i64> = if partial {
-            // union
-            doc_sets
-                .into_iter()
-                .flatten()
-                .filter_map(|id| id.parse().ok())
-                .collect()
-        } else {
-            // intersection
-            doc_sets
-                .into_iter()
-                .reduce(|acc, set| acc.intersection(&set).cloned().collect())
-                .unwrap_or_default()
-                .into_iter()
-                .filter_map(|id| id.parse().ok())
-                .collect()
+        // Count the query terms contained in each doc
+        let mut matches: HashMap<i64, usize> = HashMap::new();
+        let mut synonym_docs: Vec<(&String, &HashSet<i64>)> = vec![];
+        for alternatives in terms.iter() {
+            let mut docs: HashSet<i64> = HashSet::new();
+            for (i, token) in alternatives.iter().enumerate() {
+                let ids = &token_docs[token];
+                docs.extend(ids);
+                if i > 0 {
+                    synonym_docs.push((token, ids));
+                }
+            }
+            for id in docs {
+                *matches.entry(id).or_default() += 1;
+            }
+        }
+        if let Some(filter) = filter {
+            let ids: Vec<i64> = matches.keys().copied().collect();
+            let kept = filter.retain(&ids).await?;
+            matches.retain(|id, _| kept.contains(id));
+        }
+
+        // The tokens of a phrase must be next to each other, which needs the positions of the docs
+        // containing all of them
+        let phrases: Vec<&Term> = match partial {
+            true => vec![],
+            false => query.groups.iter().flatten().collect(),
+        }
+        .into_iter()
+        .chain(query.excluded.iter())
+        .filter(|term| term.ordered())
+        .collect();
+        let phrase_ids: Vec<i64> = matches
+            .keys()
+            .filter(|id| {
+                phrases
+                    .iter()
+                    .any(|term| term.matched_by(**id, &token_docs))
+            })
+            .copied()
+            .collect();
+        let positions: HashMap<i64, TokenPositions> = match phrase_ids.is_empty() {
+            true => HashMap::new(),
+            false => phrase_ids
+                .iter()
+                .copied()
+                .zip(self.get_token_positions_many(&phrase_ids).await?)
+                .filter_map(|(id, positions)| Some((id, positions?)))
+                .collect(),
+        };
+        let contains = |id: i64, term: &Term| {
+            term.matched_by(id, &token_docs) && term.in_order(positions.get(&id))
         };
 
-        if ids.is_empty() {
-            return Ok((tokens, vec![]));
+        if !partial {
+            // Each group is matched by any of its terms
+            matches.retain(|id, _| {
+                query
+                    .groups
+                    .iter()
+                    .all(|group| group.iter().any(|term| contains(*id, term)))
+            });
         }
+        matches.retain(|id, _| !query.excluded.iter().any(|term| contains(*id, term)));
+
+        if matches.is_empty() {
+            return Ok(SearchResults {
+                tokens,
+                ..Default::default()
+            });
+        }
+
+        // Keep the docs which are likely the most relevant, without fetching their frequencies
+        let mut candidates: Vec<(i64, usize)> = matches.into_iter().collect();
+        let mut truncated = self.max_candidates > 0 && candidates.len() > self.max_candidates;
+        if truncated {
+            // Partitions in linear time, the candidates are not sorted
+            candidates.select_nth_unstable_by(self.max_candidates - 1, |a, b| {
+                b.1.cmp(&a.1).then(b.0.cmp(&a.0))
+            });
+            candidates.truncate(self.max_candidates);
+        }
+        let ids: Vec<i64> = candidates.iter().map(|c| c.0).collect();
 
         // Calculate the relevance score
-        let mut ranked_results = self.rank(&tokens, &ids).await?;
-        ranked_results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let ranked = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.rank(&terms, &ids, limit))
+                .await
+                .ok(),
+            None => Some(self.rank(&terms, &ids, limit).await),
+        };
+        let hits = match ranked {
+            Some(results) => results?,
+            None => {
+                warn!(
+                    "Ranking {} docs timed out, they are ordered by matched tokens",
+                    ids.len()
+                );
+                truncated = true;
+                let hits = candidates
+                    .into_iter()
+                    .map(|(id, n)| (id, n as f64 / terms.len() as f64));
+                top_k(hits, limit)
+            }
+        };
 
-        // Limit the number of results
-        if limit > 0 && ranked_results.len() > limit {
-            ranked_results.truncate(limit);
+        // Highlight the synonyms found in the hits as well
+        let mut tokens = tokens;
+        for (token, docs) in synonym_docs {
+            if !tokens.contains(token) && hits.iter().any(|(id, _)| docs.contains(id)) {
+                tokens.push(token.clone());
+            }
         }
 
-        Ok((tokens, ranked_results))
+        Ok(SearchResults {
+            tokens,
+            hits,
+            truncated,
+        })
     }
 
-    async fn rank(&self, tokens: &[String], ids: &HashSet<i64>) -> Result<Vec<(i64, f64)>> {
-        let mut results = Vec::new();
+    /// The docs of each token, in which the query is matched.
+    ///
+    /// If a required group has only posting lists up to `max_candidates` long, no doc outside of them
+    /// can match, so the longer lists of required terms are only checked for those docs. The longer
+    /// lists of excluded terms are always checked for the docs found instead of being loaded whole.
+    async fn find_token_docs<'a>(
+        &self,
+        query: &AnalyzedQuery,
+        required: &HashSet<&'a String>,
+        tokens: Vec<&'a String>,
+        partial: bool,
+    ) -> Result<HashMap<&'a String, HashSet<i64>>> {
+        let keys: Vec<String> = tokens.iter().map(|t| self.token_docs_key(t)).collect();
+        let sizes: HashMap<&String, usize> = match self.max_candidates {
+            0 => HashMap::new(),
+            _ => tokens
+                .iter()
+                .copied()
+                .zip(self.kv.scard_many(&keys).await?)
+                .collect(),
+        };
+        let long = |token: &String| sizes.get(token).is_some_and(|n| *n > self.max_candidates);
+        let bounded = !partial
+            && query.groups.iter().any(|group| {
+                group
+                    .iter()
+                    .flat_map(|term| term.tokens.iter().flatten())
+                    .all(|t| !long(t))
+            });
+
+        // Without a bound, the long lists of required terms are loaded as well
+        let (checked, loaded): (Vec<&String>, Vec<&String>) = tokens
+            .into_iter()
+            .partition(|t| long(t) && (bounded || !required.contains(t)));
+        let keys: Vec<String> = loaded.iter().map(|t| self.token_docs_key(t)).collect();
+        let mut token_docs: HashMap<&String, HashSet<i64>> = loaded
+            .into_iter()
+            .zip(self.kv.smembers_many(&keys).await?)
+            .map(|(token, set)| (token, set.iter().filter_map(|id| id.parse().ok()).collect()))
+            .collect();
+
+        // Required terms first, so that excluded ones are checked for all docs found
+        let (checked_required, checked_excluded): (Vec<&String>, Vec<&String>) =
+            checked.into_iter().partition(|t| required.contains(t));
+        for tokens in [checked_required, checked_excluded] {
+            if !tokens.is_empty() {
+                let ids = found_ids(&token_docs);
+                self.find_members(&mut token_docs, tokens, &ids).await?;
+            }
+        }
 
-        let total_docs = self.get_doc_count().await? as f64;
+        Ok(token_docs)
+    }
 
-        let token_frequencies: Vec<Option<TokenFrequency>> = self
-            .rd
-            .mget_object(
-                ids.iter()
-                    .map(|id| self.doc_tokens_key(*id))
-                    .collect::<Vec<String>>(),
-            )
-            .await?;
+    /// Sets the docs of each token to those of `ids` in its posting list
+    async fn find_members<'a>(
+        &self,
+        token_docs: &mut HashMap<&'a String, HashSet<i64>>,
+        tokens: Vec<&'a String>,
+        ids: &[i64],
+    ) -> Result<()> {
+        let members: Vec<String> = ids.iter().map(i64::to_string).collect();
+        for token in tokens {
+            let found = self
+                .kv
+                .sismember_many(&self.token_docs_key(token), &members)
+                .await?;
+            let docs = ids
+                .iter()
+                .zip(found)
+                .filter_map(|(id, found)| found.then_some(*id))
+                .collect();
+            token_docs.insert(token, docs);
+        }
+        Ok(())
+    }
 
-        let doc_frequencies: Vec<f64> = self
-            .rd
-            .pipeline(|pipe| {
-                for token in tokens.iter() {
-                    pipe.scard(self.token_docs_key(token));
-                }
-            })
-            .await?;
+    /// Scores the docs, returns the `limit` most relevant ones in order, or all of them if `limit` is 0.
+    /// Each term is a token of the query followed by its synonyms, which count as the same token.
+    async fn rank(
+        &self,
+        terms: &[Vec<String>],
+        ids: &[i64],
+        limit: usize,
+    ) -> Result<Vec<(i64, f64)>> {
+        let mut results = TopK::new(limit, ids.len());
+
+        let total_docs = self.get_doc_count().await? as f64;
 
-        for (&id, token_frequency) in ids.iter().zip(token_frequencies.iter()) {
-            let token_freq = token_frequency
-                .as_ref()
-                .unwrap_or_else(|| panic!("Token frequency of doc `{}` not found", id));
+        let token_positions = self.get_token_positions_many(ids).await?;
+
+        let keys: Vec<String> = terms
+            .iter()
+            .flatten()
+            .map(|t| self.token_docs_key(t))
+            .collect();
+        let mut sizes = self.kv.scard_many(&keys).await?.into_iter();
+        // The docs containing any of the synonyms are not counted exactly, the largest set is used instead
+        let doc_frequencies: Vec<f64> = terms
+            .iter()
+            .map(|alternatives| (&mut sizes).take(alternatives.len()).max().unwrap_or(0) as f64)
+            .collect();
+
+        for (&id, token_positions) in ids.iter().zip(token_positions.iter()) {
+            // A doc may be in the sets of some tokens without being indexed, if indexing it failed halfway
+            let Some(token_positions) = token_positions.as_ref() else {
+                continue;
+            };
 
             let mut score = 0.0;
             let mut matching_terms = 0;
-
-            for (token, df) in tokens.iter().zip(doc_frequencies.iter()) {
-                let tf = *token_freq.0.get(token).unwrap_or(&0) as f64;
+            let mut term_positions: Vec<Vec<usize>> = vec![];
+
+            for (alternatives, df) in terms.iter().zip(doc_frequencies.iter()) {
+                let found: Vec<&Positions> = alternatives
+                    .iter()
+                    .filter_map(|token| token_positions.0.get(token))
+                    .collect();
+                let tf = found.iter().map(|p| p.frequency()).sum::<usize>() as f64;
                 if tf > 0.0 {
                     matching_terms += 1;
                 }
+                let positions: Vec<usize> = found.iter().flat_map(|p| p.list()).copied().collect();
+                if !positions.is_empty() {
+                    term_positions.push(positions);
+                }
 
                 // Use an improved TF calculation: 1 + log(tf) to reduce the weight of high-frequency terms
                 let normalized_tf = if tf > 0.0 { 1.0 + (tf.log10()) } else { 0.0 };
@@ -275,13 +973,25 @@ impl FullTextSearch {
             }
 
             // Apply a length normalization factor to avoid advantages for long documents
-            let total_terms = token_freq.0.values().sum::<usize>() as f64;
+            let total_terms = token_positions
+                .0
+                .values()
+                .map(Positions::frequency)
+                .sum::<usize>() as f64;
             if total_terms > 0.0 {
                 score /= total_terms.sqrt();
             }
 
+            // Boost the docs where the tokens are close, the most when they are next to each other
+            if term_positions.len() > 1 {
+                if let Some(window) = min_window(&term_positions) {
+                    let proximity = (term_positions.len() as f64 / window as f64).min(1.0);
+                    score *= 1.0 + PROXIMITY_WEIGHT * proximity;
+                }
+            }
+
             // Calculate query term coverage
-            let coverage_ratio = matching_terms as f64 / tokens.len() as f64;
+            let coverage_ratio = matching_terms as f64 / terms.len() as f64;
             score *= if coverage_ratio > 0.999 {
                 2.0
             } else {
@@ -291,10 +1001,103 @@ impl FullTextSearch {
             results.push((id, score));
         }
 
-        Ok(results)
+        Ok(results.into_sorted_vec())
+    }
+
+    /// Applies the writes in batches, so that the pipeline of a long doc is not too large for Redis.
+    /// Each batch is atomic and tried again on transient errors, but the batches are not atomic together,
+    /// so the writes marking a doc as indexed or not come last.
+    async fn write(&self, ops: Vec<KvOp>) -> Result<()> {
+        for batch in ops.chunks(BATCH_SIZE) {
+            let mut attempt = 1;
+            loop {
+                match self.kv.apply(batch.to_vec()).await {
+                    Ok(()) => {
+                        self.metrics.record_batch(batch.len());
+                        break;
+                    }
+                    Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                        warn!("Cannot write to the index, retrying: {:#}", e);
+                        self.metrics.retries.fetch_add(1, Relaxed);
+                        tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        self.metrics.failures.fetch_add(1, Relaxed);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of indexed docs, the versions, and the counts of the writes since the app started.
+    pub async fn status(&self) -> Result<IndexStatus> {
+        Ok(IndexStatus {
+            docs: self.get_doc_count().await?,
+            version: self.version(),
+            stored_version: self.stored_version().await?,
+            outdated: self.is_outdated().await?,
+            rebuilding: is_rebuilding(),
+            ops: self.metrics.ops.load(Relaxed),
+            batches: self.metrics.batches.load(Relaxed),
+            retries: self.metrics.retries.load(Relaxed),
+            failures: self.metrics.failures.load(Relaxed),
+        })
+    }
+
+    async fn get_token_positions(&self, id: i64) -> Result<Option<TokenPositions>> {
+        match self.kv.get(&self.doc_tokens_key(id)).await? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_token_positions_many(&self, ids: &[i64]) -> Result<Vec<Option<TokenPositions>>> {
+        let keys: Vec<String> = ids.iter().map(|id| self.doc_tokens_key(*id)).collect();
+        Ok(self
+            .kv
+            .mget(&keys)
+            .await?
+            .into_iter()
+            .map(|json| json.map(|s| serde_json::from_str(&s)).transpose())
+            .collect::<serde_json::Result<_>>()?)
+    }
+
+    /// The version of the index, made of the schema version and the signature of the analyzer,
+    /// e.g. `1:jieba+stem:english`
+    pub fn version(&self) -> String {
+        format!("{}:{}", SCHEMA_VERSION, self.tokenizer.signature())
     }
 
-    fn doc_count_key(&self) -> String {
+    /// The version the index is built with, the legacy one if not recorded.
+    pub async fn stored_version(&self) -> Result<String> {
+        let stored = self.kv.get(&self.version_key()).await?;
+        Ok(stored.unwrap_or_else(|| LEGACY_VERSION.to_string()))
+    }
+
+    /// Whether the index is built with another version, e.g. after stemming is enabled.
+    /// An empty index is never outdated.
+    pub async fn is_outdated(&self) -> Result<bool> {
+        Ok(self.stored_version().await? != self.version() && self.get_doc_count().await? > 0)
+    }
+
+    /// Records the version the index is built with.
+    pub async fn mark_version(&self) -> Result<()> {
+        let op = KvOp::Set(self.version_key(), self.version());
+        self.kv.apply(vec![op]).await
+    }
+
+    fn version_key(&self) -> String {
+        format!("{}version", self.key_prefix)
+    }
+
+    fn doc_ids_key(&self) -> String {
+        format!("{}ids", self.key_prefix)
+    }
+
+    fn legacy_doc_count_key(&self) -> String {
         format!("{}count", self.key_prefix)
     }
 
@@ -306,35 +1109,1025 @@ impl FullTextSearch {
         format!("{}{}:docs", self.key_prefix, token)
     }
 
+    #[instrument(skip_all)]
     pub async fn clear_all_indexes(&self) -> Result<()> {
-        let keys: Vec<String> = self.rd.keys(format!("{}*", self.key_prefix)).await?;
-        if !keys.is_empty() {
-            self.rd.del(&keys).await?;
+        let keys = self.kv.keys_with_prefix(&self.key_prefix).await?;
+        self.kv.del_many(&keys).await?;
+
+        Ok(())
+    }
+}
+
+impl SearchBackend for FullTextSearch {
+    fn indexed(&self, id: i64) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(FullTextSearch::indexed(self, id))
+    }
+
+    fn get_doc_count(&self) -> BoxFuture<'_, Result<i64>> {
+        Box::pin(FullTextSearch::get_doc_count(self))
+    }
+
+    fn index<'a>(&'a self, id: i64, text: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(FullTextSearch::index(self, id, text))
+    }
+
+    fn deindex(&self, id: i64) -> BoxFuture<'_, Result<()>> {
+        Box::pin(FullTextSearch::deindex(self, id))
+    }
+
+    fn search_among<'a>(
+        &'a self,
+        query: &'a str,
+        partial: bool,
+        limit: usize,
+        filter: Option<&'a dyn DocFilter>,
+    ) -> BoxFuture<'a, Result<SearchResults>> {
+        Box::pin(FullTextSearch::search_among(
+            self, query, partial, limit, filter,
+        ))
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<IndexStatus>> {
+        Box::pin(FullTextSearch::status(self))
+    }
+
+    fn version(&self) -> String {
+        FullTextSearch::version(self)
+    }
+
+    fn stored_version(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(FullTextSearch::stored_version(self))
+    }
+
+    fn is_outdated(&self) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(FullTextSearch::is_outdated(self))
+    }
+
+    fn mark_version(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(FullTextSearch::mark_version(self))
+    }
+
+    fn clear_all_indexes(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(FullTextSearch::clear_all_indexes(self))
+    }
+}
+
+/// The search index in an FTS5 table of SQLite, for deployments which would rather not
+/// keep it in Redis. The texts are analyzed like in Redis, and the tokens are stored separated
+/// by spaces, so that FTS5 only splits them. The docs are ranked with BM25, all matched docs
+/// are ranked, so the candidates and the timeout of ranking are not limited.
+pub struct SqliteSearch {
+    db: Arc<DB>,
+    tokenizer: Arc<dyn Tokenizer>,
+    synonyms: HashMap<String, Vec<String>>,
+    metrics: IndexMetrics,
+}
+
+impl SqliteSearch {
+    pub fn new(db: Arc<DB>, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        Self {
+            db,
+            tokenizer,
+            synonyms: HashMap::new(),
+            metrics: IndexMetrics::default(),
+        }
+    }
+
+    /// Like [`FullTextSearch::with_synonyms`]
+    pub fn with_synonyms(mut self, groups: &[Vec<String>]) -> Self {
+        self.synonyms = analyze_synonyms(self.tokenizer.as_ref(), groups);
+        self
+    }
+
+    async fn get_doc_count(&self) -> Result<i64> {
+        let count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!: i64" FROM post_fts"#)
+            .fetch_one(&self.db.pool)
+            .await?;
+        Ok(count)
+    }
+
+    #[instrument(skip(self, text))]
+    async fn index(&self, id: i64, text: &str) -> Result<()> {
+        let tokens = self.tokenizer.analyze(text);
+        let mut tx = self.db.writer.begin().await?;
+        sqlx::query!("DELETE FROM post_fts WHERE rowid = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        if !tokens.is_empty() {
+            let tokens = tokens.join(" ");
+            sqlx::query!(
+                "INSERT INTO post_fts (rowid, tokens) VALUES (?, ?)",
+                id,
+                tokens
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        self.commit(tx, 2).await
+    }
+
+    #[instrument(skip(self))]
+    async fn deindex(&self, id: i64) -> Result<()> {
+        let mut tx = self.db.writer.begin().await?;
+        sqlx::query!("DELETE FROM post_fts WHERE rowid = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        self.commit(tx, 1).await
+    }
+
+    async fn commit(&self, tx: Transaction<'_, Sqlite>, ops: usize) -> Result<()> {
+        match tx.commit().await {
+            Ok(()) => {
+                self.metrics.record_batch(ops);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics.failures.fetch_add(1, Relaxed);
+                Err(e.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, filter))]
+    async fn search_among(
+        &self,
+        query: &str,
+        partial: bool,
+        limit: usize,
+        filter: Option<&dyn DocFilter>,
+    ) -> Result<SearchResults> {
+        let mut query = AnalyzedQuery::new(self.tokenizer.as_ref(), &self.synonyms, query);
+        let tokens = query.tokens();
+        // FTS5 drops the tokens without letters or digits, a phrase of none matches nothing
+        query.retain_tokens(|token| token.chars().any(char::is_alphanumeric));
+        // A token of the query is matched by itself or any of its synonyms
+        let terms = query.terms();
+        if terms.is_empty() {
+            return Ok(SearchResults {
+                tokens,
+                ..Default::default()
+            });
+        }
+
+        let expression = fts_expression(&query, partial);
+        // A negative limit is no limit, and BM25 scores are negative, the most relevant the lowest.
+        // The filter is applied to the matched docs, so they are all fetched then.
+        let sql_limit = match (limit, filter) {
+            (0, _) | (_, Some(_)) => -1,
+            (limit, None) => limit as i64,
+        };
+        let mut hits: Vec<(i64, f64)> = sqlx::query!(
+            r#"
+            SELECT rowid as "id!: i64", bm25(post_fts) as "score!: f64"
+            FROM post_fts
+            WHERE post_fts MATCH ?
+            ORDER BY 2, 1 DESC
+            LIMIT ?
+            "#,
+            expression,
+            sql_limit
+        )
+        .fetch_all(&self.db.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.id, -row.score))
+        .collect();
+        if let Some(filter) = filter {
+            let ids: Vec<i64> = hits.iter().map(|hit| hit.0).collect();
+            let kept = filter.retain(&ids).await?;
+            hits.retain(|hit| kept.contains(&hit.0));
+            if limit > 0 {
+                hits.truncate(limit);
+            }
+        }
+
+        // Highlight the synonyms found in the hits as well
+        let mut tokens = tokens;
+        if !hits.is_empty() {
+            let ids = serde_json::to_string(&hits.iter().map(|h| h.0).collect::<Vec<_>>())?;
+            for token in terms.iter().flat_map(|t| t.iter().skip(1)) {
+                if tokens.contains(token) {
+                    continue;
+                }
+                let expression = quote_fts(token);
+                let found = sqlx::query_scalar!(
+                    r#"
+                    SELECT EXISTS(
+                      SELECT 1 FROM post_fts
+                      WHERE post_fts MATCH ? AND rowid IN (SELECT value FROM json_each(?))
+                    ) as "found!: bool"
+                    "#,
+                    expression,
+                    ids
+                )
+                .fetch_one(&self.db.pool)
+                .await?;
+                if found {
+                    tokens.push(token.clone());
+                }
+            }
         }
 
+        Ok(SearchResults {
+            tokens,
+            hits,
+            truncated: false,
+        })
+    }
+
+    async fn stored_version(&self) -> Result<String> {
+        let stored = sqlx::query_scalar!("SELECT value FROM search_meta WHERE key = 'version'")
+            .fetch_optional(&self.db.pool)
+            .await?;
+        Ok(stored.unwrap_or_default())
+    }
+
+    /// Unlike Redis, an index which is not built yet is outdated if there are posts,
+    /// e.g. when the backend is switched to SQLite.
+    async fn is_outdated(&self) -> Result<bool> {
+        if self.stored_version().await? == SearchBackend::version(self) {
+            return Ok(false);
+        }
+        let any_post = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM posts) as "any!: bool""#)
+            .fetch_one(&self.db.pool)
+            .await?;
+        Ok(any_post || self.get_doc_count().await? > 0)
+    }
+
+    async fn status(&self) -> Result<IndexStatus> {
+        Ok(IndexStatus {
+            docs: self.get_doc_count().await?,
+            version: SearchBackend::version(self),
+            stored_version: self.stored_version().await?,
+            outdated: self.is_outdated().await?,
+            rebuilding: is_rebuilding(),
+            ops: self.metrics.ops.load(Relaxed),
+            batches: self.metrics.batches.load(Relaxed),
+            retries: self.metrics.retries.load(Relaxed),
+            failures: self.metrics.failures.load(Relaxed),
+        })
+    }
+
+    async fn mark_version(&self) -> Result<()> {
+        let version = SearchBackend::version(self);
+        sqlx::query!(
+            r#"
+            INSERT INTO search_meta (key, value) VALUES ('version', ?)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value
+            "#,
+            version
+        )
+        .execute(&self.db.writer)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn clear_all_indexes(&self) -> Result<()> {
+        let mut tx = self.db.writer.begin().await?;
+        sqlx::query!("DELETE FROM post_fts")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM search_meta WHERE key = 'version'")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
         Ok(())
     }
 }
 
-pub fn count_frequencies<T>(items: &[T]) -> HashMap<T, usize>
-where
-    T: Eq + Hash + Clone,
-{
-    let mut frequencies = HashMap::new();
-    for item in items {
-        *frequencies.entry(item.clone()).or_insert(0) += 1;
+impl SearchBackend for SqliteSearch {
+    fn indexed(&self, id: i64) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move {
+            let indexed = sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM post_fts WHERE rowid = ?) as "indexed!: bool""#,
+                id
+            )
+            .fetch_one(&self.db.pool)
+            .await?;
+            Ok(indexed)
+        })
+    }
+
+    fn get_doc_count(&self) -> BoxFuture<'_, Result<i64>> {
+        Box::pin(SqliteSearch::get_doc_count(self))
+    }
+
+    fn index<'a>(&'a self, id: i64, text: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(SqliteSearch::index(self, id, text))
+    }
+
+    fn deindex(&self, id: i64) -> BoxFuture<'_, Result<()>> {
+        Box::pin(SqliteSearch::deindex(self, id))
+    }
+
+    fn search_among<'a>(
+        &'a self,
+        query: &'a str,
+        partial: bool,
+        limit: usize,
+        filter: Option<&'a dyn DocFilter>,
+    ) -> BoxFuture<'a, Result<SearchResults>> {
+        Box::pin(SqliteSearch::search_among(
+            self, query, partial, limit, filter,
+        ))
+    }
+
+    fn status(&self) -> BoxFuture<'_, Result<IndexStatus>> {
+        Box::pin(SqliteSearch::status(self))
+    }
+
+    fn version(&self) -> String {
+        format!("{}:{}", SCHEMA_VERSION, self.tokenizer.signature())
+    }
+
+    fn stored_version(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(SqliteSearch::stored_version(self))
+    }
+
+    fn is_outdated(&self) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(SqliteSearch::is_outdated(self))
+    }
+
+    fn mark_version(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(SqliteSearch::mark_version(self))
+    }
+
+    fn clear_all_indexes(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(SqliteSearch::clear_all_indexes(self))
+    }
+}
+
+/// Builds an FTS5 query matching each group by any of its terms, or any of the tokens if `partial`,
+/// without the docs matching an excluded term. A token is matched by any of its synonyms.
+fn fts_expression(query: &AnalyzedQuery, partial: bool) -> String {
+    let any = |alternatives: &[String]| {
+        let phrases: Vec<String> = alternatives.iter().map(|token| quote_fts(token)).collect();
+        format!("({})", phrases.join(" OR "))
+    };
+    let term = |term: &Term| match term.phrase {
+        true => {
+            let tokens: Vec<&str> = term.tokens.iter().map(|t| t[0].as_str()).collect();
+            quote_fts(&tokens.join(" "))
+        }
+        false => {
+            let tokens: Vec<String> = term.tokens.iter().map(|t| any(t)).collect();
+            format!("({})", tokens.join(" AND "))
+        }
+    };
+
+    let required = if partial {
+        let terms: Vec<String> = query.terms().iter().map(|t| any(t)).collect();
+        terms.join(" OR ")
+    } else {
+        let groups: Vec<String> = query
+            .groups
+            .iter()
+            .map(|group| {
+                let terms: Vec<String> = group.iter().map(term).collect();
+                format!("({})", terms.join(" OR "))
+            })
+            .collect();
+        groups.join(" AND ")
+    };
+    if query.excluded.is_empty() {
+        return required;
+    }
+    let excluded: Vec<String> = query.excluded.iter().map(term).collect();
+    format!("({}) NOT ({})", required, excluded.join(" OR "))
+}
+
+/// Quotes a phrase of FTS5, so that its words are not taken as operators
+fn quote_fts(phrase: &str) -> String {
+    format!("\"{}\"", phrase.replace('"', "\"\""))
+}
+
+/// Maps each token of the groups to the other tokens of its group
+fn analyze_synonyms(
+    tokenizer: &dyn Tokenizer,
+    groups: &[Vec<String>],
+) -> HashMap<String, Vec<String>> {
+    let mut synonyms: HashMap<String, Vec<String>> = HashMap::new();
+    for group in groups {
+        let tokens: Vec<String> = group
+            .iter()
+            .filter_map(|term| match tokenizer.analyze(term).as_slice() {
+                [token] => Some(token.clone()),
+                _ => {
+                    warn!("Synonym '{}' is not a single word, it is ignored", term);
+                    None
+                }
+            })
+            .collect();
+
+        for token in tokens.iter() {
+            let others = synonyms.entry(token.clone()).or_default();
+            for other in tokens.iter() {
+                if other != token && !others.contains(other) {
+                    others.push(other.clone());
+                }
+            }
+        }
+    }
+    synonyms
+}
+
+/// Returns the token followed by its synonyms
+fn expand_token(synonyms: &HashMap<String, Vec<String>>, token: &str) -> Vec<String> {
+    let mut tokens = vec![token.to_string()];
+    if let Some(synonyms) = synonyms.get(token) {
+        tokens.extend(synonyms.iter().cloned());
+    }
+    tokens
+}
+
+/// Set while the index is rebuilt, only one rebuild may run at a time
+static REBUILDING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_rebuilding() -> bool {
+    REBUILDING.load(Relaxed)
+}
+
+/// Rebuilds the search index of all posts, a notification is sent when done.
+/// Returns false if a rebuild is already running.
+pub async fn rebuild_index(state: &AppState) -> bool {
+    if REBUILDING.swap(true, Relaxed) {
+        return false;
+    }
+
+    let rv: Result<usize> = async {
+        let posts = sqlx::query_as!(PostRow, "SELECT * FROM posts")
+            .fetch_all(&state.db.pool)
+            .await?;
+        state.fts.clear_all_indexes().await?;
+
+        for post in posts.iter() {
+            index_post(state, post).await?;
+        }
+        state.fts.mark_version().await?;
+        Ok(posts.len())
+    }
+    .await;
+    REBUILDING.store(false, Relaxed);
+
+    match rv {
+        Ok(count) => {
+            let message = format!("The search index of {} posts is rebuilt", count);
+            notification_service::notify(state, "index.rebuilt", &message).await;
+        }
+        Err(e) => {
+            error!("Cannot rebuild index: {:?}", e);
+            let message = format!("Cannot rebuild the search index: {:#}", e);
+            notification_service::notify(state, "index.failed", &message).await;
+        }
+    }
+    true
+}
+
+/// Checks the version of the index on startup. An outdated index is rebuilt in the background
+/// if `auto_rebuild`, otherwise a notification asks to rebuild it.
+pub async fn migrate_index(state: &AppState, auto_rebuild: bool) -> Result<()> {
+    let fts = &state.fts;
+    if !fts.is_outdated().await? {
+        return fts.mark_version().await;
+    }
+
+    let stored = fts.stored_version().await?;
+    if auto_rebuild {
+        warn!(
+            "The search index is built with {}, rebuilding it with {}",
+            stored,
+            fts.version()
+        );
+        let state = state.clone();
+        tokio::spawn(async move { rebuild_index(&state).await });
+    } else {
+        warn!(
+            "The search index is built with {}, rebuild it with {}",
+            stored,
+            fts.version()
+        );
+        let message = format!(
+            "The search index is built with another version ({} instead of {}), \
+             rebuild it in the admin panel or with POST /api/admin/rebuild-indexes",
+            stored,
+            fts.version()
+        );
+        notification_service::notify(state, "index.outdated", &message).await;
+    }
+    Ok(())
+}
+
+/// Index the content of a post together with the text of its attachments,
+/// including the text recognized in images when OCR is enabled.
+/// Only the files uploaded by the owner of the post are read.
+#[instrument(skip_all, fields(id = post.id))]
+pub async fn index_post(state: &AppState, post: &PostRow) -> Result<()> {
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+
+    let mut texts = vec![post.content.clone()];
+    for file in post.file_infos() {
+        let text = upload_service
+            .attachment_text(&state.db, state.ocr.as_deref(), post.user_id, &file)
+            .await;
+        texts.extend(text);
+    }
+
+    state.fts.index(post.id, &texts.join("\n")).await
+}
+
+/// A hit ordered by its score, then by its id, so that newer docs win ties
+#[derive(Debug, PartialEq)]
+struct Hit(f64, i64);
+
+impl Eq for Hit {}
+
+impl PartialOrd for Hit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Hit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Keeps the `k` hits with the highest scores, which takes O(n log k) time instead of sorting all of them.
+/// The worst of the kept hits is on the top of a min-heap, and is replaced by any better one.
+struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<Hit>>,
+}
+
+impl TopK {
+    /// Keeps all hits if `k` is 0. The heap is sized for the expected number of hits,
+    /// `k` may be far larger when it comes from a request.
+    fn new(k: usize, candidates: usize) -> Self {
+        let capacity = if k == 0 {
+            candidates
+        } else {
+            k.min(candidates)
+        };
+        Self {
+            k,
+            heap: BinaryHeap::with_capacity(capacity),
+        }
+    }
+
+    fn push(&mut self, (id, score): (i64, f64)) {
+        let hit = Hit(score, id);
+        if self.k == 0 || self.heap.len() < self.k {
+            self.heap.push(Reverse(hit));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if hit > worst.0 {
+                *worst = Reverse(hit);
+            }
+        }
+    }
+
+    /// Returns the hits, the highest score first
+    fn into_sorted_vec(self) -> Vec<(i64, f64)> {
+        // Ascending order of `Reverse` is the descending order of hits
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(Hit(score, id))| (id, score))
+            .collect()
+    }
+}
+
+/// Returns the `k` hits with the highest scores in order, or all of them if `k` is 0.
+pub fn top_k(hits: impl IntoIterator<Item = (i64, f64)>, k: usize) -> Vec<(i64, f64)> {
+    let hits = hits.into_iter();
+    let mut top = TopK::new(k, hits.size_hint().0);
+    for hit in hits {
+        top.push(hit);
+    }
+    top.into_sorted_vec()
+}
+
+/// Parses groups of synonyms, one group in a line, whose terms are separated by `=` or `,`, e.g.
+///
+/// ```text
+/// # Comments start with #
+/// js = javascript
+/// 笔记 = 备忘 = note
+/// ```
+pub fn parse_synonyms(text: &str) -> Vec<Vec<String>> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .map(|line| {
+            line.split(['=', ','])
+                .map(str::trim)
+                .filter(|term| !term.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// The docs of any of the tokens
+fn found_ids(token_docs: &HashMap<&String, HashSet<i64>>) -> Vec<i64> {
+    let ids: HashSet<i64> = token_docs.values().flatten().copied().collect();
+    ids.into_iter().collect()
+}
+
+/// The length of the shortest run of positions containing a position of each list,
+/// `None` if any list is empty
+fn min_window(lists: &[Vec<usize>]) -> Option<usize> {
+    if lists.iter().any(Vec::is_empty) {
+        return None;
     }
-    frequencies
+    let mut positions: Vec<(usize, usize)> = lists
+        .iter()
+        .enumerate()
+        .flat_map(|(i, list)| list.iter().map(move |&position| (position, i)))
+        .collect();
+    positions.sort_unstable();
+
+    // Slide a window over the sorted positions, shrinking it while it contains all lists
+    let mut counts = vec![0; lists.len()];
+    let mut covered = 0;
+    let mut left = 0;
+    let mut best: Option<usize> = None;
+    for &(end, i) in positions.iter() {
+        if counts[i] == 0 {
+            covered += 1;
+        }
+        counts[i] += 1;
+        while covered == lists.len() {
+            let (start, j) = positions[left];
+            let len = end - start + 1;
+            best = Some(best.map_or(len, |best| best.min(len)));
+            counts[j] -= 1;
+            if counts[j] == 0 {
+                covered -= 1;
+            }
+            left += 1;
+        }
+    }
+    best
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DBConfig;
+    use crate::service::kv_service::MemoryStore;
 
     async fn setup() -> FullTextSearch {
-        let rd = Arc::new(RD::new("redis://127.0.0.1").await.unwrap());
+        let kv = Arc::new(MemoryStore::new());
         let tokenizer = Arc::new(Jieba::new());
-        FullTextSearch::new(rd, tokenizer, "test:".to_owned())
+        FullTextSearch::new(kv, tokenizer, "test:".to_owned())
+    }
+
+    #[test]
+    fn test_top_k() {
+        let hits = vec![(1, 0.5), (2, 2.0), (3, 1.0), (4, 2.0), (5, 0.1)];
+
+        assert_eq!(top_k(hits.clone(), 3), vec![(4, 2.0), (2, 2.0), (3, 1.0)]);
+        assert_eq!(top_k(hits.clone(), 1), vec![(4, 2.0)]);
+        // A huge k does not allocate for k hits
+        assert_eq!(top_k(hits.clone(), usize::MAX).len(), hits.len());
+
+        let all = top_k(hits.clone(), 0);
+        assert_eq!(all.len(), hits.len());
+        assert!(all
+            .windows(2)
+            .all(|w| Hit(w[0].1, w[0].0) >= Hit(w[1].1, w[1].0)));
+    }
+
+    #[tokio::test]
+    async fn test_write_in_batches() {
+        let fts = setup().await;
+
+        let text = (0..BATCH_SIZE * 2)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        fts.index(1, &text).await.unwrap();
+
+        let status = fts.status().await.unwrap();
+        assert_eq!(status.docs, 1);
+        assert!(status.batches > 2);
+        assert!(status.ops > BATCH_SIZE as u64 * 2);
+        assert_eq!(
+            fts.search("word1999", false, 10).await.unwrap().hits.len(),
+            1
+        );
+
+        // A batch applied again, as when it is retried after a partial failure, counts the doc once
+        fts.kv
+            .apply(vec![KvOp::SAdd(fts.doc_ids_key(), "1".to_string())])
+            .await
+            .unwrap();
+        assert_eq!(fts.get_doc_count().await.unwrap(), 1);
+
+        fts.deindex(1).await.unwrap();
+        assert_eq!(fts.get_doc_count().await.unwrap(), 0);
+        assert!(fts
+            .search("word0", false, 10)
+            .await
+            .unwrap()
+            .hits
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_long_posting_lists() {
+        let fts = setup().await.with_limits(2, None);
+        for id in 1..=5 {
+            fts.index(id, "common").await.unwrap();
+        }
+        fts.index(6, "common rare").await.unwrap();
+        fts.index(7, "rare").await.unwrap();
+
+        let ids = |rv: &SearchResults| {
+            let mut ids: Vec<i64> = rv.hits.iter().map(|h| h.0).collect();
+            ids.sort();
+            ids
+        };
+
+        // The long list of `common` is only checked for the docs of `rare`
+        let rv = fts.search("common rare", false, 10).await.unwrap();
+        assert_eq!(ids(&rv), vec![6]);
+        assert!(!rv.truncated);
+        let rv = fts.search("rare -common", false, 10).await.unwrap();
+        assert_eq!(ids(&rv), vec![7]);
+        assert!(!rv.truncated);
+
+        // A long list is loaded whole if nothing bounds it
+        let rv = fts.search("common", false, 10).await.unwrap();
+        assert_eq!(rv.hits.len(), 2);
+        assert!(rv.truncated);
+    }
+
+    #[test]
+    fn test_normalizer() {
+        assert_eq!(
+            fold_diacritics("Crème brûlée, naïve"),
+            "Creme brulee, naive"
+        );
+        assert_eq!(fold_diacritics("ガギ 가"), "ガギ 가");
+
+        let tokenizer = Normalizer::new(Jieba::new(), true, Some(Algorithm::English));
+        assert_eq!(
+            tokenizer.analyze("Running at the café, 跑步"),
+            vec!["run", "cafe", "跑步"]
+        );
+        assert_eq!(tokenizer.signature(), "jieba+fold+stem:english");
+
+        let tokenizer = Normalizer::new(Jieba::new(), false, None);
+        let text = "Running café";
+        assert_eq!(tokenizer.analyze(text), Jieba::new().analyze(text));
+        assert_eq!(tokenizer.signature(), DEFAULT_ANALYZER);
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let kv = Arc::new(MemoryStore::new());
+        let fts = FullTextSearch::new(kv.clone(), Arc::new(Jieba::new()), "test:".to_owned());
+        assert_eq!(fts.version(), "3:jieba");
+        fts.index(1, "running").await.unwrap();
+        // An index without a recorded version has no token positions
+        assert!(fts.is_outdated().await.unwrap());
+        fts.mark_version().await.unwrap();
+        assert!(!fts.is_outdated().await.unwrap());
+
+        let tokenizer = Normalizer::new(Jieba::new(), false, Some(Algorithm::English));
+        let stemmed = FullTextSearch::new(kv, Arc::new(tokenizer), "test:".to_owned());
+        assert_eq!(stemmed.version(), "3:jieba+stem:english");
+        assert!(stemmed.is_outdated().await.unwrap());
+
+        stemmed.mark_version().await.unwrap();
+        assert!(!stemmed.is_outdated().await.unwrap());
+        assert!(fts.is_outdated().await.unwrap());
+
+        fts.clear_all_indexes().await.unwrap();
+        assert!(!stemmed.is_outdated().await.unwrap());
+
+        // An index of schema version 2 counts its docs, and is outdated
+        fts.kv
+            .apply(vec![
+                KvOp::Set(fts.version_key(), "2:jieba".to_string()),
+                KvOp::Incr(fts.legacy_doc_count_key(), 5),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(fts.get_doc_count().await.unwrap(), 5);
+        assert!(fts.is_outdated().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_proximity() {
+        assert_eq!(min_window(&[vec![0, 9], vec![4, 10]]), Some(2));
+        assert_eq!(min_window(&[vec![3], vec![1], vec![7]]), Some(7));
+        assert_eq!(min_window(&[vec![3], vec![]]), None);
+
+        // Of the docs with the same terms, the one where they are next to each other ranks first
+        let fts = setup().await;
+        fts.index(1, "apple banana cherry grape lemon mango")
+            .await
+            .unwrap();
+        fts.index(2, "apple cherry grape lemon mango banana")
+            .await
+            .unwrap();
+        fts.index(3, "orange peach").await.unwrap();
+        let rv = fts.search("apple banana", false, 10).await.unwrap();
+        let ids: Vec<i64> = rv.hits.iter().map(|h| h.0).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(rv.hits[0].1 > rv.hits[1].1);
+    }
+
+    #[tokio::test]
+    async fn test_synonyms() {
+        let groups = parse_synonyms(
+            "# languages\njs = JavaScript, ecmascript\n\n笔记 = 备忘 # notes\nlonely\n",
+        );
+        assert_eq!(
+            groups,
+            vec![vec!["js", "JavaScript", "ecmascript"], vec!["笔记", "备忘"]]
+        );
+
+        let fts = setup().await.with_synonyms(&groups);
+        fts.index(1, "Learning javascript").await.unwrap();
+        fts.index(2, "js tips").await.unwrap();
+        fts.index(3, "读书笔记").await.unwrap();
+        fts.index(4, "备忘录 rust").await.unwrap();
+
+        let rv = fts.search("js", false, 10).await.unwrap();
+        assert_eq!(rv.hits.len(), 2);
+        assert_eq!(rv.tokens, vec!["js", "javascript"]);
+
+        let rv = fts.search("备忘", false, 10).await.unwrap();
+        let mut ids: Vec<i64> = rv.hits.iter().map(|h| h.0).collect();
+        ids.sort();
+        assert_eq!(ids, vec![3, 4]);
+        assert!(rv.tokens.contains(&"笔记".to_string()));
+
+        // All terms must be matched, by any of their synonyms
+        let rv = fts.search("javascript tips", false, 10).await.unwrap();
+        assert_eq!(rv.hits.len(), 1);
+        assert_eq!(rv.hits[0].0, 2);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        use QueryTerm::{Phrase, Word};
+        let word = |w: &str| Word(w.to_string());
+
+        assert_eq!(
+            parse_query("rust  async"),
+            ParsedQuery {
+                groups: vec![vec![word("rust")], vec![word("async")]],
+                excluded: vec![],
+            }
+        );
+        assert_eq!(
+            parse_query(r#"OR "rust async" go OR zig OR -c -"c++ 20" e-mail OR"#),
+            ParsedQuery {
+                groups: vec![
+                    vec![Phrase("rust async".to_string())],
+                    vec![word("go"), word("zig")],
+                    vec![word("e-mail")],
+                ],
+                excluded: vec![word("c"), Phrase("c++ 20".to_string())],
+            }
+        );
+        assert_eq!(
+            parse_query(r#" - "" "unclosed"#).groups,
+            vec![vec![Phrase("unclosed".to_string())]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_syntax() {
+        let fts = setup().await;
+        fts.index(1, "rust async runtime").await.unwrap();
+        fts.index(2, "async rust").await.unwrap();
+        fts.index(3, "go runtime").await.unwrap();
+
+        let ids = |rv: SearchResults| {
+            let mut ids: Vec<i64> = rv.hits.iter().map(|h| h.0).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(
+            ids(fts.search("rust OR go", false, 10).await.unwrap()),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            ids(fts.search("runtime -go", false, 10).await.unwrap()),
+            vec![1]
+        );
+        assert_eq!(
+            ids(fts
+                .search("runtime -\"go runtime\"", false, 10)
+                .await
+                .unwrap()),
+            vec![1]
+        );
+        // A phrase matches its words next to each other and in order
+        assert_eq!(
+            ids(fts.search("\"rust async\"", false, 10).await.unwrap()),
+            vec![1]
+        );
+        assert!(fts
+            .search("\"rust runtime\"", false, 10)
+            .await
+            .unwrap()
+            .hits
+            .is_empty());
+        assert_eq!(
+            ids(fts.search("rust -\"async rust\"", false, 10).await.unwrap()),
+            vec![1]
+        );
+        let rv = fts.search("async OR go -rust", false, 10).await.unwrap();
+        assert_eq!(rv.tokens, vec!["async", "go"]);
+        assert_eq!(ids(rv), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_backend() {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let groups = vec![vec!["js".to_string(), "javascript".to_string()]];
+        let fts: Arc<dyn SearchBackend> = Arc::new(
+            SqliteSearch::new(Arc::new(db), Arc::new(Jieba::new())).with_synonyms(&groups),
+        );
+
+        fts.index(1, "hello world").await.unwrap();
+        fts.index(2, "hello rust, 测试文档").await.unwrap();
+        fts.index(3, "world of rust programming").await.unwrap();
+        assert!(fts.indexed(1).await.unwrap());
+        assert_eq!(fts.get_doc_count().await.unwrap(), 3);
+
+        let rv = fts.search("world", false, 10).await.unwrap();
+        assert_eq!(rv.hits.iter().map(|h| h.0).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(rv.hits[0].1 > rv.hits[1].1);
+
+        let rv = fts.search("hello rust", false, 10).await.unwrap();
+        assert_eq!(rv.hits.len(), 1);
+        assert_eq!(rv.hits[0].0, 2);
+        assert_eq!(
+            fts.search("hello rust", true, 10).await.unwrap().hits.len(),
+            3
+        );
+        assert_eq!(fts.search("测试", false, 10).await.unwrap().hits.len(), 1);
+        assert_eq!(fts.search("hello", false, 1).await.unwrap().hits.len(), 1);
+
+        let docs = HashSet::from([1, 3]);
+        let rv = fts
+            .search_among("rust", false, 10, Some(&docs))
+            .await
+            .unwrap();
+        assert_eq!(rv.hits.iter().map(|h| h.0).collect::<Vec<_>>(), vec![3]);
+        // The limit applies after the filter
+        let docs = HashSet::from([3]);
+        let rv = fts
+            .search_among("world", false, 1, Some(&docs))
+            .await
+            .unwrap();
+        assert_eq!(rv.hits.iter().map(|h| h.0).collect::<Vec<_>>(), vec![3]);
+
+        // Quotes and operators of FTS5 are taken as text
+        assert!(fts
+            .search("\"NOT AND*", true, 10)
+            .await
+            .unwrap()
+            .hits
+            .is_empty());
+
+        fts.index(4, "JavaScript tips").await.unwrap();
+        let rv = fts.search("js", false, 10).await.unwrap();
+        assert_eq!(rv.hits.len(), 1);
+        assert_eq!(rv.tokens, vec!["js", "javascript"]);
+
+        fts.index(1, "goodbye").await.unwrap();
+        assert_eq!(fts.search("world", false, 10).await.unwrap().hits.len(), 1);
+        fts.deindex(1).await.unwrap();
+        assert!(!fts.indexed(1).await.unwrap());
+
+        assert_ne!(fts.stored_version().await.unwrap(), fts.version());
+        fts.mark_version().await.unwrap();
+        assert!(!fts.is_outdated().await.unwrap());
+
+        fts.clear_all_indexes().await.unwrap();
+        assert_eq!(fts.get_doc_count().await.unwrap(), 0);
+        assert_eq!(fts.stored_version().await.unwrap(), "");
     }
 
     #[tokio::test]
@@ -346,13 +2139,13 @@ mod tests {
 
         assert_eq!(fts.get_doc_count().await.unwrap(), 1);
 
-        let (_, results) = fts.search("hello", true, 300).await.unwrap();
+        let results = fts.search("hello", true, 300).await.unwrap().hits;
         assert_eq!(results.len(), 1);
 
-        let (_, results) = fts.search("测试", true, 300).await.unwrap();
+        let results = fts.search("测试", true, 300).await.unwrap().hits;
         assert_eq!(results.len(), 1);
 
-        let (_, results) = fts.search("hello rust", true, 300).await.unwrap();
+        let results = fts.search("hello rust", true, 300).await.unwrap().hits;
         assert_eq!(results.len(), 1);
 
         fts.clear_all_indexes().await.unwrap();
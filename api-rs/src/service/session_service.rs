@@ -0,0 +1,153 @@
+use crate::config::db::DB;
+use crate::errors::ApiResult;
+use crate::model::session::Session;
+use chrono::Utc;
+use sqlx::{query, query_as, SqlitePool};
+
+/// How often the last seen time of a session is written, not to write on every request
+const TOUCH_INTERVAL_MS: i64 = 60 * 1000;
+
+impl Session {
+    pub async fn create(
+        db: &DB,
+        user_id: i64,
+        token_hash: &str,
+        expires_at: i64,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> ApiResult<Session> {
+        let now = Utc::now().timestamp_millis();
+        let session = query_as!(
+            Session,
+            r#"
+            INSERT INTO sessions (user_id, token_hash, user_agent, ip, created_at, expires_at, last_seen_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING id, user_id, user_agent, ip, created_at, expires_at, last_seen_at
+            "#,
+            user_id,
+            token_hash,
+            user_agent,
+            ip,
+            now,
+            expires_at,
+            now
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Finds the session of a token hash, if it has not expired.
+    pub async fn find_by_token_hash(
+        pool: &SqlitePool,
+        token_hash: &str,
+    ) -> ApiResult<Option<Session>> {
+        let now = Utc::now().timestamp_millis();
+        let session = query_as!(
+            Session,
+            r#"
+            SELECT id AS "id!", user_id, user_agent, ip, created_at, expires_at, last_seen_at
+            FROM sessions
+            WHERE token_hash = ? AND expires_at > ?
+            "#,
+            token_hash,
+            now
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Returns the sessions of a user which have not expired, the most recently used first.
+    pub async fn find_active(pool: &SqlitePool, user_id: i64) -> ApiResult<Vec<Session>> {
+        let now = Utc::now().timestamp_millis();
+        let sessions = query_as!(
+            Session,
+            r#"
+            SELECT id AS "id!", user_id, user_agent, ip, created_at, expires_at, last_seen_at
+            FROM sessions
+            WHERE user_id = ? AND expires_at > ?
+            ORDER BY COALESCE(last_seen_at, created_at) DESC
+            "#,
+            user_id,
+            now
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    /// Records that the session is used from `ip`, at most once every minute.
+    pub async fn touch(&self, db: &DB, ip: Option<&str>) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        if self
+            .last_seen_at
+            .is_some_and(|at| now - at < TOUCH_INTERVAL_MS)
+            && self.ip.as_deref() == ip
+        {
+            return Ok(());
+        }
+
+        query!(
+            "UPDATE sessions SET last_seen_at = ?, ip = ? WHERE id = ?",
+            now,
+            ip,
+            self.id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the token of a session and extends it, returns none if it does not exist anymore.
+    pub async fn renew(
+        db: &DB,
+        id: i64,
+        token_hash: &str,
+        expires_at: i64,
+    ) -> ApiResult<Option<Session>> {
+        let session = query_as!(
+            Session,
+            r#"
+            UPDATE sessions
+            SET token_hash = ?, expires_at = ?
+            WHERE id = ?
+            RETURNING id, user_id, user_agent, ip, created_at, expires_at, last_seen_at
+            "#,
+            token_hash,
+            expires_at,
+            id
+        )
+        .fetch_optional(&db.writer)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Removes a session of a user, so its token cannot be used anymore. Returns whether it exists.
+    pub async fn delete(db: &DB, user_id: i64, id: i64) -> ApiResult<bool> {
+        let result = query!(
+            "DELETE FROM sessions WHERE id = ? AND user_id = ?",
+            id,
+            user_id
+        )
+        .execute(&db.writer)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes the expired sessions, returns how many are removed.
+    pub async fn delete_expired(db: &DB) -> ApiResult<u64> {
+        let now = Utc::now().timestamp_millis();
+        let result = query!("DELETE FROM sessions WHERE expires_at <= ?", now)
+            .execute(&db.writer)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,156 @@
+use crate::util::html::escape;
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use ring::hmac;
+use std::collections::HashMap;
+
+/// Requests signed longer ago are rejected, so that a captured request cannot be replayed later
+const MAX_SIGNATURE_AGE_SECS: i64 = 300;
+
+lazy_static! {
+    static ref HASH_TAG: Regex = Regex::new(r"(^|\s)#([\w/\-]+)").unwrap();
+}
+
+/// Checks the `X-Slack-Signature` of a request, the hex HMAC-SHA256 of `v0:{timestamp}:{body}`
+/// with the signing secret, and that its `X-Slack-Request-Timestamp` is recent.
+pub fn verify_signature(
+    secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now_secs: i64,
+) -> bool {
+    let Ok(sent_at) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (now_secs - sent_at).abs() > MAX_SIGNATURE_AGE_SECS {
+        return false;
+    }
+    let Some(tag) = signature.strip_prefix("v0=").and_then(decode_hex) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut message = format!("v0:{}:", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    hmac::verify(&key, &message, &tag).is_ok()
+}
+
+/// Parses a form sent as `application/x-www-form-urlencoded`, such as a slash command.
+pub fn parse_form(body: &[u8]) -> HashMap<String, String> {
+    let decode = |s: &str| {
+        percent_decode_str(&s.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Turns the text of a slash command into the content of a post, a paragraph per line,
+/// with its hashtags tagging the post.
+pub fn command_to_html(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let line = escape(line);
+            let line = HASH_TAG.replace_all(&line, r#"$1<span class="hash-tag">#$2</span>"#);
+            format!("<p>{}</p>", line)
+        })
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let mut message = format!("v0:{}:", timestamp).into_bytes();
+        message.extend_from_slice(body);
+        let tag = hmac::sign(&key, &message);
+        let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("v0={}", hex)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = b"user_id=U1&text=hello";
+        let signature = sign("secret", "1700000000", body);
+
+        assert!(verify_signature(
+            "secret",
+            "1700000000",
+            body,
+            &signature,
+            1700000100
+        ));
+        assert!(!verify_signature(
+            "other",
+            "1700000000",
+            body,
+            &signature,
+            1700000100
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "1700000000",
+            b"user_id=U2",
+            &signature,
+            1700000100
+        ));
+        // Too old
+        assert!(!verify_signature(
+            "secret",
+            "1700000000",
+            body,
+            &signature,
+            1700001000
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "1700000000",
+            body,
+            "v0=zz",
+            1700000100
+        ));
+        assert!(!verify_signature(
+            "secret", "now", body, &signature, 1700000100
+        ));
+    }
+
+    #[test]
+    fn test_parse_form() {
+        let form = parse_form(b"user_id=U1&text=read+%23books%2Fnovels+%26+more&empty");
+        assert_eq!(form["user_id"], "U1");
+        assert_eq!(form["text"], "read #books/novels & more");
+        assert_eq!(form["empty"], "");
+    }
+
+    #[test]
+    fn test_command_to_html() {
+        assert_eq!(
+            command_to_html("#work notes <b>\n\n a#b #读书 "),
+            r#"<p><span class="hash-tag">#work</span> notes &lt;b&gt;</p><p>a#b <span class="hash-tag">#读书</span></p>"#
+        );
+    }
+}
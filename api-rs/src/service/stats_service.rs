@@ -0,0 +1,66 @@
+use crate::config::db::DB;
+use crate::config::rd::RD;
+use crate::errors::ApiResult;
+use crate::model::post::{Post, PostStats};
+use crate::model::tag::{Tag, TagSort};
+use tracing::warn;
+
+const STATS_KEY: &str = "post-stats";
+pub const SHARED_POSTS_KEY: &str = "shared-posts-page";
+
+/// The caches are dropped on changes of posts and tags, they expire anyway in case one is missed.
+pub const CACHE_TTL_SECONDS: u64 = 600;
+
+/// Returns the overall counts of a user, from the cache if possible.
+pub async fn get_stats(db: &DB, rd: &RD, user_id: i64) -> ApiResult<PostStats> {
+    let key = format!("{}:{}", STATS_KEY, user_id);
+    rd.cached(&key, CACHE_TTL_SECONDS, || async {
+        Ok(PostStats {
+            post_count: Post::get_count(db, user_id).await?,
+            tag_count: Tag::get_count(db, user_id).await?,
+            day_count: Post::get_active_days(db, user_id).await?,
+            color_counts: Post::get_color_counts(db, user_id).await?,
+            tag_counts: Tag::get_post_counts(db, user_id).await?,
+        })
+    })
+    .await
+}
+
+/// Returns the cache key of the tag list of a user
+pub fn tags_key(user_id: i64, include_hidden: bool, sort: Option<TagSort>) -> String {
+    format!(
+        "tags:{}:{}:{}",
+        user_id,
+        if include_hidden {
+            "with-hidden"
+        } else {
+            "visible"
+        },
+        sort.map_or("custom", |sort| sort.as_str())
+    )
+}
+
+fn all_tags_keys(user_id: i64) -> Vec<String> {
+    let sorts = std::iter::once(None).chain(TagSort::ALL.map(Some));
+    sorts
+        .flat_map(|sort| {
+            [
+                tags_key(user_id, false, sort),
+                tags_key(user_id, true, sort),
+            ]
+        })
+        .collect()
+}
+
+/// Drops the cached counts and tag lists of a user and the shared pages,
+/// it should be called after the posts or tags of the user are changed.
+pub async fn invalidate(rd: &RD, user_id: i64) {
+    let mut keys = all_tags_keys(user_id);
+    keys.extend([
+        format!("{}:{}", STATS_KEY, user_id),
+        SHARED_POSTS_KEY.to_string(),
+    ]);
+    if let Err(e) = rd.del(&keys).await {
+        warn!("Cannot invalidate the caches: {:?}", e);
+    }
+}
@@ -1,37 +1,135 @@
+use crate::config::db::DB;
 use crate::errors::{bad_request, ApiResult};
 use crate::model::post::PostRow;
-use crate::model::tag::{Tag, TagWithPostCount};
+use crate::model::tag::{Tag, TagSort, TagWithPostCount};
+use crate::service::event_service::{emit, AppEvent};
 use chrono::Utc;
-use sqlx::{query, query_as, Sqlite, SqlitePool, Transaction};
+use regex::Regex;
+use sqlx::{query, query_as, query_scalar, Sqlite, SqlitePool, Transaction};
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use tracing::instrument;
 
 impl Tag {
-    pub async fn get_count(pool: &SqlitePool) -> ApiResult<i64> {
-        let count = query!("SELECT COUNT(*) as count FROM tags")
-            .fetch_one(pool)
-            .await?
-            .count;
+    pub async fn get_count(pool: &SqlitePool, user_id: i64) -> ApiResult<i64> {
+        let count = query!(
+            "SELECT COUNT(*) as count FROM tags WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(pool)
+        .await?
+        .count;
 
         Ok(count)
     }
 
-    pub async fn get_all_with_post_count(pool: &SqlitePool) -> ApiResult<Vec<TagWithPostCount>> {
+    /// Get the number of undeleted posts of each tag, tags without posts are omitted.
+    pub async fn get_post_counts(
+        pool: &SqlitePool,
+        user_id: i64,
+    ) -> ApiResult<BTreeMap<String, i64>> {
+        let counts = query!(
+            r#"
+            SELECT t.name, COUNT(*) as "count!: i64"
+            FROM tags t
+            INNER JOIN tag_post_assoc tp ON tp.tag_id = t.id
+            INNER JOIN posts p ON p.id = tp.post_id
+            WHERE t.user_id = ? AND p.deleted_at IS NULL
+            GROUP BY t.id
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.name, row.count))
+        .collect();
+
+        Ok(counts)
+    }
+
+    /// Get the names of the tags of all users which neither they nor their descendants are attached to any post.
+    pub async fn find_empty(pool: &SqlitePool) -> ApiResult<Vec<String>> {
+        let names = query_scalar!(
+            r#"
+            SELECT t.name FROM tags t
+            WHERE NOT EXISTS (
+                SELECT 1 FROM tags d
+                JOIN tag_post_assoc tp ON tp.tag_id = d.id
+                WHERE d.user_id = t.user_id AND (d.name = t.name OR d.name LIKE t.name || '/%')
+            )
+            ORDER BY t.name
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(names)
+    }
+
+    /// Get the tags with the counts of their posts, including those of their descendants.
+    /// Without `sort`, they are in their custom order.
+    #[instrument(skip_all)]
+    pub async fn get_all_with_post_count(
+        pool: &SqlitePool,
+        user_id: i64,
+        include_hidden: bool,
+        sort: Option<TagSort>,
+    ) -> ApiResult<Vec<TagWithPostCount>> {
+        let sort = sort.map(|sort| sort.as_str());
         let tags = query_as!(
             TagWithPostCount,
             r#"
-            SELECT t.name, t.sticky,
-                (
-                    SELECT COUNT(DISTINCT a.post_id)
-                    FROM tag_post_assoc a
-                    WHERE a.tag_id IN (
-                        SELECT id
-                        FROM tags
-                        WHERE name = t.name
-                           OR name LIKE t.name || '/%'
-                    )
-            ) AS post_count
-            FROM tags t;
-            "#
+            WITH user_tags AS (
+                SELECT * FROM tags WHERE user_id = ?3
+            ),
+            hidden_tags AS (
+                SELECT t.id FROM user_tags t
+                WHERE EXISTS (
+                    SELECT 1 FROM user_tags h
+                    WHERE h.hidden AND (t.name = h.name OR t.name LIKE h.name || '/%')
+                )
+            ),
+            counted AS (
+                SELECT t.name, t.sticky, t.sort_order,
+                    t.id IN hidden_tags AS hidden,
+                    (
+                        SELECT COUNT(DISTINCT a.post_id)
+                        FROM tag_post_assoc a
+                        WHERE a.tag_id IN (
+                            SELECT id
+                            FROM user_tags
+                            WHERE name = t.name
+                               OR name LIKE t.name || '/%'
+                        )
+                    ) AS post_count,
+                    (
+                        SELECT MAX(p.created_at)
+                        FROM tag_post_assoc a
+                        JOIN posts p ON p.id = a.post_id
+                        WHERE p.deleted_at IS NULL
+                        AND a.tag_id IN (
+                            SELECT id
+                            FROM user_tags
+                            WHERE name = t.name
+                               OR name LIKE t.name || '/%'
+                        )
+                    ) AS last_post_at
+                FROM user_tags t
+                WHERE ?1 OR t.id NOT IN hidden_tags
+            )
+            SELECT name AS "name!", sticky AS "sticky!: bool", sort_order AS "sort_order!",
+                hidden AS "hidden!: bool", post_count AS "post_count!: i64"
+            FROM counted
+            ORDER BY
+                CASE WHEN ?2 = 'post_count' THEN post_count END DESC,
+                CASE WHEN ?2 = 'recent' THEN COALESCE(last_post_at, 0) END DESC,
+                CASE WHEN ?2 IS NULL THEN sort_order END,
+                name;
+            "#,
+            include_hidden,
+            sort,
+            user_id,
         )
         .fetch_all(pool)
         .await?;
@@ -40,6 +138,7 @@ impl Tag {
     }
 
     #[allow(dead_code)]
+    #[instrument(skip_all)]
     pub async fn get_all_with_undeleted_post_count(
         pool: &SqlitePool,
     ) -> ApiResult<Vec<TagWithPostCount>> {
@@ -55,10 +154,13 @@ impl Tag {
             )
             SELECT t.name AS name,
                    t.sticky AS sticky,
+                   t.sort_order AS sort_order,
+                   t.hidden AS hidden,
                    COUNT(DISTINCT tp.post_id) AS post_count
             FROM tags t
             LEFT JOIN tag_posts tp ON tp.tag_name = t.name OR tp.tag_name LIKE (t.name || '/%')
             GROUP BY t.name
+            ORDER BY t.sort_order, t.name
             "#
         )
         .fetch_all(pool)
@@ -67,9 +169,8 @@ impl Tag {
         Ok(tags)
     }
 
-    // It will be useful in tests
-    #[allow(dead_code)]
-    pub async fn get_posts(pool: &SqlitePool, name: &str) -> ApiResult<Vec<PostRow>> {
+    #[instrument(skip(pool))]
+    pub async fn get_posts(pool: &SqlitePool, user_id: i64, name: &str) -> ApiResult<Vec<PostRow>> {
         let name_pattern = format!("{}/%", name);
         let posts = query_as!(
             PostRow,
@@ -82,10 +183,11 @@ impl Tag {
                 JOIN tag_post_assoc tp ON t.id = tp.tag_id
                 WHERE tp.post_id = p.id
                 AND (t.name = ? OR t.name LIKE ?))
-            AND p.deleted_at IS NULL
+            AND p.user_id = ? AND p.deleted_at IS NULL
             "#,
             name,
             name_pattern,
+            user_id,
         )
         .fetch_all(pool)
         .await?;
@@ -93,82 +195,297 @@ impl Tag {
         Ok(posts)
     }
 
-    pub async fn find_or_create(tx: &mut Transaction<'_, Sqlite>, name: &str) -> ApiResult<Tag> {
-        let tag = if let Some(tag) = Tag::find_by_name(tx, name).await? {
+    pub async fn find_or_create(
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        name: &str,
+    ) -> ApiResult<Tag> {
+        let tag = if let Some(tag) = Tag::find_by_name(tx, user_id, name).await? {
             tag
         } else {
-            Tag::create(tx, name).await?
+            Tag::create(tx, user_id, name).await?
         };
         Ok(tag)
     }
 
-    pub async fn insert_or_update(pool: &SqlitePool, name: &str, sticky: bool) -> ApiResult<()> {
+    #[instrument(skip(db))]
+    pub async fn insert_or_update(
+        db: &DB,
+        user_id: i64,
+        name: &str,
+        sticky: bool,
+    ) -> ApiResult<()> {
         let now = Utc::now().timestamp_millis();
 
         sqlx::query!(
             r#"
-            INSERT INTO tags (name, sticky, created_at, updated_at)
-            VALUES (?, ?, ?, ?)
-            ON CONFLICT(name) DO UPDATE SET
+            INSERT INTO tags (user_id, name, sticky, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user_id, name) DO UPDATE SET
                 sticky = excluded.sticky,
                 updated_at = excluded.updated_at
             "#,
+            user_id,
             name,
             sticky,
             now,
             now,
         )
-        .execute(pool)
+        .execute(&db.writer)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_hidden(db: &DB, user_id: i64, name: &str, hidden: bool) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+
+        query!(
+            r#"
+            INSERT INTO tags (user_id, name, hidden, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user_id, name) DO UPDATE SET
+                hidden = excluded.hidden,
+                updated_at = excluded.updated_at
+            "#,
+            user_id,
+            name,
+            hidden,
+            now,
+            now,
+        )
+        .execute(&db.writer)
         .await?;
 
         Ok(())
     }
 
-    pub async fn delete_associated_posts(pool: &SqlitePool, name: &str) -> ApiResult<()> {
+    /// Sets the positions of the tags to their indexes in `names`, starting from 1.
+    pub async fn reorder(db: &DB, user_id: i64, names: &[String]) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let mut tx = db.writer.begin().await?;
+
+        for (i, name) in names.iter().enumerate() {
+            let sort_order = i as i64 + 1;
+            query!(
+                "UPDATE tags SET sort_order = ?, updated_at = ? WHERE user_id = ? AND name = ?",
+                sort_order,
+                now,
+                user_id,
+                name
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(skip(db))]
+    pub async fn delete_associated_posts(db: &DB, user_id: i64, name: &str) -> ApiResult<()> {
         let now = Utc::now().timestamp_millis();
         let name_pattern = format!("{}/%", name);
 
-        sqlx::query!(
+        let ids = query_scalar!(
             r#"
             UPDATE posts
             SET deleted_at = ?1
-            WHERE id IN (
+            WHERE deleted_at IS NULL AND id IN (
                 SELECT post_id
                 FROM tag_post_assoc
                 WHERE tag_id IN (
                     SELECT id
                     FROM tags
-                    WHERE name = ?2 OR name LIKE ?3
+                    WHERE user_id = ?4 AND (name = ?2 OR name LIKE ?3)
                 )
             )
+            RETURNING id
             "#,
             now,
             name,
+            name_pattern,
+            user_id
+        )
+        .fetch_all(&db.writer)
+        .await?;
+
+        for id in ids {
+            emit(AppEvent::PostDeleted { id, user_id });
+        }
+        Ok(())
+    }
+
+    /// Deletes a tag and its descendants, the posts are untouched.
+    #[instrument(skip(db))]
+    pub async fn delete_only(db: &DB, user_id: i64, name: &str) -> ApiResult<()> {
+        let name_pattern = format!("{}/%", name);
+        query!(
+            "DELETE FROM tags WHERE user_id = ? AND (name = ? OR name LIKE ?)",
+            user_id,
+            name,
             name_pattern
         )
-        .execute(pool)
+        .execute(&db.writer)
         .await?;
 
+        emit(AppEvent::TagDeleted {
+            user_id,
+            name: name.to_string(),
+        });
         Ok(())
     }
 
-    async fn find_by_name(tx: &mut Transaction<'_, Sqlite>, name: &str) -> ApiResult<Option<Self>> {
-        let tag = query_as!(Tag, "SELECT * FROM tags WHERE name = ?", name,)
-            .fetch_optional(&mut **tx)
+    /// Deletes a tag and its descendants, and removes them from the content of their posts.
+    #[instrument(skip(db))]
+    pub async fn detach(db: &DB, user_id: i64, name: &str) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let name_pattern = format!("{}/%", name);
+        let mut tx = db.writer.begin().await?;
+
+        let posts = query!(
+            r#"
+            SELECT id, content FROM posts
+            WHERE id IN (
+                SELECT tp.post_id
+                FROM tag_post_assoc tp
+                INNER JOIN tags t ON tp.tag_id = t.id
+                WHERE t.user_id = ? AND (t.name = ? OR t.name LIKE ?)
+            )
+            "#,
+            user_id,
+            name,
+            name_pattern
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for post in posts.iter() {
+            let content = remove_hash_tags(&post.content, name);
+            query!(
+                "UPDATE posts SET content = ?, updated_at = ? WHERE id = ?",
+                content,
+                now,
+                post.id
+            )
+            .execute(&mut *tx)
             .await?;
+        }
+
+        // The associations are deleted in cascade
+        query!(
+            "DELETE FROM tags WHERE user_id = ? AND (name = ? OR name LIKE ?)",
+            user_id,
+            name,
+            name_pattern
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        for post in posts {
+            emit(AppEvent::PostUpdated {
+                id: post.id,
+                user_id,
+                reindex: true,
+            });
+        }
+        emit(AppEvent::TagDeleted {
+            user_id,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Returns the current name of a renamed tag, or `None` if the tag was not renamed
+    /// or a new tag took the old name.
+    pub async fn resolve_rename(
+        pool: &SqlitePool,
+        user_id: i64,
+        name: &str,
+    ) -> ApiResult<Option<String>> {
+        let new_name = query_scalar!(
+            r#"
+            SELECT new_name FROM tag_renames
+            WHERE user_id = ?1 AND old_name = ?2
+            AND NOT EXISTS (SELECT 1 FROM tags WHERE user_id = ?1 AND name = ?2)
+            "#,
+            user_id,
+            name
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(new_name)
+    }
+
+    /// Points the old name, and the names renamed to it before, to the new name.
+    async fn record_rename(
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        old_name: &str,
+        new_name: &str,
+    ) -> ApiResult<()> {
+        let now = Utc::now().timestamp_millis();
+
+        query!(
+            "UPDATE tag_renames SET new_name = ? WHERE user_id = ? AND new_name = ?",
+            new_name,
+            user_id,
+            old_name
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        query!(
+            r#"
+            INSERT INTO tag_renames (user_id, old_name, new_name, created_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (user_id, old_name) DO UPDATE SET new_name = excluded.new_name, created_at = excluded.created_at
+            "#,
+            user_id,
+            old_name,
+            new_name,
+            now
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        // A tag renamed back to an old name
+        query!("DELETE FROM tag_renames WHERE old_name = new_name")
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn find_by_name(
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: i64,
+        name: &str,
+    ) -> ApiResult<Option<Self>> {
+        let tag = query_as!(
+            Tag,
+            "SELECT * FROM tags WHERE user_id = ? AND name = ?",
+            user_id,
+            name
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
 
         Ok(tag)
     }
 
-    async fn create(tx: &mut Transaction<'_, Sqlite>, name: &str) -> ApiResult<Tag> {
+    async fn create(tx: &mut Transaction<'_, Sqlite>, user_id: i64, name: &str) -> ApiResult<Tag> {
         let now = Utc::now().timestamp_millis();
 
         let id = query!(
             r#"
-            INSERT INTO tags (name, sticky, created_at, updated_at)
-            VALUES (?, false, ?, ?)
+            INSERT INTO tags (user_id, name, sticky, created_at, updated_at)
+            VALUES (?, ?, false, ?, ?)
             RETURNING id
             "#,
+            user_id,
             name,
             now,
             now
@@ -179,8 +496,11 @@ impl Tag {
 
         Ok(Tag {
             id,
+            user_id,
             name: name.to_string(),
             sticky: false,
+            sort_order: 0,
+            hidden: false,
             created_at: now,
             updated_at: now,
         })
@@ -188,7 +508,13 @@ impl Tag {
 
     /// Rename a tag, and if the new tag already exists, merge the tags.
     /// Handles all descendant tags recursively with optimal performance.
-    pub async fn rename_or_merge(pool: &SqlitePool, name: &str, new_name: &str) -> ApiResult<()> {
+    #[instrument(skip(db))]
+    pub async fn rename_or_merge(
+        db: &DB,
+        user_id: i64,
+        name: &str,
+        new_name: &str,
+    ) -> ApiResult<()> {
         if name == new_name {
             return Ok(());
         }
@@ -208,22 +534,23 @@ impl Tag {
             Tag,
             r#"
             SELECT * FROM tags
-            WHERE name = ? OR name = ? OR name LIKE ?
+            WHERE user_id = ? AND (name = ? OR name = ? OR name LIKE ?)
             "#,
+            user_id,
             name,
             new_name,
             name_pattern
         )
-        .fetch_all(pool)
+        .fetch_all(&db.writer)
         .await?;
 
-        let mut tx = pool.begin().await?;
+        let mut tx = db.writer.begin().await?;
 
         // Split into source tag, target tag and descendants
         let source_tag = if let Some(tag) = affected_tags.iter().find(|t| t.name == name) {
             tag
         } else {
-            let new_tag = Tag::create(&mut tx, name).await?;
+            let new_tag = Tag::create(&mut tx, user_id, name).await?;
             affected_tags.push(new_tag);
             affected_tags.last().unwrap()
         };
@@ -238,7 +565,8 @@ impl Tag {
 
         for descendant in descendants {
             let new_descendant_name = replace_from_start(&descendant.name, name, new_name);
-            let target_descendant = Tag::find_by_name(&mut tx, &new_descendant_name).await?;
+            let target_descendant =
+                Tag::find_by_name(&mut tx, user_id, &new_descendant_name).await?;
 
             if let Some(target_descendant) = target_descendant {
                 // Target exists - merge
@@ -256,6 +584,11 @@ impl Tag {
         }
 
         tx.commit().await?;
+        emit(AppEvent::TagRenamed {
+            user_id,
+            name: name.to_string(),
+            new_name: new_name.to_string(),
+        });
         Ok(())
     }
 
@@ -277,6 +610,8 @@ impl Tag {
         .execute(&mut **tx)
         .await?;
 
+        Tag::record_rename(tx, tag.user_id, &tag.name, new_name).await?;
+
         let source_pattern = format!(">#{}<", tag.name);
         let target_pattern = format!(">#{}<", new_name);
 
@@ -364,6 +699,8 @@ impl Tag {
         .execute(&mut **tx)
         .await?;
 
+        Tag::record_rename(tx, source_tag.user_id, &source_tag.name, &target_tag.name).await?;
+
         Ok(())
     }
 }
@@ -377,9 +714,35 @@ pub fn replace_from_start(s: &str, from: &str, to: &str) -> String {
     }
 }
 
+/// Removes the hash tags of the tag and its descendants from the content.
+fn remove_hash_tags(content: &str, name: &str) -> String {
+    let re = Regex::new(&format!(
+        r#"<span class="hash-tag">#{}(/[^<]*)?</span>"#,
+        regex::escape(name)
+    ))
+    .unwrap();
+    re.replace_all(content, "").into_owned()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::replace_from_start;
+    use super::{remove_hash_tags, replace_from_start};
+
+    #[test]
+    fn test_remove_hash_tags() {
+        let content = concat!(
+            r#"<p>a <span class="hash-tag">#work</span> b "#,
+            r#"<span class="hash-tag">#work/design</span> "#,
+            r#"<span class="hash-tag">#workout</span> <span class="hash-tag">#a.b</span></p>"#
+        );
+        assert_eq!(
+            remove_hash_tags(content, "work"),
+            r#"<p>a  b  <span class="hash-tag">#workout</span> <span class="hash-tag">#a.b</span></p>"#
+        );
+        assert!(!remove_hash_tags(content, "a.b").contains("#a.b"));
+        assert!(remove_hash_tags(content, "a.b").contains("#workout"));
+    }
+
     #[test]
     fn test_replace_from_start() {
         assert_eq!(
@@ -1,33 +1,425 @@
+use crate::config::{JobConfig, JobsConfig};
+use crate::model::admin::{JobInfo, JobRun};
+use crate::model::file::StoredFile;
+use crate::model::post::{FileInfo, Post};
+use crate::service::event_service::{emit, AppEvent};
+use crate::service::search_service::index_post;
+use crate::service::upload_service::{thumb_key, FileUploadService};
+use crate::service::{demo_service, link_service, notification_service, orphan_service};
 use crate::AppState;
+use anyhow::Result;
 use chrono::{Duration, Local, Utc};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::{fs, time};
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::info;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
-pub async fn start_jobs(state: AppState) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let clear_deleted_posts = Job::new_async_tz("0 0 3 * * *", Local, move |_uuid, _l| {
-        let db = state.db.pool.clone();
+/// Posts in the trash are deleted permanently after this many days
+const TRASH_DAYS: i64 = 30;
+
+/// Files are collected only if they were uploaded before this many hours,
+/// so that those of a post being edited are not deleted.
+const FILE_GRACE_HOURS: i64 = 24;
+
+/// How long the lock of a running job lasts if it is not renewed, e.g. when the instance crashed
+const LOCK_TTL_MS: u64 = 30_000;
+
+/// A background job, which runs on the schedule in [`JobsConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    PurgeTrash,
+    CollectFiles,
+    Backup,
+    ReconcileIndex,
+    CheckLinks,
+    PruneUploads,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 6] = [
+        JobKind::PurgeTrash,
+        JobKind::CollectFiles,
+        JobKind::Backup,
+        JobKind::ReconcileIndex,
+        JobKind::CheckLinks,
+        JobKind::PruneUploads,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JobKind::PurgeTrash => "purge-trash",
+            JobKind::CollectFiles => "collect-files",
+            JobKind::Backup => "backup",
+            JobKind::ReconcileIndex => "reconcile-index",
+            JobKind::CheckLinks => "check-links",
+            JobKind::PruneUploads => "prune-uploads",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        JobKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn config(self, config: &JobsConfig) -> &JobConfig {
+        match self {
+            JobKind::PurgeTrash => &config.purge_trash,
+            JobKind::CollectFiles => &config.collect_files,
+            JobKind::Backup => &config.backup,
+            JobKind::ReconcileIndex => &config.reconcile_index,
+            JobKind::CheckLinks => &config.check_links,
+            JobKind::PruneUploads => &config.prune_uploads,
+        }
+    }
+
+    /// Runs the job and returns a summary of what it did.
+    async fn run(self, state: &AppState) -> Result<String> {
+        match self {
+            JobKind::PurgeTrash => purge_trash(state).await,
+            JobKind::CollectFiles => collect_files(state).await,
+            JobKind::Backup => backup(state).await,
+            JobKind::ReconcileIndex => reconcile_index(state).await,
+            JobKind::CheckLinks => check_links(state).await,
+            JobKind::PruneUploads => prune_uploads(state).await,
+        }
+    }
+}
+
+/// Keeps the outcome of the last run of each job, and which jobs are running.
+#[derive(Default)]
+pub struct JobRegistry {
+    runs: Mutex<HashMap<&'static str, JobRun>>,
+    running: Mutex<HashSet<&'static str>>,
+}
+
+/// Marks a job as running until it is dropped.
+struct RunningGuard<'a> {
+    registry: &'a JobRegistry,
+    kind: JobKind,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .running
+            .lock()
+            .unwrap()
+            .remove(self.kind.name());
+    }
+}
 
-        Box::pin(async move {
-            info!("[Daily] Checking the posts to be deleted...");
+impl JobRegistry {
+    /// Lists all jobs with their schedules and last runs.
+    pub fn jobs(&self, config: &JobsConfig) -> Vec<JobInfo> {
+        let runs = self.runs.lock().unwrap();
+        let running = self.running.lock().unwrap();
 
-            let thirty_days_ago = (Utc::now() - Duration::days(30)).timestamp_millis();
-            let rv = sqlx::query!("DELETE FROM posts WHERE deleted_at < $1", thirty_days_ago,)
-                .execute(&db)
-                .await
-                .ok();
+        JobKind::ALL
+            .iter()
+            .map(|kind| {
+                let job = kind.config(config);
+                JobInfo {
+                    name: kind.name(),
+                    cron: job.cron.clone(),
+                    enabled: job.enabled,
+                    running: running.contains(kind.name()),
+                    last_run: runs.get(kind.name()).cloned(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns `None` if the job is already running.
+    fn try_start(&self, kind: JobKind) -> Option<RunningGuard<'_>> {
+        if !self.running.lock().unwrap().insert(kind.name()) {
+            return None;
+        }
+        Some(RunningGuard {
+            registry: self,
+            kind,
+        })
+    }
+
+    fn record(&self, kind: JobKind, run: JobRun) {
+        self.runs.lock().unwrap().insert(kind.name(), run);
+    }
+}
 
-            if let Some(rv) = rv {
-                if rv.rows_affected() > 0 {
-                    info!("[Daily] Successfully deleted {} posts", rv.rows_affected());
+/// Runs a job and records its outcome in the registry.
+/// Returns `None` without running it if the job is already running,
+/// on this instance or on another one sharing the same Redis.
+pub async fn run_job(state: &AppState, kind: JobKind) -> Option<JobRun> {
+    let Some(_guard) = state.jobs.try_start(kind) else {
+        info!("[Job] {} is already running, skipped", kind.name());
+        return None;
+    };
+
+    let started_at = Utc::now().timestamp_millis();
+    let rv = match run_locked(state, kind).await {
+        Ok(None) => {
+            info!(
+                "[Job] {} is running on another instance, skipped",
+                kind.name()
+            );
+            return None;
+        }
+        Ok(Some(rv)) => rv,
+        Err(e) => Err(e.context("cannot lock the job")),
+    };
+
+    let run = JobRun {
+        started_at,
+        finished_at: Utc::now().timestamp_millis(),
+        ok: rv.is_ok(),
+        summary: match rv {
+            Ok(summary) => {
+                info!("[Job] {}: {}", kind.name(), summary);
+                summary
+            }
+            Err(e) => {
+                error!("[Job] {} failed: {:?}", kind.name(), e);
+                let message = format!("{} failed: {:#}", kind.name(), e);
+                notification_service::notify(state, "job.failed", &message).await;
+                e.to_string()
+            }
+        },
+    };
+
+    state.jobs.record(kind, run.clone());
+    Some(run)
+}
+
+/// Runs a job while holding its lock in Redis, which is renewed until the job is done,
+/// so that a long job is not run again by another instance after the lock expires.
+/// Returns `None` if the lock is held by another instance.
+async fn run_locked(state: &AppState, kind: JobKind) -> Result<Option<Result<String>>> {
+    let key = format!("job-lock:{}", kind.name());
+    let token = Uuid::new_v4().to_string();
+    if !state.rd.try_lock(&key, &token, LOCK_TTL_MS).await? {
+        return Ok(None);
+    }
+
+    let renewal = {
+        let (rd, key, token) = (state.rd.clone(), key.clone(), token.clone());
+        tokio::spawn(async move {
+            let mut interval = time::interval(std::time::Duration::from_millis(LOCK_TTL_MS / 3));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match rd.extend_lock(&key, &token, LOCK_TTL_MS).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("[Job] Lost the lock {}", key);
+                        break;
+                    }
+                    Err(e) => warn!("[Job] Cannot renew the lock {}: {:?}", key, e),
                 }
             }
         })
-    })?;
+    };
 
+    info!("[Job] Running {}...", kind.name());
+    let rv = kind.run(state).await;
+
+    renewal.abort();
+    if let Err(e) = state.rd.unlock(&key, &token).await {
+        warn!("[Job] Cannot release the lock {}: {:?}", key, e);
+    }
+    Ok(Some(rv))
+}
+
+pub async fn start_jobs(state: AppState) -> Result<(), Box<dyn Error + Send + Sync>> {
     let sched = JobScheduler::new().await?;
-    sched.add(clear_deleted_posts).await?;
+
+    for kind in JobKind::ALL {
+        let config = kind.config(&state.config.jobs);
+        if !config.enabled {
+            continue;
+        }
+
+        let job_state = state.clone();
+        let job = Job::new_async_tz(config.cron.as_str(), Local, move |_uuid, _l| {
+            let state = job_state.clone();
+
+            Box::pin(async move {
+                run_job(&state, kind).await;
+            })
+        })?;
+        sched.add(job).await?;
+    }
+
+    let demo = &state.config.demo;
+    if demo.enabled {
+        let interval = std::time::Duration::from_secs(demo.reset_hours * 3600);
+        let demo_state = state.clone();
+        let reset_demo = Job::new_repeated_async(interval, move |_uuid, _l| {
+            let state = demo_state.clone();
+
+            Box::pin(async move {
+                info!("[Demo] Resetting the demo data...");
+                if let Err(e) = demo_service::reset(&state).await {
+                    error!("[Demo] Failed to reset the demo data: {:?}", e);
+                }
+            })
+        })?;
+        sched.add(reset_demo).await?;
+    }
+
     sched.start().await?;
 
     Ok(())
 }
+
+/// Deletes the posts which have been in the trash for long, or are past their purge time.
+async fn purge_trash(state: &AppState) -> Result<String> {
+    let now = Utc::now();
+    let before = (now - Duration::days(TRASH_DAYS)).timestamp_millis();
+    let now = now.timestamp_millis();
+    let purged = sqlx::query!(
+        r#"
+        DELETE FROM posts
+        WHERE deleted_at < $1 OR (deleted_at IS NOT NULL AND purge_after <= $2)
+        RETURNING id, user_id
+        "#,
+        before,
+        now
+    )
+    .fetch_all(&state.db.writer)
+    .await?;
+
+    // They are removed from the search index right away
+    for post in purged.iter() {
+        emit(AppEvent::PostCleared {
+            id: post.id,
+            user_id: post.user_id,
+        });
+    }
+    Ok(format!("deleted {} posts", purged.len()))
+}
+
+/// Deletes the uploaded files, with their thumbnails, which are not attached to any post.
+async fn collect_files(state: &AppState) -> Result<String> {
+    let config = &state.config.upload;
+    let before = (Utc::now() - Duration::hours(FILE_GRACE_HOURS)).timestamp_millis();
+    let files = StoredFile::find_unattached(&state.db, before).await?;
+
+    let upload_service = FileUploadService::new(config.clone());
+    for file in files.iter() {
+        let info = FileInfo {
+            url: file.filename.clone(),
+            thumb_url: Some(thumb_key(&file.filename)),
+            ..Default::default()
+        };
+        upload_service.discard(&info).await?;
+        StoredFile::delete(&state.db, &file.id).await?;
+    }
+
+    let size: i64 = files.iter().map(|file| file.size).sum();
+    Ok(format!("deleted {} files of {} bytes", files.len(), size))
+}
+
+/// Copies the database into the backup directory, and removes the oldest copies.
+async fn backup(state: &AppState) -> Result<String> {
+    let config = &state.config.jobs;
+    fs::create_dir_all(&config.backup_path).await?;
+
+    let filename = format!("backup-{}.db", Local::now().format("%Y%m%d-%H%M%S"));
+    let path = Path::new(&config.backup_path).join(&filename);
+    state.db.backup(&path.to_string_lossy()).await?;
+
+    let mut backups = Vec::new();
+    let mut entries = fs::read_dir(&config.backup_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("backup-") && name.ends_with(".db") {
+            backups.push(entry.path());
+        }
+    }
+    // The names sort by time, the latest last
+    backups.sort();
+
+    let removed = backups.len().saturating_sub(config.backup_keep);
+    for path in &backups[..removed] {
+        fs::remove_file(path).await?;
+    }
+
+    Ok(format!(
+        "saved {}, removed {} old backups",
+        filename, removed
+    ))
+}
+
+/// Indexes the undeleted posts which are missing from the search index,
+/// e.g. when indexing failed or Redis lost its data.
+async fn reconcile_index(state: &AppState) -> Result<String> {
+    let ids = sqlx::query_scalar!("SELECT id FROM posts WHERE deleted_at IS NULL")
+        .fetch_all(&state.db.pool)
+        .await?;
+
+    let mut count = 0;
+    for id in ids {
+        if state.fts.indexed(id).await? {
+            continue;
+        }
+        if let Some(post) = Post::find_by_id(&state.db, id).await? {
+            index_post(state, &post).await?;
+            count += 1;
+        }
+    }
+
+    Ok(format!("indexed {} posts", count))
+}
+
+/// Checks the external links of the shared posts, the results are listed by `/api/admin/link-health`.
+async fn check_links(state: &AppState) -> Result<String> {
+    let (links, broken) = link_service::check_shared_links(&state.db).await?;
+    Ok(format!(
+        "checked {} links, {} broken or redirected",
+        links, broken
+    ))
+}
+
+/// Deletes the files in the upload directory which no post uses, unlike `collect_files` it also finds
+/// those uploaded before they were recorded in the `files` table, and the thumbnails of deleted files.
+/// The chunks of abandoned resumable uploads are removed as well.
+async fn prune_uploads(state: &AppState) -> Result<String> {
+    let grace = Duration::hours(state.config.jobs.prune_grace_hours as i64);
+    let before = (Utc::now() - grace).timestamp_millis();
+    let upload_service = FileUploadService::new(state.config.upload.clone());
+    let files = orphan_service::prune_uploads(&state.db, &upload_service, before, false).await?;
+    // Resumable uploads which are not completed are abandoned after the same time
+    let chunked = upload_service
+        .clean_chunks((Utc::now() - grace).into())
+        .await?;
+
+    let size: u64 = files.iter().map(|file| file.size).sum();
+    Ok(format!(
+        "deleted {} files of {} bytes, {} unfinished uploads",
+        files.len(),
+        size,
+        chunked
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_runs_once_at_a_time() {
+        let registry = JobRegistry::default();
+        let kind = JobKind::from_name("backup").unwrap();
+
+        let guard = registry.try_start(kind);
+        assert!(guard.is_some());
+        assert!(registry.try_start(kind).is_none());
+        assert!(registry.try_start(JobKind::PurgeTrash).is_some());
+
+        drop(guard);
+        assert!(registry.try_start(kind).is_some());
+        assert!(JobKind::from_name("nope").is_none());
+    }
+}
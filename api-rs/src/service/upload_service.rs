@@ -1,173 +1,585 @@
+use crate::config::db::DB;
 use crate::config::UploadConfig;
 use crate::errors::{ApiError, ApiResult};
-use crate::model::post::FileInfo;
+use crate::model::file::{ChunkedUpload, InitUploadRequest, StoredFile, UploadSession};
+use crate::model::post::{FileInfo, ImageVariant};
+use crate::service::convert_service::{heic_converter_from_config, is_heic, ImageConverter};
+use crate::service::extract_service::extract_text_from_file;
+use crate::service::ocr_service::OcrEngine;
 use anyhow::{anyhow, Context, Result};
 use axum::extract::multipart::{Field, MultipartError};
-use exif::{In, Reader, Tag};
+use chrono::{FixedOffset, Local, NaiveDate, TimeZone};
+use exif::{DateTime as ExifDateTime, Exif, In, Reader, Tag, Value};
 use futures_util::TryStreamExt;
-use image::DynamicImage;
-use image::ImageReader;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat, ImageReader, ImageResult};
 use regex::Regex;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::io;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::BufWriter;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::task;
 use tokio_util::io::StreamReader;
-use tracing::error;
+use tracing::{error, instrument};
 use uuid::Uuid;
 
+/// The file describing a resumable upload, next to its chunks
+const CHUNKED_UPLOAD_META: &str = "upload.json";
+
+#[derive(Clone)]
 pub struct FileUploadService {
     config: UploadConfig,
+    heic_converter: Option<Arc<dyn ImageConverter>>,
 }
 
 impl FileUploadService {
     pub fn new(config: UploadConfig) -> Self {
-        Self { config }
+        let heic_converter = heic_converter_from_config(&config);
+        Self {
+            config,
+            heic_converter,
+        }
     }
 
+    #[instrument(skip_all, fields(filename = field.file_name()))]
     pub async fn stream_to_file(&self, field: Field<'_>) -> ApiResult<FileInfo> {
-        let file_name = field
+        let original_name = field
             .file_name()
-            .ok_or(ApiError::BadRequest("Invalid filename".into()))?;
+            .ok_or(ApiError::BadRequest("Invalid filename".into()))?
+            .to_owned();
         let content_type = field
             .content_type()
             .ok_or(ApiError::BadRequest("Invalid file type".into()))?
             .to_owned();
 
-        let file_name = generate_secure_filename(file_name, 8);
+        let file_name = generate_secure_filename(&original_name, 8);
         let upload_dir = self.config.base_path.clone();
         let file_path = Path::new(&upload_dir).join(file_name);
 
-        // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = field.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-        let body_reader = StreamReader::new(body_with_io_error);
-        futures::pin_mut!(body_reader);
+        // Hash the content while streaming it, it is used as the id of the file.
+        let mut hasher = Sha256::new();
 
-        let file = File::create(&file_path)
-            .await
-            .context("Cannot create file")?;
-        let mut buf_writer = BufWriter::new(file);
+        {
+            // Convert the stream into an `AsyncRead`.
+            #[allow(clippy::io_other_error)]
+            let body_with_io_error = field
+                .inspect_ok(|chunk| hasher.update(chunk))
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+            let body_reader = StreamReader::new(body_with_io_error);
+            futures::pin_mut!(body_reader);
+
+            let file = File::create(&file_path)
+                .await
+                .context("Cannot create file")?;
+            let mut buf_writer = BufWriter::new(file);
+
+            // Copy the body into the file.
+            tokio::io::copy(&mut body_reader, &mut buf_writer)
+                .await
+                .map_err(|err| {
+                    std::fs::remove_file(&file_path)
+                        .map_err(|e| error!("Cannot remove file: {}", e))
+                        .ok();
 
-        // Copy the body into the file.
-        tokio::io::copy(&mut body_reader, &mut buf_writer)
+                    if let Ok(err) = err.downcast::<MultipartError>() {
+                        ApiError::MultiPartError(err)
+                    } else {
+                        ApiError::Anyhow(anyhow!("cannot save file"))
+                    }
+                })?;
+        }
+
+        let id = format!("{:x}", hasher.finalize());
+        self.finish_file(file_path, original_name, content_type, id)
             .await
-            .map_err(|err| {
-                std::fs::remove_file(&file_path)
-                    .map_err(|e| error!("Cannot remove file: {}", e))
-                    .ok();
-
-                if let Ok(err) = err.downcast::<MultipartError>() {
-                    ApiError::MultiPartError(err)
-                } else {
-                    ApiError::Anyhow(anyhow!("cannot save file"))
+    }
+
+    /// Converts a received file if it is a HEIC photo, and reads its metadata.
+    async fn finish_file(
+        &self,
+        mut file_path: PathBuf,
+        original_name: String,
+        mut content_type: String,
+        id: String,
+    ) -> ApiResult<FileInfo> {
+        if let Some(ref converter) = self.heic_converter {
+            if is_heic(&content_type, &original_name) {
+                let format = &self.config.heic_format;
+                let converted =
+                    file_path.with_extension(if format == "webp" { "webp" } else { "jpg" });
+
+                match converter.convert(&file_path, &converted).await {
+                    Ok(()) => {
+                        fs::remove_file(&file_path).await.ok();
+                        file_path = converted;
+                        content_type = format!("image/{}", format);
+                    }
+                    // Keep the original file, it can still be downloaded
+                    Err(e) => error!("Cannot convert heic image {:?}: {:?}", file_path, e),
                 }
-            })?;
+            }
+        }
 
-        if self.is_image(&content_type) {
-            self.process_image_file(&file_path, &content_type)
-                .await
-                .map_err(ApiError::from)
+        let info = if self.is_image(&content_type) {
+            match self.process_image_file(&file_path).await {
+                Ok(info) => info,
+                Err(e) => {
+                    fs::remove_file(&file_path).await.ok();
+                    return Err(e.into());
+                }
+            }
         } else {
             self.process_regular_file(&file_path)
                 .await
-                .map_err(ApiError::from)
+                .map_err(ApiError::from)?
+        };
+
+        Ok(FileInfo {
+            // A rotated image is hashed again
+            id: info.id.or(Some(id)),
+            original_name: Some(original_name),
+            content_type: Some(content_type),
+            ..info
+        })
+    }
+
+    /// Starts a resumable upload, whose id is derived from the user and the checksum,
+    /// so that starting it again resumes it, with the chunks already received.
+    pub async fn init_chunked(
+        &self,
+        user_id: i64,
+        req: &InitUploadRequest,
+    ) -> ApiResult<UploadSession> {
+        if req.size > self.config.max_file_size {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "file is too large: {} bytes at most",
+                self.config.max_file_size
+            )));
         }
+
+        let checksum = req.checksum.to_lowercase();
+        let key = format!("{}:{}:{}", user_id, checksum, req.size);
+        let id: String = format!("{:x}", Sha256::digest(key))
+            .chars()
+            .take(32)
+            .collect();
+        let dir = self.chunk_dir(&id).unwrap_or_default();
+
+        let upload = match self.find_chunked(&id).await? {
+            Some(upload) => upload,
+            None => {
+                let upload = ChunkedUpload {
+                    user_id,
+                    filename: req.filename.clone(),
+                    content_type: req.content_type.clone(),
+                    size: req.size,
+                    checksum,
+                    chunk_size: self.config.chunk_size,
+                };
+                fs::create_dir_all(&dir)
+                    .await
+                    .context("Cannot create chunk directory")?;
+                let json = serde_json::to_vec(&upload).map_err(anyhow::Error::from)?;
+                fs::write(dir.join(CHUNKED_UPLOAD_META), json)
+                    .await
+                    .context("Cannot save upload")?;
+                upload
+            }
+        };
+        self.chunked_session(id, &upload).await
     }
 
-    async fn process_regular_file(&self, filepath: &Path) -> Result<FileInfo> {
-        let metadata = fs::metadata(filepath).await?;
+    /// Saves a chunk of a resumable upload, a chunk sent again replaces the previous one.
+    pub async fn save_chunk(
+        &self,
+        user_id: i64,
+        id: &str,
+        index: u64,
+        bytes: &[u8],
+    ) -> ApiResult<UploadSession> {
+        let upload = self.find_user_chunked(user_id, id).await?;
+        if index >= upload.chunks() {
+            return Err(ApiError::BadRequest("Invalid chunk index".into()));
+        }
+        if bytes.len() as u64 != upload.chunk_len(index) {
+            return Err(ApiError::BadRequest(format!(
+                "Chunk {} must be {} bytes",
+                index,
+                upload.chunk_len(index)
+            )));
+        }
+
+        // Written aside and renamed, so that a chunk cut short is not taken as received
+        let dir = self.chunk_dir(id).unwrap_or_default();
+        let partial = dir.join(format!("{}.part", index));
+        fs::write(&partial, bytes)
+            .await
+            .context("Cannot save chunk")?;
+        fs::rename(&partial, dir.join(index.to_string()))
+            .await
+            .context("Cannot save chunk")?;
+
+        self.chunked_session(id.to_string(), &upload).await
+    }
+
+    /// Assembles the chunks of a resumable upload into a file of the upload directory,
+    /// which is rejected if its hash is not the checksum given when the upload started.
+    pub async fn complete_chunked(&self, user_id: i64, id: &str) -> ApiResult<FileInfo> {
+        let upload = self.find_user_chunked(user_id, id).await?;
+        let session = self.chunked_session(id.to_string(), &upload).await?;
+        if session.received.len() as u64 != session.chunks {
+            return Err(ApiError::BadRequest(format!(
+                "{} of {} chunks are received",
+                session.received.len(),
+                session.chunks
+            )));
+        }
+
+        let dir = self.chunk_dir(id).unwrap_or_default();
+        let file_path = self.file_path(&generate_secure_filename(&upload.filename, 8));
+        let mut hasher = Sha256::new();
+        {
+            let file = File::create(&file_path)
+                .await
+                .context("Cannot create file")?;
+            let mut writer = BufWriter::new(file);
+            for index in 0..session.chunks {
+                let bytes = fs::read(dir.join(index.to_string()))
+                    .await
+                    .context("Cannot read chunk")?;
+                hasher.update(&bytes);
+                writer.write_all(&bytes).await.context("Cannot save file")?;
+            }
+            writer.flush().await.context("Cannot save file")?;
+        }
+
+        // A corrupted chunk cannot be told apart from the others, so the upload starts over
+        fs::remove_dir_all(&dir).await.ok();
+        let hash = format!("{:x}", hasher.finalize());
+        if hash != upload.checksum {
+            fs::remove_file(&file_path).await.ok();
+            return Err(ApiError::BadRequest(
+                "The checksum does not match, upload the file again".into(),
+            ));
+        }
+
+        let content_type = upload
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        self.finish_file(file_path, upload.filename, content_type, hash)
+            .await
+    }
+
+    /// Removes the resumable uploads which have not received a chunk since `before`.
+    pub async fn clean_chunks(&self, before: SystemTime) -> Result<usize> {
+        let mut entries = match fs::read_dir(&self.config.chunk_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).context("Cannot read the chunk directory"),
+        };
+
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() && metadata.modified()? < before {
+                fs::remove_dir_all(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn find_chunked(&self, id: &str) -> ApiResult<Option<ChunkedUpload>> {
+        let Some(dir) = self.chunk_dir(id) else {
+            return Ok(None);
+        };
+        match fs::read(dir.join(CHUNKED_UPLOAD_META)).await {
+            Ok(json) => Ok(Some(
+                serde_json::from_slice(&json).map_err(anyhow::Error::from)?,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::Error::from(e).into()),
+        }
+    }
+
+    async fn find_user_chunked(&self, user_id: i64, id: &str) -> ApiResult<ChunkedUpload> {
+        self.find_chunked(id)
+            .await?
+            .filter(|upload| upload.user_id == user_id)
+            .ok_or_else(|| ApiError::NotFound("Upload not found".into()))
+    }
+
+    async fn chunked_session(
+        &self,
+        id: String,
+        upload: &ChunkedUpload,
+    ) -> ApiResult<UploadSession> {
+        let dir = self.chunk_dir(&id).unwrap_or_default();
+        let mut received = vec![];
+        // Listed rather than probed chunk by chunk, the directory holds at most the chunks sent
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .context("Cannot read chunk directory")?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Cannot read chunk directory")?
+        {
+            let index = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(index) = index.filter(|&index| index < upload.chunks()) {
+                received.push(index);
+            }
+        }
+        received.sort_unstable();
+
+        Ok(UploadSession {
+            id,
+            chunk_size: upload.chunk_size,
+            chunks: upload.chunks(),
+            received,
+        })
+    }
+
+    /// The directory of the chunks of an upload, `None` if the id is not one given by `init_chunked`.
+    fn chunk_dir(&self, id: &str) -> Option<PathBuf> {
+        (id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+            .then(|| Path::new(&self.config.chunk_path).join(id))
+    }
+
+    /// Saves an image given as bytes, such as one pasted into the content of a post as a data uri.
+    pub async fn save_image(&self, bytes: &[u8], content_type: &str) -> Result<FileInfo> {
+        if !self.is_image(content_type) {
+            return Err(anyhow!("Unsupported image type: {}", content_type));
+        }
+        let original_name = format!(
+            "image.{}",
+            content_type.strip_prefix("image/").unwrap_or_default()
+        );
+        let file_path = self.file_path(&generate_secure_filename(&original_name, 8));
+        fs::write(&file_path, bytes)
+            .await
+            .context("Cannot save image")?;
+
+        let info = match self.process_image_file(&file_path).await {
+            Ok(info) => info,
+            Err(e) => {
+                fs::remove_file(&file_path).await.ok();
+                return Err(e);
+            }
+        };
         Ok(FileInfo {
-            url: format!("{}/{}", self.config.base_url, Self::get_filename(filepath)),
-            size: Some(metadata.len()),
-            thumb_url: None,
-            width: None,
-            height: None,
+            id: info
+                .id
+                .or_else(|| Some(format!("{:x}", Sha256::digest(bytes)))),
+            original_name: Some(original_name),
+            content_type: Some(content_type.to_string()),
+            ..info
         })
     }
 
-    async fn process_image_file(&self, filepath: &Path, content_type: &str) -> Result<FileInfo> {
-        // Read Image
-        let bytes = tokio::fs::read(filepath).await?;
+    /// Removes a file and its thumbnails from the upload directory.
+    #[instrument(skip_all, fields(url = %info.url))]
+    pub async fn discard(&self, info: &FileInfo) -> Result<()> {
+        let variants = info.variants.iter().map(|variant| &variant.url);
+        for url in std::iter::once(&info.url)
+            .chain(info.thumb_url.iter())
+            .chain(variants)
+        {
+            if let Some(filename) = self.filename_from_url(url) {
+                let path = Path::new(&self.config.base_path).join(filename);
+                fs::remove_file(path).await.ok();
+            }
+        }
+        Ok(())
+    }
 
-        let img = if Self::needs_exif_rotation(content_type) {
-            self.handle_exif_rotation(&bytes, filepath)?
-        } else {
-            ImageReader::new(Cursor::new(&bytes))
-                .with_guessed_format()?
-                .decode()?
+    /// Points the file info to a previously stored file with the same content.
+    pub fn reuse_stored(&self, info: FileInfo, filename: &str) -> FileInfo {
+        let variants = info
+            .variants
+            .iter()
+            .map(|variant| {
+                let ext = variant.url.rsplit_once('.').map_or("", |(_, ext)| ext);
+                ImageVariant {
+                    width: variant.width,
+                    url: variant_key(filename, variant.width, ext),
+                }
+            })
+            .collect();
+        FileInfo {
+            url: filename.to_string(),
+            thumb_url: info.thumb_url.as_ref().map(|_| thumb_key(filename)),
+            variants,
+            ..info
+        }
+    }
+
+    /// Returns the searchable text of an attachment, such as pdf text or the text recognized
+    /// in an image. The text is extracted once and cached in the `files` table.
+    /// The file is found by its url, and only read if it is an upload of the user,
+    /// the id in the info sent by the client is not trusted.
+    #[instrument(skip_all, fields(url = %file.url))]
+    pub async fn attachment_text(
+        &self,
+        db: &DB,
+        ocr: Option<&dyn OcrEngine>,
+        user_id: i64,
+        file: &FileInfo,
+    ) -> Option<String> {
+        let filename = self.filename_from_url(&file.url)?;
+
+        let stored = match StoredFile::find_owned(db, user_id, filename).await {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Cannot find file {}: {:?}", filename, e);
+                return None;
+            }
         };
+        if stored.text.is_some() {
+            return stored.text;
+        }
 
-        // Generate thumbnail
-        let thumb_path = self
-            .generate_thumbnail(filepath, &img)
-            .context("Cannot create thumbnail")?;
+        let path = self.file_path(&stored.filename);
+        let rv = match ocr {
+            Some(ocr) if file.thumb_url.is_some() => ocr.recognize(&path).await.map(Some),
+            _ => extract_text_from_file(&path).await,
+        };
 
-        let thumb_url = format!(
-            "{}/{}",
-            self.config.base_url,
-            Self::get_filename(&thumb_path)
-        );
+        let text = match rv {
+            Ok(Some(text)) => text,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Cannot extract text from {}: {:?}", filename, e);
+                return None;
+            }
+        };
 
-        let url = format!("{}/{}", self.config.base_url, Self::get_filename(filepath));
+        if let Err(e) = StoredFile::set_text(db, &stored.id, &text).await {
+            error!("Cannot cache text of file {}: {:?}", stored.id, e);
+        }
+        Some(text)
+    }
 
-        // Read filesize
-        let metadata = fs::metadata(filepath).await?;
+    /// Returns the path of an uploaded file in the upload directory.
+    pub fn file_path(&self, filename: &str) -> PathBuf {
+        PathBuf::from(&self.config.base_path).join(filename)
+    }
+
+    /// Extracts the stored filename from the url of an uploaded file, which is the storage key itself.
+    /// Urls with the upload prefix, stored by older versions, are accepted as well.
+    pub fn filename_from_url<'a>(&self, url: &'a str) -> Option<&'a str> {
+        url.strip_prefix(&self.config.base_url)
+            .map_or(Some(url), |rest| rest.strip_prefix('/'))
+            .filter(|name| !name.is_empty() && !name.contains(['/', ':']))
+    }
 
+    async fn process_regular_file(&self, filepath: &Path) -> Result<FileInfo> {
+        let metadata = fs::metadata(filepath).await?;
         Ok(FileInfo {
-            url,
-            thumb_url: Some(thumb_url),
+            url: Self::get_filename(filepath).into_owned(),
             size: Some(metadata.len()),
-            width: Some(img.width()),
-            height: Some(img.height()),
+            ..Default::default()
         })
     }
 
-    fn handle_exif_rotation(&self, bytes: &Vec<u8>, path: &Path) -> Result<DynamicImage> {
-        let mut cursor = Cursor::new(&bytes);
+    /// Checks that an image decodes and applies its exif rotation, so that the file is final before
+    /// its size and hash are recorded. The thumbnails are made later by `process_image`, their urls
+    /// are known in advance. The id is set only if the file is rewritten.
+    async fn process_image_file(&self, filepath: &Path) -> Result<FileInfo> {
+        let bytes = fs::read(filepath).await?;
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(&bytes))
+            .ok();
 
-        let exif = Reader::new().read_from_container(&mut cursor).ok();
+        let format = image::guess_format(&bytes)?;
+        let orientation = match format {
+            ImageFormat::Jpeg => exif_orientation(exif.as_ref()),
+            _ => None,
+        };
+        let path = filepath.to_path_buf();
+        let (width, height, rotated) =
+            task::spawn_blocking(move || decode_upright(&bytes, orientation, &path))
+                .await?
+                .context("Cannot decode image")?;
 
-        // Handle exif orientation
-        let orientation = exif.and_then(|exif| {
-            exif.get_field(Tag::Orientation, In::PRIMARY)
-                .and_then(|field| field.value.get_uint(0))
-        });
+        let filename = Self::get_filename(filepath).into_owned();
+        let metadata = fs::metadata(filepath).await?;
+        let gps = exif.as_ref().filter(|_| self.config.exif_location);
 
-        cursor.set_position(0);
+        Ok(FileInfo {
+            id: rotated.map(|bytes| format!("{:x}", Sha256::digest(bytes))),
+            thumb_url: Some(thumb_key(&filename)),
+            variants: self.variants(&filename, format, width),
+            url: filename,
+            size: Some(metadata.len()),
+            width: Some(width),
+            height: Some(height),
+            taken_at: exif.as_ref().and_then(exif_taken_at),
+            latitude: gps
+                .and_then(|exif| exif_gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef)),
+            longitude: gps.and_then(|exif| {
+                exif_gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef)
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// Generates the thumbnails of an uploaded image, which is already upright.
+    /// Decoding is CPU bound, so it runs on the blocking thread pool.
+    #[instrument(skip_all, fields(url = %info.url))]
+    pub async fn process_image(&self, info: &FileInfo) -> Result<()> {
+        let filename = self
+            .filename_from_url(&info.url)
+            .ok_or(anyhow!("Invalid file url: {}", info.url))?;
+        let filepath = self.file_path(filename);
+        let service = self.clone();
+
+        task::spawn_blocking(move || service.render_image(&filepath)).await?
+    }
 
-        let mut img = ImageReader::new(cursor).with_guessed_format()?.decode()?;
+    fn render_image(&self, filepath: &Path) -> Result<()> {
+        let bytes = std::fs::read(filepath)?;
+        let format = image::guess_format(&bytes)?;
 
-        img = match orientation {
-            Some(6) => img.rotate90(),
-            Some(3) => img.rotate180(),
-            Some(8) => img.rotate270(),
-            _ => img,
+        let animation = Self::decode_first_frame(&bytes)?;
+        let animated = animation.is_some();
+
+        let img = if let Some(first_frame) = animation {
+            first_frame
+        } else {
+            ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()?
+                .decode()?
         };
 
-        if orientation.is_some() {
-            img.save(path)?;
+        if animated && self.config.animated_thumb {
+            self.generate_animated_thumbnail(filepath, &bytes, format)
+        } else {
+            self.generate_thumbnail(filepath, &img)
         }
-        Ok(img)
-    }
+        .context("Cannot create thumbnail")?;
 
-    fn needs_exif_rotation(content_type: &str) -> bool {
-        let format = content_type
-            .strip_prefix("image/")
-            .unwrap_or("")
-            .to_string()
-            .to_lowercase();
-        match format.as_str() {
-            "jpeg" | "jpg" | "jif" => true,
-            "png" | "gif" | "webp" | "avif" | "svg" => false,
-            _ => false,
+        let filename = Self::get_filename(filepath);
+        for variant in self.variants(&filename, format, img.width()) {
+            self.generate_variant(&img, &variant, format)
+                .with_context(|| format!("Cannot create the {}px thumbnail", variant.width))?;
         }
+        Ok(())
     }
 
     fn generate_thumbnail(&self, original_path: &Path, img: &DynamicImage) -> Result<PathBuf> {
-        let thumb_filename = format!("thumb_{}", Self::get_filename(original_path));
+        let thumb_filename = thumb_key(&Self::get_filename(original_path));
         let thumb_path = PathBuf::from(&self.config.base_path).join(&thumb_filename);
 
         let thumbnail = img.thumbnail(self.config.thumb_width, self.config.thumb_width);
@@ -176,6 +588,122 @@ impl FileUploadService {
         Ok(thumb_path)
     }
 
+    /// The larger thumbnails of an image `width` pixels wide, only those narrower than the image are made.
+    fn variants(&self, filename: &str, format: ImageFormat, width: u32) -> Vec<ImageVariant> {
+        let ext = self.variant_format(format).extensions_str()[0];
+        let mut widths: Vec<u32> = self
+            .config
+            .variant_widths
+            .iter()
+            .copied()
+            .filter(|w| *w < width)
+            .collect();
+        widths.sort_unstable();
+        widths.dedup();
+
+        widths
+            .into_iter()
+            .map(|width| ImageVariant {
+                width,
+                url: variant_key(filename, width, ext),
+            })
+            .collect()
+    }
+
+    fn variant_format(&self, format: ImageFormat) -> ImageFormat {
+        match self.config.variant_format.as_str() {
+            "webp" => ImageFormat::WebP,
+            "avif" => ImageFormat::Avif,
+            _ => format,
+        }
+    }
+
+    fn generate_variant(
+        &self,
+        img: &DynamicImage,
+        variant: &ImageVariant,
+        format: ImageFormat,
+    ) -> Result<()> {
+        let path = self.file_path(&variant.url);
+        // As wide as stated, whatever the height, so that it fits the `w` descriptors of a srcset
+        let resized = img.thumbnail(variant.width, u32::MAX);
+
+        match self.variant_format(format) {
+            // Their encoders only take 8-bit images
+            format @ (ImageFormat::WebP | ImageFormat::Avif) => {
+                DynamicImage::ImageRgba8(resized.to_rgba8()).save_with_format(path, format)?
+            }
+            format => resized.save_with_format(path, format)?,
+        }
+        Ok(())
+    }
+
+    /// Generates an animated thumbnail of an animated gif or webp, keeping at most `thumb_max_frames` frames.
+    /// The thumbnail keeps the format of the image, as its key keeps the extension.
+    fn generate_animated_thumbnail(
+        &self,
+        original_path: &Path,
+        bytes: &[u8],
+        format: ImageFormat,
+    ) -> Result<PathBuf> {
+        let thumb_filename = thumb_key(&Self::get_filename(original_path));
+        let thumb_path = PathBuf::from(&self.config.base_path).join(&thumb_filename);
+        let width = self.config.thumb_width;
+
+        let frames = match format {
+            ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+            ImageFormat::WebP => WebPDecoder::new(Cursor::new(bytes))?.into_frames(),
+            format => return Err(anyhow!("{:?} images are not animated", format)),
+        };
+        let frames = frames
+            .take(self.config.thumb_max_frames as usize)
+            .map(|frame| {
+                frame.map(|frame| {
+                    let delay = frame.delay();
+                    let thumbnail = DynamicImage::ImageRgba8(frame.into_buffer())
+                        .thumbnail(width, width)
+                        .into_rgba8();
+                    Frame::from_parts(thumbnail, 0, 0, delay)
+                })
+            })
+            .collect::<ImageResult<Vec<_>>>()?;
+
+        if format == ImageFormat::WebP {
+            std::fs::write(&thumb_path, encode_animated_webp(&frames)?)?;
+        } else {
+            let file = std::fs::File::create(&thumb_path)?;
+            let mut encoder = GifEncoder::new(std::io::BufWriter::new(file));
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames)?;
+        }
+
+        Ok(thumb_path)
+    }
+
+    /// Decodes only the first frame of an animated gif or webp,
+    /// returns `None` if the image is not animated.
+    fn decode_first_frame(bytes: &[u8]) -> Result<Option<DynamicImage>> {
+        let frames = match image::guess_format(bytes)? {
+            ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+            ImageFormat::WebP => {
+                let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                decoder.into_frames()
+            }
+            _ => return Ok(None),
+        };
+
+        let mut frames = frames.take(2).collect::<ImageResult<Vec<_>>>()?;
+        if frames.len() < 2 {
+            return Ok(None);
+        }
+
+        let first_frame = frames.swap_remove(0).into_buffer();
+        Ok(Some(DynamicImage::ImageRgba8(first_frame)))
+    }
+
     fn is_image(&self, content_type: &str) -> bool {
         let format = content_type
             .strip_prefix("image/")
@@ -192,6 +720,140 @@ impl FileUploadService {
 
 // Helper functions
 
+/// Decodes an image to check it, and rotates it upright by its exif orientation.
+/// Returns its upright size, and the bytes of the file rewritten if it is rotated.
+fn decode_upright(
+    bytes: &[u8],
+    orientation: Option<u32>,
+    path: &Path,
+) -> Result<(u32, u32, Option<Vec<u8>>)> {
+    let img = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?;
+
+    let img = match orientation {
+        Some(6) => img.rotate90(),
+        Some(3) => img.rotate180(),
+        Some(8) => img.rotate270(),
+        _ => return Ok((img.width(), img.height(), None)),
+    };
+
+    // The exif is not written again, so the orientation is not applied twice
+    let mut rotated = Vec::new();
+    img.write_to(&mut Cursor::new(&mut rotated), ImageFormat::Jpeg)?;
+    std::fs::write(path, &rotated)?;
+    Ok((img.width(), img.height(), Some(rotated)))
+}
+
+/// Encodes the frames, all of the same size, as a looping animated webp.
+fn encode_animated_webp(frames: &[Frame]) -> Result<Vec<u8>> {
+    let (width, height) = frames
+        .first()
+        .ok_or(anyhow!("No frames to encode"))?
+        .buffer()
+        .dimensions();
+    let config =
+        webp::WebPConfig::new().map_err(|_| anyhow!("Cannot configure the webp encoder"))?;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+    // The frames are placed by their timestamps in milliseconds
+    let mut timestamp = 0;
+    for frame in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.buffer().as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        timestamp += (numer / denom.max(1)) as i32;
+    }
+
+    let data = encoder
+        .try_encode()
+        .map_err(|err| anyhow!("Cannot encode the animated webp: {:?}", err))?;
+    Ok(data.to_vec())
+}
+
+/// The storage key of the thumbnail of a file
+pub fn thumb_key(filename: &str) -> String {
+    format!("thumb_{}", filename)
+}
+
+/// The storage key of a larger thumbnail of a file, e.g. `thumb512_a.jpg.webp`,
+/// the extension is the one of its format, which may differ from the file.
+pub fn variant_key(filename: &str, width: u32, ext: &str) -> String {
+    format!("thumb{}_{}.{}", width, filename, ext)
+}
+
+/// The file a larger thumbnail is made of, `None` if the key is not one of a variant.
+pub fn variant_source(key: &str) -> Option<&str> {
+    let (width, rest) = key.strip_prefix("thumb")?.split_once('_')?;
+    if width.is_empty() || !width.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    rest.rsplit_once('.').map(|(source, _)| source)
+}
+
+fn exif_orientation(exif: Option<&Exif>) -> Option<u32> {
+    exif?
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+}
+
+/// Reads the time when a photo was taken, in milliseconds.
+/// The local timezone is assumed if the photo does not record its offset.
+fn exif_taken_at(exif: &Exif) -> Option<i64> {
+    let ascii = |tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+
+    let mut dt = ExifDateTime::from_ascii(&ascii(Tag::DateTimeOriginal)?).ok()?;
+    if let Some(offset) = ascii(Tag::OffsetTimeOriginal) {
+        dt.parse_offset(&offset).ok();
+    }
+
+    let naive = NaiveDate::from_ymd_opt(dt.year as i32, dt.month as u32, dt.day as u32)?
+        .and_hms_opt(dt.hour as u32, dt.minute as u32, dt.second as u32)?;
+
+    let datetime = match dt.offset {
+        Some(offset) => FixedOffset::east_opt(offset as i32 * 60)?
+            .from_local_datetime(&naive)
+            .single()?
+            .timestamp_millis(),
+        None => Local
+            .from_local_datetime(&naive)
+            .earliest()?
+            .timestamp_millis(),
+    };
+    Some(datetime)
+}
+
+/// Reads a GPS coordinate in decimal degrees, negative for south and west.
+fn exif_gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag) -> Option<f64> {
+    let degrees = match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Rational(dms)) if dms.len() == 3 => {
+            dms_to_degrees(dms[0].to_f64(), dms[1].to_f64(), dms[2].to_f64())
+        }
+        _ => return None,
+    };
+
+    let negative = match exif.get_field(ref_tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values
+            .first()
+            .is_some_and(|v| v.starts_with(b"S") || v.starts_with(b"W")),
+        _ => false,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+fn dms_to_degrees(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+    degrees + minutes / 60.0 + seconds / 3600.0
+}
+
 /// Generates a secure filename by sanitizing the input filename and appending a UUID.
 ///
 /// # Arguments
@@ -271,6 +933,257 @@ pub fn split_filename(filename: &str) -> (String, String) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{DBConfig, UploadConfig};
+    use crate::model::file::FileUpload;
+
+    #[test]
+    fn test_dms_to_degrees() {
+        assert_eq!(dms_to_degrees(30.0, 0.0, 0.0), 30.0);
+        assert!((dms_to_degrees(31.0, 13.0, 48.0) - 31.23).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_decode_first_frame() {
+        let frame = |value| {
+            let buffer = image::RgbaImage::from_pixel(4, 4, image::Rgba([value, 0, 0, 255]));
+            Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+        };
+
+        let mut animated = Vec::new();
+        GifEncoder::new(&mut animated)
+            .encode_frames(vec![frame(255), frame(0)])
+            .unwrap();
+        let first_frame = FileUploadService::decode_first_frame(&animated).unwrap();
+        assert_eq!(first_frame.unwrap().to_rgba8().get_pixel(0, 0)[0], 255);
+
+        let mut still = Vec::new();
+        GifEncoder::new(&mut still)
+            .encode_frames(vec![frame(255)])
+            .unwrap();
+        assert!(FileUploadService::decode_first_frame(&still)
+            .unwrap()
+            .is_none());
+
+        let webp = encode_animated_webp(&[frame(255), frame(0), frame(255)]).unwrap();
+        let frames = WebPDecoder::new(Cursor::new(&webp))
+            .unwrap()
+            .into_frames()
+            .collect::<ImageResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+        let first_frame = FileUploadService::decode_first_frame(&webp).unwrap();
+        assert_eq!(first_frame.unwrap().to_rgba8().get_pixel(0, 0)[0], 255);
+    }
+
+    #[tokio::test]
+    async fn test_variants() {
+        assert_eq!(variant_key("a.jpg", 512, "webp"), "thumb512_a.jpg.webp");
+        assert_eq!(variant_source("thumb512_a.jpg.webp"), Some("a.jpg"));
+        assert_eq!(variant_source("thumb_a.jpg"), None);
+        assert_eq!(variant_source("a.jpg"), None);
+
+        let mut config = UploadConfig::from_env();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-variants-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        config.variant_widths = vec![64, 16, 32];
+        config.variant_format = "webp".to_string();
+        fs::create_dir_all(&config.base_path).await.unwrap();
+        let service = FileUploadService::new(config.clone());
+
+        let path = service.file_path("a.png");
+        image::RgbImage::new(40, 20).save(&path).unwrap();
+        let info = service.process_image_file(&path).await.unwrap();
+        let urls: Vec<_> = info.variants.iter().map(|v| v.url.as_str()).collect();
+        assert_eq!(urls, ["thumb16_a.png.webp", "thumb32_a.png.webp"]);
+
+        service.process_image(&info).await.unwrap();
+        let variant = image::open(service.file_path("thumb32_a.png.webp")).unwrap();
+        assert_eq!((variant.width(), variant.height()), (32, 16));
+
+        // A portrait image is resized to the width of each variant as well
+        let path = service.file_path("c.png");
+        image::RgbImage::new(20, 40).save(&path).unwrap();
+        let portrait = service.process_image_file(&path).await.unwrap();
+        let urls: Vec<_> = portrait.variants.iter().map(|v| v.url.as_str()).collect();
+        assert_eq!(urls, ["thumb16_c.png.webp"]);
+        service.process_image(&portrait).await.unwrap();
+        let variant = image::open(service.file_path("thumb16_c.png.webp")).unwrap();
+        assert_eq!((variant.width(), variant.height()), (16, 32));
+
+        let reused = service.reuse_stored(info.clone(), "b.png");
+        assert_eq!(reused.variants[0].url, "thumb16_b.png.webp");
+
+        service.discard(&info).await.unwrap();
+        assert!(!service.file_path("thumb16_a.png.webp").exists());
+        fs::remove_dir_all(&config.base_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_image_file() {
+        let mut config = UploadConfig::from_env();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-images-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&config.base_path).await.unwrap();
+        let service = FileUploadService::new(config.clone());
+
+        // A corrupt image is refused, it would never get its thumbnail
+        let path = service.file_path("corrupt.png");
+        fs::write(&path, b"\x89PNG\r\n\x1a\nnot an image")
+            .await
+            .unwrap();
+        assert!(service.process_image_file(&path).await.is_err());
+
+        // A jpeg rotated by its exif orientation 6, whose APP1 segment follows the SOI marker
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::new(40, 20))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let app1: &[u8] = &[
+            0xFF, 0xE1, 0x00, 0x22, b'E', b'x', b'i', b'f', 0, 0, b'M', b'M', 0, 0x2A, 0, 0, 0,
+            0x08, 0, 0x01, 0x01, 0x12, 0, 0x03, 0, 0, 0, 0x01, 0, 0x06, 0, 0, 0, 0, 0, 0,
+        ];
+        jpeg.splice(2..2, app1.iter().copied());
+        let path = service.file_path("rotated.jpg");
+        fs::write(&path, &jpeg).await.unwrap();
+
+        let info = service.process_image_file(&path).await.unwrap();
+        assert_eq!((info.width, info.height), (Some(20), Some(40)));
+        let stored = fs::read(&path).await.unwrap();
+        assert_eq!(info.size, Some(stored.len() as u64));
+        assert_eq!(info.id, Some(format!("{:x}", Sha256::digest(&stored))));
+        let img = image::open(&path).unwrap();
+        assert_eq!((img.width(), img.height()), (20, 40));
+
+        fs::remove_dir_all(&config.base_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exif() {
+        // Taken at 2024-05-01 12:30:00 +08:00, at 31°14'24" N 121°28'12" W
+        let jpeg = include_bytes!("../tests/fixtures/gps.jpg");
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(jpeg))
+            .unwrap();
+        assert_eq!(exif_taken_at(&exif), Some(1714537800000));
+        let latitude = exif_gps_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef).unwrap();
+        assert!((latitude - 31.24).abs() < 1e-9);
+        let longitude =
+            exif_gps_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef).unwrap();
+        assert!((longitude + 121.47).abs() < 1e-9);
+
+        // The coordinates are recorded only if enabled
+        let mut config = UploadConfig::from_env();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-exif-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        config.exif_location = false;
+        fs::create_dir_all(&config.base_path).await.unwrap();
+        let service = FileUploadService::new(config.clone());
+        let path = service.file_path("gps.jpg");
+        fs::write(&path, jpeg).await.unwrap();
+
+        let info = service.process_image_file(&path).await.unwrap();
+        assert_eq!(info.taken_at, Some(1714537800000));
+        assert_eq!((info.latitude, info.longitude), (None, None));
+
+        config.exif_location = true;
+        let service = FileUploadService::new(config.clone());
+        let info = service.process_image_file(&path).await.unwrap();
+        assert!(info.latitude.is_some_and(|v| (v - 31.24).abs() < 1e-9));
+        assert!(info.longitude.is_some_and(|v| (v + 121.47).abs() < 1e-9));
+
+        fs::remove_dir_all(&config.base_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload() {
+        let dir = std::env::temp_dir().join(format!("mote-chunks-{}", Uuid::new_v4()));
+        let mut config = UploadConfig::from_env();
+        config.base_path = dir.join("uploads").to_string_lossy().into_owned();
+        config.chunk_path = dir.join("chunks").to_string_lossy().into_owned();
+        config.chunk_size = 4;
+        fs::create_dir_all(&config.base_path).await.unwrap();
+        let service = FileUploadService::new(config);
+
+        let content = b"0123456789";
+        let req = InitUploadRequest {
+            filename: "a.txt".to_string(),
+            size: content.len() as u64,
+            content_type: Some("text/plain".to_string()),
+            checksum: format!("{:x}", Sha256::digest(content)),
+        };
+        let session = service.init_chunked(1, &req).await.unwrap();
+        assert_eq!((session.chunks, session.chunk_size), (3, 4));
+        let id = session.id;
+
+        let too_large = InitUploadRequest {
+            size: u64::MAX,
+            ..req.clone()
+        };
+        assert!(matches!(
+            service.init_chunked(1, &too_large).await,
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+
+        // Chunks are sent in any order, and the upload is resumed by starting it again
+        service.save_chunk(1, &id, 2, b"89").await.unwrap();
+        service.save_chunk(1, &id, 0, b"0123").await.unwrap();
+        let session = service.init_chunked(1, &req).await.unwrap();
+        assert_eq!(
+            (session.id.as_str(), session.received),
+            (id.as_str(), vec![0, 2])
+        );
+
+        assert!(service.save_chunk(1, &id, 1, b"45").await.is_err());
+        assert!(service.save_chunk(1, &id, 3, b"").await.is_err());
+        assert!(service.save_chunk(2, &id, 1, b"4567").await.is_err());
+        assert!(service.complete_chunked(1, &id).await.is_err());
+
+        service.save_chunk(1, &id, 1, b"4567").await.unwrap();
+        let info = service.complete_chunked(1, &id).await.unwrap();
+        assert_eq!(info.id.as_deref(), Some(req.checksum.as_str()));
+        assert_eq!(info.size, Some(10));
+        assert_eq!(
+            fs::read(service.file_path(&info.url)).await.unwrap(),
+            content
+        );
+        assert!(service.complete_chunked(1, &id).await.is_err());
+
+        // A corrupted upload is discarded
+        let req = InitUploadRequest {
+            checksum: "0".repeat(64),
+            ..req
+        };
+        let id = service.init_chunked(1, &req).await.unwrap().id;
+        for (index, chunk) in content.chunks(4).enumerate() {
+            service
+                .save_chunk(1, &id, index as u64, chunk)
+                .await
+                .unwrap();
+        }
+        assert!(service.complete_chunked(1, &id).await.is_err());
+        assert!(service.find_chunked(&id).await.unwrap().is_none());
+
+        service.init_chunked(1, &req).await.unwrap();
+        assert_eq!(
+            service.clean_chunks(SystemTime::UNIX_EPOCH).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            service
+                .clean_chunks(SystemTime::now() + std::time::Duration::from_secs(1))
+                .await
+                .unwrap(),
+            1
+        );
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 
     #[test]
     fn test_split_filename() {
@@ -289,4 +1202,81 @@ mod tests {
             assert_eq!(split_filename(input), (name.to_string(), ext.to_string()));
         });
     }
+
+    #[tokio::test]
+    async fn test_attachment_text() {
+        let db = DB::new(&DBConfig {
+            url: "sqlite::memory:".to_string(),
+            pool_size: 1,
+            auto_migrate: true,
+            key: None,
+            busy_timeout_ms: 5000,
+            synchronous: "NORMAL".to_string(),
+            auto_checkpoint: true,
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let mut config = UploadConfig::from_env();
+        config.base_path = std::env::temp_dir()
+            .join(format!("mote-text-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::create_dir_all(&config.base_path).unwrap();
+        let service = FileUploadService::new(config.clone());
+
+        StoredFile::save(&db, 1, "secret", "secret.pdf", "secret.pdf", None, 1)
+            .await
+            .unwrap();
+        StoredFile::set_text(&db, "secret", "the secret")
+            .await
+            .unwrap();
+        std::fs::write(service.file_path("mine.md"), "my notes").unwrap();
+        StoredFile::save(&db, 2, "mine", "mine.md", "mine.md", None, 8)
+            .await
+            .unwrap();
+
+        let secret = FileInfo {
+            url: "secret.pdf".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            service.attachment_text(&db, None, 1, &secret).await,
+            Some("the secret".to_string())
+        );
+        assert_eq!(service.attachment_text(&db, None, 2, &secret).await, None);
+
+        // The id sent with a file of the user does not name the text of another file
+        let mine = FileInfo {
+            id: Some("secret".to_string()),
+            url: "mine.md".to_string(),
+            ..Default::default()
+        };
+        let text = service.attachment_text(&db, None, 2, &mine).await;
+        assert!(text.is_some_and(|text| text.contains("my notes")));
+        let secret = StoredFile::find_by_id(&db, "secret")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(secret.text.as_deref(), Some("the secret"));
+
+        // Uploading the same content makes it a file of the user too
+        FileUpload::create(&db, "secret", 2, "copy.pdf")
+            .await
+            .unwrap();
+        assert!(service
+            .attachment_text(
+                &db,
+                None,
+                2,
+                &FileInfo {
+                    url: "secret.pdf".to_string(),
+                    ..Default::default()
+                }
+            )
+            .await
+            .is_some());
+
+        std::fs::remove_dir_all(&config.base_path).ok();
+    }
 }
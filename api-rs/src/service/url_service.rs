@@ -0,0 +1,143 @@
+use crate::model::post::{FileInfo, ImageVariant, Post, PostRow};
+
+/// Turns the storage keys of uploaded files into urls, and the other way around.
+///
+/// Posts store the keys of their files, i.e. the names in the upload directory, rather than urls,
+/// so that moving an instance to another domain or url prefix does not break them.
+/// They are resolved when they are sent to clients, and the urls sent back are turned into keys.
+#[derive(Debug, Clone)]
+pub struct UrlResolver {
+    base_url: String,
+}
+
+impl UrlResolver {
+    /// `base_url` is the url prefix of the uploaded files, e.g. `/uploads` or `https://cdn.example.com`
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Returns the url of a key, other urls such as those of external files are kept.
+    pub fn resolve(&self, key: &str) -> String {
+        if is_key(key) {
+            format!("{}/{}", self.base_url, key)
+        } else {
+            key.to_string()
+        }
+    }
+
+    /// Returns the key of an uploaded file from its url, other urls are kept.
+    pub fn to_key<'a>(&self, url: &'a str) -> &'a str {
+        url.strip_prefix(&self.base_url)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .filter(|key| is_key(key))
+            .unwrap_or(url)
+    }
+
+    pub fn resolve_file(&self, file: FileInfo) -> FileInfo {
+        FileInfo {
+            url: self.resolve(&file.url),
+            thumb_url: file.thumb_url.as_deref().map(|url| self.resolve(url)),
+            variants: file
+                .variants
+                .into_iter()
+                .map(|variant| ImageVariant {
+                    url: self.resolve(&variant.url),
+                    ..variant
+                })
+                .collect(),
+            ..file
+        }
+    }
+
+    /// Turns the urls of a file sent by a client into keys, before it is stored.
+    pub fn to_stored(&self, file: FileInfo) -> FileInfo {
+        FileInfo {
+            url: self.to_key(&file.url).to_string(),
+            thumb_url: file
+                .thumb_url
+                .as_deref()
+                .map(|url| self.to_key(url).to_string()),
+            variants: file
+                .variants
+                .into_iter()
+                .map(|variant| ImageVariant {
+                    url: self.to_key(&variant.url).to_string(),
+                    ..variant
+                })
+                .collect(),
+            ..file
+        }
+    }
+
+    pub fn to_stored_files(&self, files: Vec<FileInfo>) -> Vec<FileInfo> {
+        files.into_iter().map(|file| self.to_stored(file)).collect()
+    }
+
+    /// Resolves the files of a post and of its parent.
+    pub fn resolve_post(&self, mut post: Post) -> Post {
+        self.resolve_row(&mut post.row);
+        post.parent = post
+            .parent
+            .map(|parent| Box::new(self.resolve_post(*parent)));
+        post
+    }
+
+    /// Resolves the files kept as JSON in the row, which is left as it is if it cannot be decoded.
+    pub fn resolve_row(&self, row: &mut PostRow) {
+        let Some(files) = row
+            .files
+            .as_deref()
+            .and_then(|files| serde_json::from_str::<Vec<FileInfo>>(files).ok())
+        else {
+            return;
+        };
+        let files: Vec<FileInfo> = files.into_iter().map(|f| self.resolve_file(f)).collect();
+        row.files = Some(serde_json::to_string(&files).unwrap());
+    }
+}
+
+/// A key is a bare filename, urls have a scheme or a path
+fn is_key(url: &str) -> bool {
+    !url.is_empty() && !url.contains(['/', ':'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let urls = UrlResolver::new("https://cdn.example.com/");
+        assert_eq!(urls.resolve("a.jpg"), "https://cdn.example.com/a.jpg");
+        assert_eq!(urls.resolve("/uploads/a.jpg"), "/uploads/a.jpg");
+        assert_eq!(urls.to_key("https://cdn.example.com/a.jpg"), "a.jpg");
+        assert_eq!(
+            urls.to_key("https://example.com/a.jpg"),
+            "https://example.com/a.jpg"
+        );
+        assert_eq!(
+            urls.to_key("https://cdn.example.com/x/a.jpg"),
+            "https://cdn.example.com/x/a.jpg"
+        );
+
+        let file = FileInfo {
+            url: "/uploads/a.jpg".to_string(),
+            thumb_url: Some("/uploads/thumb_a.jpg".to_string()),
+            ..Default::default()
+        };
+        let urls = UrlResolver::new("/uploads");
+        let stored = urls.to_stored(file);
+        assert_eq!(stored.url, "a.jpg");
+        assert_eq!(stored.thumb_url.as_deref(), Some("thumb_a.jpg"));
+
+        let resolved = urls.resolve_file(stored);
+        assert_eq!(resolved.url, "/uploads/a.jpg");
+        assert_eq!(resolved.thumb_url.as_deref(), Some("/uploads/thumb_a.jpg"));
+    }
+}
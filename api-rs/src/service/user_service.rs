@@ -0,0 +1,146 @@
+use crate::config::db::DB;
+use crate::errors::{bad_request, ApiError, ApiResult};
+use crate::model::user::{User, ADMIN_USER_ID};
+use crate::service::event_service::{emit, AppEvent};
+use chrono::Utc;
+use sqlx::{query, query_as, SqlitePool};
+
+impl User {
+    /// Adds a user with a hashed password, fails with a conflict if the name is taken.
+    pub async fn create(db: &DB, name: &str, password_hash: &str, admin: bool) -> ApiResult<User> {
+        if Self::find_by_name(&db.pool, name).await?.is_some() {
+            return Err(ApiError::Conflict(format!("User {} exists already", name)));
+        }
+
+        let now = Utc::now().timestamp_millis();
+        let user = query_as!(
+            User,
+            r#"
+            INSERT INTO users (name, password_hash, admin, created_at)
+            VALUES (?, ?, ?, ?)
+            RETURNING id, name, password_hash, admin, created_at
+            "#,
+            name,
+            password_hash,
+            admin,
+            now
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_id(pool: &SqlitePool, id: i64) -> ApiResult<Option<User>> {
+        let user = query_as!(
+            User,
+            r#"
+            SELECT id AS "id!", name, password_hash, admin, created_at
+            FROM users
+            WHERE id = ?
+            "#,
+            id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_name(pool: &SqlitePool, name: &str) -> ApiResult<Option<User>> {
+        let user = query_as!(
+            User,
+            r#"
+            SELECT id AS "id!", name, password_hash, admin, created_at
+            FROM users
+            WHERE name = ?
+            "#,
+            name
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_all(pool: &SqlitePool) -> ApiResult<Vec<User>> {
+        let users = query_as!(
+            User,
+            r#"
+            SELECT id AS "id!", name, password_hash, admin, created_at
+            FROM users
+            ORDER BY id
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// Creates the admin user with the configured name and password, or updates them,
+    /// so that the config stays the way to reset the password of the admin.
+    pub async fn ensure_admin(db: &DB, name: &str, password_hash: &str) -> ApiResult<User> {
+        let now = Utc::now().timestamp_millis();
+        let user = query_as!(
+            User,
+            r#"
+            INSERT INTO users (id, name, password_hash, admin, created_at)
+            VALUES (?, ?, ?, TRUE, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                name = excluded.name,
+                password_hash = excluded.password_hash,
+                admin = TRUE
+            RETURNING id, name, password_hash, admin, created_at
+            "#,
+            ADMIN_USER_ID,
+            name,
+            password_hash,
+            now
+        )
+        .fetch_one(&db.writer)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Removes a user with their posts, tags, sessions and passkeys, returns whether the user exists.
+    /// The admin user is configured on startup and cannot be removed.
+    pub async fn delete(db: &DB, id: i64) -> ApiResult<bool> {
+        if id == ADMIN_USER_ID {
+            return Err(bad_request("The admin user cannot be deleted"));
+        }
+
+        let mut tx = db.writer.begin().await?;
+        let deleted_ids = query!("DELETE FROM posts WHERE user_id = ? RETURNING id", id)
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        query!("DELETE FROM tags WHERE user_id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        query!("DELETE FROM tag_renames WHERE user_id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        query!("DELETE FROM sessions WHERE user_id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        query!("DELETE FROM passkeys WHERE user_id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        let result = query!("DELETE FROM users WHERE id = ?", id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        for post_id in deleted_ids {
+            emit(AppEvent::PostCleared {
+                id: post_id,
+                user_id: id,
+            });
+        }
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,32 @@
+use crate::config::rd::RD;
+use anyhow::Result;
+
+const RECENTLY_VIEWED_KEY: &str = "recently-viewed";
+
+/// How many posts are remembered, the older ones are dropped
+pub const MAX_RECENTLY_VIEWED: isize = 50;
+
+/// Each user has their own list
+fn recently_viewed_key(user_id: i64) -> String {
+    format!("{}:{}", RECENTLY_VIEWED_KEY, user_id)
+}
+
+/// Moves the post to the front of the recently viewed list of a user.
+pub async fn mark_viewed(rd: &RD, user_id: i64, post_id: i64) -> Result<()> {
+    let key = recently_viewed_key(user_id);
+    rd.pipeline(|pipe| {
+        pipe.lrem(&key, 0, post_id)
+            .ignore()
+            .lpush(&key, post_id)
+            .ignore()
+            .ltrim(&key, 0, MAX_RECENTLY_VIEWED - 1)
+            .ignore();
+    })
+    .await
+}
+
+/// Returns the ids of the posts recently viewed by a user, the latest first.
+pub async fn recently_viewed(rd: &RD, user_id: i64) -> Result<Vec<i64>> {
+    rd.lrange(&recently_viewed_key(user_id), 0, MAX_RECENTLY_VIEWED - 1)
+        .await
+}
@@ -1,2 +1,3 @@
 mod example;
+mod route;
 mod service;
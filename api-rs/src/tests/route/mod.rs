@@ -0,0 +1 @@
+mod route_coverage_test;
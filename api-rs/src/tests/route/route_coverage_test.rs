@@ -0,0 +1,331 @@
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Method, Request, StatusCode};
+    use axum::Router;
+    use jieba_rs::Jieba;
+    use mote::config::db::DB;
+    use mote::config::rd::RD;
+    use mote::config::AppConfig;
+    use mote::model::user::ADMIN_USER_ID;
+    use mote::service::auth_service::{hash_password, AuthService};
+    use mote::service::kv_service::{KvStore, MemoryStore};
+    use mote::service::realtime_service::RealtimeHub;
+    use mote::service::search_service::FullTextSearch;
+    use mote::service::task_service::JobRegistry;
+    use mote::service::url_service::UrlResolver;
+    use mote::util::retry::{CircuitBreaker, RetryPolicy};
+    use mote::{create_app, route_paths, AppState};
+    use regex::Regex;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    const ORIGIN: &str = "http://app.example.com";
+
+    // Routes that can be accessed without a token
+    const PUBLIC_ROUTES: &[&str] = &[
+        "/api/login",
+        "/api/start-passkey-login",
+        "/api/finish-passkey-login",
+        "/api/meta",
+        "/api/integrations/slack",
+        "/shared",
+        "/shared/{id}",
+        "/shared/{id}/embed",
+        "/shared/{id}/oembed.json",
+        "/shared/{id}/toggle-reaction",
+        "/uploads/dl/{id}",
+    ];
+
+    /// The routes served by the app, as listed by the route modules, so that a new route
+    /// is covered without being listed here. Each is paired with a path it matches.
+    async fn registered_routes(app: &Router, state: &AppState) -> Vec<(String, String)> {
+        let param = Regex::new(r"\{[^}]+}").unwrap();
+        let mut routes = vec![];
+        for route in route_paths(state) {
+            let path = param.replace_all(&route, "1").into_owned();
+            // No route takes PUT, the app answers 405 on a route and 404 elsewhere
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(&path)
+                .body(Body::empty())
+                .unwrap();
+            let status = send(app, request).await.status();
+            assert_ne!(status, StatusCode::NOT_FOUND, "{} is not served", route);
+            routes.push((route, path));
+        }
+        routes
+    }
+
+    async fn setup_app_with(configure: impl FnOnce(&mut AppConfig)) -> Router {
+        create_app(setup_state(configure).await).await
+    }
+
+    /// The app with the token of a session of the admin user
+    async fn setup_logged_in_app() -> (Router, String) {
+        let state = setup_state(|_| {}).await;
+        let (token, _) = state
+            .auth
+            .start_session(ADMIN_USER_ID, None, None)
+            .await
+            .unwrap();
+        (create_app(state).await, token)
+    }
+
+    async fn setup_state(configure: impl FnOnce(&mut AppConfig)) -> AppState {
+        let mut config = AppConfig::from_env_unchecked();
+        config.upload.base_path = std::env::temp_dir()
+            .join("mote-route-test")
+            .to_string_lossy()
+            .to_string();
+        config.http.cors.allowed_origins = vec![ORIGIN.to_string()];
+        config.log.log_requests = false;
+        config.db.url = "sqlite::memory:".to_string();
+        configure(&mut config);
+
+        // Redis is never called, its breaker is open: the caches fall back to the database,
+        // and the rate limits and the search index are kept in memory
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(3600));
+        breaker.record_failure();
+        let rd = Arc::new(
+            RD::new("redis://127.0.0.1/")
+                .await
+                .unwrap()
+                .with_resilience(RetryPolicy::NONE, breaker),
+        );
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let fts = Arc::new(FullTextSearch::new(
+            kv.clone(),
+            Arc::new(Jieba::new()),
+            "test:".to_string(),
+        ));
+
+        let db = Arc::new(DB::new(&config.db).await.unwrap());
+        db.migrate().await.unwrap();
+        let auth = Arc::new(AuthService::new(config.auth.clone(), db.clone()));
+        auth.ensure_admin().await.unwrap();
+        let urls = Arc::new(UrlResolver::new(&config.upload.base_url));
+
+        AppState {
+            config: Arc::new(config),
+            auth,
+            db,
+            rd,
+            kv,
+            fts,
+            ocr: None,
+            realtime: Arc::new(RealtimeHub::default()),
+            scanner: None,
+            notifier: None,
+            jobs: Arc::new(JobRegistry::default()),
+            urls,
+        }
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> axum::response::Response {
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Sends a JSON body with the token of a session, returns the status and the JSON response
+    async fn post_json(app: &Router, path: &str, token: &str, body: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = send(app, request).await;
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_routes_are_discovered() {
+        let state = setup_state(|_| {}).await;
+        let app = create_app(state.clone()).await;
+        let routes: Vec<String> = registered_routes(&app, &state)
+            .await
+            .into_iter()
+            .map(|(route, _)| route)
+            .collect();
+        for route in [
+            "/api/get-posts",
+            "/api/login",
+            "/api/ws",
+            "/api/start-passkey-login",
+            "/api/admin/status",
+            "/api/integrations/slack",
+            "/shared",
+            "/shared/{id}",
+            "/uploads/dl/{id}",
+        ] {
+            assert!(routes.contains(&route.to_string()), "{} is missing", route);
+        }
+
+        // The admin routes are not served in a demo
+        let state = setup_state(|config| config.demo.enabled = true).await;
+        let app = create_app(state.clone()).await;
+        let routes = registered_routes(&app, &state).await;
+        assert!(!routes.is_empty());
+        assert!(routes
+            .iter()
+            .all(|(route, _)| !route.starts_with("/api/admin")));
+    }
+
+    #[tokio::test]
+    async fn test_auth_is_enforced() {
+        let state = setup_state(|_| {}).await;
+        let app = create_app(state.clone()).await;
+
+        for (route, path) in registered_routes(&app, &state).await {
+            let mut statuses = vec![];
+            for method in [Method::GET, Method::POST] {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(&path)
+                    .body(Body::empty())
+                    .unwrap();
+                statuses.push(send(&app, request).await.status());
+            }
+
+            // A method that is not registered is rejected before authentication
+            let unauthorized = statuses.contains(&StatusCode::UNAUTHORIZED);
+            if PUBLIC_ROUTES.contains(&route.as_str()) {
+                assert!(!unauthorized, "{}: {:?}", route, statuses);
+            } else {
+                assert!(
+                    unauthorized
+                        && statuses.iter().all(|s| *s == StatusCode::UNAUTHORIZED
+                            || *s == StatusCode::METHOD_NOT_ALLOWED),
+                    "{}: {:?}",
+                    route,
+                    statuses
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_access() {
+        let admin_request = |token: Option<&str>| {
+            let mut request = Request::builder().uri("/api/admin/config-schema");
+            if let Some(token) = token {
+                request = request.header("X-Admin-Token", token);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let app = setup_app_with(|config| {
+            config.auth.admin_token_hash = hash_password("admin").unwrap();
+        })
+        .await;
+        let request = Request::builder()
+            .uri("/api/admin/config-schema")
+            .header(header::AUTHORIZATION, "Bearer foobar")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&app, admin_request(Some("foobar"))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&app, admin_request(Some("admin"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The address of a client is unknown without the connection info
+        let app = setup_app_with(|config| {
+            config.auth.admin_token_hash = hash_password("admin").unwrap();
+            config.auth.admin_allowed_ips = vec!["127.0.0.1".parse().unwrap()];
+        })
+        .await;
+        let response = send(&app, admin_request(Some("admin"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_hard_delete_post() {
+        let (app, token) = setup_logged_in_app().await;
+
+        let (status, body) = post_json(
+            &app,
+            "/api/create-post",
+            &token,
+            json!({ "content": "hello" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let id = body["id"].as_i64().unwrap();
+
+        // A post is moved to the trash before it is deleted permanently
+        let hard_delete = json!({ "id": id, "hard": true });
+        let (status, _) = post_json(&app, "/api/delete-post", &token, hard_delete.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = post_json(&app, "/api/delete-post", &token, json!({ "id": id })).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = post_json(&app, "/api/delete-post", &token, hard_delete.clone()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // It is gone
+        let (status, _) = post_json(&app, "/api/delete-post", &token, hard_delete).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let state = setup_state(|_| {}).await;
+        let app = create_app(state.clone()).await;
+
+        for (_, path) in registered_routes(&app, &state).await {
+            // Preflight requests are answered without a token
+            let request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri(&path)
+                .header(header::ORIGIN, ORIGIN)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+                .unwrap();
+            let response = send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK, "OPTIONS {}", path);
+            assert_eq!(
+                response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                ORIGIN,
+                "OPTIONS {}",
+                path
+            );
+
+            // Errors also carry the CORS headers, otherwise browsers hide them from clients
+            let request = Request::builder()
+                .uri(&path)
+                .header(header::ORIGIN, ORIGIN)
+                .body(Body::empty())
+                .unwrap();
+            let response = send(&app, request).await;
+            assert_eq!(
+                response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                ORIGIN,
+                "GET {}",
+                path
+            );
+        }
+
+        // Unknown origins are not allowed
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/get-posts")
+            .header(header::ORIGIN, "http://evil.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, request).await;
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+}
@@ -1,14 +1,15 @@
 #[cfg(test)]
 mod tests {
     use jieba_rs::Jieba;
-    use mote::config::rd::RD;
+    use mote::service::kv_service::MemoryStore;
     use mote::service::search_service::FullTextSearch;
     use std::sync::Arc;
+    use std::time::Duration;
 
     async fn setup_search() -> FullTextSearch {
-        let rd = Arc::new(RD::new("redis://127.0.0.1/").await.unwrap());
+        let kv = Arc::new(MemoryStore::new());
         let tokenizer = Arc::new(Jieba::new());
-        FullTextSearch::new(rd, tokenizer, "test:".to_string())
+        FullTextSearch::new(kv, tokenizer, "test:".to_string())
     }
 
     #[tokio::test]
@@ -22,14 +23,16 @@ mod tests {
         search.index(3, "world of rust programming").await.unwrap();
 
         // Simple search test
-        let (tokens, results) = search.search("hello", false, 10).await.unwrap();
-        assert_eq!(tokens, vec!["hello"]);
+        let rv = search.search("hello", false, 10).await.unwrap();
+        let results = rv.hits;
+        assert_eq!(rv.tokens, vec!["hello"]);
+        assert!(!rv.truncated);
         assert_eq!(results.len(), 2);
         assert!(results.iter().any(|(id, _)| *id == 1));
         assert!(results.iter().any(|(id, _)| *id == 2));
 
         // Verify ranking mechanism
-        let (_, results) = search.search("world", false, 10).await.unwrap();
+        let results = search.search("world", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 2);
         assert_eq!(results[0].0, 1); // "hello world" should rank first due to shorter document length
 
@@ -78,11 +81,11 @@ mod tests {
         assert_eq!(initial_count, updated_count, "文档计数在更新后应保持不变");
 
         // Check that old content is not searchable
-        let (_, results) = search.search("initial", false, 10).await.unwrap();
+        let results = search.search("initial", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 0, "旧内容不应该可被搜索到");
 
         // Check that new content is searchable
-        let (_, results) = search.search("updated", false, 10).await.unwrap();
+        let results = search.search("updated", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 1, "新内容应该可被搜索到");
 
         search.clear_all_indexes().await.unwrap();
@@ -99,17 +102,19 @@ mod tests {
         partial_search.index(3, "Go Language").await.unwrap();
 
         // Test partial match search
-        let (_, results) = partial_search
+        let results = partial_search
             .search("Rust Python", true, 10)
             .await
-            .unwrap();
+            .unwrap()
+            .hits;
         assert_eq!(results.len(), 2); // Should match documents containing "rust" or "python"
 
         // Test full phrase match
-        let (_, results) = partial_search
+        let results = partial_search
             .search("programming", true, 10)
             .await
-            .unwrap();
+            .unwrap()
+            .hits;
         assert_eq!(results.len(), 2); // Should match all documents containing "programming"
 
         partial_search.clear_all_indexes().await.unwrap();
@@ -139,7 +144,7 @@ mod tests {
         // Very long document
         let long_text = "rust ".repeat(1000);
         search.index(5, &long_text).await.unwrap();
-        let (_, results) = search.search("rust", false, 10).await.unwrap();
+        let results = search.search("rust", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 1);
 
         // HTML content
@@ -147,7 +152,11 @@ mod tests {
             .index(6, "<p>Hello World</p><div>Rust</div>")
             .await
             .unwrap();
-        let (_, results) = search.search("hello world rust", false, 10).await.unwrap();
+        let results = search
+            .search("hello world rust", false, 10)
+            .await
+            .unwrap()
+            .hits;
         assert_eq!(results.len(), 1);
 
         // Special characters
@@ -155,10 +164,11 @@ mod tests {
             .index(7, "rust#programming$language@test")
             .await
             .unwrap();
-        let (_, results) = search
+        let results = search
             .search("rust programming language test", false, 10)
             .await
-            .unwrap();
+            .unwrap()
+            .hits;
         assert_eq!(results.len(), 1);
 
         // Unicode characters
@@ -166,10 +176,11 @@ mod tests {
             .index(8, "rust😀programming🚀language")
             .await
             .unwrap();
-        let (_, results) = search
+        let results = search
             .search("rust programming language😀", false, 10)
             .await
-            .unwrap();
+            .unwrap()
+            .hits;
         assert_eq!(results.len(), 1);
 
         search.clear_all_indexes().await.unwrap();
@@ -185,7 +196,7 @@ mod tests {
         search.index(2, "python开发指南").await.unwrap();
 
         // Search with Chinese characters
-        let (_, results) = search.search("编程", false, 10).await.unwrap();
+        let results = search.search("编程", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].0, 1);
 
@@ -194,7 +205,7 @@ mod tests {
             .index(3, "学习 rust 和 python programming")
             .await
             .unwrap();
-        let (_, results) = search.search("rust python", false, 10).await.unwrap();
+        let results = search.search("rust python", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 1);
 
         // Chinese punctuation
@@ -202,7 +213,7 @@ mod tests {
             .index(4, "rust（编程）语言，开发。教程！")
             .await
             .unwrap();
-        let (_, results) = search.search("编程 语言", false, 10).await.unwrap();
+        let results = search.search("编程 语言", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 2);
 
         search.clear_all_indexes().await.unwrap();
@@ -226,10 +237,11 @@ mod tests {
         partial_search.index(4, "rust").await.unwrap();
         partial_search.index(5, "rust rust rust").await.unwrap(); // Test term frequency impact
 
-        let (_, results) = partial_search
+        let results = partial_search
             .search("rust programming", true, 10)
             .await
-            .unwrap();
+            .unwrap()
+            .hits;
 
         // Verify the ranking
         assert_eq!(results.len(), 5);
@@ -261,16 +273,15 @@ mod tests {
 
         // Test searching after clearing indexes
         search.clear_all_indexes().await.unwrap();
-        let (_, results) = search.search("test", false, 10).await.unwrap();
+        let results = search.search("test", false, 10).await.unwrap().hits;
         assert_eq!(results.len(), 0);
     }
 
     #[tokio::test]
     async fn test_max_results_limit() {
-        let rd = RD::new("redis://127.0.0.1/").await.unwrap();
+        let kv = Arc::new(MemoryStore::new());
         let tokenizer = Arc::new(Jieba::new());
-        let limited_search =
-            FullTextSearch::new(Arc::new(rd), tokenizer, "test_limited:".to_string());
+        let limited_search = FullTextSearch::new(kv, tokenizer, "test_limited:".to_string());
         limited_search.clear_all_indexes().await.unwrap();
 
         // Index 5 documents with the same relevance
@@ -279,15 +290,39 @@ mod tests {
         }
 
         // Verify result count limit
-        let (_, results) = limited_search.search("test", false, 3).await.unwrap();
+        let results = limited_search.search("test", false, 3).await.unwrap().hits;
         assert_eq!(results.len(), 3, "结果数量应该被限制在3个");
 
         // Index additional documents to create varying relevance
         limited_search.index(6, "test").await.unwrap();
         limited_search.index(7, "test test test").await.unwrap();
-        let (_, results) = limited_search.search("test", false, 3).await.unwrap();
+        let results = limited_search.search("test", false, 3).await.unwrap().hits;
         assert_eq!(results.len(), 3, "结果数量应该被限制在3个");
 
         limited_search.clear_all_indexes().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_max_candidates() {
+        let kv = Arc::new(MemoryStore::new());
+        let tokenizer = Arc::new(Jieba::new());
+        let search = FullTextSearch::new(kv, tokenizer, "test_candidates:".to_string())
+            .with_limits(2, Some(Duration::from_secs(5)));
+
+        search.index(1, "rust and python").await.unwrap();
+        for i in 2..=5 {
+            search.index(i, "rust").await.unwrap();
+        }
+
+        // Only the doc matching both terms and the newest one are ranked
+        let rv = search.search("rust python", true, 10).await.unwrap();
+        assert!(rv.truncated);
+        let mut ids: Vec<i64> = rv.hits.iter().map(|h| h.0).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 5]);
+
+        let rv = search.search("python", true, 10).await.unwrap();
+        assert!(!rv.truncated);
+        assert_eq!(rv.hits.len(), 1);
+    }
 }
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, bail, Result};
+
+/// A decoded CBOR item, only the types used by WebAuthn are supported:
+/// integers, byte and text strings, arrays, maps and simple values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    /// Looks up a map entry by an integer key, as in COSE keys
+    pub fn get_int(&self, key: i128) -> Option<&Value> {
+        self.get(&Value::Integer(key))
+    }
+
+    /// Looks up a map entry by a text key, as in attestation objects
+    pub fn get_text(&self, key: &str) -> Option<&Value> {
+        self.get(&Value::Text(key.to_string()))
+    }
+
+    fn get(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i128> {
+        match self {
+            Value::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Nested items deeper than this are rejected, so a malicious input cannot overflow the stack
+const MAX_DEPTH: usize = 16;
+
+/// Decodes the first item of `data`, returns it with the number of bytes it takes.
+pub fn decode(data: &[u8]) -> Result<(Value, usize)> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("Unexpected end of CBOR data"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reads the argument after the initial byte, which is a length or an integer
+    fn argument(&mut self, info: u8) -> Result<u64> {
+        let size = match info {
+            0..=23 => return Ok(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => bail!("Unsupported CBOR argument: {}", info),
+        };
+        let bytes = self.take(size)?;
+        Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    fn length(&mut self, info: u8) -> Result<usize> {
+        let len = self.argument(info)? as usize;
+        // Each item takes at least a byte, so a longer length is invalid
+        if len > self.data.len() - self.pos {
+            bail!("Invalid CBOR length: {}", len);
+        }
+        Ok(len)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("CBOR data is nested too deeply");
+        }
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+
+        let value = match major {
+            0 => Value::Integer(self.argument(info)? as i128),
+            1 => Value::Integer(-1 - self.argument(info)? as i128),
+            2 => {
+                let len = self.length(info)?;
+                Value::Bytes(self.take(len)?.to_vec())
+            }
+            3 => {
+                let len = self.length(info)?;
+                Value::Text(String::from_utf8(self.take(len)?.to_vec())?)
+            }
+            4 => {
+                let len = self.length(info)?;
+                let items = (0..len)
+                    .map(|_| self.value(depth + 1))
+                    .collect::<Result<_>>()?;
+                Value::Array(items)
+            }
+            5 => {
+                let len = self.length(info)?;
+                let entries = (0..len)
+                    .map(|_| Ok((self.value(depth + 1)?, self.value(depth + 1)?)))
+                    .collect::<Result<_>>()?;
+                Value::Map(entries)
+            }
+            7 => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 => Value::Null,
+                _ => bail!("Unsupported CBOR simple value: {}", info),
+            },
+            _ => bail!("Unsupported CBOR type: {}", major),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // {"fmt": "none", "attStmt": {}, "authData": h'0102'}
+        let data = [
+            0xa3, 0x63, b'f', b'm', b't', 0x64, b'n', b'o', b'n', b'e', 0x67, b'a', b't', b't',
+            b'S', b't', b'm', b't', 0xa0, 0x68, b'a', b'u', b't', b'h', b'D', b'a', b't', b'a',
+            0x42, 0x01, 0x02, 0xff,
+        ];
+        let (value, len) = decode(&data).unwrap();
+        assert_eq!(len, data.len() - 1);
+        assert_eq!(
+            value.get_text("fmt"),
+            Some(&Value::Text("none".to_string()))
+        );
+        assert_eq!(
+            value.get_text("authData").and_then(Value::as_bytes),
+            Some(&[1u8, 2][..])
+        );
+
+        // {1: 2, 3: -7, -1: 1}
+        let (value, _) = decode(&[0xa3, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01]).unwrap();
+        assert_eq!(value.get_int(3).and_then(Value::as_int), Some(-7));
+        assert_eq!(value.get_int(-1).and_then(Value::as_int), Some(1));
+
+        assert_eq!(decode(&[0x19, 0x01, 0x00]).unwrap().0, Value::Integer(256));
+        assert!(decode(&[0x42, 0x01]).is_err());
+        assert!(decode(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(decode(&[0x81; 64]).is_err());
+    }
+}
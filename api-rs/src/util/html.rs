@@ -0,0 +1,277 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cmp::Reverse;
+
+lazy_static! {
+    static ref TAG_PATTERN: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref WHITESPACE_PATTERN: Regex = Regex::new(r"\s+").unwrap();
+    static ref HEADING_PATTERN: Regex = Regex::new(r"(?s)<h[1-3][^>]*>(.*?)</h[1-3]>").unwrap();
+    static ref TAG_NAME_PATTERN: Regex = Regex::new(r"^</?\s*([a-zA-Z][a-zA-Z0-9-]*)").unwrap();
+}
+
+const VOID_ELEMENTS: &[&str] = &["br", "hr", "img", "input", "source", "wbr"];
+
+/// Removes the tags of a html fragment and collapses whitespace.
+/// Entities are kept as they are, so the text is still safe to embed in html.
+pub fn strip_tags(html: &str) -> String {
+    let text = TAG_PATTERN.replace_all(html, " ");
+    WHITESPACE_PATTERN
+        .replace_all(&text, " ")
+        .trim()
+        .to_string()
+}
+
+/// The most chars of a title derived from the content of a post
+pub const TITLE_MAX_CHARS: usize = 100;
+
+/// Returns the first heading of a post, or the beginning of its text.
+pub fn extract_title(html: &str, max_chars: usize) -> String {
+    let title = first_heading(html).unwrap_or_else(|| strip_tags(html));
+    truncate_with_ellipsis(&title, 0, max_chars)
+}
+
+/// Derives the title of a post from its first heading, it has none without a heading.
+/// Unlike the text of `extract_title`, it is plain text, with the common entities decoded.
+pub fn derive_title(html: &str) -> Option<String> {
+    first_heading(html).map(|title| truncate_with_ellipsis(&unescape(&title), 0, TITLE_MAX_CHARS))
+}
+
+/// Decodes the common entities, the others are kept as they are
+pub fn unescape(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Encodes the chars which have a meaning in html
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Returns the title of a post if it has one, or else extracts one from its content.
+pub fn display_title(title: Option<&str>, html: &str, max_chars: usize) -> String {
+    match title {
+        Some(title) => truncate_with_ellipsis(title, 0, max_chars),
+        None => extract_title(html, max_chars),
+    }
+}
+
+fn first_heading(html: &str) -> Option<String> {
+    HEADING_PATTERN
+        .captures(html)
+        .map(|caps| strip_tags(&caps[1]))
+        .filter(|title| !title.is_empty())
+}
+
+/// Returns an excerpt of `max_chars` chars of the text around the best match of the tokens:
+/// the shortest run of text containing the most distinct tokens which fits in the excerpt.
+/// The excerpt starts at the beginning of the text if no token is found.
+pub fn snippet(text: &str, tokens: &[String], max_chars: usize) -> String {
+    let lower: Vec<char> = text
+        .chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect();
+
+    // The occurrences of the tokens, as the start and end chars, and the index of the token
+    let mut matches: Vec<(usize, usize, usize)> = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let token: Vec<char> = token.to_lowercase().chars().collect();
+        if token.is_empty() || token.len() > max_chars {
+            continue;
+        }
+        for (start, window) in lower.windows(token.len()).enumerate() {
+            if window == token.as_slice() {
+                matches.push((start, start + token.len(), i));
+            }
+        }
+    }
+    matches.sort_unstable();
+
+    // Slide a window over the occurrences, keeping it within the excerpt
+    let mut counts = vec![0; tokens.len()];
+    let mut distinct = 0;
+    let mut left = 0;
+    let mut best: Option<(usize, Reverse<usize>, usize, usize)> = None;
+    for right in 0..matches.len() {
+        let (_, end, i) = matches[right];
+        if counts[i] == 0 {
+            distinct += 1;
+        }
+        counts[i] += 1;
+        while end - matches[left].0 > max_chars {
+            let j = matches[left].2;
+            counts[j] -= 1;
+            if counts[j] == 0 {
+                distinct -= 1;
+            }
+            left += 1;
+        }
+        let start = matches[left].0;
+        let candidate = (distinct, Reverse(end - start), start, end);
+        if best.as_ref().is_none_or(|best| {
+            candidate.0 > best.0 || (candidate.0 == best.0 && candidate.1 > best.1)
+        }) {
+            best = Some(candidate);
+        }
+    }
+
+    // Center the best match in the excerpt
+    let (start, end) = best.map_or((0, 0), |(_, _, start, end)| (start, end));
+    let len = lower.len();
+    let from = start.saturating_sub(max_chars.saturating_sub(end - start) / 2);
+    let from = from.min(len.saturating_sub(max_chars));
+    truncate_with_ellipsis(text, from, from + max_chars)
+}
+
+/// Cuts a html fragment after `max_chars` chars of text and closes the open tags,
+/// returns `None` if it is short enough. An entity like `&amp;` counts as one char.
+pub fn truncate_html(html: &str, max_chars: usize) -> Option<String> {
+    let mut rv = String::new();
+    let mut open_tags: Vec<String> = vec![];
+    let mut count = 0;
+    let mut last = 0;
+
+    // Tags and the text between them, with their offsets
+    let mut parts = vec![];
+    for m in TAG_PATTERN.find_iter(html) {
+        parts.push((false, last, &html[last..m.start()]));
+        parts.push((true, m.start(), m.as_str()));
+        last = m.end();
+    }
+    parts.push((false, last, &html[last..]));
+
+    for (is_tag, offset, part) in parts {
+        if is_tag {
+            let name = TAG_NAME_PATTERN
+                .captures(part)
+                .map(|caps| caps[1].to_lowercase());
+            match name {
+                Some(name) if part.starts_with("</") => {
+                    if let Some(i) = open_tags.iter().rposition(|tag| *tag == name) {
+                        open_tags.truncate(i);
+                    }
+                }
+                Some(name) if !part.ends_with("/>") && !VOID_ELEMENTS.contains(&name.as_str()) => {
+                    open_tags.push(name)
+                }
+                _ => {}
+            }
+            rv.push_str(part);
+            continue;
+        }
+
+        let mut chars = part.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if count == max_chars {
+                // Nothing is cut if only whitespace and closing tags are left
+                if strip_tags(&html[offset + i..]).is_empty() {
+                    break;
+                }
+                rv.push('…');
+                for tag in open_tags.iter().rev() {
+                    rv.push_str(&format!("</{}>", tag));
+                }
+                return Some(rv);
+            }
+
+            let entity_end = (c == '&')
+                .then(|| part[i..].find(';').filter(|n| *n <= 10))
+                .flatten();
+            match entity_end {
+                Some(n) => {
+                    rv.push_str(&part[i..=i + n]);
+                    while chars.peek().is_some_and(|(j, _)| *j <= i + n) {
+                        chars.next();
+                    }
+                }
+                None => rv.push(c),
+            }
+            count += 1;
+        }
+    }
+    None
+}
+
+/// Takes the chars in `start..end`, marking the cut ends with an ellipsis.
+fn truncate_with_ellipsis(text: &str, start: usize, end: usize) -> String {
+    let len = text.chars().count();
+    let end = end.min(len);
+    let mut rv: String = text
+        .chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect();
+
+    if start > 0 {
+        rv.insert(0, '…');
+    }
+    if end < len {
+        rv.push('…');
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tags() {
+        assert_eq!(
+            strip_tags("<p>hello <b>world</b></p>\n<p>a &lt; b</p>"),
+            "hello world a &lt; b"
+        );
+    }
+
+    #[test]
+    fn test_extract_title() {
+        assert_eq!(
+            extract_title("<h2>Plan <i>A</i></h2><p>body</p>", 20),
+            "Plan A"
+        );
+        assert_eq!(extract_title("<p>just some text</p>", 9), "just some…");
+        assert_eq!(
+            derive_title("<p>a</p><h1>Plan</h1>"),
+            Some("Plan".to_string())
+        );
+        assert_eq!(derive_title("<p>just some text</p><h2> </h2>"), None);
+        assert_eq!(
+            derive_title("<h1>A &amp; B &lt;C&gt;</h1>"),
+            Some("A & B <C>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_truncate_html() {
+        assert_eq!(truncate_html("<p>hello</p>", 5), None);
+        assert_eq!(
+            truncate_html("<p>hello <b>world</b></p><p>more</p>", 8).unwrap(),
+            "<p>hello <b>wo…</b></p>"
+        );
+        assert_eq!(
+            truncate_html("<p>a &amp; b<br>c d</p>", 6).unwrap(),
+            "<p>a &amp; b<br>c…</p>"
+        );
+        assert_eq!(truncate_html("<p>你好世界</p>", 2).unwrap(), "<p>你好…</p>");
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(snippet(text, &["FOX".to_string()], 15), "…brown fox jumps…");
+        assert_eq!(snippet(text, &["cat".to_string()], 4), "the …");
+        assert_eq!(snippet("你好世界", &["世界".to_string()], 3), "…好世界");
+
+        // The window with the most tokens wins over the first match
+        let text = "rust is fast. Many words are here, then rust and async go together at last";
+        let tokens = vec!["rust".to_string(), "async".to_string()];
+        assert_eq!(snippet(text, &tokens, 20), "…en rust and async go…");
+    }
+}
@@ -0,0 +1,153 @@
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Locale {
+    /// Picks the supported language with the highest quality from an `Accept-Language` header,
+    /// e.g. `zh-CN,zh;q=0.9,en;q=0.8`.
+    pub fn from_accept_language(header: &str) -> Self {
+        header
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.trim().split(';');
+                let tag = parts.next()?.trim().to_lowercase();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                let locale = match tag.split('-').next()? {
+                    "zh" => Locale::Zh,
+                    "en" => Locale::En,
+                    _ => return None,
+                };
+                Some((locale, quality))
+            })
+            .fold(None, |best: Option<(Locale, f32)>, item| match best {
+                Some(best) if best.1 >= item.1 => Some(best),
+                _ => Some(item),
+            })
+            .map(|(locale, _)| locale)
+            .unwrap_or_default()
+    }
+}
+
+lazy_static! {
+    // Messages are written in English, so only other languages need a catalog.
+    static ref ZH_CATALOG: HashMap<&'static str, &'static str> = HashMap::from([
+        // Reasons
+        ("Bad Request", "请求错误"),
+        ("Unauthorized", "未授权"),
+        ("Forbidden", "禁止访问"),
+        ("Not Found", "未找到"),
+        ("Method Not Allowed", "不支持的请求方法"),
+        ("Conflict", "冲突"),
+        ("Payload Too Large", "请求内容过大"),
+        ("Too Many Requests", "请求过于频繁"),
+        ("Internal Server Error", "服务器内部错误"),
+        ("Insufficient Storage", "存储空间不足"),
+        ("Service Unavailable", "服务暂不可用"),
+        // Messages
+        ("Invalid Multipart", "无效的表单数据"),
+        ("Invalid file type", "无效的文件类型"),
+        ("Invalid filename", "无效的文件名"),
+        ("Invalid token", "无效的令牌"),
+        ("No token provided", "未提供令牌"),
+        ("Invalid admin token", "无效的管理令牌"),
+        ("No admin token provided", "未提供管理令牌"),
+        ("Admin routes are not allowed from this address", "不允许从此地址访问管理接口"),
+        ("wrong password", "密码错误"),
+        ("post not found", "笔记不存在"),
+        ("Post not found", "笔记不存在"),
+        ("file not found", "文件不存在"),
+        ("File not found", "文件不存在"),
+        ("Data not found", "数据不存在"),
+        ("cannot scan file", "无法扫描文件"),
+        ("upload quota exceeded", "超出上传空间配额"),
+        ("optimization is already running", "优化正在进行中"),
+        ("Job not found", "任务不存在"),
+        ("Session not found", "会话不存在"),
+        ("Passkey not found", "通行密钥不存在"),
+        ("Passkeys are not enabled", "未启用通行密钥"),
+        ("job is already running", "任务正在运行中"),
+        ("Too many attempts, try again later", "尝试次数过多，请稍后再试"),
+        ("Unique value already in use", "该值已被使用"),
+        ("Missing related record", "缺少关联的记录"),
+        ("Missing required field", "缺少必填字段"),
+        ("Invalid input value", "无效的输入值"),
+        ("Redis is unavailable", "Redis 暂不可用"),
+        // Messages with details after the colon
+        ("post is linked from other posts", "笔记被其他笔记引用"),
+        ("file is infected", "文件含有病毒"),
+        ("invalid passkey", "无效的通行密钥"),
+        ("post content is too large", "笔记内容过长"),
+        // Validation messages and codes
+        ("can not be empty", "不能为空"),
+        ("must be a valid timestamp", "必须是有效的时间戳"),
+        ("must be an emoji", "必须是表情符号"),
+        ("must be a hex color", "必须是十六进制颜色"),
+        ("must be in 'yyyy-MM-dd' format", "必须是 yyyy-MM-dd 格式"),
+        ("length", "长度无效"),
+        ("range", "超出范围"),
+        ("email", "无效的邮箱地址"),
+        ("url", "无效的网址"),
+        ("required", "不能为空"),
+    ]);
+}
+
+/// Translates a message, unknown messages are returned as they are.
+/// For messages like `file is infected: xxx`, only the part before the colon is translated.
+pub fn translate(locale: Locale, text: &str) -> Cow<'_, str> {
+    let catalog = match locale {
+        Locale::En => return Cow::Borrowed(text),
+        Locale::Zh => &*ZH_CATALOG,
+    };
+
+    if let Some(translated) = catalog.get(text) {
+        return Cow::Borrowed(translated);
+    }
+    if let Some((head, detail)) = text.split_once(": ") {
+        if let Some(translated) = catalog.get(head) {
+            return Cow::Owned(format!("{}: {}", translated, detail));
+        }
+    }
+    Cow::Borrowed(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_accept_language() {
+        assert_eq!(
+            Locale::from_accept_language("zh-CN,zh;q=0.9,en;q=0.8"),
+            Locale::Zh
+        );
+        assert_eq!(Locale::from_accept_language("en-US,zh;q=0.5"), Locale::En);
+        assert_eq!(
+            Locale::from_accept_language("fr;q=1.0,zh;q=0.3"),
+            Locale::Zh
+        );
+        assert_eq!(Locale::from_accept_language("fr"), Locale::En);
+        assert_eq!(Locale::from_accept_language(""), Locale::En);
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate(Locale::Zh, "Not Found"), "未找到");
+        assert_eq!(
+            translate(Locale::Zh, "file is infected: Eicar"),
+            "文件含有病毒: Eicar"
+        );
+        assert_eq!(translate(Locale::Zh, "something else"), "something else");
+        assert_eq!(translate(Locale::En, "Not Found"), "Not Found");
+    }
+}
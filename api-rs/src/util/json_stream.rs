@@ -0,0 +1,72 @@
+use axum::body::{Body, Bytes};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use futures::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+use std::fmt::Debug;
+use std::io;
+use tracing::error;
+
+/// A response which serializes the items of a stream into a JSON array as they come,
+/// so that large listings are never buffered as a whole.
+///
+/// The status is sent before the items are read, so an error in the middle aborts the response,
+/// and clients get an incomplete body rather than a truncated but valid array.
+pub struct JsonArray<S>(pub S);
+
+impl<S, T, E> IntoResponse for JsonArray<S>
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Serialize,
+    E: Debug,
+{
+    fn into_response(self) -> Response {
+        let items = self.0.enumerate().map(|(i, item)| {
+            let item = item.map_err(|e| {
+                error!("cannot stream items: {:?}", e);
+                io::Error::other("stream aborted")
+            })?;
+
+            let mut buf = if i == 0 { vec![] } else { vec![b','] };
+            serde_json::to_writer(&mut buf, &item)?;
+            Ok::<_, io::Error>(Bytes::from(buf))
+        });
+
+        let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+            .chain(items)
+            .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
+
+        (
+            [(header::CONTENT_TYPE, "application/json")],
+            Body::from_stream(body),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn to_string<S, T>(items: S) -> Result<String, axum::Error>
+    where
+        S: Stream<Item = Result<T, io::Error>> + Send + 'static,
+        T: Serialize,
+    {
+        let body = JsonArray(items).into_response().into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await?;
+        Ok(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_json_array() {
+        let items = stream::iter(vec![Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(to_string(items).await.unwrap(), "[1,2,3]");
+
+        let items = stream::iter(Vec::<Result<i32, io::Error>>::new());
+        assert_eq!(to_string(items).await.unwrap(), "[]");
+
+        let items = stream::iter(vec![Ok(1), Err(io::Error::other("oops"))]);
+        assert!(to_string(items).await.is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use crate::util::html::unescape;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref TOKEN_PATTERN: Regex = Regex::new(r"(?s)<!--.*?-->|<[^>]*>|[^<]+").unwrap();
+    static ref TAG_PATTERN: Regex = Regex::new(r"^<\s*(/?)\s*([a-zA-Z][a-zA-Z0-9]*)").unwrap();
+    static ref ATTR_PATTERN: Regex =
+        Regex::new(r#"([a-zA-Z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    static ref WHITESPACE_PATTERN: Regex = Regex::new(r"\s+").unwrap();
+}
+
+/// Converts the html of a post into Markdown, the tags without a Markdown syntax are dropped but their text is kept.
+/// Hashtags are kept as they are, and `link` rewrites the urls of links and images,
+/// e.g. to the paths of files next to the Markdown file.
+pub fn html_to_markdown(html: &str, link: &dyn Fn(&str) -> String) -> String {
+    let mut writer = Writer::new(link);
+    for token in TOKEN_PATTERN.find_iter(html).map(|m| m.as_str()) {
+        if token.starts_with("<!--") {
+            continue;
+        }
+        match TAG_PATTERN.captures(token) {
+            Some(caps) => writer.tag(&caps[2].to_lowercase(), caps[1].is_empty(), token),
+            None => writer.text(token),
+        }
+    }
+    writer.finish()
+}
+
+/// Wraps a url in angle brackets if it would end a Markdown link early
+pub fn link_destination(url: &str) -> String {
+    if url.contains([' ', '(', ')']) {
+        format!("<{}>", url)
+    } else {
+        url.to_string()
+    }
+}
+
+/// Escapes the chars of text which Markdown would take for syntax
+pub fn escape(text: &str) -> String {
+    let mut rv = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<') {
+            rv.push('\\');
+        }
+        rv.push(c);
+    }
+    rv
+}
+
+struct List {
+    ordered: bool,
+    next: usize,
+}
+
+impl List {
+    /// The indentation of the lines of an item after its first one
+    fn indent(&self) -> &'static str {
+        if self.ordered {
+            "   "
+        } else {
+            "  "
+        }
+    }
+}
+
+struct Writer<'a> {
+    out: String,
+    link: &'a dyn Fn(&str) -> String,
+    /// The newlines to write before the next text, they are written lazily so that the prefix of a line is known
+    breaks: usize,
+    line_start: bool,
+    /// The marker of a list item, written before its first text
+    marker: Option<String>,
+    quotes: usize,
+    lists: Vec<List>,
+    /// The urls of the open links, none for anchors without one
+    links: Vec<Option<String>>,
+    /// Whether each open span is a hashtag
+    spans: Vec<bool>,
+    pre: bool,
+    code: bool,
+}
+
+impl<'a> Writer<'a> {
+    fn new(link: &'a dyn Fn(&str) -> String) -> Self {
+        Self {
+            out: String::new(),
+            link,
+            breaks: 0,
+            line_start: true,
+            marker: None,
+            quotes: 0,
+            lists: vec![],
+            links: vec![],
+            spans: vec![],
+            pre: false,
+            code: false,
+        }
+    }
+
+    fn tag(&mut self, name: &str, open: bool, token: &str) {
+        match name {
+            "p" | "div" => self.block(),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block();
+                if open {
+                    let level = name[1..].parse().unwrap_or(1);
+                    self.write(&format!("{} ", "#".repeat(level)));
+                }
+            }
+            "br" if self.pre => self.breaks += 1,
+            "br" => {
+                self.write("\\");
+                self.breaks = 1;
+            }
+            "hr" => {
+                self.block();
+                self.write("---");
+                self.block();
+            }
+            "strong" | "b" => self.write("**"),
+            "em" | "i" => self.write("*"),
+            "s" | "del" | "strike" => self.write("~~"),
+            "code" if !self.pre => {
+                self.code = open;
+                self.write("`");
+            }
+            "pre" if open => {
+                self.block();
+                self.write("```");
+                self.pre = true;
+                self.breaks = 1;
+            }
+            "pre" => {
+                self.pre = false;
+                self.breaks = 1;
+                self.write("```");
+                self.block();
+            }
+            "blockquote" => {
+                self.block();
+                self.quotes = if open {
+                    self.quotes + 1
+                } else {
+                    self.quotes.saturating_sub(1)
+                };
+            }
+            "ul" | "ol" => {
+                self.block();
+                if open {
+                    let next = attribute(token, "start")
+                        .and_then(|start| start.parse().ok())
+                        .unwrap_or(1);
+                    self.lists.push(List {
+                        ordered: name == "ol",
+                        next,
+                    });
+                } else {
+                    self.lists.pop();
+                    // A blank line follows the outermost list only
+                    self.breaks = self.breaks.max(if self.lists.is_empty() { 2 } else { 1 });
+                }
+            }
+            "li" => {
+                self.breaks = self.breaks.max(1);
+                if open {
+                    self.marker = Some(match self.lists.last_mut() {
+                        Some(list) if list.ordered => {
+                            list.next += 1;
+                            format!("{}. ", list.next - 1)
+                        }
+                        _ => "- ".to_string(),
+                    });
+                }
+            }
+            "a" if open => {
+                let href = attribute(token, "href");
+                if href.is_some() {
+                    self.write("[");
+                }
+                self.links.push(href);
+            }
+            "a" => {
+                if let Some(Some(href)) = self.links.pop() {
+                    let url = (self.link)(&href);
+                    self.write(&format!("]({})", link_destination(&url)));
+                }
+            }
+            "img" => {
+                if let Some(src) = attribute(token, "src") {
+                    let alt = attribute(token, "alt").unwrap_or_default();
+                    let url = (self.link)(&src);
+                    self.write(&format!("![{}]({})", escape(&alt), link_destination(&url)));
+                }
+            }
+            "span" if open => {
+                let class = attribute(token, "class").unwrap_or_default();
+                self.spans
+                    .push(class.split_whitespace().any(|c| c == "hash-tag"));
+            }
+            "span" => {
+                self.spans.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn text(&mut self, token: &str) {
+        let text = unescape(token);
+        if self.pre {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.breaks += 1;
+                }
+                if !line.is_empty() {
+                    self.write(line);
+                }
+            }
+            return;
+        }
+
+        let text = WHITESPACE_PATTERN.replace_all(&text, " ");
+        let text = if self.breaks > 0 || self.line_start {
+            text.trim_start()
+        } else {
+            &text
+        };
+        if text.is_empty() {
+            return;
+        }
+        if self.code || self.spans.last() == Some(&true) {
+            self.write(text);
+        } else {
+            self.write(&escape(text));
+        }
+    }
+
+    /// Starts or ends a block, separated by a blank line, or by a newline in a list
+    fn block(&mut self) {
+        let breaks = if self.lists.is_empty() { 2 } else { 1 };
+        self.breaks = self.breaks.max(breaks);
+    }
+
+    fn prefix(&self, lists: usize) -> String {
+        let mut prefix = "> ".repeat(self.quotes);
+        for list in &self.lists[..lists] {
+            prefix.push_str(list.indent());
+        }
+        prefix
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.breaks > 0 && !self.out.is_empty() {
+            for i in 0..self.breaks {
+                self.out.push('\n');
+                // Blank lines keep the quote marks, without trailing spaces
+                if i + 1 < self.breaks {
+                    let prefix = self.prefix(self.lists.len());
+                    self.out.push_str(prefix.trim_end());
+                }
+            }
+            self.line_start = true;
+        }
+        self.breaks = 0;
+
+        if self.line_start {
+            // The marker of an item is indented like the items of the outer lists
+            let lists =
+                self.lists.len() - usize::from(self.marker.is_some() && !self.lists.is_empty());
+            let prefix = self.prefix(lists);
+            self.out.push_str(&prefix);
+            if let Some(marker) = self.marker.take() {
+                self.out.push_str(&marker);
+            }
+            self.line_start = false;
+        }
+        self.out.push_str(text);
+    }
+
+    fn finish(self) -> String {
+        let mut out = self.out.trim_end().to_string();
+        out.push('\n');
+        out
+    }
+}
+
+/// Returns the decoded value of an attribute of a tag
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    ATTR_PATTERN
+        .captures_iter(tag)
+        .find(|caps| caps[1].eq_ignore_ascii_case(name))
+        .and_then(|caps| caps.get(2).or(caps.get(3)))
+        .map(|value| unescape(value.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(html: &str) -> String {
+        html_to_markdown(html, &|url| url.replace("/uploads/", "attachments/"))
+    }
+
+    #[test]
+    fn test_html_to_markdown() {
+        assert_eq!(
+            convert(
+                r#"<h1>Plan &amp; <em>notes</em></h1><p>See <a href="https://example.com/a b">this</a>, a*b
+                <span class="hash-tag">#work/q_1</span></p><p>line<br>break</p>"#
+            ),
+            "# Plan & *notes*\n\nSee [this](<https://example.com/a b>), a\\*b #work/q_1\n\nline\\\nbreak\n"
+        );
+        assert_eq!(
+            convert("<ul><li><p>one</p><ol start=\"3\"><li>two</li><li>three</li></ol></li><li>four</li></ul><p>end</p>"),
+            "- one\n  3. two\n  4. three\n- four\n\nend\n"
+        );
+        assert_eq!(
+            convert("<blockquote><p>a</p><p>b</p></blockquote><pre><code>let a = 1;\n\nlet b = 2;</code></pre>"),
+            "> a\n>\n> b\n\n```\nlet a = 1;\n\nlet b = 2;\n```\n"
+        );
+        assert_eq!(
+            convert(r#"<p><img src="/uploads/a.png" alt="[x]"><code>a*b</code></p>"#),
+            "![\\[x\\]](attachments/a.png)`a*b`\n"
+        );
+    }
+}
@@ -9,6 +9,8 @@ pub enum MaybeAbsent<T> {
     Absent,
 }
 
+// Derived, it would require `T: Default`
+#[allow(clippy::derivable_impls)]
 impl<T> Default for MaybeAbsent<T> {
     fn default() -> Self {
         Self::Absent
@@ -1,4 +1,12 @@
+pub mod cbor;
 pub mod env;
 pub mod extractor;
 pub mod fp;
+pub mod html;
+pub mod i18n;
+pub mod json_stream;
+pub mod markdown;
 pub mod maybe;
+pub mod net;
+pub mod retry;
+pub mod s3;
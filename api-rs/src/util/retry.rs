@@ -0,0 +1,182 @@
+use rand::Rng;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How an operation is tried again after a transient error.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The retries after the first attempt, 0 to try only once
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each following one
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const NONE: RetryPolicy = RetryPolicy {
+        retries: 0,
+        base_delay: Duration::ZERO,
+    };
+
+    /// Returns the delay before the retry, counted from 1.
+    /// It is between half and all of the doubled delay, so that clients failing together do not retry together.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let max = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        let half = max / 2;
+        half + (max - half).mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// Calls fail at once, until the cooldown is over
+    Open,
+    /// The cooldown is over, the next call closes the breaker if it succeeds, or opens it again
+    HalfOpen,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    /// The failures in a row
+    pub failures: u32,
+    /// How many times the breaker has opened since the app started
+    pub trips: u64,
+}
+
+/// The error of a call refused by an open breaker.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub name: &'static str,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is unavailable", self.name)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    failures: u32,
+    opened_at: Option<Instant>,
+    trips: u64,
+}
+
+/// Stops calling a failing service for a while, so that requests fail fast instead of waiting for timeouts.
+/// It opens after `threshold` failures in a row, and lets a call through after `cooldown`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(at) if at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn allows(&self) -> bool {
+        self.state() != BreakerState::Open
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = 0;
+        inner.opened_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        if inner.failures >= self.threshold {
+            if inner.opened_at.is_none() {
+                inner.trips += 1;
+            }
+            // A failed call after the cooldown opens it again
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let state = self.state();
+        let inner = self.inner.lock().unwrap();
+        BreakerStatus {
+            state,
+            failures: inner.failures,
+            trips: inner.trips,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            retries: 3,
+            base_delay: Duration::from_millis(100),
+        };
+        for (retry, max) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = policy.delay(retry);
+            let max = Duration::from_millis(max);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+        assert_eq!(RetryPolicy::NONE.delay(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allows());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.status().trips, 1);
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.status().failures, 0);
+
+        let disabled = CircuitBreaker::new(0, Duration::from_secs(1));
+        for _ in 0..10 {
+            disabled.record_failure();
+        }
+        assert!(disabled.allows());
+    }
+}
@@ -13,7 +13,7 @@ import { AppError } from '@/error.ts'
 
 export function useLogin() {
   const { trigger, error, isMutating } = useSWRMutation<
-    { token: string }, // Data
+    { token?: string }, // Data
     AppError, // Error
     string, // Key
     string // ExtraArg
@@ -30,10 +30,12 @@ export function useLogin() {
     if (!password) {
       return
     }
-    await trigger(password)
-    localStorage.setItem('token', password)
+    const data = await trigger(password)
+    // NOTE: Servers which do not start sessions accept the password itself as the token
+    const token = data.token ?? password
+    localStorage.setItem('token', token)
     // NOTE: Cookie is used to utilize nginx `auth_request`
-    setCookie('token', password, -1)
+    setCookie('token', token, -1)
 
     void navigate(from, { replace: true })
   }